@@ -3,6 +3,7 @@ package me.caelunshun.quicproxy.client;
 import me.caelunshun.quicproxy.jni.RustLoader;
 import me.caelunshun.quicproxy.jni.RustQuicContext;
 import net.fabricmc.api.ClientModInitializer;
+import net.fabricmc.loader.api.FabricLoader;
 
 import java.io.IOException;
 
@@ -21,7 +22,9 @@ public class QUICProxyClient implements ClientModInitializer {
         } catch (IOException e) {
             e.printStackTrace();
         }
-        quicContext = new RustQuicContext();
+        String storePath = FabricLoader.getInstance().getConfigDir()
+                .resolve("quic-proxy-store.json").toString();
+        quicContext = new RustQuicContext(storePath);
     }
 
     public RustQuicContext getQuicContext() {
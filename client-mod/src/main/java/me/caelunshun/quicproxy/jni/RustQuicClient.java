@@ -24,6 +24,86 @@ public class RustQuicClient {
         lock.unlock();
     }
 
+    /**
+     * Describes the parameters negotiated with the gateway,
+     * e.g. QUIC version and whether datagrams are in use.
+     */
+    public String getNegotiated() {
+        lock.lock();
+        String result = getNegotiated(ptr);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Describes the bandwidth used over the last second,
+     * split into chunk data, entity movement, chat and other.
+     */
+    public String getBandwidth() {
+        lock.lock();
+        String result = getBandwidth(ptr);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Gets the connection statistics as a JSON object: the QUIC
+     * {@code transport} statistics (RTT, congestion window, lost packets,
+     * UDP bytes in each direction), the {@code dropped_datagrams} that
+     * arrived outdated, and the {@code play} packets and bytes received
+     * and sent.
+     */
+    public String getStats() {
+        lock.lock();
+        String result = getStats(ptr);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Summarizes the lag events (loss bursts, stalls, RTT spikes) of the
+     * last {@code windowSeconds}, e.g. "3 loss bursts in the last minute".
+     */
+    public String getLagSummary(int windowSeconds) {
+        lock.lock();
+        String result = getLagSummary(ptr, windowSeconds);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Gets the lag events of the last {@code windowSeconds} as a JSON array,
+     * oldest first. Each event has a {@code kind}, a {@code started_at_millis}
+     * Unix timestamp and a {@code duration_millis}.
+     */
+    public String getLagEvents(int windowSeconds) {
+        lock.lock();
+        String result = getLagEvents(ptr, windowSeconds);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Compares the round-trip times of probes sent over QUIC and over TCP,
+     * e.g. "QUIC: p50 21.0 ms, ...; TCP: p50 24.5 ms, ...", or returns null
+     * if measurement is not enabled for this session.
+     */
+    public String getMeasurementReport() {
+        lock.lock();
+        String result = getMeasurementReport(ptr);
+        lock.unlock();
+        return result;
+    }
+
+    /**
+     * Closes the session, also if the game has not connected to it yet.
+     */
+    public void close() {
+        lock.lock();
+        close(ptr);
+        lock.unlock();
+    }
+
     @Override
     protected void finalize() {
         lock.lock();
@@ -33,5 +113,12 @@ public class RustQuicClient {
 
     private static native int getPort(long ptr);
     private static native void enableEncryption(long ptr, byte[] key);
+    private static native String getNegotiated(long ptr);
+    private static native String getBandwidth(long ptr);
+    private static native String getStats(long ptr);
+    private static native String getLagSummary(long ptr, int windowSeconds);
+    private static native String getLagEvents(long ptr, int windowSeconds);
+    private static native String getMeasurementReport(long ptr);
+    private static native void close(long ptr);
     private static native void drop(long ptr);
 }
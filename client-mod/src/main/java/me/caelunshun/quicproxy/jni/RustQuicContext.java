@@ -3,13 +3,47 @@ package me.caelunshun.quicproxy.jni;
 public class RustQuicContext {
     private final long ptr;
 
-    public RustQuicContext() {
-        ptr = init();
+    /**
+     * @param storePath file in which state about gateways (pinned certificates,
+     *                  affinity tokens, transport hints) is kept across restarts,
+     *                  or null to keep nothing
+     */
+    public RustQuicContext(String storePath) {
+        ptr = init(storePath);
     }
 
+    /**
+     * Closes any previous client for the same destination server,
+     * so that a retried connection does not leave the old one behind.
+     *
+     * @param destinationServerAddress socket address of the destination, or its host name
+     *                                 with an optional port for the gateway to resolve
+     * @param handshakeHost host that the handshake sent to the destination is addressed to,
+     *                      typically the address the player entered
+     * @param handshakePort port that the handshake is addressed to
+     */
     public RustQuicClient createClient(String gatewayHost, int gatewayPort,
-                                       String destinationServerAddress, String authenticationKey) {
-        return new RustQuicClient(createClient(ptr, gatewayHost, gatewayPort, destinationServerAddress, authenticationKey));
+                                       String destinationServerAddress, String authenticationKey,
+                                       String handshakeHost, int handshakePort) {
+        return new RustQuicClient(createClient(ptr, gatewayHost, gatewayPort, destinationServerAddress,
+                authenticationKey, handshakeHost, handshakePort));
+    }
+
+    /**
+     * Gets the local port of the current client for the destination server,
+     * which is the one to connect to, or -1 if there is none or it has ended.
+     */
+    public int getSessionPort(String destinationServerAddress) {
+        return getSessionPort(ptr, destinationServerAddress);
+    }
+
+    /**
+     * Sets whether clients created afterwards send the same probes over QUIC
+     * and over TCP, to compare their round-trip times. Requires a gateway
+     * with a measurement endpoint. Disabled by default.
+     */
+    public void setMeasurementEnabled(boolean enabled) {
+        setMeasurementEnabled(ptr, enabled);
     }
 
     @Override
@@ -17,8 +51,11 @@ public class RustQuicContext {
         drop(ptr);
     }
 
-    private static native long init();
+    private static native long init(String storePath);
     private static native long createClient(long ptr, String gatewayHost, int gatewayPort,
-                                            String destinationServerAddress, String authenticationKey);
+                                            String destinationServerAddress, String authenticationKey,
+                                            String handshakeHost, int handshakePort);
+    private static native int getSessionPort(long ptr, String destinationServerAddress);
+    private static native void setMeasurementEnabled(long ptr, boolean enabled);
     private static native void drop(long ptr);
 }
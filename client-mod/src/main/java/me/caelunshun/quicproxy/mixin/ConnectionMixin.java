@@ -27,7 +27,8 @@ public class ConnectionMixin implements ConnectionExt {
         this.type = ConnectionType.QUIC;
         String address = destinationServer.getAddress().getHostAddress() + ":" + destinationServer.getPort();
         this.quicClient = QUICProxyClient.instance.getQuicContext()
-                .createClient(gatewayAddress, gatewayPort, address, authenticationKey);
+                .createClient(gatewayAddress, gatewayPort, address, authenticationKey,
+                        destinationServer.getHostString(), destinationServer.getPort());
 
         InetSocketAddress clientAddr = new InetSocketAddress("127.0.0.1", quicClient.getPort());
 
@@ -1,14 +1,26 @@
 use anyhow::{anyhow, Context as _};
 use jni::{
     objects::{JByteArray, JClass, JString},
-    sys::{jint, jlong},
+    sys::{jboolean, jint, jlong, jstring, JNI_TRUE},
     JNIEnv,
 };
 use minecraft_quic_proxy::{
-    client::ClientHandle,
+    prelude::{
+        BuildInfo, ClientHandle, ClientOptions, ClientSessions, ClientStore, Destination,
+        MeasurementOptions, Resolver, ResolverBackend,
+    },
     quinn::{ClientConfig, Endpoint},
 };
-use std::{convert::identity, panic, panic::AssertUnwindSafe, sync::Arc};
+use std::{
+    convert::identity,
+    panic,
+    panic::AssertUnwindSafe,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{runtime, runtime::Runtime};
 
 unsafe fn deref_from_long<'a, T>(long: jlong) -> &'a T {
@@ -18,41 +30,73 @@ unsafe fn deref_from_long<'a, T>(long: jlong) -> &'a T {
 struct Context {
     runtime: Runtime,
     endpoint: Endpoint,
+    client_config: ClientConfig,
+    store: Option<Arc<ClientStore>>,
+    resolver: Arc<Resolver>,
+    /// Lets a retried `createClient` close the session it replaces.
+    sessions: ClientSessions,
+    /// Whether new clients compare QUIC and TCP round-trip times.
+    measurement: AtomicBool,
 }
 
+/// # Safety
+///
+/// Must only be called by the JVM, with a valid `JNIEnv`.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_init(
     mut env: JNIEnv,
     _class: JClass,
+    store_path: JString,
 ) -> jlong {
-    wrap_with_error_handling(&mut env, |_env| {
+    wrap_with_error_handling(&mut env, |env| {
         tracing_subscriber::fmt()
             .with_max_level(tracing_subscriber::filter::LevelFilter::DEBUG)
             .with_ansi(false)
             .try_init()
             .ok();
         std::env::set_var("RUST_BACKTRACE", "1");
+        tracing::info!("Loaded {}", BuildInfo::current());
 
         let runtime = runtime::Builder::new_multi_thread().enable_all().build()?;
         let _guard = runtime.enter();
 
         #[cfg(feature = "ignore-server-certificates")]
-        let mut client_config = {
+        let client_config = {
             let crypto = rustls::ClientConfig::builder()
                 .with_safe_defaults()
                 .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
                 .with_no_client_auth();
-            ClientConfig::new(Arc::new(crypto))
+            minecraft_quic_proxy::client_config_with_crypto(crypto)
         };
         #[cfg(not(feature = "ignore-server-certificates"))]
-        let mut client_config = ClientConfig::with_native_roots();
-
-        client_config.transport_config(Arc::new(minecraft_quic_proxy::transport_config()));
+        let client_config = minecraft_quic_proxy::client_config()?;
 
         let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
-        endpoint.set_default_client_config(client_config);
+        endpoint.set_default_client_config(client_config.clone());
 
-        let context = Box::new(Context { runtime, endpoint });
+        let store = if store_path.is_null() {
+            None
+        } else {
+            let store_path = env.get_string(&store_path)?.to_string_lossy().into_owned();
+            Some(ClientStore::open(store_path))
+        };
+
+        // Caches the gateway's address across joins, so a slow
+        // system resolver only delays the first one.
+        let resolver = Resolver::new(ResolverBackend::Caching).or_else(|e| {
+            tracing::warn!("Using the system resolver: {e:#}");
+            Resolver::new(ResolverBackend::System)
+        })?;
+
+        let context = Box::new(Context {
+            runtime,
+            endpoint,
+            client_config,
+            store,
+            resolver,
+            sessions: ClientSessions::new(),
+            measurement: AtomicBool::new(false),
+        });
         Ok(Box::into_raw(context) as jlong)
     })
 }
@@ -75,6 +119,10 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
+/// # Safety
+///
+/// `context_ptr` must have been returned by `RustQuicContext.init`
+/// and not have been dropped yet.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_createClient(
     mut env: JNIEnv,
@@ -84,6 +132,8 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_c
     gateway_port: jint,
     destination_address: JString,
     authentication_key: JString,
+    handshake_host: JString,
+    handshake_port: jint,
 ) -> jlong {
     wrap_with_error_handling(&mut env, |env| {
         let context = deref_from_long::<Context>(context_ptr);
@@ -99,24 +149,96 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_c
             .get_string(&gateway_host)?
             .to_string_lossy()
             .into_owned();
+        let handshake_host = env
+            .get_string(&handshake_host)?
+            .to_string_lossy()
+            .into_owned();
+        let options = ClientOptions {
+            handshake_address: Some((handshake_host, handshake_port as u16)),
+            // Set per connection so that transport hints from the store apply.
+            client_config: Some(context.client_config.clone()),
+            request_affinity: context.store.is_some(),
+            store: context.store.clone(),
+            // Without certificate verification, pinning is the only
+            // protection against a different gateway.
+            pin_certificates: cfg!(feature = "ignore-server-certificates"),
+            resolver: Some(Arc::clone(&context.resolver)),
+            measurement: context
+                .measurement
+                .load(Ordering::Relaxed)
+                .then(MeasurementOptions::default),
+            // Only takes effect on gateways that grant resumption.
+            resume_sessions: true,
+            ..ClientOptions::default()
+        };
 
-        let destination_address = destination_address.parse()?;
+        let destination_address: Destination = destination_address.parse()?;
         let client = context.runtime.block_on(async move {
-            ClientHandle::open(
+            ClientHandle::open_with_options(
                 &context.endpoint,
                 &gateway_host,
                 gateway_port as u16,
                 destination_address,
                 &authentication_key,
+                &options,
             )
             .await
             .context("failed to connect to gateway")
         })?;
+        context.sessions.register(&client);
 
         Ok(Box::into_raw(Box::new(client)) as jlong)
     })
 }
 
+/// # Safety
+///
+/// `context_ptr` must have been returned by `RustQuicContext.init`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_getSessionPort(
+    mut env: JNIEnv,
+    _class: JClass,
+    context_ptr: jlong,
+    destination_address: JString,
+) -> jint {
+    wrap_with_error_handling(&mut env, |env| {
+        let context = deref_from_long::<Context>(context_ptr);
+        let destination_address = env
+            .get_string(&destination_address)?
+            .to_string_lossy()
+            .parse::<Destination>()?;
+        Ok(context
+            .sessions
+            .port(&destination_address)
+            .map_or(-1, jint::from))
+    })
+}
+
+/// # Safety
+///
+/// `context_ptr` must have been returned by `RustQuicContext.init`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_setMeasurementEnabled(
+    _env: JNIEnv,
+    _class: JClass,
+    context_ptr: jlong,
+    enabled: jboolean,
+) {
+    let context = deref_from_long::<Context>(context_ptr);
+    context
+        .measurement
+        .store(enabled == JNI_TRUE, Ordering::Relaxed);
+}
+
+/// # Safety
+///
+/// `context_ptr` must have been returned by `RustQuicContext.init`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_drop(
     mut env: JNIEnv,
@@ -129,6 +251,10 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicContext_d
     })
 }
 
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getPort(
     _env: JNIEnv,
@@ -139,6 +265,10 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_ge
     client.bound_port() as jint
 }
 
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_enableEncryption(
     mut env: JNIEnv,
@@ -155,6 +285,140 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_en
     })
 }
 
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getNegotiated(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        Ok(env.new_string(client.negotiated().to_string())?)
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getBandwidth(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        Ok(env.new_string(client.bandwidth().to_string())?)
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getStats(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        let stats = serde_json::to_string(&client.stats())?;
+        Ok(env.new_string(stats)?)
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getLagSummary(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+    window_secs: jint,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        let window = Duration::from_secs(window_secs.try_into()?);
+        Ok(env.new_string(client.lag_events().summary(window).to_string())?)
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getLagEvents(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+    window_secs: jint,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        let window = Duration::from_secs(window_secs.try_into()?);
+        let events = serde_json::to_string(&client.lag_events().recent(window))?;
+        Ok(env.new_string(events)?)
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_getMeasurementReport(
+    mut env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+) -> jstring {
+    wrap_with_error_handling(&mut env, |env| {
+        let client: &ClientHandle = deref_from_long(client_ptr);
+        match client.measurement() {
+            Some(measurement) => Ok(env.new_string(measurement.report().to_string())?),
+            None => Ok(JString::default()),
+        }
+    })
+    .into_raw()
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+#[no_mangle]
+pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_close(
+    _env: JNIEnv,
+    _class: JClass,
+    client_ptr: jlong,
+) {
+    let client: &ClientHandle = deref_from_long(client_ptr);
+    client.close("closed by the game");
+}
+
+/// # Safety
+///
+/// `client_ptr` must have been returned by `RustQuicContext.createClient`
+/// and not have been dropped yet.
+/// It must not be used again afterwards.
 #[no_mangle]
 pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_drop(
     mut env: JNIEnv,
@@ -167,9 +431,9 @@ pub unsafe extern "system" fn Java_me_caelunshun_quicproxy_jni_RustQuicClient_dr
     })
 }
 
-fn wrap_with_error_handling<R: Default>(
-    env: &mut JNIEnv,
-    callback: impl FnOnce(&mut JNIEnv) -> anyhow::Result<R>,
+fn wrap_with_error_handling<'local, R: Default>(
+    env: &mut JNIEnv<'local>,
+    callback: impl FnOnce(&mut JNIEnv<'local>) -> anyhow::Result<R>,
 ) -> R {
     let result = panic::catch_unwind(AssertUnwindSafe(|| callback(env)));
 
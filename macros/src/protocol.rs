@@ -364,7 +364,7 @@ fn decode_enum(input: &EnumInput) -> TokenStream {
 
         match discriminant {
             #(#match_arms,)*
-            _ => Err(crate::protocol::DecodeError::Other(::anyhow::format_err!("invalid discriminant '{}'", discriminant))),
+            _ => Err(crate::protocol::DecodeError::InvalidDiscriminant(discriminant)),
         }
     }
 }
@@ -0,0 +1,58 @@
+//! Session affinity for pools of gateways behind one DNS name.
+//!
+//! A gateway configured with an ID issues an `AffinityToken` to clients
+//! that ask for one. Clients keep the token and present it when they
+//! reconnect. If the token names the gateway's own address, the client
+//! connects there directly rather than to the pool's address, so that it
+//! returns to the gateway it was last connected to.
+//!
+//! Tokens are only routing hints. They grant nothing: a client still has
+//! to authenticate with whichever gateway it reaches.
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// Identifies the gateway of a pool that a client was connected to.
+///
+/// Written as `<gateway_id>` or `<gateway_id>@<host>:<port>`,
+/// e.g. `gw-2@gw-2.example.net:6666`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AffinityToken {
+    /// ID of the issuing gateway, unique within its pool.
+    pub gateway_id: String,
+    /// Address at which the issuing gateway can be reached directly,
+    /// as `host:port`. If unset, clients can only return to the gateway
+    /// through the pool's load balancer.
+    pub address: Option<String>,
+}
+
+impl Display for AffinityToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.address {
+            Some(address) => write!(f, "{}@{address}", self.gateway_id),
+            None => write!(f, "{}", self.gateway_id),
+        }
+    }
+}
+
+impl FromStr for AffinityToken {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (gateway_id, address) = match s.split_once('@') {
+            Some((gateway_id, address)) => (gateway_id, Some(address.to_owned())),
+            None => (s, None),
+        };
+        if gateway_id.is_empty() {
+            bail!("affinity token '{s}' has no gateway ID");
+        }
+        Ok(Self {
+            gateway_id: gateway_id.to_owned(),
+            address,
+        })
+    }
+}
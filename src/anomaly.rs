@@ -0,0 +1,165 @@
+//! Counting of unusual but non-fatal traffic on a connection.
+//!
+//! Anomalies can occur at packet rates, so instead of logging each one,
+//! a summary is logged at most once per `SUMMARY_INTERVAL`.
+//!
+//! Anomalies caused by the peer's traffic are also weighted into a score,
+//! which a gateway in strict mode compares against a threshold.
+
+use crate::protocol::DecodeError;
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use strum::{EnumCount, IntoEnumIterator};
+
+/// Minimum interval between two logged summaries.
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A kind of anomaly.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, strum::AsRefStr, strum::EnumIter, strum::EnumCount)]
+#[strum(serialize_all = "snake_case")]
+pub enum Anomaly {
+    /// A packet that could not be decoded and was skipped.
+    DecodeWarning,
+    /// A boolean encoded as neither 0 nor 1.
+    InvalidBool,
+    /// A string longer than the protocol allows.
+    StringTooLong,
+    /// A packet ID or enum discriminant with no matching variant.
+    UnknownId,
+    /// A relative movement packet for an entity whose position is unknown,
+    /// which therefore could not be translated.
+    UnknownEntityPosition,
+    /// An entity ID reused by the server shortly after its previous
+    /// entity was removed, whose streams were therefore remapped.
+    EntityIdReused,
+}
+
+impl Anomaly {
+    /// Classifies an error that occurred while decoding a packet.
+    pub fn of_decode_error(error: &anyhow::Error) -> Self {
+        match error.downcast_ref::<DecodeError>() {
+            Some(DecodeError::InvalidBool(_)) => Anomaly::InvalidBool,
+            Some(DecodeError::StringTooLong) => Anomaly::StringTooLong,
+            Some(DecodeError::InvalidDiscriminant(_)) => Anomaly::UnknownId,
+            _ => Anomaly::DecodeWarning,
+        }
+    }
+
+    /// Contribution of one occurrence to the anomaly score.
+    ///
+    /// Anomalies in the server's traffic are not the peer's
+    /// doing and weigh nothing.
+    pub fn weight(self) -> u64 {
+        match self {
+            Anomaly::DecodeWarning => 1,
+            Anomaly::InvalidBool | Anomaly::UnknownId => 5,
+            Anomaly::StringTooLong => 10,
+            Anomaly::UnknownEntityPosition | Anomaly::EntityIdReused => 0,
+        }
+    }
+}
+
+/// Counts the anomalies of a connection and logs rate-limited summaries.
+#[derive(Debug)]
+pub struct AnomalyCollector {
+    /// Identifies the connection in log messages.
+    label: String,
+    counts: [AtomicU64; Anomaly::COUNT],
+    last_summary: Mutex<LastSummary>,
+}
+
+#[derive(Debug)]
+struct LastSummary {
+    time: Instant,
+    counts: [u64; Anomaly::COUNT],
+}
+
+impl AnomalyCollector {
+    pub fn new(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            counts: Default::default(),
+            last_summary: Mutex::new(LastSummary {
+                time: Instant::now(),
+                counts: [0; Anomaly::COUNT],
+            }),
+        }
+    }
+
+    pub fn record(&self, anomaly: Anomaly) {
+        self.counts[anomaly as usize].fetch_add(1, Ordering::Relaxed);
+        self.log_summary(false);
+    }
+
+    pub fn get(&self, anomaly: Anomaly) -> u64 {
+        self.counts[anomaly as usize].load(Ordering::Relaxed)
+    }
+
+    /// Gets the weighted sum of all anomalies so far.
+    pub fn score(&self) -> u64 {
+        Anomaly::iter()
+            .map(|anomaly| self.get(anomaly) * anomaly.weight())
+            .sum()
+    }
+
+    /// Gets the total count of each anomaly.
+    pub fn summary(&self) -> AnomalySummary {
+        AnomalySummary(
+            Anomaly::iter()
+                .map(|anomaly| (anomaly.as_ref().to_owned(), self.get(anomaly)))
+                .collect(),
+        )
+    }
+
+    /// Logs the anomalies since the last summary, unless there were none
+    /// or (if not `force`) the last summary was too recent.
+    fn log_summary(&self, force: bool) {
+        let Ok(mut last_summary) = self.last_summary.try_lock() else {
+            // Another thread is logging right now.
+            return;
+        };
+        let elapsed = last_summary.time.elapsed();
+        if !force && elapsed < SUMMARY_INTERVAL {
+            return;
+        }
+
+        let counts = Anomaly::iter().map(|anomaly| self.get(anomaly));
+        let mut message = String::new();
+        for ((anomaly, count), last_count) in
+            Anomaly::iter().zip(counts).zip(&mut last_summary.counts)
+        {
+            let delta = count - *last_count;
+            *last_count = count;
+            if delta > 0 {
+                write!(message, " {}={delta}", anomaly.as_ref()).unwrap();
+            }
+        }
+        last_summary.time = Instant::now();
+
+        if !message.is_empty() {
+            tracing::warn!(
+                "{}: protocol anomalies in the last {}s:{message}",
+                self.label,
+                elapsed.as_secs()
+            );
+        }
+    }
+}
+
+impl Drop for AnomalyCollector {
+    fn drop(&mut self) {
+        self.log_summary(true);
+    }
+}
+
+/// Total count of each anomaly on a connection.
+#[derive(Debug, Clone, Serialize)]
+pub struct AnomalySummary(BTreeMap<String, u64>);
@@ -0,0 +1,325 @@
+//! Bandwidth usage of a connection, split into broad categories of
+//! packets, so that players can see what their connection spends
+//! bandwidth on.
+//!
+//! Sizes are of packets as sent over QUIC, i.e. after compression,
+//! but without QUIC and UDP overhead. Only Play packets are counted.
+//!
+//! A `BandwidthQuota` limits the bytes a connection, or a group of
+//! connections, sends and receives, either by throttling it or by
+//! failing it once it exceeds the quota.
+
+use crate::clock::{self, Instant, SharedClock};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use strum::IntoEnumIterator;
+
+/// Interval over which usage is averaged.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+const CHUNK_DATA: &[&str] = &[
+    "ChunkAndLightData",
+    "UnloadChunk",
+    "UpdateLight",
+    "ChunkBiomes",
+    "ChunkBatchStart",
+    "ChunkBatchFinished",
+    "ChunkBatchReceived",
+    "BlockUpdate",
+    "UpdateSectionBlocks",
+    "SetCenterChunk",
+];
+const ENTITY_MOVEMENT: &[&str] = &[
+    "UpdateEntityPosition",
+    "UpdateEntityPositionAndRotation",
+    "UpdateEntityRotation",
+    "TeleportEntity",
+    "SetHeadRotation",
+    "SetEntityVelocity",
+    "MoveVehicle",
+    "SynchronizePlayerPosition",
+    "ConfirmTeleportation",
+    "SetPlayerPosition",
+    "SetPlayerPositionAndRotation",
+    "SetPlayerRotation",
+    "SetPlayerOnGround",
+];
+const CHAT: &[&str] = &[
+    "PlayerChatMessage",
+    "SystemChatMessage",
+    "DisguisedChatMessage",
+    "DeleteMessage",
+    "ChatSuggestions",
+    "ChatCommand",
+    "ChatMessage",
+    "AcknowledgeMessage",
+];
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, strum::EnumIter)]
+pub enum BandwidthCategory {
+    ChunkData,
+    EntityMovement,
+    Chat,
+    Other,
+}
+
+impl BandwidthCategory {
+    /// Gets the category of a packet by its name
+    /// (the same in both directions and all states).
+    pub fn of_packet(packet_name: &str) -> Self {
+        if CHUNK_DATA.contains(&packet_name) {
+            BandwidthCategory::ChunkData
+        } else if ENTITY_MOVEMENT.contains(&packet_name) {
+            BandwidthCategory::EntityMovement
+        } else if CHAT.contains(&packet_name) {
+            BandwidthCategory::Chat
+        } else {
+            BandwidthCategory::Other
+        }
+    }
+}
+
+/// Bytes per second in each category.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CategoryRates {
+    pub chunk_data: u64,
+    pub entity_movement: u64,
+    pub chat: u64,
+    pub other: u64,
+}
+
+impl CategoryRates {
+    pub fn get(&self, category: BandwidthCategory) -> u64 {
+        match category {
+            BandwidthCategory::ChunkData => self.chunk_data,
+            BandwidthCategory::EntityMovement => self.entity_movement,
+            BandwidthCategory::Chat => self.chat,
+            BandwidthCategory::Other => self.other,
+        }
+    }
+
+    fn get_mut(&mut self, category: BandwidthCategory) -> &mut u64 {
+        match category {
+            BandwidthCategory::ChunkData => &mut self.chunk_data,
+            BandwidthCategory::EntityMovement => &mut self.entity_movement,
+            BandwidthCategory::Chat => &mut self.chat,
+            BandwidthCategory::Other => &mut self.other,
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        BandwidthCategory::iter()
+            .map(|category| self.get(category))
+            .sum()
+    }
+}
+
+impl Display for CategoryRates {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (chunks {}, entity movement {}, chat {}, other {})",
+            format_rate(self.total()),
+            format_rate(self.chunk_data),
+            format_rate(self.entity_movement),
+            format_rate(self.chat),
+            format_rate(self.other)
+        )
+    }
+}
+
+fn format_rate(bytes_per_second: u64) -> String {
+    match bytes_per_second {
+        0..1_000 => format!("{bytes_per_second} B/s"),
+        1_000..1_000_000 => format!("{:.1} kB/s", bytes_per_second as f64 / 1e3),
+        _ => format!("{:.1} MB/s", bytes_per_second as f64 / 1e6),
+    }
+}
+
+/// Bandwidth usage over the last second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthUsage {
+    pub received: CategoryRates,
+    pub sent: CategoryRates,
+}
+
+impl Display for BandwidthUsage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "received {}, sent {}", self.received, self.sent)
+    }
+}
+
+/// Packets and bytes sent and received on a connection so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BandwidthTotals {
+    pub received_packets: u64,
+    pub received_bytes: u64,
+    pub sent_packets: u64,
+    pub sent_bytes: u64,
+}
+
+/// Counts the packets and bytes sent and received on a connection.
+#[derive(Debug, Default)]
+pub struct BandwidthMeter {
+    /// Cumulative byte counts, indexed by `BandwidthCategory`.
+    received: [AtomicU64; 4],
+    sent: [AtomicU64; 4],
+    received_packets: AtomicU64,
+    sent_packets: AtomicU64,
+    /// Usage over the last sample interval.
+    usage: Mutex<BandwidthUsage>,
+}
+
+impl BandwidthMeter {
+    /// Creates a meter whose usage is updated every second on the given clock.
+    pub fn new(clock: SharedClock) -> Arc<Self> {
+        let meter = Arc::new(Self::default());
+        tokio::spawn(sample(Arc::downgrade(&meter), clock));
+        meter
+    }
+
+    pub fn record_received(&self, category: BandwidthCategory, bytes: usize) {
+        self.received[category as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+        self.received_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_sent(&self, category: BandwidthCategory, bytes: usize) {
+        self.sent[category as usize].fetch_add(bytes as u64, Ordering::Relaxed);
+        self.sent_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Gets the usage over the last second.
+    pub fn usage(&self) -> BandwidthUsage {
+        *self.usage.lock().unwrap()
+    }
+
+    /// Gets the bytes received so far.
+    pub(crate) fn total_received(&self) -> u64 {
+        self.received
+            .iter()
+            .map(|bytes| bytes.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Gets the packets and bytes sent and received so far.
+    pub fn totals(&self) -> BandwidthTotals {
+        let sum = |bytes: &[AtomicU64; 4]| -> u64 {
+            bytes
+                .iter()
+                .map(|bytes| bytes.load(Ordering::Relaxed))
+                .sum()
+        };
+        BandwidthTotals {
+            received_packets: self.received_packets.load(Ordering::Relaxed),
+            received_bytes: sum(&self.received),
+            sent_packets: self.sent_packets.load(Ordering::Relaxed),
+            sent_bytes: sum(&self.sent),
+        }
+    }
+
+    fn category_totals(&self) -> BandwidthUsage {
+        let mut totals = BandwidthUsage::default();
+        for category in BandwidthCategory::iter() {
+            *totals.received.get_mut(category) =
+                self.received[category as usize].load(Ordering::Relaxed);
+            *totals.sent.get_mut(category) = self.sent[category as usize].load(Ordering::Relaxed);
+        }
+        totals
+    }
+}
+
+/// Updates the meter's usage every sample interval until it is dropped.
+async fn sample(meter: Weak<BandwidthMeter>, clock: SharedClock) {
+    let mut interval = clock::Interval::new(clock, SAMPLE_INTERVAL);
+    let mut last_totals = BandwidthUsage::default();
+    loop {
+        interval.tick().await;
+        let Some(meter) = meter.upgrade() else {
+            return;
+        };
+        let totals = meter.category_totals();
+        let mut usage = BandwidthUsage::default();
+        for category in BandwidthCategory::iter() {
+            *usage.received.get_mut(category) =
+                totals.received.get(category) - last_totals.received.get(category);
+            *usage.sent.get_mut(category) =
+                totals.sent.get(category) - last_totals.sent.get(category);
+        }
+        *meter.usage.lock().unwrap() = usage;
+        last_totals = totals;
+    }
+}
+
+/// Returned by `BandwidthQuota::consume` when a quota
+/// that does not throttle is exceeded.
+#[derive(Debug, thiserror::Error)]
+#[error("bandwidth quota of {max_bytes_per_sec} bytes per second exceeded")]
+pub struct QuotaExceeded {
+    pub max_bytes_per_sec: u64,
+}
+
+/// Limits the bytes sent and received, counted together, to an average
+/// rate with bursts up to a maximum. Shared by the connections it applies to.
+#[derive(Debug)]
+pub struct BandwidthQuota {
+    max_bytes_per_sec: u64,
+    burst_bytes: u64,
+    /// Whether to wait for the quota to refill rather than fail.
+    throttle: bool,
+    /// Bytes available, negative if the quota is overdrawn,
+    /// and when they were last refilled.
+    bucket: Mutex<(f64, Instant)>,
+    clock: SharedClock,
+}
+
+impl BandwidthQuota {
+    pub fn new(
+        max_bytes_per_sec: u64,
+        burst_bytes: u64,
+        throttle: bool,
+        clock: SharedClock,
+    ) -> Self {
+        let max_bytes_per_sec = max_bytes_per_sec.max(1);
+        Self {
+            max_bytes_per_sec,
+            burst_bytes,
+            throttle,
+            bucket: Mutex::new((burst_bytes as f64, clock.now())),
+            clock,
+        }
+    }
+
+    /// Takes `bytes` from the quota. If that overdraws it, either waits
+    /// until it has refilled to zero or fails, depending on the quota.
+    pub async fn consume(&self, bytes: usize) -> Result<(), QuotaExceeded> {
+        let now = self.clock.now();
+        let deficit = {
+            let mut bucket = self.bucket.lock().unwrap();
+            let (available, refilled_at) = &mut *bucket;
+            *available = (*available
+                + now.duration_since(*refilled_at).as_secs_f64() * self.max_bytes_per_sec as f64)
+                .min(self.burst_bytes as f64);
+            *refilled_at = now;
+            *available -= bytes as f64;
+            -*available
+        };
+        if deficit <= 0.0 {
+            return Ok(());
+        }
+        if !self.throttle {
+            return Err(QuotaExceeded {
+                max_bytes_per_sec: self.max_bytes_per_sec,
+            });
+        }
+        let wait = Duration::from_secs_f64(deficit / self.max_bytes_per_sec as f64);
+        self.clock.sleep_until(now + wait).await;
+        Ok(())
+    }
+}
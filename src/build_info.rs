@@ -0,0 +1,81 @@
+//! What a build of this crate supports, for diagnosing compatibility
+//! problems between builds of the mod and of the gateway.
+//!
+//! The report is printed by `minecraft-quic-proxy --version --json`,
+//! logged when a gateway or the JNI library starts, and exchanged over
+//! the control stream, so each side knows what the other was built with.
+
+use crate::{
+    control_stream::Capability,
+    protocol::{optimized_codec::CodecVersion, PROTOCOL_VERSION},
+};
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Display};
+use strum::IntoEnumIterator;
+
+/// Report of what a build supports.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BuildInfo {
+    /// Version of the crate.
+    pub version: String,
+    /// Cargo features the crate was compiled with.
+    pub features: Vec<String>,
+    /// Minecraft protocol versions that can be proxied.
+    pub protocol_versions: Vec<i32>,
+    /// Optimized codec versions, in order of preference (lowest first).
+    pub codec_versions: Vec<u8>,
+    /// Control stream extensions, named as by `Capability`.
+    pub capabilities: Vec<String>,
+}
+
+impl BuildInfo {
+    /// Gets the report of this build.
+    pub fn current() -> Self {
+        let features = [
+            ("client", cfg!(feature = "client")),
+            ("gateway", cfg!(feature = "gateway")),
+            ("cli", cfg!(feature = "cli")),
+            ("pprof", cfg!(feature = "pprof")),
+            ("tokio-console", cfg!(feature = "tokio-console")),
+        ];
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            features: features
+                .into_iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(feature, _)| feature.to_owned())
+                .collect(),
+            protocol_versions: vec![PROTOCOL_VERSION],
+            codec_versions: CodecVersion::supported_bytes(),
+            capabilities: Capability::iter()
+                .map(|capability| <&str>::from(capability).to_owned())
+                .collect(),
+        }
+    }
+}
+
+impl Display for BuildInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "minecraft-quic-proxy {} (features: {}; Minecraft protocol: {}; \
+             codec versions: {}; capabilities: {})",
+            self.version,
+            list(&self.features),
+            list(&self.protocol_versions),
+            list(&self.codec_versions),
+            list(&self.capabilities),
+        )
+    }
+}
+
+fn list<T: Display>(values: &[T]) -> String {
+    if values.is_empty() {
+        return "none".to_owned();
+    }
+    values
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
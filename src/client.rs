@@ -2,86 +2,425 @@
 //! from TCP to QUIC.
 
 use crate::{
+    affinity::AffinityToken,
+    anomaly::AnomalyCollector,
+    bandwidth::{BandwidthMeter, BandwidthUsage},
+    build_info::BuildInfo,
+    clock,
+    clock::SharedClock,
     control_stream,
-    protocol::packet::{client, client::handshake::NextState, server, side, state},
-    proxy::{PacketIo, Proxy, QuicPacketIo, SingleQuicPacketIo, VanillaPacketIo},
+    control_stream::{ClientMetrics, ConnectedDestination, TransportPreferences},
+    destination::Destination,
+    protocol::{
+        optimized_codec::CodecVersion,
+        packet::{client, client::handshake::NextState, server, side, state},
+    },
+    proxy::{Instrumentation, PacketIo, Proxy, QuicPacketIo, SingleQuicPacketIo, VanillaPacketIo},
+    sequence::SequencesHandle,
+    stats::{ConnectionStats, NegotiatedParameters, SessionReport, TransportStats},
     stream,
+    tcp_options::TcpOptions,
+    timeline::{Timeline, TimelineSource},
+    watchdog::Stalled,
 };
-use anyhow::Context;
-use quinn::{Connection, Endpoint};
+use anyhow::{anyhow, Context};
+use lag_events::{LagEventLog, LagThresholds};
+use measurement::{MeasurementLog, MeasurementOptions};
+use quinn::{ClientConfig, Connection, ConnectionError, Endpoint, IdleTimeout, VarInt};
+use resolver::Resolver;
+use resumption::{ReconnectBackoff, Reconnector};
 use std::{
-    net::{SocketAddr, ToSocketAddrs},
+    convert::Infallible,
+    net::{Ipv4Addr, SocketAddr},
     ops::ControlFlow,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
     thread,
+    time::{Duration, Instant},
 };
+use store::{ClientStore, TransportHints};
 use tokio::{
-    net::{TcpListener, TcpStream},
-    runtime,
-    sync::oneshot,
+    net::{TcpSocket, TcpStream},
+    runtime, select,
+    sync::{oneshot, watch},
     task::LocalSet,
 };
 
+pub mod lag_events;
+pub mod measurement;
+pub mod resolver;
+pub mod resumption;
+pub mod sessions;
+pub mod store;
+
+/// Options for opening a client.
+#[derive(Debug, Clone, Default)]
+pub struct ClientOptions {
+    /// If set, anonymous connection quality metrics (RTT, packet loss,
+    /// dropped datagrams) are reported to the gateway at this interval.
+    ///
+    /// Not reported if the gateway does not support metrics reports.
+    pub metrics_interval: Option<Duration>,
+    /// If set, the server host and port in the handshake are rewritten
+    /// to these before being forwarded. Otherwise they are the address
+    /// of the local listener the game connected to, which servers that
+    /// validate the address (e.g. behind BungeeCord) reject.
+    ///
+    /// Typically the address the player entered, e.g. `("play.example.net", 25565)`.
+    pub handshake_address: Option<(String, u16)>,
+    /// If set, keepalives and teleports are sent twice on independent
+    /// streams, reducing their delay on very lossy links at the cost of
+    /// bandwidth. Both directions are affected.
+    ///
+    /// Not enabled if the gateway does not support redundancy.
+    pub redundancy: bool,
+    /// If set, asks the gateway for an affinity token, which identifies the
+    /// gateway within a pool behind one DNS name (see `ClientHandle::affinity_token`).
+    ///
+    /// Not requested if the gateway does not support affinity tokens.
+    pub request_affinity: bool,
+    /// Affinity token issued on a previous connection. If it names the
+    /// issuing gateway's address, the client connects there directly,
+    /// falling back to the given gateway host if that fails.
+    ///
+    /// Presented to the gateway if `request_affinity` is set.
+    /// Defaults to the token in the `store`, if any.
+    pub affinity_token: Option<AffinityToken>,
+    /// QUIC and TLS configuration for the connection to the gateway, e.g.
+    /// to pin its certificate or tune the transport. The endpoint's default
+    /// configuration if unset.
+    ///
+    /// This allows clients with different configurations to share an endpoint.
+    /// It must offer `ALPN`, as configs from `client_config()` and
+    /// `client_config_with_crypto()` do, and so must the endpoint's default.
+    /// The transport should be based on `transport_config()`. If the `store`
+    /// has transport hints for the gateway, the transport is replaced with
+    /// `transport_config()` tuned by the hints.
+    pub client_config: Option<ClientConfig>,
+    /// Persistent state about gateways, kept across restarts.
+    pub store: Option<Arc<ClientStore>>,
+    /// If set, the gateway's certificate is pinned in the `store` on the
+    /// first connection, and later connections to the same address fail if
+    /// it changes. Intended for gateways with self-signed certificates.
+    pub pin_certificates: bool,
+    /// Resolver for the gateway's host name. Sharing one between clients
+    /// lets them share its cache. The system resolver if unset.
+    pub resolver: Option<Arc<Resolver>>,
+    /// Clock that timers and idle expiry are measured on.
+    /// The system clock if unset.
+    pub clock: Option<SharedClock>,
+    /// Thresholds at which lag events are recorded
+    /// (see `ClientHandle::lag_events`).
+    pub lag_thresholds: LagThresholds,
+    /// If set, the same probes are sent to the gateway over QUIC and
+    /// over TCP during the Play state, comparing the round-trip times
+    /// of both (see `ClientHandle::measurement`).
+    ///
+    /// Not enabled if the gateway does not support measurement
+    /// or has no measurement endpoint.
+    pub measurement: Option<MeasurementOptions>,
+    /// If set, the player is disconnected with a message when one
+    /// direction of the Play state delivers no packets for this long
+    /// while the other stays active, after one attempt at recovering
+    /// by reopening the misc stream to the gateway.
+    pub stall_timeout: Option<Duration>,
+    /// If set, a session whose connection to the gateway drops during the
+    /// Play state is resumed on a new connection, as long as the client
+    /// reconnects within the grace time the gateway grants. The game stays
+    /// connected throughout.
+    ///
+    /// Drops are detected by the idle timeout of the connection, so a
+    /// `client_config` with a shorter idle timeout resumes sooner.
+    /// Not enabled if the gateway does not support resumption.
+    pub resume_sessions: bool,
+    /// Delays between attempts to reconnect to the gateway while
+    /// resuming a session (see `resume_sessions`).
+    pub reconnect_backoff: ReconnectBackoff,
+    /// If set, the gateway is asked which destination server it connected
+    /// to, which may be a fallback of the one requested (see
+    /// `ClientHandle::connected_destination`).
+    ///
+    /// Not asked if the gateway does not support destination reports.
+    pub query_destination: bool,
+    /// If set, the gateway is asked to apply these to the connection, e.g.
+    /// a shorter idle timeout so that drops are detected sooner, or no
+    /// datagrams on networks that block them. The gateway bounds them by
+    /// its limits (see `ClientHandle::transport_preferences`).
+    ///
+    /// The idle timeout, which must not be zero, requires a `client_config`,
+    /// whose transport is then replaced as with transport hints.
+    /// Not negotiated if the gateway does not support transport preferences.
+    pub transport: Option<TransportPreferences>,
+    /// Socket options of the game's connection to the local listener.
+    pub game_tcp: TcpOptions,
+}
+
 pub struct ClientHandle {
     bound_port: u16,
+    destination: Destination,
     encryption_key_tx: Option<oneshot::Sender<[u8; 16]>>,
+    timeline: Arc<Timeline>,
+    bandwidth: Arc<BandwidthMeter>,
+    /// Datagrams from the gateway dropped as outdated, over all Play states.
+    dropped_datagrams: Arc<AtomicU64>,
+    lag_events: Arc<LagEventLog>,
+    measurement: Option<Arc<MeasurementLog>>,
+    affinity_token: Option<AffinityToken>,
+    gateway_build_info: Option<BuildInfo>,
+    connected_destination: Option<ConnectedDestination>,
+    transport_preferences: Option<TransportPreferences>,
+    /// Replaced when the session is resumed on a new connection.
+    gateway_connection: Arc<Mutex<Connection>>,
+    codec_version: CodecVersion,
+    /// Set when the session ends.
+    report: watch::Receiver<Option<SessionReport>>,
 }
 
 impl ClientHandle {
-    /// Opens a new client.
+    /// Opens a new client with the default options.
     pub async fn open(
         endpoint: &Endpoint,
         gateway_host: &str,
         gateway_port: u16,
-        destination_address: SocketAddr,
+        destination: impl Into<Destination>,
         authentication_key: &str,
     ) -> anyhow::Result<Self> {
-        let client_listener = TcpListener::bind("127.0.0.1:0").await?;
+        Self::open_with_options(
+            endpoint,
+            gateway_host,
+            gateway_port,
+            destination,
+            authentication_key,
+            &ClientOptions::default(),
+        )
+        .await
+    }
+
+    /// Opens a new client.
+    ///
+    /// A destination named by host name is resolved by the gateway. Gateways
+    /// that predate this reject such destinations, so clients that must work
+    /// with them resolve the name themselves.
+    pub async fn open_with_options(
+        endpoint: &Endpoint,
+        gateway_host: &str,
+        gateway_port: u16,
+        destination: impl Into<Destination>,
+        authentication_key: &str,
+        options: &ClientOptions,
+    ) -> anyhow::Result<Self> {
+        let destination = destination.into();
+        let started = Instant::now();
+        let client_socket = TcpSocket::new_v4()?;
+        options.game_tcp.configure_socket(&client_socket)?;
+        client_socket.bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))?;
+        let client_listener = client_socket.listen(1)?;
         let bound_port = client_listener.local_addr()?.port();
 
-        let endpoint_addr = endpoint.local_addr()?;
-        // Resolves address must match IP version
-        let gateway_address: SocketAddr = format!("{gateway_host}:{gateway_port}")
-            .to_socket_addrs()?
-            .find(|addr| {
-                (addr.is_ipv4() && endpoint_addr.is_ipv4())
-                    || (addr.is_ipv6() && endpoint_addr.is_ipv6())
-            })
-            .context("failed to resolve address")?;
-        let gateway_connection = endpoint.connect(gateway_address, gateway_host)?.await?;
+        let pool_address = format!("{gateway_host}:{gateway_port}");
+        let presented_token = options.affinity_token.clone().or_else(|| {
+            options
+                .store
+                .as_ref()
+                .and_then(|store| store.gateway(&pool_address).affinity_token)
+        });
+        let affinity_address = presented_token
+            .as_ref()
+            .and_then(|token| token.address.as_deref());
+        let (gateway_connection, gateway_address) = match affinity_address {
+            Some(address) => match connect(endpoint, options, address, gateway_host).await {
+                Ok(connection) => (connection, address),
+                Err(e) => {
+                    tracing::warn!(
+                        "Failed to return to gateway {address}, connecting to {pool_address}: {e}"
+                    );
+                    let connection =
+                        connect(endpoint, options, &pool_address, gateway_host).await?;
+                    (connection, pool_address.as_str())
+                }
+            },
+            None => (
+                connect(endpoint, options, &pool_address, gateway_host).await?,
+                pool_address.as_str(),
+            ),
+        };
+        if let (Some(store), true) = (&options.store, options.pin_certificates) {
+            // Before the authentication key is sent.
+            store.verify_pinned_certificate(gateway_address, &gateway_connection)?;
+        }
 
         let mut control_stream = control_stream::ClientSide::open(&gateway_connection).await?;
-        control_stream
-            .connect_to(destination_address, authentication_key)
+        let codec_version = control_stream
+            .connect_to(&destination, authentication_key)
             .await?;
+        if options.resume_sessions {
+            control_stream.request_resumption().await?;
+        }
+        let connected_destination = if options.query_destination {
+            control_stream.query_destination().await?
+        } else {
+            None
+        };
+        if let Some(connected) = connected_destination.as_ref().filter(|c| c.fallback) {
+            tracing::info!(
+                "Gateway fell back from {destination} to {} ({})",
+                connected.destination,
+                connected.address
+            );
+        }
+        let transport_preferences = match &options.transport {
+            Some(preferences) => {
+                control_stream
+                    .negotiate_transport(preferences.clone())
+                    .await?
+            }
+            None => None,
+        };
+        if let Some(max_uni_streams) = transport_preferences
+            .as_ref()
+            .and_then(|preferences| preferences.max_uni_streams)
+        {
+            gateway_connection.set_max_concurrent_uni_streams(VarInt::from_u32(max_uni_streams));
+        }
+        let affinity_token = if options.request_affinity {
+            control_stream
+                .request_affinity(presented_token.clone())
+                .await?
+        } else {
+            None
+        };
+        let gateway_build_info = control_stream.exchange_build_info().await?;
+        if let Some(build_info) = &gateway_build_info {
+            tracing::debug!("Gateway is {build_info}");
+        }
+        let measurement_endpoint = if options.measurement.is_some() {
+            control_stream.enable_measurement().await?
+        } else {
+            None
+        };
+        let clock_offset = control_stream.sync_time().await?;
+        if let Some(store) = &options.store {
+            let rtt_millis = gateway_connection
+                .rtt()
+                .as_millis()
+                .try_into()
+                .unwrap_or(u32::MAX);
+            store.update(gateway_address, |record| {
+                record.transport = Some(TransportHints { rtt_millis })
+            });
+            if let Some(token) = &affinity_token {
+                store.update(&pool_address, |record| {
+                    record.affinity_token = Some(token.clone())
+                });
+            }
+        }
+        if options.redundancy {
+            control_stream.enable_redundancy().await?;
+        }
+        tracing::debug!(
+            "Clock offset to gateway is {}µs (±{}µs)",
+            clock_offset.offset_micros,
+            clock_offset.round_trip_micros / 2
+        );
+        let timeline = Arc::new(Timeline::new(TimelineSource::Client));
+        timeline.set_clock_offset_micros(clock_offset.offset_micros);
+        let clock = options.clock.clone().unwrap_or_else(clock::system);
+        let bandwidth = BandwidthMeter::new(Arc::clone(&clock));
+        let dropped_datagrams = Arc::new(AtomicU64::new(0));
+        let lag_events = LagEventLog::monitor(
+            gateway_connection.clone(),
+            Arc::clone(&timeline),
+            Arc::clone(&bandwidth),
+            Arc::clone(&clock),
+            options.lag_thresholds,
+        );
+        let measurement =
+            options
+                .measurement
+                .zip(measurement_endpoint)
+                .map(|(measurement_options, endpoint)| {
+                    MeasurementLog::monitor(
+                        gateway_connection.clone(),
+                        endpoint,
+                        Arc::clone(&timeline),
+                        Arc::clone(&clock),
+                        measurement_options,
+                    )
+                });
+        let instrumentation = Instrumentation {
+            allocation_counters: Arc::default(),
+            anomalies: Arc::new(AnomalyCollector::new(format!(
+                "connection to {}",
+                gateway_connection.remote_address()
+            ))),
+            timeline: Arc::clone(&timeline),
+            clock,
+            bandwidth: Some(Arc::clone(&bandwidth)),
+            quota: None,
+            memory_budget: None,
+            dropped_datagrams: Arc::clone(&dropped_datagrams),
+        };
 
         let (encryption_key_tx, encryption_key_rx) = oneshot::channel();
+        let options = options.clone();
 
+        let (report_tx, report_rx) = watch::channel(None);
         let runtime = runtime::Handle::current();
+        let shared_connection = Arc::new(Mutex::new(gateway_connection.clone()));
+        let reconnector = options.resume_sessions.then(|| {
+            Reconnector::new(
+                endpoint.clone(),
+                options.clone(),
+                gateway_address.to_owned(),
+                gateway_host.to_owned(),
+                Arc::clone(&shared_connection),
+            )
+        });
+        let handle_connection = Arc::clone(&shared_connection);
         thread::spawn(move || {
             let local_set = LocalSet::new();
             local_set.spawn_local(async move {
-                let client_stream = match client_listener.accept().await {
-                    Ok((stream, _)) => stream,
-                    Err(e) => {
-                        tracing::warn!("Failed to accept connection from client: {e}");
-                        return;
-                    }
-                };
-                let client = match Client::new(
+                let timeline = Arc::clone(&instrumentation.timeline);
+                let result = async {
+                    let (client_stream, _) = select! {
+                        accepted = client_listener.accept() => {
+                            accepted.context("failed to accept connection from client")?
+                        }
+                        _ = gateway_connection.closed() => {
+                            anyhow::bail!("closed before the game connected")
+                        }
+                    };
+                    options
+                        .game_tcp
+                        .configure_stream(&client_stream)
+                        .context("failed to configure connection from client")?;
+                    let client = Client::new(
+                        &gateway_connection,
+                        codec_version,
+                        instrumentation,
+                        client_stream,
+                        control_stream,
+                        encryption_key_rx,
+                        options,
+                        reconnector,
+                    )
+                    .await
+                    .context("failed to initialize client")?;
+                    client.run().await
+                }
+                .await;
+                if let Err(e) = &result {
+                    tracing::warn!("Error in connection: {e:#}");
+                }
+                let gateway_connection = shared_connection.lock().unwrap().clone();
+                report_tx.send_replace(Some(SessionReport::new(
+                    started.elapsed(),
                     &gateway_connection,
-                    client_stream,
-                    control_stream,
-                    encryption_key_rx,
-                )
-                .await
-                {
-                    Ok(client) => client,
-                    Err(e) => {
-                        tracing::warn!("Failed to initialize client: {e}");
-                        return;
-                    }
-                };
-                client.run().await;
+                    &timeline,
+                    &result,
+                )));
             });
 
             runtime.block_on(local_set);
@@ -90,6 +429,19 @@ impl ClientHandle {
         Ok(Self {
             encryption_key_tx: Some(encryption_key_tx),
             bound_port,
+            destination,
+            timeline,
+            bandwidth,
+            dropped_datagrams,
+            lag_events,
+            measurement,
+            affinity_token,
+            gateway_build_info,
+            connected_destination,
+            transport_preferences,
+            gateway_connection: handle_connection,
+            report: report_rx,
+            codec_version,
         })
     }
 
@@ -112,40 +464,244 @@ impl ClientHandle {
     pub fn bound_port(&self) -> u16 {
         self.bound_port
     }
+
+    /// Gets the destination server the client proxies to.
+    pub fn destination(&self) -> &Destination {
+        &self.destination
+    }
+
+    /// Closes the session, also if the game has not connected yet.
+    pub fn close(&self, reason: &str) {
+        Self::close_connection(&self.gateway_connection(), reason);
+    }
+
+    fn close_connection(connection: &Connection, reason: &str) {
+        connection.close(VarInt::from_u32(0), reason.as_bytes());
+    }
+
+    /// Gets the client's event timeline, with timestamps on the gateway's
+    /// clock so it can be merged with the gateway's session diagnostics.
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
+    /// Gets the bandwidth used by the connection over the last second,
+    /// by category of packet. Only counts the Play state.
+    pub fn bandwidth(&self) -> BandwidthUsage {
+        self.bandwidth.usage()
+    }
+
+    /// Gets the connection's current transport statistics, the datagrams
+    /// dropped as outdated and the Play packets sent in each direction.
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            transport: TransportStats::from_connection(&self.gateway_connection()),
+            dropped_datagrams: self.dropped_datagrams.load(Ordering::Relaxed),
+            play: self.bandwidth.totals(),
+        }
+    }
+
+    /// Gets the lag events (loss bursts, stalls, RTT spikes)
+    /// recorded on the connection to the gateway.
+    pub fn lag_events(&self) -> &Arc<LagEventLog> {
+        &self.lag_events
+    }
+
+    /// Gets the round-trip times measured over QUIC and TCP,
+    /// if measurement was requested and enabled by the gateway.
+    pub fn measurement(&self) -> Option<&Arc<MeasurementLog>> {
+        self.measurement.as_ref()
+    }
+
+    /// Gets the affinity token issued by the gateway, if requested
+    /// and supported. Pass it in `ClientOptions::affinity_token`
+    /// when reconnecting to return to the same gateway.
+    pub fn affinity_token(&self) -> Option<&AffinityToken> {
+        self.affinity_token.as_ref()
+    }
+
+    /// Gets the build the gateway reported, if it supports build reports.
+    pub fn gateway_build_info(&self) -> Option<&BuildInfo> {
+        self.gateway_build_info.as_ref()
+    }
+
+    /// Gets the destination server the gateway connected to, if queried
+    /// and reported. Not reported by gateways with virtual hosts, which
+    /// only connect once the game has sent its handshake.
+    pub fn connected_destination(&self) -> Option<&ConnectedDestination> {
+        self.connected_destination.as_ref()
+    }
+
+    /// Gets the transport preferences as the gateway applied them,
+    /// if requested and supported.
+    pub fn transport_preferences(&self) -> Option<&TransportPreferences> {
+        self.transport_preferences.as_ref()
+    }
+
+    /// Waits until the session has ended, then reports on it.
+    ///
+    /// May be called any number of times, also after the session has ended.
+    pub async fn wait_closed(&self) -> SessionReport {
+        let mut report = self.report.clone();
+        let report = report
+            .wait_for(Option::is_some)
+            .await
+            .map(|report| report.clone());
+        match report {
+            Ok(report) => report.expect("waited for report"),
+            // The session's thread panicked.
+            Err(_) => SessionReport::new(
+                Duration::ZERO,
+                &self.gateway_connection(),
+                &self.timeline,
+                &Err(anyhow::anyhow!("session ended unexpectedly")),
+            ),
+        }
+    }
+
+    /// Gets the parameters negotiated with the gateway.
+    pub fn negotiated(&self) -> NegotiatedParameters {
+        NegotiatedParameters::from_connection(
+            &self.gateway_connection(),
+            self.codec_version.as_u8(),
+        )
+    }
+
+    fn gateway_connection(&self) -> Connection {
+        self.gateway_connection.lock().unwrap().clone()
+    }
+}
+
+/// QUIC error code with which a peer refuses a handshake whose
+/// ALPN protocols it does not support (RFC 9001, section 8.1).
+const NO_APPLICATION_PROTOCOL: u64 = 0x178;
+
+/// Connects to a gateway at `address` (as `host:port`), verifying
+/// its certificate against `server_name`.
+async fn connect(
+    endpoint: &Endpoint,
+    options: &ClientOptions,
+    address: &str,
+    server_name: &str,
+) -> anyhow::Result<Connection> {
+    let endpoint_addr = endpoint.local_addr()?;
+    let addresses = match &options.resolver {
+        Some(resolver) => resolver.lookup(address).await?,
+        None => resolver::lookup_system(address).await?,
+    };
+    // Resolves address must match IP version
+    let socket_address: SocketAddr = addresses
+        .into_iter()
+        .find(|addr| {
+            (addr.is_ipv4() && endpoint_addr.is_ipv4())
+                || (addr.is_ipv6() && endpoint_addr.is_ipv6())
+        })
+        .context("failed to resolve address")?;
+    let mut client_config = options.client_config.clone();
+    let hints = options
+        .store
+        .as_ref()
+        .and_then(|store| store.gateway(address).transport);
+    let idle_timeout = match options
+        .transport
+        .as_ref()
+        .and_then(|preferences| preferences.idle_timeout_millis)
+    {
+        Some(0) => anyhow::bail!("the preferred idle timeout must not be zero"),
+        Some(millis) => Some(Duration::from_millis(millis)),
+        None => None,
+    };
+    if idle_timeout.is_some() && client_config.is_none() {
+        // The endpoint's default configuration is shared, so
+        // its transport cannot be changed for one connection.
+        anyhow::bail!("a preferred idle timeout requires `ClientOptions::client_config`");
+    }
+    if let Some(client_config) = &mut client_config {
+        if hints.is_some() || idle_timeout.is_some() {
+            let mut transport = crate::transport_config();
+            if let Some(hints) = hints {
+                hints.apply(&mut transport);
+            }
+            if let Some(idle_timeout) = idle_timeout {
+                transport
+                    .max_idle_timeout(Some(IdleTimeout::try_from(idle_timeout)?))
+                    .keep_alive_interval(Some(idle_timeout / 3));
+            }
+            client_config.transport_config(Arc::new(transport));
+        }
+    }
+    let connecting = match client_config {
+        Some(client_config) => endpoint.connect_with(client_config, socket_address, server_name)?,
+        None => endpoint.connect(socket_address, server_name)?,
+    };
+    connecting.await.map_err(|e| match e {
+        ConnectionError::ConnectionClosed(close)
+            if u64::from(close.error_code) == NO_APPLICATION_PROTOCOL =>
+        {
+            anyhow!(
+                "gateway does not speak {}; it or this client needs to be updated",
+                String::from_utf8_lossy(crate::ALPN)
+            )
+        }
+        e => e.into(),
+    })
 }
 
 struct Client {
     state: State,
     control_stream: control_stream::ClientSide,
     encryption_key_future: Option<oneshot::Receiver<[u8; 16]>>,
+    metrics_reporter: Option<MetricsReporter>,
+    /// See `ClientOptions::stall_timeout`.
+    stall_timeout: Option<Duration>,
+    instrumentation: Instrumentation,
+    /// Set if `ClientOptions::resume_sessions` is.
+    reconnector: Option<Reconnector>,
 }
 
 impl Client {
     pub async fn new(
         gateway_connection: &Connection,
+        codec_version: CodecVersion,
+        instrumentation: Instrumentation,
         client_stream: TcpStream,
         control_stream: control_stream::ClientSide,
         encryption_key_future: oneshot::Receiver<[u8; 16]>,
+        options: ClientOptions,
+        reconnector: Option<Reconnector>,
     ) -> anyhow::Result<Self> {
-        let state = State::Handshake(HandshakeState::new(gateway_connection, client_stream).await?);
+        let state = State::Handshake(
+            HandshakeState::new(
+                gateway_connection,
+                codec_version,
+                Arc::clone(&instrumentation.timeline),
+                client_stream,
+                options.handshake_address,
+            )
+            .await?,
+        );
 
         Ok(Self {
             state,
             control_stream,
             encryption_key_future: Some(encryption_key_future),
+            metrics_reporter: options
+                .metrics_interval
+                .map(|interval| MetricsReporter::new(interval, Arc::clone(&instrumentation.clock))),
+            stall_timeout: options.stall_timeout,
+            instrumentation,
+            reconnector,
         })
     }
 
-    pub async fn run(self) {
-        if let Err(e) = self.run_inner().await {
-            tracing::warn!("Error in connection: {e}");
-        }
-    }
-
-    async fn run_inner(mut self) -> anyhow::Result<()> {
+    async fn run(mut self) -> anyhow::Result<()> {
         loop {
             let new_state = match self.state {
-                State::Handshake(handshake) => handshake.proxy_until_next_state().await?,
+                State::Handshake(handshake) => {
+                    handshake
+                        .proxy_until_next_state(&mut self.control_stream)
+                        .await?
+                }
                 State::Status(status) => {
                     status.proxy().await?;
                     break;
@@ -160,11 +716,21 @@ impl Client {
                         )
                         .await?
                 }
-                State::Configuration(config) => config.proxy_until_next_state().await?,
-                State::Play(play) => {
-                    play.proxy_until_next_state(&mut self.control_stream)
+                State::Configuration(config) => {
+                    config
+                        .proxy_until_next_state(&mut self.control_stream, &self.instrumentation)
                         .await?
                 }
+                State::Play(play) => {
+                    play.proxy_until_next_state(
+                        &mut self.control_stream,
+                        self.metrics_reporter.as_mut(),
+                        self.stall_timeout,
+                        &self.instrumentation,
+                        self.reconnector.as_ref(),
+                    )
+                    .await?
+                }
             };
             self.state = new_state;
         }
@@ -172,6 +738,7 @@ impl Client {
     }
 }
 
+#[allow(clippy::large_enum_variant)]
 enum State {
     Handshake(HandshakeState),
     Status(StatusState),
@@ -183,48 +750,60 @@ enum State {
 struct HandshakeState {
     gateway: SingleQuicPacketIo<side::Client, state::Handshake>,
     client: VanillaPacketIo<side::Server, state::Handshake>,
+    /// See `ClientOptions::handshake_address`.
+    handshake_address: Option<(String, u16)>,
 }
 
 impl HandshakeState {
     pub async fn new(
         gateway_connection: &Connection,
+        codec_version: CodecVersion,
+        timeline: Arc<Timeline>,
         client_stream: TcpStream,
+        handshake_address: Option<(String, u16)>,
     ) -> anyhow::Result<Self> {
         Ok(Self {
-            gateway: SingleQuicPacketIo::new(gateway_connection).await?,
+            gateway: SingleQuicPacketIo::new(gateway_connection, codec_version, timeline).await?,
             client: VanillaPacketIo::new(client_stream)?,
+            handshake_address,
         })
     }
 
     /// Proxies packets until we arrive at the next state, returning the new state.
-    pub async fn proxy_until_next_state(self) -> anyhow::Result<State> {
-        let client::handshake::Packet::Handshake(handshake) = self.client.recv_packet().await?;
+    pub async fn proxy_until_next_state(
+        self,
+        control_stream: &mut control_stream::ClientSide,
+    ) -> anyhow::Result<State> {
+        let client::handshake::Packet::Handshake(mut handshake) = self.client.recv_packet().await?;
+        if let Some((host, port)) = &self.handshake_address {
+            handshake.rewrite_address(host, *port);
+        }
         self.gateway
             .send_packet(client::handshake::Packet::Handshake(handshake.clone()))
             .await?;
 
-        // HACK: "consume" the receive stream for the Handshake state now that the gateway
-        // will close it. Otherwise, the receive stream for Handshake is incorrectly
-        // used for the next state (since no data has been received on it and therefore
-        // QUIC has not notified us of its existence).
-        self.gateway.connection().accept_uni().await?;
-
         match handshake.next_state {
-            NextState::Status => self.into_status().await.map(State::Status),
-            NextState::Login => self.into_login().await.map(State::Login),
+            NextState::Status => self.into_status(control_stream).await.map(State::Status),
+            NextState::Login => self.into_login(control_stream).await.map(State::Login),
         }
     }
 
-    pub async fn into_status(self) -> anyhow::Result<StatusState> {
+    pub async fn into_status(
+        self,
+        control_stream: &mut control_stream::ClientSide,
+    ) -> anyhow::Result<StatusState> {
         tracing::debug!("Transition to Status state");
-        let gateway = self.gateway.switch_state().await?;
+        let gateway = self.gateway.switch_state(control_stream).await?;
         let client = self.client.switch_state();
         Ok(StatusState { gateway, client })
     }
 
-    pub async fn into_login(self) -> anyhow::Result<LoginState> {
+    pub async fn into_login(
+        self,
+        control_stream: &mut control_stream::ClientSide,
+    ) -> anyhow::Result<LoginState> {
         tracing::debug!("Transition to Login state");
-        let gateway = self.gateway.switch_state().await?;
+        let gateway = self.gateway.switch_state(control_stream).await?;
         let client = self.client.switch_state();
         Ok(LoginState { gateway, client })
     }
@@ -300,12 +879,17 @@ impl LoginState {
         self.gateway = gateway;
         self.client = client;
 
-        self.into_configuration().await.map(State::Configuration)
+        self.into_configuration(control_stream)
+            .await
+            .map(State::Configuration)
     }
 
-    pub async fn into_configuration(self) -> anyhow::Result<ConfigurationState> {
+    pub async fn into_configuration(
+        self,
+        control_stream: &mut control_stream::ClientSide,
+    ) -> anyhow::Result<ConfigurationState> {
         tracing::debug!("Transition to Configuration state");
-        let gateway = self.gateway.switch_state().await?;
+        let gateway = self.gateway.switch_state(control_stream).await?;
         let client = self.client.switch_state();
         Ok(ConfigurationState { gateway, client })
     }
@@ -317,7 +901,11 @@ struct ConfigurationState {
 }
 
 impl ConfigurationState {
-    pub async fn proxy_until_next_state(mut self) -> anyhow::Result<State> {
+    pub async fn proxy_until_next_state(
+        mut self,
+        control_stream: &mut control_stream::ClientSide,
+        instrumentation: &Instrumentation,
+    ) -> anyhow::Result<State> {
         let mut proxy = Proxy::new(self.client, self.gateway);
 
         proxy
@@ -334,12 +922,32 @@ impl ConfigurationState {
             .await?;
 
         (self.client, self.gateway) = proxy.into_parts();
-        self.into_play().await.map(State::Play)
+        self.into_play(control_stream, instrumentation)
+            .await
+            .map(State::Play)
     }
 
-    pub async fn into_play(self) -> anyhow::Result<PlayState> {
+    pub async fn into_play(
+        self,
+        control_stream: &mut control_stream::ClientSide,
+        instrumentation: &Instrumentation,
+    ) -> anyhow::Result<PlayState> {
         tracing::debug!("Transition to Play state");
-        let gateway = QuicPacketIo::new(self.gateway.connection().clone()).await?;
+        let connection = self.gateway.connection().clone();
+        let codec_version = self.gateway.codec_version();
+        self.gateway.switch_to_play(control_stream).await?;
+        let mut gateway =
+            QuicPacketIo::with_instrumentation(connection, codec_version, instrumentation.clone())
+                .await?;
+        if control_stream.redundancy_enabled() {
+            gateway = gateway.with_redundancy();
+        }
+        if control_stream
+            .transport_preferences()
+            .is_some_and(|preferences| !preferences.datagrams)
+        {
+            gateway = gateway.without_datagrams();
+        }
         let client = self.client.switch_state();
         Ok(PlayState { gateway, client })
     }
@@ -354,20 +962,61 @@ impl PlayState {
     pub async fn proxy_until_next_state(
         mut self,
         control_stream: &mut control_stream::ClientSide,
+        mut metrics_reporter: Option<&mut MetricsReporter>,
+        stall_timeout: Option<Duration>,
+        instrumentation: &Instrumentation,
+        reconnector: Option<&Reconnector>,
     ) -> anyhow::Result<State> {
+        let clock = &instrumentation.clock;
+        let codec_version = self.gateway.codec_version();
+        let connection = self.gateway.connection().clone();
+        let sequences = self.gateway.sequences().clone();
         let mut proxy = Proxy::new(self.client, self.gateway);
-        proxy
-            .run(
-                |_| ControlFlow::Continue(()),
-                |server_packet| {
-                    if let server::play::Packet::StartConfiguration(_) = server_packet {
-                        ControlFlow::Break(())
-                    } else {
-                        ControlFlow::Continue(())
-                    }
-                },
-            )
-            .await?;
+        if let Some(stall_timeout) = stall_timeout {
+            proxy = proxy.with_stall_watchdog(Arc::clone(clock), stall_timeout);
+        }
+        let run = proxy.run(
+            |_| ControlFlow::Continue(()),
+            |server_packet| {
+                if let server::play::Packet::StartConfiguration(_) = server_packet {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+        );
+        let result = match metrics_reporter.as_deref_mut() {
+            Some(metrics_reporter) => select! {
+                result = run => result,
+                result = metrics_reporter.run(control_stream, &connection, &sequences) => {
+                    result.map(|never| match never {})
+                }
+            },
+            None => select! {
+                result = run => result,
+                result = wait_for_shutdown_notices(control_stream) => {
+                    result.map(|never| match never {})
+                }
+            },
+        };
+        if let (Err(e), Some(reconnector)) = (&result, reconnector) {
+            if reconnector.may_resume(&connection, control_stream) {
+                tracing::warn!("Connection to gateway dropped, resuming the session: {e:#}");
+                proxy.finish_pending().await;
+                let (client, _) = proxy.into_parts();
+                let (gateway, held) = reconnector
+                    .resume(control_stream, &client, codec_version, instrumentation)
+                    .await?;
+                for packet in held {
+                    gateway.send_packet(packet).await?;
+                }
+                return Ok(State::Play(PlayState { gateway, client }));
+            }
+        }
+        disconnect_on_stall(&proxy, result, clock).await?;
+        if let Some(metrics_reporter) = metrics_reporter {
+            metrics_reporter.reconfigurations += 1;
+        }
 
         // Wait for client to send AcknowledgeConfiguration.
         // Ignore remaining server packets until after
@@ -399,10 +1048,118 @@ impl PlayState {
     }
 
     pub async fn into_configuration(self) -> anyhow::Result<ConfigurationState> {
-        let (send, recv) = stream::accept_bi(self.gateway.connection(), "configuration").await?;
+        let codec_version = self.gateway.codec_version();
+        let (send, recv) =
+            stream::accept_bi(self.gateway.connection(), codec_version, "configuration").await?;
         tracing::debug!("Transition out of Play and into Configuration");
-        let gateway = SingleQuicPacketIo::from_streams(self.gateway.connection(), send, recv);
+        let gateway = SingleQuicPacketIo::from_streams(
+            self.gateway.connection(),
+            codec_version,
+            Arc::clone(self.gateway.timeline()),
+            send,
+            recv,
+        );
         let client = self.client.switch_state();
         Ok(ConfigurationState { gateway, client })
     }
 }
+
+/// Disconnect message shown to the player when the Play state stalls.
+const STALLED_MESSAGE: &str = "Lost connection: the proxy stopped delivering packets.";
+/// Maximum time spent disconnecting the player after a stall,
+/// since the stalled direction may be the one to the game.
+const STALL_DISCONNECT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Disconnects the game with a message if the Play state
+/// ended because it stalled (see `ClientOptions::stall_timeout`).
+async fn disconnect_on_stall(
+    proxy: &Proxy<
+        VanillaPacketIo<side::Server, state::Play>,
+        QuicPacketIo<side::Client>,
+        state::Play,
+    >,
+    result: anyhow::Result<()>,
+    clock: &SharedClock,
+) -> anyhow::Result<()> {
+    if let Err(e) = &result {
+        if e.is::<Stalled>() {
+            let disconnect = proxy.inject_to_client(server::play::Packet::Disconnect(
+                server::play::Disconnect::with_reason(STALLED_MESSAGE),
+            ));
+            clock::timeout(&**clock, STALL_DISCONNECT_TIMEOUT, disconnect)
+                .await
+                .ok();
+        }
+    }
+    result
+}
+
+/// Notes the gateway's shutdown notice, if it sends one, during the
+/// Play state. Only used if metrics are not reported (see `MetricsReporter::run`).
+async fn wait_for_shutdown_notices(
+    control_stream: &mut control_stream::ClientSide,
+) -> anyhow::Result<Infallible> {
+    loop {
+        control_stream.wait_for_shutdown_notice().await?;
+    }
+}
+
+/// Periodically reports connection quality metrics to the gateway
+/// during the Play state.
+struct MetricsReporter {
+    interval: Duration,
+    clock: SharedClock,
+    sent_packets: u64,
+    lost_packets: u64,
+    /// Play to Configuration switches not yet reported.
+    reconfigurations: u32,
+}
+
+impl MetricsReporter {
+    fn new(interval: Duration, clock: SharedClock) -> Self {
+        Self {
+            interval,
+            clock,
+            sent_packets: 0,
+            lost_packets: 0,
+            reconfigurations: 0,
+        }
+    }
+
+    /// Sends reports until an error occurs. Also notes
+    /// the gateway's shutdown notice, if it sends one.
+    async fn run(
+        &mut self,
+        control_stream: &mut control_stream::ClientSide,
+        connection: &Connection,
+        sequences: &SequencesHandle<side::Client>,
+    ) -> anyhow::Result<Infallible> {
+        // The drop counter outlives the Play state, so only
+        // the drops from here on count towards this reporter.
+        let mut dropped_datagrams = sequences.dropped_datagrams();
+        let mut interval = clock::Interval::new(Arc::clone(&self.clock), self.interval);
+        loop {
+            select! {
+                _ = interval.tick() => {}
+                result = control_stream.wait_for_shutdown_notice() => {
+                    result?;
+                    continue;
+                }
+            }
+            let stats = connection.stats();
+            let total_dropped_datagrams = sequences.dropped_datagrams();
+            let metrics = ClientMetrics {
+                rtt_millis: stats.path.rtt.as_millis().try_into().unwrap_or(u32::MAX),
+                sent_packets: stats.path.sent_packets - self.sent_packets,
+                lost_packets: stats.path.lost_packets - self.lost_packets,
+                dropped_datagrams: total_dropped_datagrams - dropped_datagrams,
+                reconfigurations: self.reconfigurations,
+            };
+            self.sent_packets = stats.path.sent_packets;
+            self.lost_packets = stats.path.lost_packets;
+            dropped_datagrams = total_dropped_datagrams;
+            self.reconfigurations = 0;
+            control_stream.send_metrics(metrics).await?;
+        }
+    }
+}
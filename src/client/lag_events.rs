@@ -0,0 +1,368 @@
+//! Discrete lag events on the connection to the gateway (bursts of
+//! packet loss, stalls, RTT spikes), so that players can be shown what
+//! went wrong afterwards, e.g. "3 loss bursts in the last minute",
+//! rather than blaming the server.
+//!
+//! Events are detected by sampling the connection every `SAMPLE_INTERVAL`,
+//! so shorter events may be missed, and are recorded once they end.
+
+use crate::{
+    bandwidth::BandwidthMeter,
+    clock::{self, Instant, SharedClock},
+    phase::Phase,
+    timeline::Timeline,
+};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// Maximum number of events retained.
+const MAX_EVENTS: usize = 256;
+
+/// Thresholds at which lag events are recorded.
+#[derive(Debug, Clone, Copy)]
+pub struct LagThresholds {
+    /// Packets lost within one sample interval that start a loss burst.
+    /// The burst lasts as long as further packets are lost.
+    pub loss_burst_packets: u64,
+    /// Time without receiving anything in the Play state that counts
+    /// as a stall. Vanilla servers send at least the time every second.
+    pub stall: Duration,
+    /// Factor by which the RTT must exceed its moving average
+    /// to count as a spike.
+    pub rtt_spike_factor: f64,
+    /// Amount by which the RTT must exceed its moving average to count
+    /// as a spike, so that jitter on very low RTTs is not counted.
+    pub rtt_spike_min_increase: Duration,
+}
+
+impl Default for LagThresholds {
+    fn default() -> Self {
+        Self {
+            loss_burst_packets: 5,
+            stall: Duration::from_secs(1),
+            rtt_spike_factor: 2.0,
+            rtt_spike_min_increase: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum LagEventKind {
+    /// Packets were lost in consecutive sample intervals.
+    LossBurst { lost_packets: u64 },
+    /// Nothing was received from the gateway in the Play state.
+    Stall,
+    /// The RTT rose well above its moving average.
+    RttSpike {
+        peak_rtt_millis: u64,
+        average_rtt_millis: u64,
+    },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LagEvent {
+    /// Milliseconds since the Unix epoch at which the event started.
+    pub started_at_millis: u64,
+    pub duration_millis: u64,
+    #[serde(flatten)]
+    pub kind: LagEventKind,
+}
+
+/// Number of lag events of each kind that started within a window.
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+pub struct LagSummary {
+    pub window_secs: u64,
+    pub loss_bursts: usize,
+    pub stalls: usize,
+    pub rtt_spikes: usize,
+}
+
+impl Display for LagSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = [
+            (self.loss_bursts, "loss burst", "loss bursts"),
+            (self.stalls, "stall", "stalls"),
+            (self.rtt_spikes, "RTT spike", "RTT spikes"),
+        ];
+        let counts: Vec<_> = counts
+            .into_iter()
+            .filter(|(count, ..)| *count > 0)
+            .map(|(count, singular, plural)| match count {
+                1 => format!("1 {singular}"),
+                _ => format!("{count} {plural}"),
+            })
+            .collect();
+        if counts.is_empty() {
+            write!(f, "no lag events")?;
+        } else {
+            write!(f, "{}", counts.join(", "))?;
+        }
+        match self.window_secs {
+            60 => write!(f, " in the last minute"),
+            secs if secs % 60 == 0 => write!(f, " in the last {} minutes", secs / 60),
+            secs => write!(f, " in the last {secs} seconds"),
+        }
+    }
+}
+
+/// The most recent lag events of a connection.
+#[derive(Debug, Default)]
+pub struct LagEventLog {
+    events: Mutex<VecDeque<LagEvent>>,
+}
+
+impl LagEventLog {
+    /// Creates a log that records the events of the connection
+    /// until either is dropped.
+    pub(crate) fn monitor(
+        connection: Connection,
+        timeline: Arc<Timeline>,
+        bandwidth: Arc<BandwidthMeter>,
+        clock: SharedClock,
+        thresholds: LagThresholds,
+    ) -> Arc<Self> {
+        let log = Arc::new(Self::default());
+        tokio::spawn(monitor(
+            Arc::downgrade(&log),
+            connection,
+            timeline,
+            bandwidth,
+            clock,
+            thresholds,
+        ));
+        log
+    }
+
+    /// Gets the retained events, oldest first.
+    pub fn events(&self) -> Vec<LagEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Gets the events that started within `window` of now, oldest first.
+    pub fn recent(&self, window: Duration) -> Vec<LagEvent> {
+        let since = unix_millis().saturating_sub(window.as_millis() as u64);
+        self.events
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|event| event.started_at_millis >= since)
+            .cloned()
+            .collect()
+    }
+
+    /// Counts the events that started within `window` of now.
+    pub fn summary(&self, window: Duration) -> LagSummary {
+        let mut summary = LagSummary {
+            window_secs: window.as_secs(),
+            ..Default::default()
+        };
+        for event in self.recent(window) {
+            match event.kind {
+                LagEventKind::LossBurst { .. } => summary.loss_bursts += 1,
+                LagEventKind::Stall => summary.stalls += 1,
+                LagEventKind::RttSpike { .. } => summary.rtt_spikes += 1,
+            }
+        }
+        summary
+    }
+
+    fn record(&self, event: LagEvent) {
+        tracing::debug!("Lag event: {event:?}");
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+}
+
+/// Samples the connection until it closes or the log is dropped.
+async fn monitor(
+    log: Weak<LagEventLog>,
+    connection: Connection,
+    timeline: Arc<Timeline>,
+    bandwidth: Arc<BandwidthMeter>,
+    clock: SharedClock,
+    thresholds: LagThresholds,
+) {
+    let mut interval = clock::Interval::new(Arc::clone(&clock), SAMPLE_INTERVAL);
+    let mut detector = Detector::new(
+        thresholds,
+        clock.now(),
+        connection.stats().path.lost_packets,
+        bandwidth.total_received(),
+    );
+    loop {
+        interval.tick().await;
+        let Some(log) = log.upgrade() else {
+            return;
+        };
+        let closed = connection.close_reason().is_some();
+        let stats = connection.stats();
+        let sample = Sample {
+            now: clock.now(),
+            lost_packets: stats.path.lost_packets,
+            rtt: stats.path.rtt,
+            received_bytes: bandwidth.total_received(),
+            in_play: timeline.phase() == Some(Phase::Play),
+        };
+        detector.sample(&sample, |event| log.record(event));
+        if closed {
+            detector.finish(sample.now, |event| log.record(event));
+            return;
+        }
+    }
+}
+
+struct Sample {
+    now: Instant,
+    lost_packets: u64,
+    rtt: Duration,
+    /// Bytes received in the Play state so far.
+    received_bytes: u64,
+    in_play: bool,
+}
+
+/// An event that has started but not yet ended.
+struct Ongoing {
+    started: Instant,
+    started_at_millis: u64,
+}
+
+impl Ongoing {
+    fn new(started: Instant, now: Instant) -> Self {
+        Self {
+            started,
+            started_at_millis: unix_millis()
+                .saturating_sub(now.saturating_duration_since(started).as_millis() as u64),
+        }
+    }
+
+    fn end(self, now: Instant, kind: LagEventKind) -> LagEvent {
+        LagEvent {
+            started_at_millis: self.started_at_millis,
+            duration_millis: now.saturating_duration_since(self.started).as_millis() as u64,
+            kind,
+        }
+    }
+}
+
+struct Detector {
+    thresholds: LagThresholds,
+    lost_packets: u64,
+    received_bytes: u64,
+    last_received: Instant,
+    /// Moving average of the RTT, not updated during spikes.
+    average_rtt: Option<Duration>,
+    loss_burst: Option<(Ongoing, u64)>,
+    stall: Option<Ongoing>,
+    rtt_spike: Option<(Ongoing, Duration)>,
+}
+
+impl Detector {
+    fn new(
+        thresholds: LagThresholds,
+        now: Instant,
+        lost_packets: u64,
+        received_bytes: u64,
+    ) -> Self {
+        Self {
+            thresholds,
+            lost_packets,
+            received_bytes,
+            last_received: now,
+            average_rtt: None,
+            loss_burst: None,
+            stall: None,
+            rtt_spike: None,
+        }
+    }
+
+    fn sample(&mut self, sample: &Sample, mut record: impl FnMut(LagEvent)) {
+        let now = sample.now;
+
+        let lost = sample.lost_packets - self.lost_packets;
+        self.lost_packets = sample.lost_packets;
+        match &mut self.loss_burst {
+            Some((_, lost_packets)) if lost > 0 => *lost_packets += lost,
+            Some(_) => {
+                let (burst, lost_packets) = self.loss_burst.take().unwrap();
+                record(burst.end(now, LagEventKind::LossBurst { lost_packets }));
+            }
+            None if lost >= self.thresholds.loss_burst_packets => {
+                self.loss_burst = Some((Ongoing::new(now, now), lost));
+            }
+            None => {}
+        }
+
+        let received = sample.received_bytes > self.received_bytes;
+        self.received_bytes = sample.received_bytes;
+        if received || !sample.in_play {
+            self.last_received = now;
+            if let Some(stall) = self.stall.take() {
+                record(stall.end(now, LagEventKind::Stall));
+            }
+        } else if self.stall.is_none()
+            && now.saturating_duration_since(self.last_received) >= self.thresholds.stall
+        {
+            self.stall = Some(Ongoing::new(self.last_received, now));
+        }
+
+        let average_rtt = *self.average_rtt.get_or_insert(sample.rtt);
+        let spiking = sample.rtt.as_secs_f64()
+            >= average_rtt.as_secs_f64() * self.thresholds.rtt_spike_factor
+            && sample.rtt >= average_rtt + self.thresholds.rtt_spike_min_increase;
+        if spiking {
+            match &mut self.rtt_spike {
+                Some((_, peak)) => *peak = (*peak).max(sample.rtt),
+                None => self.rtt_spike = Some((Ongoing::new(now, now), sample.rtt)),
+            }
+        } else {
+            if let Some((spike, peak)) = self.rtt_spike.take() {
+                record(spike.end(
+                    now,
+                    LagEventKind::RttSpike {
+                        peak_rtt_millis: peak.as_millis() as u64,
+                        average_rtt_millis: average_rtt.as_millis() as u64,
+                    },
+                ));
+            }
+            self.average_rtt = Some((average_rtt * 7 + sample.rtt) / 8);
+        }
+    }
+
+    /// Records the events still ongoing when the connection closed.
+    fn finish(&mut self, now: Instant, mut record: impl FnMut(LagEvent)) {
+        if let Some((burst, lost_packets)) = self.loss_burst.take() {
+            record(burst.end(now, LagEventKind::LossBurst { lost_packets }));
+        }
+        if let Some(stall) = self.stall.take() {
+            record(stall.end(now, LagEventKind::Stall));
+        }
+        if let Some((spike, peak)) = self.rtt_spike.take() {
+            let average_rtt = self.average_rtt.unwrap_or_default();
+            record(spike.end(
+                now,
+                LagEventKind::RttSpike {
+                    peak_rtt_millis: peak.as_millis() as u64,
+                    average_rtt_millis: average_rtt.as_millis() as u64,
+                },
+            ));
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
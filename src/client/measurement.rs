@@ -0,0 +1,280 @@
+//! The client side of dual transport measurement (see the `measurement`
+//! module): sends a probe over QUIC and over TCP every interval while in
+//! the Play state, and keeps the round-trip times of the echoes.
+
+use crate::{
+    clock::{self, Instant, SharedClock},
+    measurement::{self, MeasurementEndpoint, PROBE_LEN},
+    phase::Phase,
+    timeline::Timeline,
+};
+use ahash::AHashMap;
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    sync::{Arc, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+/// Maximum number of round-trip times retained per transport.
+const MAX_SAMPLES: usize = 1024;
+
+/// Options for dual transport measurement.
+#[derive(Debug, Clone, Copy)]
+pub struct MeasurementOptions {
+    /// Time between probes.
+    pub interval: Duration,
+    /// Time after which a probe without an echo counts as timed out.
+    pub timeout: Duration,
+}
+
+impl Default for MeasurementOptions {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    Quic,
+    Tcp,
+}
+
+/// Round-trip time percentiles of one transport.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TransportSummary {
+    /// Probes sent, including those still awaiting their echo.
+    pub probes: u64,
+    pub timeouts: u64,
+    /// `None` until an echo has been received.
+    pub p50_millis: Option<f64>,
+    pub p90_millis: Option<f64>,
+    pub p99_millis: Option<f64>,
+    pub max_millis: Option<f64>,
+}
+
+impl TransportSummary {
+    fn new(probes: &Probes) -> Self {
+        let mut round_trips: Vec<_> = probes.round_trips.iter().copied().collect();
+        round_trips.sort_unstable();
+        let percentile = |q: f64| {
+            let index = ((round_trips.len() - 1) as f64 * q).round() as usize;
+            round_trips[index].as_secs_f64() * 1000.0
+        };
+        let has_samples = !round_trips.is_empty();
+        Self {
+            probes: probes.sent,
+            timeouts: probes.timeouts,
+            p50_millis: has_samples.then(|| percentile(0.5)),
+            p90_millis: has_samples.then(|| percentile(0.9)),
+            p99_millis: has_samples.then(|| percentile(0.99)),
+            max_millis: has_samples.then(|| percentile(1.0)),
+        }
+    }
+}
+
+impl Display for TransportSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.p50_millis, self.p90_millis, self.p99_millis) {
+            (Some(p50), Some(p90), Some(p99)) => {
+                write!(f, "p50 {p50:.1} ms, p90 {p90:.1} ms, p99 {p99:.1} ms")?
+            }
+            _ => write!(f, "no echoes")?,
+        }
+        write!(f, " ({} probes, {} timed out)", self.probes, self.timeouts)
+    }
+}
+
+/// Round-trip times of the same probes over QUIC and TCP.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct MeasurementReport {
+    pub quic: TransportSummary,
+    pub tcp: TransportSummary,
+}
+
+impl Display for MeasurementReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QUIC: {}; TCP: {}", self.quic, self.tcp)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Probes {
+    /// Send time of each probe awaiting its echo, by ID.
+    pending: AHashMap<u64, Instant>,
+    round_trips: VecDeque<Duration>,
+    sent: u64,
+    timeouts: u64,
+}
+
+impl Probes {
+    fn expire(&mut self, now: Instant, timeout: Duration) {
+        let before = self.pending.len();
+        self.pending
+            .retain(|_, sent| now.saturating_duration_since(*sent) < timeout);
+        self.timeouts += (before - self.pending.len()) as u64;
+    }
+}
+
+/// Round-trip times measured on a session.
+#[derive(Debug, Default)]
+pub struct MeasurementLog {
+    quic: Mutex<Probes>,
+    tcp: Mutex<Probes>,
+}
+
+impl MeasurementLog {
+    /// Creates a log that measures the connection until either
+    /// is dropped, using the endpoint issued by the gateway.
+    pub(crate) fn monitor(
+        connection: Connection,
+        endpoint: MeasurementEndpoint,
+        timeline: Arc<Timeline>,
+        clock: SharedClock,
+        options: MeasurementOptions,
+    ) -> Arc<Self> {
+        let log = Arc::new(Self::default());
+        let weak = Arc::downgrade(&log);
+        tokio::spawn(async move {
+            if let Err(e) = measure(weak, connection, endpoint, timeline, clock, options).await {
+                tracing::warn!("Measurement failed: {e:#}");
+            }
+        });
+        log
+    }
+
+    /// Gets the retained round-trip times of `transport`, oldest first.
+    pub fn samples(&self, transport: Transport) -> Vec<Duration> {
+        self.probes(transport)
+            .lock()
+            .unwrap()
+            .round_trips
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    pub fn report(&self) -> MeasurementReport {
+        MeasurementReport {
+            quic: TransportSummary::new(&self.quic.lock().unwrap()),
+            tcp: TransportSummary::new(&self.tcp.lock().unwrap()),
+        }
+    }
+
+    fn probes(&self, transport: Transport) -> &Mutex<Probes> {
+        match transport {
+            Transport::Quic => &self.quic,
+            Transport::Tcp => &self.tcp,
+        }
+    }
+
+    fn record_sent(&self, transport: Transport, id: u64, now: Instant) {
+        let mut probes = self.probes(transport).lock().unwrap();
+        probes.pending.insert(id, now);
+        probes.sent += 1;
+    }
+
+    fn record_echo(&self, transport: Transport, id: u64, now: Instant) {
+        let mut probes = self.probes(transport).lock().unwrap();
+        // Echoes of timed out probes are ignored.
+        let Some(sent) = probes.pending.remove(&id) else {
+            return;
+        };
+        if probes.round_trips.len() == MAX_SAMPLES {
+            probes.round_trips.pop_front();
+        }
+        probes
+            .round_trips
+            .push_back(now.saturating_duration_since(sent));
+    }
+}
+
+/// Sends probes until the connection closes or the log is dropped.
+async fn measure(
+    log: Weak<MeasurementLog>,
+    connection: Connection,
+    endpoint: MeasurementEndpoint,
+    timeline: Arc<Timeline>,
+    clock: SharedClock,
+    options: MeasurementOptions,
+) -> anyhow::Result<()> {
+    let (mut quic_send, quic_recv) = connection.open_bi().await?;
+    // Connect right away, since the token expires.
+    let mut tcp = TcpStream::connect((connection.remote_address().ip(), endpoint.port)).await?;
+    tcp.set_nodelay(true)?;
+    tcp.write_all(&endpoint.token).await?;
+    let (tcp_recv, mut tcp_send) = tcp.into_split();
+    tokio::spawn(receive_echoes(
+        log.clone(),
+        Transport::Quic,
+        quic_recv,
+        Arc::clone(&clock),
+    ));
+    tokio::spawn(receive_echoes(
+        log.clone(),
+        Transport::Tcp,
+        tcp_recv,
+        Arc::clone(&clock),
+    ));
+
+    let mut interval = clock::Interval::new(Arc::clone(&clock), options.interval);
+    let mut next_id = 0;
+    loop {
+        interval.tick().await;
+        let Some(log) = log.upgrade() else {
+            return Ok(());
+        };
+        if connection.close_reason().is_some() {
+            return Ok(());
+        }
+        for transport in [Transport::Quic, Transport::Tcp] {
+            log.probes(transport)
+                .lock()
+                .unwrap()
+                .expire(clock.now(), options.timeout);
+        }
+        if timeline.phase() != Some(Phase::Play) {
+            continue;
+        }
+        let probe = measurement::encode_probe(next_id);
+        log.record_sent(Transport::Quic, next_id, clock.now());
+        send_probe(&mut quic_send, &probe).await?;
+        log.record_sent(Transport::Tcp, next_id, clock.now());
+        send_probe(&mut tcp_send, &probe).await?;
+        next_id += 1;
+    }
+}
+
+async fn send_probe(
+    writer: &mut (impl AsyncWrite + Unpin),
+    probe: &[u8; PROBE_LEN],
+) -> anyhow::Result<()> {
+    writer.write_all(probe).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn receive_echoes(
+    log: Weak<MeasurementLog>,
+    transport: Transport,
+    mut reader: impl AsyncRead + Unpin,
+    clock: SharedClock,
+) {
+    let mut probe = [0; PROBE_LEN];
+    while reader.read_exact(&mut probe).await.is_ok() {
+        let Some(log) = log.upgrade() else {
+            return;
+        };
+        log.record_echo(transport, measurement::decode_probe(&probe), clock.now());
+    }
+}
@@ -0,0 +1,169 @@
+//! Resolution of gateway host names.
+//!
+//! The system resolver can be slow or broken on players' machines, adding
+//! seconds to every join. Instead of the system resolver, a `Resolver` can
+//! use an in-process caching resolver, querying the system's DNS servers,
+//! or resolve over HTTPS (DoH) with a public provider.
+//!
+//! Failures of the in-process backends other than the name not existing
+//! (e.g. DoH being blocked) fall back to the system resolver, so they cost
+//! at most a timeout, never a connection. Nonexistent names are cached for
+//! a short time only, so a record that was just created is picked up soon.
+
+use anyhow::{bail, Context};
+use hickory_resolver::{
+    config::{ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
+    system_conf, TokioAsyncResolver,
+};
+use std::{
+    fmt::{self, Display},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use strum::{EnumString, IntoStaticStr};
+
+/// Number of names cached by the in-process backends.
+const CACHE_SIZE: usize = 64;
+/// Bounds on how long a name that does not exist is cached.
+const NEGATIVE_MIN_TTL: Duration = Duration::from_secs(5);
+const NEGATIVE_MAX_TTL: Duration = Duration::from_secs(30);
+/// Timeout of each query of the in-process backends.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How a `Resolver` resolves host names.
+///
+/// Written as `system`, `caching` or `doh:<provider>`, e.g. `doh:cloudflare`.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub enum ResolverBackend {
+    /// The operating system's resolver, without caching of its own.
+    #[default]
+    System,
+    /// An in-process resolver querying the system's configured DNS
+    /// servers, caching answers for their TTL.
+    Caching,
+    /// DNS over HTTPS, caching answers for their TTL.
+    DnsOverHttps(DohProvider),
+}
+
+/// Public DNS-over-HTTPS services.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, EnumString, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum DohProvider {
+    Cloudflare,
+    Google,
+    Quad9,
+}
+
+impl FromStr for ResolverBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Self::System),
+            "caching" => Ok(Self::Caching),
+            _ => match s.strip_prefix("doh:") {
+                Some(provider) => {
+                    Ok(Self::DnsOverHttps(provider.parse().with_context(|| {
+                        format!("unknown DNS-over-HTTPS provider '{provider}'")
+                    })?))
+                }
+                None => bail!(
+                    "invalid resolver '{s}' (expected `system`, `caching` or `doh:<provider>`)"
+                ),
+            },
+        }
+    }
+}
+
+impl Display for ResolverBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::Caching => write!(f, "caching"),
+            Self::DnsOverHttps(provider) => write!(f, "doh:{}", <&str>::from(provider)),
+        }
+    }
+}
+
+/// Resolves `host:port` addresses. See the module docs.
+#[derive(Debug)]
+pub struct Resolver {
+    backend: ResolverBackend,
+    /// Unset for the system backend.
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl Resolver {
+    pub fn new(backend: ResolverBackend) -> anyhow::Result<Arc<Self>> {
+        let (config, mut opts) = match backend {
+            ResolverBackend::System => {
+                return Ok(Arc::new(Self {
+                    backend,
+                    resolver: None,
+                }))
+            }
+            ResolverBackend::Caching => system_conf::read_system_conf()
+                .context("failed to read the system's DNS configuration")?,
+            ResolverBackend::DnsOverHttps(provider) => {
+                let config = match provider {
+                    DohProvider::Cloudflare => ResolverConfig::cloudflare_https(),
+                    DohProvider::Google => ResolverConfig::google_https(),
+                    DohProvider::Quad9 => ResolverConfig::quad9_https(),
+                };
+                (config, ResolverOpts::default())
+            }
+        };
+        opts.cache_size = CACHE_SIZE;
+        opts.negative_min_ttl = Some(NEGATIVE_MIN_TTL);
+        opts.negative_max_ttl = Some(NEGATIVE_MAX_TTL);
+        opts.timeout = QUERY_TIMEOUT;
+        Ok(Arc::new(Self {
+            backend,
+            resolver: Some(TokioAsyncResolver::tokio(config, opts)),
+        }))
+    }
+
+    pub fn backend(&self) -> ResolverBackend {
+        self.backend
+    }
+
+    /// Resolves an address given as `host:port`.
+    pub async fn lookup(&self, address: &str) -> anyhow::Result<Vec<SocketAddr>> {
+        if let Ok(address) = address.parse::<SocketAddr>() {
+            return Ok(vec![address]);
+        }
+        let Some(resolver) = &self.resolver else {
+            return lookup_system(address).await;
+        };
+        let (host, port) = address
+            .rsplit_once(':')
+            .with_context(|| format!("address '{address}' has no port"))?;
+        let port: u16 = port
+            .parse()
+            .with_context(|| format!("invalid port in address '{address}'"))?;
+        match resolver.lookup_ip(host).await {
+            Ok(ips) => Ok(ips.iter().map(|ip| SocketAddr::new(ip, port)).collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                bail!("host '{host}' does not exist")
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to resolve {host} with the {} resolver, falling back to the system resolver: {e}",
+                    self.backend
+                );
+                lookup_system(address).await
+            }
+        }
+    }
+}
+
+/// Resolves an address given as `host:port` with the system resolver.
+pub(super) async fn lookup_system(address: &str) -> anyhow::Result<Vec<SocketAddr>> {
+    Ok(tokio::net::lookup_host(address)
+        .await
+        .with_context(|| format!("failed to resolve '{address}'"))?
+        .collect())
+}
@@ -0,0 +1,196 @@
+//! Resumption of the Play state on a new connection to the gateway
+//! after the connection drops (see `ClientOptions::resume_sessions`).
+//!
+//! While reconnecting, packets from the game are held, and sent to the
+//! gateway once the session is resumed. Packets in flight on the dropped
+//! connection are lost. Failed attempts to reconnect are retried with
+//! exponential backoff (see `ReconnectBackoff`) until the grace time ends.
+
+use super::{connect, ClientOptions};
+use crate::{
+    clock, control_stream,
+    hold::HeldPackets,
+    protocol::{
+        optimized_codec::CodecVersion,
+        packet::{client, side, state},
+    },
+    proxy::{Instrumentation, PacketIo, QuicPacketIo, VanillaPacketIo},
+};
+use anyhow::{anyhow, Context};
+use quinn::{Connection, ConnectionError, Endpoint, VarInt};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::select;
+
+/// Maximum number of packets from the game held while reconnecting,
+/// besides keepalives.
+const MAX_HELD_PACKETS: usize = 4096;
+
+/// Delays between attempts to reconnect to the gateway. The delay
+/// starts at `initial` and doubles after each failed attempt, up to `max`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReconnectBackoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl ReconnectBackoff {
+    /// Gets the delay after the given failed attempt, counting from zero.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        self.initial
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max)
+    }
+}
+
+impl Default for ReconnectBackoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(250),
+            max: Duration::from_secs(8),
+        }
+    }
+}
+
+/// Reconnects to the gateway to resume the session.
+pub(crate) struct Reconnector {
+    endpoint: Endpoint,
+    options: ClientOptions,
+    /// Address of the gateway the session is on, as `host:port`.
+    address: String,
+    server_name: String,
+    /// The current connection to the gateway, shared with the `ClientHandle`.
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl Reconnector {
+    pub fn new(
+        endpoint: Endpoint,
+        options: ClientOptions,
+        address: String,
+        server_name: String,
+        connection: Arc<Mutex<Connection>>,
+    ) -> Self {
+        Self {
+            endpoint,
+            options,
+            address,
+            server_name,
+            connection,
+        }
+    }
+
+    /// Whether the session on `connection` may be resumed after
+    /// the Play state failed: the gateway granted resumption, and the
+    /// connection dropped rather than being closed by either side.
+    pub fn may_resume(
+        &self,
+        connection: &Connection,
+        control_stream: &control_stream::ClientSide,
+    ) -> bool {
+        control_stream.resumption_grant().is_some()
+            && matches!(
+                connection.close_reason(),
+                Some(ConnectionError::TimedOut | ConnectionError::Reset)
+            )
+    }
+
+    /// Reconnects to the gateway and resumes the session within the grace
+    /// time, holding the packets the game sends in the meantime. Returns
+    /// the new connection for the Play state, and the held packets.
+    pub async fn resume(
+        &self,
+        control_stream: &mut control_stream::ClientSide,
+        client: &VanillaPacketIo<side::Server, state::Play>,
+        codec_version: CodecVersion,
+        instrumentation: &Instrumentation,
+    ) -> anyhow::Result<(QuicPacketIo<side::Client>, Vec<client::play::Packet>)> {
+        let grace = control_stream
+            .resumption_grant()
+            .context("gateway has not granted resumption")?
+            .grace();
+        let mut held = HeldPackets::new(MAX_HELD_PACKETS);
+        let connection = {
+            let reconnect = clock::timeout(
+                &*instrumentation.clock,
+                grace,
+                self.reconnect(control_stream, codec_version, &*instrumentation.clock),
+            );
+            tokio::pin!(reconnect);
+            loop {
+                select! {
+                    result = &mut reconnect => {
+                        break result.map_err(|_| {
+                            anyhow!("failed to resume the session within {}s", grace.as_secs())
+                        })??;
+                    }
+                    packet = client.recv_packet() => {
+                        held.hold(packet?)
+                            .context("game sent too many packets while reconnecting")?;
+                    }
+                }
+            }
+        };
+
+        let mut gateway = QuicPacketIo::with_instrumentation(
+            connection.clone(),
+            codec_version,
+            instrumentation.clone(),
+        )
+        .await?;
+        if control_stream.redundancy_enabled() {
+            gateway = gateway.with_redundancy();
+        }
+        if let Some(preferences) = control_stream.transport_preferences() {
+            if let Some(max_uni_streams) = preferences.max_uni_streams {
+                connection.set_max_concurrent_uni_streams(VarInt::from_u32(max_uni_streams));
+            }
+            if !preferences.datagrams {
+                gateway = gateway.without_datagrams();
+            }
+        }
+        *self.connection.lock().unwrap() = connection;
+        Ok((gateway, held.release().collect()))
+    }
+
+    /// Connects to the gateway until a connection succeeds, then
+    /// asks it to resume the session. Cancel safe.
+    async fn reconnect(
+        &self,
+        control_stream: &mut control_stream::ClientSide,
+        codec_version: CodecVersion,
+        clock: &dyn clock::Clock,
+    ) -> anyhow::Result<Connection> {
+        let mut attempt = 0;
+        let connection = loop {
+            match connect(
+                &self.endpoint,
+                &self.options,
+                &self.address,
+                &self.server_name,
+            )
+            .await
+            {
+                Ok(connection) => break connection,
+                Err(e) => {
+                    let delay = self.options.reconnect_backoff.delay(attempt);
+                    tracing::debug!(
+                        "Failed to reconnect to {}, retrying in {delay:?}: {e:#}",
+                        self.address
+                    );
+                    clock.sleep_until(clock.now() + delay).await;
+                    attempt += 1;
+                }
+            }
+        };
+        // The gateway only hands the session over once, so a refusal is final.
+        control_stream.resume(&connection, codec_version).await?;
+        tracing::info!(
+            "Resumed the session on a new connection to {}",
+            self.address
+        );
+        Ok(connection)
+    }
+}
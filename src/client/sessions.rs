@@ -0,0 +1,71 @@
+//! Tracks the open session to each destination, so that opening
+//! a second client for the same destination (e.g. when the player
+//! retries from the UI) closes the first one rather than leaving
+//! its listener waiting for a game connection that never comes.
+
+use crate::{client::ClientHandle, destination::Destination};
+use ahash::AHashMap;
+use quinn::Connection;
+use std::sync::Mutex;
+
+/// The current session to each destination.
+#[derive(Debug, Default)]
+pub struct ClientSessions {
+    sessions: Mutex<AHashMap<Destination, Session>>,
+}
+
+#[derive(Debug)]
+struct Session {
+    bound_port: u16,
+    gateway_connection: Connection,
+}
+
+impl Session {
+    fn is_open(&self) -> bool {
+        self.gateway_connection.close_reason().is_none()
+    }
+}
+
+impl ClientSessions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Makes `client` the session to its destination, closing the
+    /// previous one if it is still open.
+    ///
+    /// Returns the local port of the closed session, if any.
+    pub fn register(&self, client: &ClientHandle) -> Option<u16> {
+        let previous = self.sessions.lock().unwrap().insert(
+            client.destination().clone(),
+            Session {
+                bound_port: client.bound_port(),
+                gateway_connection: client.gateway_connection.clone(),
+            },
+        );
+        let previous = previous.filter(Session::is_open)?;
+        tracing::info!(
+            "Closing the previous session to {} on port {}",
+            client.destination(),
+            previous.bound_port
+        );
+        ClientHandle::close_connection(&previous.gateway_connection, "superseded by a new session");
+        Some(previous.bound_port)
+    }
+
+    /// Gets the local port of the current session to `destination`,
+    /// which is the one the game should connect to.
+    ///
+    /// `None` if there is none or it has ended.
+    pub fn port(&self, destination: &Destination) -> Option<u16> {
+        let mut sessions = self.sessions.lock().unwrap();
+        match sessions.get(destination) {
+            Some(session) if session.is_open() => Some(session.bound_port),
+            Some(_) => {
+                sessions.remove(destination);
+                None
+            }
+            None => None,
+        }
+    }
+}
@@ -0,0 +1,162 @@
+//! Persistent client-side state, remembered per gateway across game restarts.
+//!
+//! The store is a JSON file holding a `GatewayRecord` for each gateway
+//! address (`host:port`) the client has connected to:
+//! * the fingerprint of the gateway's certificate, pinned on first use
+//!   if `ClientOptions::pin_certificates` is set;
+//! * the affinity token last issued through the address;
+//! * transport hints learned from the last connection, which seed the
+//!   transport of the next one.
+//!
+//! A missing or unreadable file is treated as empty, so a corrupt store
+//! costs at most the benefits above, never a connection.
+
+use crate::affinity::AffinityToken;
+use anyhow::{bail, Context};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// State remembered about one gateway address.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GatewayRecord {
+    /// Hex-encoded SHA-256 of the gateway's end-entity certificate.
+    pub certificate_fingerprint: Option<String>,
+    /// Affinity token last issued by a gateway reached through this address.
+    pub affinity_token: Option<AffinityToken>,
+    pub transport: Option<TransportHints>,
+}
+
+/// Transport parameters learned from a previous connection to a gateway.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TransportHints {
+    /// Round-trip time measured after the handshake, used as the
+    /// initial estimate so the first packets aren't paced for the
+    /// default RTT.
+    pub rtt_millis: u32,
+}
+
+impl TransportHints {
+    /// Applies the hints to a transport config based on `transport_config()`.
+    pub fn apply(&self, config: &mut quinn::TransportConfig) {
+        // Sub-millisecond RTTs (e.g. on loopback) are stored as 0.
+        config.initial_rtt(Duration::from_millis(self.rtt_millis.max(1).into()));
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct StoreData {
+    gateways: BTreeMap<String, GatewayRecord>,
+}
+
+/// A store shared by the clients of a process. See the module docs.
+#[derive(Debug)]
+pub struct ClientStore {
+    path: PathBuf,
+    data: Mutex<StoreData>,
+}
+
+impl ClientStore {
+    /// Opens the store at `path`, which is created on the first update.
+    pub fn open(path: impl Into<PathBuf>) -> Arc<Self> {
+        let path = path.into();
+        let data = match load(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                tracing::warn!("Ignoring client store {}: {e:#}", path.display());
+                StoreData::default()
+            }
+        };
+        Arc::new(Self {
+            path,
+            data: Mutex::new(data),
+        })
+    }
+
+    /// Gets the record of a gateway address (`host:port`).
+    pub fn gateway(&self, address: &str) -> GatewayRecord {
+        self.data
+            .lock()
+            .unwrap()
+            .gateways
+            .get(address)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Modifies the record of a gateway address and saves the store.
+    pub fn update(&self, address: &str, update: impl FnOnce(&mut GatewayRecord)) {
+        let mut data = self.data.lock().unwrap();
+        update(data.gateways.entry(address.to_owned()).or_default());
+        if let Err(e) = save(&self.path, &data) {
+            tracing::warn!("Failed to save client store {}: {e:#}", self.path.display());
+        }
+    }
+
+    /// Checks the certificate of a new connection to `address` against
+    /// the pinned fingerprint, pinning it if there is none yet.
+    pub fn verify_pinned_certificate(
+        &self,
+        address: &str,
+        connection: &Connection,
+    ) -> anyhow::Result<()> {
+        let fingerprint = certificate_fingerprint(connection)?;
+        match self.gateway(address).certificate_fingerprint {
+            Some(pinned) if pinned != fingerprint => bail!(
+                "certificate of gateway {address} does not match the pinned fingerprint \
+                 (pinned {pinned}, presented {fingerprint}); remove it from {} if the \
+                 gateway's certificate was replaced",
+                self.path.display()
+            ),
+            Some(_) => Ok(()),
+            None => {
+                tracing::info!("Pinning certificate of gateway {address} ({fingerprint})");
+                self.update(address, |record| {
+                    record.certificate_fingerprint = Some(fingerprint)
+                });
+                Ok(())
+            }
+        }
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<StoreData> {
+    if !path.exists() {
+        return Ok(StoreData::default());
+    }
+    let contents = fs_err::read(path)?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Writes to a temporary file first, so an interrupted
+/// save cannot leave a truncated store behind.
+fn save(path: &Path, data: &StoreData) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    fs_err::write(&temp_path, serde_json::to_vec_pretty(data)?)?;
+    fs_err::rename(&temp_path, path)?;
+    Ok(())
+}
+
+fn certificate_fingerprint(connection: &Connection) -> anyhow::Result<String> {
+    let certificates = connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .context("gateway presented no certificate")?;
+    let certificate = certificates
+        .first()
+        .context("gateway presented no certificate")?;
+    let mut fingerprint = String::new();
+    for byte in Sha256::digest(&certificate.0) {
+        write!(fingerprint, "{byte:02x}").unwrap();
+    }
+    Ok(fingerprint)
+}
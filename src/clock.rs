@@ -0,0 +1,240 @@
+//! Source of time for expiring state, timeouts and periodic tasks.
+//!
+//! Components read the time from a [`Clock`] rather than directly from
+//! the OS, so that tests and simulations can substitute a [`ManualClock`]
+//! and advance time at will instead of waiting for it to pass.
+
+pub use std::time::Instant;
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{select, sync::watch};
+
+/// A source of time.
+pub trait Clock: Debug + Send + Sync + 'static {
+    /// Gets the current time.
+    fn now(&self) -> Instant;
+
+    /// Gets the current wall-clock time, for comparing
+    /// with timestamps that come from outside the process.
+    fn system_time(&self) -> SystemTime;
+
+    /// Returns a future that completes once the clock reaches `deadline`.
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+/// A clock shared between components.
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Gets a shared handle to the system clock.
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+/// The real (monotonic) time. Timers are driven by Tokio.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(tokio::time::sleep_until(deadline.into()))
+    }
+}
+
+/// A clock that only advances when `advance` is called.
+///
+/// Clones share the same time.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<watch::Sender<Instant>>,
+    /// The wall-clock time at `started`.
+    started_at: SystemTime,
+    started: Instant,
+}
+
+impl ManualClock {
+    /// Creates a clock starting at the current system time.
+    pub fn new() -> Self {
+        let started = Instant::now();
+        Self {
+            now: Arc::new(watch::Sender::new(started)),
+            started_at: SystemTime::now(),
+            started,
+        }
+    }
+
+    /// Advances the clock, completing any sleeps whose deadline is reached.
+    pub fn advance(&self, duration: Duration) {
+        self.now.send_modify(|now| *now += duration);
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        *self.now.borrow()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.started_at + (self.now() - self.started)
+    }
+
+    fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        let mut now = self.now.subscribe();
+        Box::pin(async move {
+            // The sender lives as long as any clone of the clock,
+            // so `Err` means time can never advance again.
+            if now.wait_for(|now| *now >= deadline).await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    }
+}
+
+/// Error returned by `timeout` when the future did not complete in time.
+#[derive(Debug, thiserror::Error)]
+#[error("deadline has elapsed")]
+pub struct Elapsed;
+
+/// Like `tokio::time::timeout`, but measured on the given clock.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    let sleep = clock.sleep_until(clock.now() + duration);
+    select! {
+        output = future => Ok(output),
+        _ = sleep => Err(Elapsed),
+    }
+}
+
+/// Like `tokio::time::Interval`, but measured on the given clock.
+///
+/// Missed ticks are skipped rather than fired in a burst.
+#[derive(Debug)]
+pub struct Interval {
+    clock: SharedClock,
+    period: Duration,
+    next_tick: Instant,
+}
+
+impl Interval {
+    /// Creates an interval whose first tick is one period from now.
+    pub fn new(clock: SharedClock, period: Duration) -> Self {
+        let next_tick = clock.now() + period;
+        Self {
+            clock,
+            period,
+            next_tick,
+        }
+    }
+
+    /// Waits for the next tick. Cancel safe.
+    pub async fn tick(&mut self) -> Instant {
+        self.clock.sleep_until(self.next_tick).await;
+        let tick = self.next_tick;
+        let now = self.clock.now();
+        self.next_tick += self.period;
+        if self.next_tick <= now {
+            self.next_tick = now + self.period;
+        }
+        tick
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+    use std::{pin::pin, task::Poll};
+
+    #[tokio::test]
+    async fn sleep_completes_once_advanced_to_deadline() {
+        let clock = ManualClock::new();
+        let mut sleep = clock.sleep_until(clock.now() + Duration::from_secs(10));
+        assert_eq!(poll!(&mut sleep), Poll::Pending);
+
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(poll!(&mut sleep), Poll::Pending);
+
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(poll!(&mut sleep), Poll::Ready(()));
+    }
+
+    #[test]
+    fn system_time_advances_with_manual_clock() {
+        let clock = ManualClock::new();
+        let before = clock.system_time();
+        clock.advance(Duration::from_secs(90));
+        assert_eq!(
+            clock.system_time().duration_since(before).unwrap(),
+            Duration::from_secs(90)
+        );
+    }
+
+    #[tokio::test]
+    async fn timeout_elapses_on_manual_clock() {
+        let clock = ManualClock::new();
+        let mut elapsed = pin!(timeout(
+            &clock,
+            Duration::from_secs(30),
+            std::future::pending::<()>()
+        ));
+        assert!(poll!(&mut elapsed).is_pending());
+
+        clock.advance(Duration::from_secs(29));
+        assert!(poll!(&mut elapsed).is_pending());
+
+        clock.advance(Duration::from_secs(1));
+        assert!(matches!(poll!(&mut elapsed), Poll::Ready(Err(Elapsed))));
+    }
+
+    #[tokio::test]
+    async fn timeout_returns_output_before_deadline() {
+        let clock = ManualClock::new();
+        let output = timeout(&clock, Duration::from_secs(30), async { 7 }).await;
+        assert_eq!(output.unwrap(), 7);
+    }
+
+    #[tokio::test]
+    async fn interval_skips_missed_ticks() {
+        let clock = ManualClock::new();
+        let start = clock.now();
+        let period = Duration::from_secs(1);
+        let mut interval = Interval::new(Arc::new(clock.clone()), period);
+
+        clock.advance(period);
+        assert_eq!(interval.tick().await, start + period);
+
+        // Missing three ticks fires one, then resumes a period later.
+        clock.advance(period * 3 + Duration::from_millis(500));
+        assert_eq!(interval.tick().await, start + period * 2);
+        let mut tick = pin!(interval.tick());
+        assert!(poll!(&mut tick).is_pending());
+        clock.advance(Duration::from_millis(999));
+        assert!(poll!(&mut tick).is_pending());
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(
+            poll!(&mut tick),
+            Poll::Ready(start + period * 5 + Duration::from_millis(500))
+        );
+    }
+}
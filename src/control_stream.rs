@@ -4,14 +4,30 @@
 //! This stream contains special messages used by the proxy system.
 //! It uses `bincode` for encoding and a simple length-delimited codec
 //! for packet framing. It is not related to the Minecraft protocol encoding.
+//!
+//! Messages beyond the core exchange are extensions, which a peer that
+//! predates them cannot decode. The gateway advertises the extensions it
+//! supports (see `Capability`), and the client only uses those. Gateways
+//! never send extension messages unprompted, except to clients whose build
+//! report lists the extension, so older clients are unaffected.
 
-use crate::io_duplex::IoDuplex;
-use anyhow::{anyhow, Context};
+use crate::{
+    affinity::AffinityToken,
+    build_info::BuildInfo,
+    destination::Destination,
+    io_duplex::IoDuplex,
+    measurement::{MeasurementEndpoint, MeasurementIssuer},
+    phase::{Phase, PhaseTracker},
+    protocol::{optimized_codec::CodecVersion, packet::ProtocolState},
+    timeline::unix_micros,
+};
+use anyhow::{anyhow, bail, Context};
 use bincode::Options;
-use futures::{SinkExt, StreamExt};
-use quinn::{Connection, RecvStream, SendStream};
+use futures::{future, SinkExt, StreamExt};
+use quinn::{Connection, RecvStream, SendStream, StreamId, VarInt};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
+use strum::IntoEnumIterator;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 /// A message sent by the client over the control stream.
@@ -19,8 +35,88 @@ use tokio_util::codec::{Framed, LengthDelimitedCodec};
 enum ClientMessage {
     ConnectTo(ConnectTo),
     EnableTerminalEncryption(EnableTerminalEncryption),
+    ClientMetrics(ClientMetrics),
+    TimeSync(TimeSync),
+    StateTransition(StateTransition),
+    /// Asks the gateway to send critical packets redundantly and to
+    /// drop duplicates of those received (see the `redundancy` module).
+    /// Sent right after the time sync, and never acknowledged.
+    EnableRedundancy,
+    /// Asks the gateway for an affinity token (see the `affinity` module),
+    /// presenting the one from a previous connection, if any. Sent between
+    /// `ConnectTo` and the time sync, and answered with `GatewayMessage::AffinityToken`.
+    RequestAffinity {
+        presented_token: Option<AffinityToken>,
+    },
+    /// Reports the client's build, asking for the gateway's. Sent between
+    /// `ConnectTo` and the time sync, and answered with `GatewayMessage::BuildInfo`.
+    BuildInfo(BuildInfo),
+    /// Asks the gateway for a dual transport measurement endpoint (see the
+    /// `measurement` module). Sent between `ConnectTo` and the time sync,
+    /// and answered with `GatewayMessage::MeasurementEndpoint`.
+    EnableMeasurement,
+    /// Like `ConnectTo`, naming the destination by host name for the
+    /// gateway to resolve. Sent instead of `ConnectTo` only for such
+    /// destinations, since the gateway's capabilities are not known yet:
+    /// gateways that predate it fail to decode it and close the stream.
+    ConnectToHost(ConnectToHost),
+    /// Asks the gateway to let the client resume the session if its
+    /// connection drops (see `ClientSide::resume`). Sent between `ConnectTo`
+    /// and the time sync, and answered with `GatewayMessage::ResumptionGrant`.
+    RequestResumption,
+    /// Sent instead of `ConnectTo` on a new connection, to continue the Play
+    /// state of the session the token was granted for. Answered like
+    /// `ConnectTo`, after which both sides are in the Play state.
+    Resume {
+        token: ResumptionToken,
+        codec_versions: Vec<u8>,
+    },
+    /// Asks the gateway which destination server it connected to, which
+    /// may be a fallback of the one requested. Sent between `ConnectTo` and
+    /// the time sync, and answered with `GatewayMessage::ConnectedDestination`.
+    QueryDestination,
+    /// Asks the gateway to apply transport settings to the connection.
+    /// Sent between `ConnectTo` and the time sync, and answered with
+    /// `GatewayMessage::TransportPreferences`.
+    TransportPreferences(TransportPreferences),
+}
+
+/// An optional control stream extension.
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, strum::EnumIter, strum::EnumString, strum::IntoStaticStr,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum Capability {
+    /// `ClientMessage::ClientMetrics`.
+    ClientMetrics,
+    /// `ClientMessage::EnableRedundancy`.
+    Redundancy,
+    /// `ClientMessage::RequestAffinity`.
+    Affinity,
+    /// `ClientMessage::BuildInfo`.
+    BuildInfo,
+    /// `ClientMessage::EnableMeasurement`.
+    Measurement,
+    /// `GatewayMessage::ShuttingDown`. Only sent to clients that
+    /// list this capability in their `ClientMessage::BuildInfo`.
+    ShutdownNotice,
+    /// `ClientMessage::RequestResumption` and `ClientMessage::Resume`.
+    Resumption,
+    /// `ClientMessage::QueryDestination`.
+    DestinationReport,
+    /// `ClientMessage::TransportPreferences`.
+    TransportPreferences,
 }
 
+/// Appended to `ConnectTo::codec_versions` by clients that understand
+/// `GatewayMessage::Capabilities`. It is never a codec version, so
+/// gateways that predate capabilities skip it like any unknown version.
+const CAPABILITIES_MARKER: u8 = u8::MAX;
+
+/// Number of time sync round trips made after `ConnectTo`.
+/// The sample with the lowest round-trip time is used.
+const TIME_SYNC_ROUNDS: usize = 4;
+
 /// Message sent by the client to indicate the destination server it wishes
 /// to connect to.
 #[derive(Debug, Serialize, Deserialize)]
@@ -29,6 +125,28 @@ pub struct ConnectTo {
     pub authentication_key: String,
     /// Destination server to proxy the connection to.
     pub destination_server: SocketAddr,
+    /// Optimized codec versions supported by the client.
+    /// Sent as raw bytes so that the gateway can skip versions it does not know.
+    pub codec_versions: Vec<u8>,
+}
+
+/// Message sent by the client to indicate the destination server it wishes
+/// to connect to by host name, leaving its resolution to the gateway.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConnectToHost {
+    pub authentication_key: String,
+    pub host: String,
+    /// If `None`, the gateway looks up the `_minecraft._tcp` SRV record.
+    pub port: Option<u16>,
+    pub codec_versions: Vec<u8>,
+}
+
+/// A `ConnectTo` or `ConnectToHost`, as received by the gateway.
+#[derive(Debug)]
+pub struct ConnectRequest {
+    pub authentication_key: String,
+    pub destination: Destination,
+    pub codec_versions: Vec<u8>,
 }
 
 /// Message sent by the client to inform the gateway of the shared
@@ -41,16 +159,239 @@ pub struct EnableTerminalEncryption {
     pub key: [u8; 16],
 }
 
+/// Message sent by the client when it switches its packet streams to a new
+/// protocol state, after finishing the stream of the previous state.
+///
+/// The client always sends the packet that causes a switch in the single-stream
+/// states, so it announces every switch and the gateway follows.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateTransition {
+    /// Name of the new state, as displayed by `Phase`.
+    pub state: String,
+    /// QUIC ID of the stream the client sends packets of the new state on.
+    /// `None` for Play, where packets are spread over many streams.
+    pub stream_id: Option<u64>,
+}
+
+/// Anonymous connection quality metrics, periodically sent by clients
+/// that opt in. Only sent during the Play state, and never acknowledged.
+///
+/// Counts are deltas since the previous report.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientMetrics {
+    /// Smoothed round-trip time at the time of the report.
+    pub rtt_millis: u32,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    /// Sequenced datagrams dropped because a newer one had already arrived.
+    pub dropped_datagrams: u64,
+    /// Switches from the Play to the Configuration state.
+    pub reconfigurations: u32,
+}
+
+/// Message sent by the client to estimate its clock offset to the gateway.
+/// Answered with `GatewayMessage::TimeSyncReply`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TimeSync {
+    pub client_time_micros: i64,
+}
+
+/// Secret identifying a session to resume. Stands in
+/// for the authentication key of the session's identity.
+pub type ResumptionToken = [u8; 16];
+
+/// Lets the client resume its session on a new connection
+/// if its connection drops during the Play state.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumptionGrant {
+    pub token: ResumptionToken,
+    /// Time the gateway keeps the session after the connection drops.
+    pub grace_millis: u64,
+}
+
+impl ResumptionGrant {
+    pub fn grace(&self) -> Duration {
+        Duration::from_millis(self.grace_millis)
+    }
+}
+
+/// The destination server the gateway connected a session to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectedDestination {
+    /// Destination as named in the gateway's configuration or by the client.
+    pub destination: String,
+    pub address: SocketAddr,
+    /// Whether it is a fallback of the destination requested.
+    pub fallback: bool,
+}
+
+/// Transport settings a client asks for, e.g. a mobile client on a
+/// network that drops out or blocks UDP datagrams.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransportPreferences {
+    /// Idle timeout of the connection. It cannot change once connected,
+    /// so the client advertises it when connecting, and the connection
+    /// uses the shorter of it and the gateway's maximum. Zero, which
+    /// would disable the timeout, is treated as no preference.
+    pub idle_timeout_millis: Option<u64>,
+    /// Whether entity movement is sent as unreliable datagrams. If not,
+    /// it is sent on the entity's stream, which tolerates networks that
+    /// drop datagrams at the cost of head-of-line blocking.
+    pub datagrams: bool,
+    /// Maximum number of unidirectional streams each side may have
+    /// open at once. Fewer streams cost less memory.
+    pub max_uni_streams: Option<u32>,
+}
+
+impl Default for TransportPreferences {
+    fn default() -> Self {
+        Self {
+            idle_timeout_millis: None,
+            datagrams: true,
+            max_uni_streams: None,
+        }
+    }
+}
+
+/// Bounds the gateway applies to the `TransportPreferences` of clients.
+#[derive(Debug, Clone)]
+pub struct TransportLimits {
+    pub max_idle_timeout: Duration,
+    pub min_uni_streams: u32,
+    pub max_uni_streams: u32,
+}
+
+impl Default for TransportLimits {
+    fn default() -> Self {
+        Self {
+            max_idle_timeout: crate::IDLE_TIMEOUT,
+            min_uni_streams: 64,
+            max_uni_streams: crate::MAX_CONCURRENT_UNI_STREAMS,
+        }
+    }
+}
+
+impl TransportLimits {
+    /// Gets the preferences as applied within the limits.
+    pub fn apply(&self, preferences: &TransportPreferences) -> TransportPreferences {
+        let max_idle_millis = self
+            .max_idle_timeout
+            .as_millis()
+            .try_into()
+            .unwrap_or(u64::MAX);
+        TransportPreferences {
+            // Zero would disable the timeout, so counts as no preference.
+            idle_timeout_millis: Some(
+                preferences
+                    .idle_timeout_millis
+                    .filter(|&millis| millis != 0)
+                    .map_or(max_idle_millis, |millis| millis.min(max_idle_millis)),
+            ),
+            datagrams: preferences.datagrams,
+            // Not `clamp`, which panics if the limits are inverted;
+            // the maximum wins then.
+            max_uni_streams: preferences
+                .max_uni_streams
+                .map(|count| count.max(self.min_uni_streams).min(self.max_uni_streams)),
+        }
+    }
+}
+
+/// The first message of a connection, as received by the gateway.
+#[derive(Debug)]
+pub enum OpeningRequest {
+    Connect(ConnectRequest),
+    Resume {
+        token: ResumptionToken,
+        codec_versions: Vec<u8>,
+    },
+}
+
+/// Result of a time sync exchange.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockOffset {
+    /// Estimated `gateway time - client time`.
+    pub offset_micros: i64,
+    /// Round-trip time of the sample used; the offset is accurate
+    /// to within half of this.
+    pub round_trip_micros: i64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 enum GatewayMessage {
     /// Sent when the gateway has completed the ConnectTo request.
-    AcknowledgeConnectTo,
+    /// Contains the optimized codec version used for all packet streams.
+    AcknowledgeConnectTo { codec_version: u8 },
     /// Sent when the gateway has received the encryption secret
     /// and has now enabled encryption for all future packets.
     AcknowledgeEnableTerminalEncryption,
     /// Sent when the gateway has received an Acknowledge Configuration
     /// packet and is ready to accept the configuration stream.
     AcknowledgeTransitionPlayToConfig,
+    /// Sent when the gateway has received a `StateTransition`
+    /// and switched its own packet stream.
+    AcknowledgeStateTransition,
+    /// Answers a `TimeSync` message.
+    TimeSyncReply {
+        client_time_micros: i64,
+        gateway_time_micros: i64,
+    },
+    /// Answers a `RequestAffinity` message. `None` if the
+    /// gateway is not configured to issue affinity tokens.
+    AffinityToken(Option<AffinityToken>),
+    /// The extensions the gateway supports, named as by `Capability`.
+    /// Sent right before `AcknowledgeConnectTo` if the client's
+    /// `ConnectTo` carries the capabilities marker.
+    Capabilities(Vec<String>),
+    /// Answers a `ClientMessage::BuildInfo` with the gateway's build.
+    BuildInfo(BuildInfo),
+    /// Answers a `ClientMessage::EnableMeasurement`. `None` if the
+    /// gateway has no measurement endpoint configured.
+    MeasurementEndpoint(Option<MeasurementEndpoint>),
+    /// Sent at most once, during the Play state, when the gateway shuts
+    /// down. The session is disconnected after the given time, unless
+    /// it ends before.
+    ShuttingDown { drain_millis: u64 },
+    /// Sent instead of answering `ConnectTo` when the gateway refuses
+    /// the connection, e.g. because it is at its session limit. The
+    /// gateway closes the connection afterward.
+    Refused { reason: String },
+    /// Answers a `RequestResumption` message. `None` if the
+    /// gateway is not configured to resume sessions.
+    ResumptionGrant(Option<ResumptionGrant>),
+    /// Answers a `QueryDestination` message. `None` if the gateway has
+    /// not connected to a destination server yet, as with virtual hosts,
+    /// where it only does once it has received the handshake.
+    ConnectedDestination(Option<ConnectedDestination>),
+    /// Answers a `TransportPreferences` message with
+    /// the preferences as the gateway applies them.
+    TransportPreferences(TransportPreferences),
+}
+
+/// Error returned by `GatewaySide` when the client sends a `ConnectTo`
+/// after it has already authenticated.
+///
+/// The identity a connection authenticated as is bound to it for its
+/// lifetime, so a connection can never act on behalf of a second identity.
+#[derive(Debug, thiserror::Error)]
+#[error("client attempted to authenticate again on the same connection")]
+pub struct ReauthenticationAttempt;
+
+/// Coordinates a switch of the packet streams used in the single-stream
+/// protocol states (everything but Play) with the other side of the connection.
+///
+/// Each side finishes its stream for the old state before switching, so the
+/// peer can drain it completely before accepting the stream for the new one.
+pub trait StateTransitions {
+    /// Called after finishing the stream for the old state and opening
+    /// `send_stream` for `State`.
+    ///
+    /// Returns the ID of the stream the peer sends packets of `State` on, if known.
+    async fn switch_state<State: ProtocolState>(
+        &mut self,
+        send_stream: Option<StreamId>,
+    ) -> anyhow::Result<Option<StreamId>>;
 }
 
 /// Used to send and receive `Message`s.
@@ -88,6 +429,21 @@ impl Codec {
 /// Wrapper over the control stream on the client's side.
 pub struct ClientSide {
     codec: Codec,
+    /// Number of `StateTransition`s the gateway has yet to acknowledge.
+    pending_transition_acks: usize,
+    /// Whether `EnableRedundancy` has been sent.
+    redundancy: bool,
+    /// Extensions the gateway advertised. Empty for
+    /// gateways that predate capabilities.
+    gateway_capabilities: Vec<Capability>,
+    /// Time left before disconnection, as of the gateway's
+    /// `ShuttingDown`. Set once it has been received.
+    gateway_shutdown: Option<Duration>,
+    /// Set once the gateway has granted resumption.
+    resumption: Option<ResumptionGrant>,
+    /// Set once the gateway has applied transport preferences.
+    transport_preferences: Option<TransportPreferences>,
+    phase: PhaseTracker,
 }
 
 impl ClientSide {
@@ -97,25 +453,93 @@ impl ClientSide {
         let (send_stream, recv_stream) = connection.open_bi().await?;
         Ok(Self {
             codec: Codec::new(send_stream, recv_stream),
+            pending_transition_acks: 0,
+            redundancy: false,
+            gateway_capabilities: Vec::new(),
+            gateway_shutdown: None,
+            resumption: None,
+            transport_preferences: None,
+            phase: PhaseTracker::new(),
         })
     }
 
     /// Sends a ConnectTo message to the gateway,
     /// then waits for acknowledgement.
+    ///
+    /// Returns the codec version chosen by the gateway. The
+    /// gateway's capabilities are known afterward.
     pub async fn connect_to(
         &mut self,
-        destination_server: SocketAddr,
+        destination: &Destination,
         authentication_key: &str,
-    ) -> anyhow::Result<()> {
-        self.codec
-            .send_message(&ClientMessage::ConnectTo(ConnectTo {
-                destination_server,
-                authentication_key: authentication_key.to_owned(),
-            }))
-            .await?;
-        self.wait_for_ack(|msg| matches!(msg, GatewayMessage::AcknowledgeConnectTo))
-            .await?;
-        Ok(())
+    ) -> anyhow::Result<CodecVersion> {
+        let mut codec_versions = CodecVersion::supported_bytes();
+        codec_versions.push(CAPABILITIES_MARKER);
+        let authentication_key = authentication_key.to_owned();
+        let message = match destination {
+            Destination::Address(destination_server) => ClientMessage::ConnectTo(ConnectTo {
+                destination_server: *destination_server,
+                authentication_key,
+                codec_versions,
+            }),
+            Destination::Host { host, port } => ClientMessage::ConnectToHost(ConnectToHost {
+                authentication_key,
+                host: host.clone(),
+                port: *port,
+                codec_versions,
+            }),
+        };
+        self.codec.send_message(&message).await?;
+        let mut message = self.recv_message().await?;
+        if let GatewayMessage::Capabilities(capabilities) = message {
+            // Skip extensions added after this build.
+            self.gateway_capabilities = capabilities
+                .iter()
+                .filter_map(|capability| capability.parse().ok())
+                .collect();
+            message = self.recv_message().await?;
+        }
+        match message {
+            GatewayMessage::AcknowledgeConnectTo { codec_version } => {
+                CodecVersion::from_u8(codec_version).with_context(|| {
+                    format!("gateway chose unsupported codec version {codec_version}")
+                })
+            }
+            GatewayMessage::Refused { reason } => {
+                Err(anyhow!("gateway refused the connection: {reason}"))
+            }
+            _ => Err(anyhow!("wrong acknowledgement received from gateway")),
+        }
+    }
+
+    /// Estimates the clock offset to the gateway. Must be called
+    /// immediately after `connect_to` (and `request_affinity`, if used).
+    pub async fn sync_time(&mut self) -> anyhow::Result<ClockOffset> {
+        let mut best: Option<ClockOffset> = None;
+        for _ in 0..TIME_SYNC_ROUNDS {
+            self.codec
+                .send_message(&ClientMessage::TimeSync(TimeSync {
+                    client_time_micros: unix_micros(),
+                }))
+                .await?;
+            let GatewayMessage::TimeSyncReply {
+                client_time_micros: sent,
+                gateway_time_micros,
+            } = self.recv_message().await?
+            else {
+                return Err(anyhow!("expected time sync reply from gateway"));
+            };
+            let received = unix_micros();
+            let sample = ClockOffset {
+                // Assume the reply was sent halfway through the round trip.
+                offset_micros: gateway_time_micros - (sent + received) / 2,
+                round_trip_micros: received - sent,
+            };
+            if best.is_none_or(|best| sample.round_trip_micros < best.round_trip_micros) {
+                best = Some(sample);
+            }
+        }
+        Ok(best.expect("no time sync rounds"))
     }
 
     pub async fn enable_terminal_encryption(&mut self, key: [u8; 16]) -> anyhow::Result<()> {
@@ -129,27 +553,357 @@ impl ClientSide {
         Ok(())
     }
 
+    /// Whether the gateway supports the given extension.
+    pub fn gateway_supports(&self, capability: Capability) -> bool {
+        self.gateway_capabilities.contains(&capability)
+    }
+
+    /// Requests redundant transmission of critical packets.
+    /// Must be called before the first state transition.
+    ///
+    /// Does nothing if the gateway does not support redundancy.
+    pub async fn enable_redundancy(&mut self) -> anyhow::Result<()> {
+        if !self.gateway_supports(Capability::Redundancy) {
+            tracing::debug!("Gateway does not support redundancy, not enabling it");
+            return Ok(());
+        }
+        self.codec
+            .send_message(&ClientMessage::EnableRedundancy)
+            .await?;
+        self.redundancy = true;
+        Ok(())
+    }
+
+    pub fn redundancy_enabled(&self) -> bool {
+        self.redundancy
+    }
+
+    /// Gets the protocol state the client has switched its streams to.
+    pub fn phase(&self) -> Phase {
+        self.phase.current()
+    }
+
+    /// Asks the gateway for an affinity token, presenting the one issued
+    /// on a previous connection. Must be called right after `connect_to`.
+    ///
+    /// Returns `None` without asking if the gateway does not support affinity tokens.
+    pub async fn request_affinity(
+        &mut self,
+        presented_token: Option<AffinityToken>,
+    ) -> anyhow::Result<Option<AffinityToken>> {
+        if !self.gateway_supports(Capability::Affinity) {
+            tracing::debug!("Gateway does not support affinity tokens, not requesting one");
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::RequestAffinity { presented_token })
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::AffinityToken(token) => Ok(token),
+            _ => Err(anyhow!("expected affinity token from gateway")),
+        }
+    }
+
+    /// Exchanges build reports with the gateway.
+    /// Must be called right after `connect_to`.
+    ///
+    /// Returns `None` without sending anything if the
+    /// gateway does not support build reports.
+    pub async fn exchange_build_info(&mut self) -> anyhow::Result<Option<BuildInfo>> {
+        if !self.gateway_supports(Capability::BuildInfo) {
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::BuildInfo(BuildInfo::current()))
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::BuildInfo(build_info) => Ok(Some(build_info)),
+            _ => Err(anyhow!("expected build report from gateway")),
+        }
+    }
+
+    /// Asks the gateway for a dual transport measurement endpoint.
+    /// Must be called before `sync_time`. Once it returns an
+    /// endpoint, the gateway echoes probes on the next bidirectional
+    /// stream the client opens.
+    ///
+    /// Returns `None` without asking if the gateway does not support measurement.
+    pub async fn enable_measurement(&mut self) -> anyhow::Result<Option<MeasurementEndpoint>> {
+        if !self.gateway_supports(Capability::Measurement) {
+            tracing::debug!("Gateway does not support measurement, not enabling it");
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::EnableMeasurement)
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::MeasurementEndpoint(endpoint) => Ok(endpoint),
+            _ => Err(anyhow!("expected measurement endpoint from gateway")),
+        }
+    }
+
+    /// Asks the gateway to let the session be resumed if the connection
+    /// drops. Must be called right after `connect_to`.
+    ///
+    /// Returns `None` without asking if the gateway does not support resumption.
+    pub async fn request_resumption(&mut self) -> anyhow::Result<Option<&ResumptionGrant>> {
+        if !self.gateway_supports(Capability::Resumption) {
+            tracing::debug!("Gateway does not support resumption, not requesting it");
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::RequestResumption)
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::ResumptionGrant(grant) => {
+                self.resumption = grant;
+                Ok(self.resumption.as_ref())
+            }
+            _ => Err(anyhow!("expected resumption grant from gateway")),
+        }
+    }
+
+    /// Asks the gateway which destination server it connected to.
+    /// Must be called before `sync_time`.
+    ///
+    /// Returns `None` without asking if the gateway does not support
+    /// destination reports.
+    pub async fn query_destination(&mut self) -> anyhow::Result<Option<ConnectedDestination>> {
+        if !self.gateway_supports(Capability::DestinationReport) {
+            tracing::debug!("Gateway does not support destination reports, not querying");
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::QueryDestination)
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::ConnectedDestination(destination) => Ok(destination),
+            _ => Err(anyhow!("expected connected destination from gateway")),
+        }
+    }
+
+    /// Asks the gateway to apply `preferences` to the connection.
+    /// Must be called before `sync_time`.
+    ///
+    /// Returns the preferences as the gateway applies them, or `None`
+    /// without asking if the gateway does not support transport preferences.
+    pub async fn negotiate_transport(
+        &mut self,
+        preferences: TransportPreferences,
+    ) -> anyhow::Result<Option<TransportPreferences>> {
+        if !self.gateway_supports(Capability::TransportPreferences) {
+            tracing::debug!("Gateway does not support transport preferences, not sending them");
+            return Ok(None);
+        }
+        self.codec
+            .send_message(&ClientMessage::TransportPreferences(preferences))
+            .await?;
+        match self.recv_message().await? {
+            GatewayMessage::TransportPreferences(applied) => {
+                self.transport_preferences = Some(applied.clone());
+                Ok(Some(applied))
+            }
+            _ => Err(anyhow!(
+                "expected applied transport preferences from gateway"
+            )),
+        }
+    }
+
+    /// Gets the transport preferences the gateway applied, if negotiated.
+    pub fn transport_preferences(&self) -> Option<&TransportPreferences> {
+        self.transport_preferences.as_ref()
+    }
+
+    /// Gets the gateway's grant of resumption, if it has granted it.
+    pub fn resumption_grant(&self) -> Option<&ResumptionGrant> {
+        self.resumption.as_ref()
+    }
+
+    /// Asks the gateway to resume the session on `connection`, a new
+    /// connection to the gateway, then moves the control stream over
+    /// to it. Only for use during Play, after the gateway granted
+    /// resumption; the session is in the Play state afterward.
+    pub async fn resume(
+        &mut self,
+        connection: &Connection,
+        codec_version: CodecVersion,
+    ) -> anyhow::Result<()> {
+        let grant = self
+            .resumption
+            .as_ref()
+            .context("gateway has not granted resumption")?;
+        let (send_stream, recv_stream) = connection.open_bi().await?;
+        let mut codec = Codec::new(send_stream, recv_stream);
+        codec
+            .send_message(&ClientMessage::Resume {
+                token: grant.token,
+                codec_versions: vec![codec_version.as_u8()],
+            })
+            .await?;
+        match codec.recv_message().await? {
+            GatewayMessage::AcknowledgeConnectTo {
+                codec_version: chosen,
+            } if chosen == codec_version.as_u8() => {}
+            GatewayMessage::Refused { reason } => {
+                bail!("gateway refused to resume the session: {reason}")
+            }
+            _ => bail!("wrong acknowledgement received from gateway"),
+        }
+        self.codec = codec;
+        // Acknowledgements still pending were lost with the old stream.
+        self.pending_transition_acks = 0;
+        Ok(())
+    }
+
+    /// Sends a metrics report. Not acknowledged by the gateway.
+    ///
+    /// Does nothing if the gateway does not support metrics reports.
+    pub async fn send_metrics(&mut self, metrics: ClientMetrics) -> anyhow::Result<()> {
+        if !self.gateway_supports(Capability::ClientMetrics) {
+            return Ok(());
+        }
+        self.codec
+            .send_message(&ClientMessage::ClientMetrics(metrics))
+            .await
+    }
+
+    /// Gets the time the gateway gave the session before disconnecting
+    /// it, if it has announced that it is shutting down.
+    pub fn gateway_shutdown(&self) -> Option<Duration> {
+        self.gateway_shutdown
+    }
+
+    /// Waits for the gateway to announce that it is shutting down, returning
+    /// the time left before the session is disconnected. Only the state
+    /// transition acknowledgements pending from entering the Play state may
+    /// be received meanwhile, so this is only for use during Play.
+    ///
+    /// Never returns if the announcement has already been received. Cancel safe.
+    pub async fn wait_for_shutdown_notice(&mut self) -> anyhow::Result<Duration> {
+        if self.gateway_shutdown.is_some() {
+            return future::pending().await;
+        }
+        loop {
+            match self.codec.recv_message().await? {
+                GatewayMessage::ShuttingDown { drain_millis } => {
+                    return Ok(self.note_shutdown(drain_millis));
+                }
+                GatewayMessage::AcknowledgeStateTransition if self.pending_transition_acks > 0 => {
+                    self.pending_transition_acks -= 1;
+                }
+                _ => bail!("unexpected message received from gateway"),
+            }
+        }
+    }
+
     pub async fn wait_for_ack_transition_play_to_config(&mut self) -> anyhow::Result<()> {
         self.wait_for_ack(|msg| matches!(msg, GatewayMessage::AcknowledgeTransitionPlayToConfig))
-            .await
+            .await?;
+        self.phase.transition(Phase::Configuration)?;
+        Ok(())
+    }
+
+    /// Waits until the gateway has acknowledged all previous state transitions.
+    async fn wait_for_transition_acks(&mut self) -> anyhow::Result<()> {
+        while self.pending_transition_acks > 0 {
+            let message: GatewayMessage = self.recv_message().await?;
+            if !matches!(message, GatewayMessage::AcknowledgeStateTransition) {
+                bail!("wrong acknowledgement received from gateway");
+            }
+            self.pending_transition_acks -= 1;
+        }
+        Ok(())
     }
 
     async fn wait_for_ack(
         &mut self,
         expected_message: impl FnOnce(&GatewayMessage) -> bool,
     ) -> anyhow::Result<()> {
-        let message: GatewayMessage = self.codec.recv_message().await?;
+        let mut message: GatewayMessage = self.recv_message().await?;
+        // State transitions are acknowledged lazily, so that the client
+        // does not wait a round trip before sending packets of the new state.
+        while self.pending_transition_acks > 0
+            && matches!(message, GatewayMessage::AcknowledgeStateTransition)
+        {
+            self.pending_transition_acks -= 1;
+            message = self.recv_message().await?;
+        }
         if expected_message(&message) {
             Ok(())
         } else {
             Err(anyhow!("wrong acknowledgement received from gateway"))
         }
     }
+
+    /// Receives the next message, noting a shutdown announcement,
+    /// which may arrive in between any others.
+    async fn recv_message(&mut self) -> anyhow::Result<GatewayMessage> {
+        loop {
+            match self.codec.recv_message().await? {
+                GatewayMessage::ShuttingDown { drain_millis } => {
+                    self.note_shutdown(drain_millis);
+                }
+                message => return Ok(message),
+            }
+        }
+    }
+
+    fn note_shutdown(&mut self, drain_millis: u64) -> Duration {
+        let drain = Duration::from_millis(drain_millis);
+        tracing::info!("Gateway is shutting down, disconnecting in {drain:?}");
+        self.gateway_shutdown = Some(drain);
+        drain
+    }
+}
+
+impl StateTransitions for ClientSide {
+    async fn switch_state<State: ProtocolState>(
+        &mut self,
+        send_stream: Option<StreamId>,
+    ) -> anyhow::Result<Option<StreamId>> {
+        self.phase.transition(State::PHASE)?;
+        // Never start a transition before the gateway has completed the previous one.
+        self.wait_for_transition_acks().await?;
+        self.codec
+            .send_message(&ClientMessage::StateTransition(StateTransition {
+                state: State::PHASE.to_string(),
+                stream_id: send_stream.map(|id| VarInt::from(id).into_inner()),
+            }))
+            .await?;
+        self.pending_transition_acks += 1;
+        Ok(None)
+    }
 }
 
 /// Wrapper over the control stream on the gateway's side.
 pub struct GatewaySide {
     codec: Codec,
+    /// Whether the client has sent its `ConnectTo`.
+    authenticated: bool,
+    /// Whether the client's `ConnectTo` carried the capabilities marker.
+    client_understands_capabilities: bool,
+    /// Whether the client has sent `EnableRedundancy`.
+    redundancy: bool,
+    /// Token issued to clients that request affinity.
+    affinity_token: Option<AffinityToken>,
+    /// Build reported by the client, if it sent one.
+    client_build_info: Option<BuildInfo>,
+    /// Issues endpoints to clients that enable measurement.
+    measurement_issuer: Option<MeasurementIssuer>,
+    /// Whether an endpoint has been issued to the client.
+    measurement: bool,
+    /// Whether `ShuttingDown` has been sent.
+    shutdown_notified: bool,
+    /// Granted to clients that request resumption.
+    resumption_grant: Option<ResumptionGrant>,
+    /// Whether resumption has been granted to the client.
+    resumption: bool,
+    /// Reported to clients that query it.
+    connected_destination: Option<ConnectedDestination>,
+    transport_limits: TransportLimits,
+    /// Applied preferences of the client, if it sent any.
+    transport_preferences: Option<TransportPreferences>,
+    phase: PhaseTracker,
 }
 
 impl GatewaySide {
@@ -162,24 +916,203 @@ impl GatewaySide {
         let (send_stream, recv_stream) = connection.accept_bi().await?;
         Ok(Self {
             codec: Codec::new(send_stream, recv_stream),
+            authenticated: false,
+            client_understands_capabilities: false,
+            redundancy: false,
+            affinity_token: None,
+            client_build_info: None,
+            measurement_issuer: None,
+            measurement: false,
+            shutdown_notified: false,
+            resumption_grant: None,
+            resumption: false,
+            connected_destination: None,
+            transport_limits: TransportLimits::default(),
+            transport_preferences: None,
+            phase: PhaseTracker::new(),
         })
     }
 
-    /// Waits for a `ConnectTo` message.
-    pub async fn wait_for_connect_to(&mut self) -> anyhow::Result<ConnectTo> {
-        self.wait_for_message(|msg| match msg {
-            ClientMessage::ConnectTo(m) => Some(m),
-            _ => None,
-        })
-        .await
+    /// Applies `limits` to the transport preferences of the client.
+    pub fn with_transport_limits(mut self, limits: TransportLimits) -> Self {
+        self.transport_limits = limits;
+        self
+    }
+
+    /// Issues the given token to the client if it requests affinity.
+    pub fn with_affinity_token(mut self, affinity_token: Option<AffinityToken>) -> Self {
+        self.affinity_token = affinity_token;
+        self
+    }
+
+    /// Issues measurement endpoints to clients that enable measurement.
+    pub fn with_measurement_issuer(mut self, issuer: Option<MeasurementIssuer>) -> Self {
+        self.measurement_issuer = issuer;
+        self
+    }
+
+    /// Grants resumption to the client if it requests it.
+    pub fn with_resumption_grant(mut self, grant: Option<ResumptionGrant>) -> Self {
+        self.resumption_grant = grant;
+        self
+    }
+
+    /// Sets the destination server reported to the client if it queries
+    /// it. Must be set before the connection is acknowledged.
+    pub fn set_connected_destination(&mut self, destination: Option<ConnectedDestination>) {
+        self.connected_destination = destination;
+    }
+
+    /// Gets the token of the resumption granted to the client, if
+    /// granted. Known once the time sync has been answered.
+    pub fn resumption_token(&self) -> Option<ResumptionToken> {
+        self.resumption_grant
+            .as_ref()
+            .filter(|_| self.resumption)
+            .map(|grant| grant.token)
+    }
+
+    /// Gets the transport preferences of the client as applied, if it
+    /// sent any. Known once the time sync has been answered.
+    pub fn transport_preferences(&self) -> Option<&TransportPreferences> {
+        self.transport_preferences.as_ref()
+    }
+
+    /// Whether the client has requested redundant transmission of
+    /// critical packets. Known once the first state transition is received.
+    pub fn redundancy_enabled(&self) -> bool {
+        self.redundancy
+    }
+
+    /// Whether a measurement endpoint has been issued to the client, which
+    /// then opens a stream for QUIC probes. Known once the time sync has
+    /// been answered.
+    pub fn measurement_enabled(&self) -> bool {
+        self.measurement
+    }
+
+    /// Gets the protocol state the client has switched its streams to.
+    pub fn phase(&self) -> Phase {
+        self.phase.current()
+    }
+
+    /// Gets the build reported by the client. Known once
+    /// the time sync has been answered, if it sent one.
+    pub fn client_build_info(&self) -> Option<&BuildInfo> {
+        self.client_build_info.as_ref()
+    }
+
+    /// Waits for a `ConnectTo`, `ConnectToHost` or `Resume` message.
+    /// Only one is accepted per connection.
+    pub async fn wait_for_opening_request(&mut self) -> anyhow::Result<OpeningRequest> {
+        let request = self
+            .wait_for_message(|msg| match msg {
+                ClientMessage::ConnectTo(m) => Some(OpeningRequest::Connect(ConnectRequest {
+                    authentication_key: m.authentication_key,
+                    destination: Destination::Address(m.destination_server),
+                    codec_versions: m.codec_versions,
+                })),
+                ClientMessage::ConnectToHost(m) => {
+                    let destination = match Destination::host(&m.host, m.port) {
+                        Ok(destination) => destination,
+                        Err(e) => {
+                            tracing::debug!("Client named an invalid destination: {e}");
+                            return None;
+                        }
+                    };
+                    Some(OpeningRequest::Connect(ConnectRequest {
+                        authentication_key: m.authentication_key,
+                        destination,
+                        codec_versions: m.codec_versions,
+                    }))
+                }
+                ClientMessage::Resume {
+                    token,
+                    codec_versions,
+                } => Some(OpeningRequest::Resume {
+                    token,
+                    codec_versions,
+                }),
+                _ => None,
+            })
+            .await?;
+        self.authenticated = true;
+        if let OpeningRequest::Connect(connect_to) = &request {
+            self.client_understands_capabilities =
+                connect_to.codec_versions.contains(&CAPABILITIES_MARKER);
+        }
+        Ok(request)
     }
 
-    pub async fn acknowledge_connect_to(&mut self) -> anyhow::Result<()> {
+    /// Acknowledges the `ConnectTo`, first advertising
+    /// this gateway's capabilities if the client understands them.
+    pub async fn acknowledge_connect_to(
+        &mut self,
+        codec_version: CodecVersion,
+    ) -> anyhow::Result<()> {
+        if self.client_understands_capabilities {
+            let capabilities = Capability::iter()
+                .map(|capability| <&str>::from(capability).to_owned())
+                .collect();
+            self.codec
+                .send_message(&GatewayMessage::Capabilities(capabilities))
+                .await?;
+        }
         self.codec
-            .send_message(&GatewayMessage::AcknowledgeConnectTo)
+            .send_message(&GatewayMessage::AcknowledgeConnectTo {
+                codec_version: codec_version.as_u8(),
+            })
             .await
     }
 
+    /// Tells the client the gateway refuses its connection. Sent in place
+    /// of the `ConnectTo` acknowledgement, without waiting for `ConnectTo`.
+    pub async fn refuse(&mut self, reason: &str) -> anyhow::Result<()> {
+        self.codec
+            .send_message(&GatewayMessage::Refused {
+                reason: reason.to_owned(),
+            })
+            .await
+    }
+
+    /// Acknowledges a `Resume` with the codec version of the resumed
+    /// session, then moves the control stream of the session over to
+    /// `resumed`, the control stream of the new connection.
+    pub async fn take_over(
+        &mut self,
+        mut resumed: GatewaySide,
+        codec_version: CodecVersion,
+    ) -> anyhow::Result<()> {
+        resumed
+            .codec
+            .send_message(&GatewayMessage::AcknowledgeConnectTo {
+                codec_version: codec_version.as_u8(),
+            })
+            .await?;
+        self.codec = resumed.codec;
+        Ok(())
+    }
+
+    /// Answers the client's time sync messages, which
+    /// immediately follow the `ConnectTo` acknowledgement.
+    pub async fn answer_time_sync(&mut self) -> anyhow::Result<()> {
+        for _ in 0..TIME_SYNC_ROUNDS {
+            let time_sync = self
+                .wait_for_message(|msg| match msg {
+                    ClientMessage::TimeSync(m) => Some(m),
+                    _ => None,
+                })
+                .await?;
+            self.codec
+                .send_message(&GatewayMessage::TimeSyncReply {
+                    client_time_micros: time_sync.client_time_micros,
+                    gateway_time_micros: unix_micros(),
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
     /// Waits for an encryption message.
     pub async fn wait_for_terminal_encryption(
         &mut self,
@@ -197,7 +1130,42 @@ impl GatewaySide {
             .await
     }
 
+    /// Waits for the next metrics report.
+    ///
+    /// Cancel safe.
+    pub async fn wait_for_metrics(&mut self) -> anyhow::Result<ClientMetrics> {
+        match self.recv_message().await? {
+            ClientMessage::ClientMetrics(metrics) => Ok(metrics),
+            _ => Err(anyhow!("unexpected message received on control stream")),
+        }
+    }
+
+    /// Tells the client that the gateway is shutting down and will
+    /// disconnect it after `drain`, if its build report lists
+    /// `Capability::ShutdownNotice`. Only for use during Play.
+    ///
+    /// Returns whether the client was told. Only the first call tells it.
+    pub async fn notify_shutdown(&mut self, drain: Duration) -> anyhow::Result<bool> {
+        let understood = self.client_build_info.as_ref().is_some_and(|build_info| {
+            build_info
+                .capabilities
+                .iter()
+                .any(|capability| capability == <&str>::from(Capability::ShutdownNotice))
+        });
+        if !understood || self.shutdown_notified {
+            return Ok(false);
+        }
+        self.shutdown_notified = true;
+        self.codec
+            .send_message(&GatewayMessage::ShuttingDown {
+                drain_millis: drain.as_millis().try_into().unwrap_or(u64::MAX),
+            })
+            .await?;
+        Ok(true)
+    }
+
     pub async fn acknowledge_transition_play_to_config(&mut self) -> anyhow::Result<()> {
+        self.phase.transition(Phase::Configuration)?;
         self.codec
             .send_message(&GatewayMessage::AcknowledgeTransitionPlayToConfig)
             .await
@@ -207,9 +1175,318 @@ impl GatewaySide {
         &mut self,
         map_message: impl FnOnce(ClientMessage) -> Option<M>,
     ) -> anyhow::Result<M> {
-        let message = self.codec.recv_message().await?;
+        let message = self.recv_message().await?;
         map_message(message).ok_or_else(|| anyhow!("unexpected message received on control stream"))
     }
+
+    async fn recv_message(&mut self) -> anyhow::Result<ClientMessage> {
+        loop {
+            let message = self.codec.recv_message().await?;
+            match message {
+                ClientMessage::ConnectTo(_)
+                | ClientMessage::ConnectToHost(_)
+                | ClientMessage::Resume { .. }
+                    if self.authenticated =>
+                {
+                    return Err(ReauthenticationAttempt.into());
+                }
+                ClientMessage::EnableRedundancy => self.redundancy = true,
+                ClientMessage::RequestAffinity { presented_token } => {
+                    self.answer_affinity_request(presented_token).await?
+                }
+                ClientMessage::RequestResumption => {
+                    self.resumption = self.resumption_grant.is_some();
+                    self.codec
+                        .send_message(&GatewayMessage::ResumptionGrant(
+                            self.resumption_grant.clone(),
+                        ))
+                        .await?;
+                }
+                ClientMessage::QueryDestination => {
+                    self.codec
+                        .send_message(&GatewayMessage::ConnectedDestination(
+                            self.connected_destination.clone(),
+                        ))
+                        .await?;
+                }
+                ClientMessage::TransportPreferences(preferences) => {
+                    let applied = self.transport_limits.apply(&preferences);
+                    self.codec
+                        .send_message(&GatewayMessage::TransportPreferences(applied.clone()))
+                        .await?;
+                    self.transport_preferences = Some(applied);
+                }
+                ClientMessage::BuildInfo(build_info) => {
+                    self.client_build_info = Some(build_info);
+                    self.codec
+                        .send_message(&GatewayMessage::BuildInfo(BuildInfo::current()))
+                        .await?;
+                }
+                ClientMessage::EnableMeasurement => {
+                    let endpoint = self.measurement_issuer.as_ref().map(|issue| issue());
+                    self.measurement = endpoint.is_some();
+                    self.codec
+                        .send_message(&GatewayMessage::MeasurementEndpoint(endpoint))
+                        .await?;
+                }
+                message => return Ok(message),
+            }
+        }
+    }
+
+    async fn answer_affinity_request(
+        &mut self,
+        presented_token: Option<AffinityToken>,
+    ) -> anyhow::Result<()> {
+        if let (Some(presented), Some(own)) = (&presented_token, &self.affinity_token) {
+            if presented.gateway_id != own.gateway_id {
+                tracing::debug!(
+                    "Client presented an affinity token for gateway {}, but reached {}",
+                    presented.gateway_id,
+                    own.gateway_id
+                );
+            }
+        }
+        self.codec
+            .send_message(&GatewayMessage::AffinityToken(self.affinity_token.clone()))
+            .await
+    }
+}
+
+impl StateTransitions for GatewaySide {
+    async fn switch_state<State: ProtocolState>(
+        &mut self,
+        _send_stream: Option<StreamId>,
+    ) -> anyhow::Result<Option<StreamId>> {
+        let transition = self
+            .wait_for_message(|msg| match msg {
+                ClientMessage::StateTransition(m) => Some(m),
+                _ => None,
+            })
+            .await?;
+        let requested: Phase = transition
+            .state
+            .parse()
+            .with_context(|| format!("client switched to unknown state {}", transition.state))?;
+        if requested != State::PHASE {
+            bail!(
+                "client switched to the {requested} state, but the gateway to {}",
+                State::PHASE
+            );
+        }
+        self.phase.transition(requested)?;
+        let stream_id = transition
+            .stream_id
+            .map(|id| VarInt::from_u64(id).map(StreamId::from))
+            .transpose()
+            .context("invalid stream ID in state transition")?;
+        self.codec
+            .send_message(&GatewayMessage::AcknowledgeStateTransition)
+            .await?;
+        Ok(stream_id)
+    }
+}
+
+/// One message of each kind with fixed contents, framed
+/// as on the control stream, for the `test_vectors` module.
+#[cfg(feature = "cli")]
+pub(crate) fn sample_messages() -> anyhow::Result<Vec<(&'static str, Vec<u8>)>> {
+    use tokio_util::codec::Encoder;
+
+    let build_info = BuildInfo {
+        version: "0.1.0".to_owned(),
+        features: vec!["client".to_owned()],
+        protocol_versions: vec![765],
+        codec_versions: vec![1],
+        capabilities: vec!["client_metrics".to_owned()],
+    };
+    let client_messages = [
+        (
+            "client/connect_to",
+            ClientMessage::ConnectTo(ConnectTo {
+                authentication_key: "key".to_owned(),
+                destination_server: SocketAddr::from(([127, 0, 0, 1], 25565)),
+                codec_versions: vec![1, CAPABILITIES_MARKER],
+            }),
+        ),
+        (
+            "client/enable_terminal_encryption",
+            ClientMessage::EnableTerminalEncryption(EnableTerminalEncryption {
+                key: std::array::from_fn(|i| i as u8),
+            }),
+        ),
+        (
+            "client/client_metrics",
+            ClientMessage::ClientMetrics(ClientMetrics {
+                rtt_millis: 42,
+                sent_packets: 1000,
+                lost_packets: 3,
+                dropped_datagrams: 7,
+                reconfigurations: 1,
+            }),
+        ),
+        (
+            "client/time_sync",
+            ClientMessage::TimeSync(TimeSync {
+                client_time_micros: 1_700_000_000_000_000,
+            }),
+        ),
+        (
+            "client/state_transition",
+            ClientMessage::StateTransition(StateTransition {
+                state: "Login".to_owned(),
+                stream_id: Some(4),
+            }),
+        ),
+        (
+            "client/state_transition_to_play",
+            ClientMessage::StateTransition(StateTransition {
+                state: "Play".to_owned(),
+                stream_id: None,
+            }),
+        ),
+        ("client/enable_redundancy", ClientMessage::EnableRedundancy),
+        (
+            "client/request_affinity",
+            ClientMessage::RequestAffinity {
+                presented_token: Some(AffinityToken {
+                    gateway_id: "gateway-1".to_owned(),
+                    address: Some("gateway-1.example.net:6666".to_owned()),
+                }),
+            },
+        ),
+        (
+            "client/build_info",
+            ClientMessage::BuildInfo(build_info.clone()),
+        ),
+        (
+            "client/enable_measurement",
+            ClientMessage::EnableMeasurement,
+        ),
+        (
+            "client/connect_to_host",
+            ClientMessage::ConnectToHost(ConnectToHost {
+                authentication_key: "key".to_owned(),
+                host: "mc.example.net".to_owned(),
+                port: None,
+                codec_versions: vec![1, CAPABILITIES_MARKER],
+            }),
+        ),
+        (
+            "client/request_resumption",
+            ClientMessage::RequestResumption,
+        ),
+        (
+            "client/resume",
+            ClientMessage::Resume {
+                token: std::array::from_fn(|i| i as u8),
+                codec_versions: vec![1],
+            },
+        ),
+        ("client/query_destination", ClientMessage::QueryDestination),
+        (
+            "client/transport_preferences",
+            ClientMessage::TransportPreferences(TransportPreferences {
+                idle_timeout_millis: Some(60_000),
+                datagrams: false,
+                max_uni_streams: Some(1024),
+            }),
+        ),
+    ];
+    let gateway_messages = [
+        (
+            "gateway/acknowledge_connect_to",
+            GatewayMessage::AcknowledgeConnectTo { codec_version: 1 },
+        ),
+        (
+            "gateway/acknowledge_enable_terminal_encryption",
+            GatewayMessage::AcknowledgeEnableTerminalEncryption,
+        ),
+        (
+            "gateway/acknowledge_transition_play_to_config",
+            GatewayMessage::AcknowledgeTransitionPlayToConfig,
+        ),
+        (
+            "gateway/acknowledge_state_transition",
+            GatewayMessage::AcknowledgeStateTransition,
+        ),
+        (
+            "gateway/time_sync_reply",
+            GatewayMessage::TimeSyncReply {
+                client_time_micros: 1_700_000_000_000_000,
+                gateway_time_micros: 1_700_000_000_001_234,
+            },
+        ),
+        (
+            "gateway/affinity_token",
+            GatewayMessage::AffinityToken(None),
+        ),
+        (
+            "gateway/capabilities",
+            GatewayMessage::Capabilities(vec![
+                "client_metrics".to_owned(),
+                "redundancy".to_owned(),
+            ]),
+        ),
+        ("gateway/build_info", GatewayMessage::BuildInfo(build_info)),
+        (
+            "gateway/measurement_endpoint",
+            GatewayMessage::MeasurementEndpoint(Some(MeasurementEndpoint {
+                port: 6667,
+                token: std::array::from_fn(|i| i as u8),
+            })),
+        ),
+        (
+            "gateway/shutting_down",
+            GatewayMessage::ShuttingDown {
+                drain_millis: 30_000,
+            },
+        ),
+        (
+            "gateway/refused",
+            GatewayMessage::Refused {
+                reason: "session limit reached".to_owned(),
+            },
+        ),
+        (
+            "gateway/resumption_grant",
+            GatewayMessage::ResumptionGrant(Some(ResumptionGrant {
+                token: std::array::from_fn(|i| i as u8),
+                grace_millis: 15_000,
+            })),
+        ),
+        (
+            "gateway/connected_destination",
+            GatewayMessage::ConnectedDestination(Some(ConnectedDestination {
+                destination: "lobby.example.net".to_owned(),
+                address: "203.0.113.7:25565".parse().unwrap(),
+                fallback: true,
+            })),
+        ),
+        (
+            "gateway/transport_preferences",
+            GatewayMessage::TransportPreferences(TransportPreferences {
+                idle_timeout_millis: Some(30_000),
+                datagrams: false,
+                max_uni_streams: Some(1024),
+            }),
+        ),
+    ];
+
+    let mut samples = Vec::new();
+    let mut frame = |name, message: Vec<u8>| -> anyhow::Result<()> {
+        let mut framed = bytes::BytesMut::new();
+        LengthDelimitedCodec::new().encode(message.into(), &mut framed)?;
+        samples.push((name, framed.to_vec()));
+        Ok(())
+    };
+    for (name, message) in client_messages {
+        frame(name, encode(&message)?)?;
+    }
+    for (name, message) in gateway_messages {
+        frame(name, encode(&message)?)?;
+    }
+    Ok(samples)
 }
 
 fn encode<T: Serialize>(value: &T) -> anyhow::Result<Vec<u8>> {
@@ -223,3 +1500,54 @@ fn decode<T: DeserializeOwned>(bytes: &[u8]) -> anyhow::Result<T> {
         .deserialize(bytes)
         .map_err(anyhow::Error::from)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> TransportLimits {
+        TransportLimits {
+            max_idle_timeout: Duration::from_secs(30),
+            min_uni_streams: 16,
+            max_uni_streams: 256,
+        }
+    }
+
+    fn preferences(
+        idle_timeout_millis: Option<u64>,
+        max_uni_streams: Option<u32>,
+    ) -> TransportPreferences {
+        TransportPreferences {
+            idle_timeout_millis,
+            datagrams: true,
+            max_uni_streams,
+        }
+    }
+
+    #[test]
+    fn apply_bounds_preferences() {
+        let applied = limits().apply(&preferences(Some(10_000), Some(1)));
+        assert_eq!(applied.idle_timeout_millis, Some(10_000));
+        assert_eq!(applied.max_uni_streams, Some(16));
+
+        let applied = limits().apply(&preferences(Some(60_000), Some(1_000)));
+        assert_eq!(applied.idle_timeout_millis, Some(30_000));
+        assert_eq!(applied.max_uni_streams, Some(256));
+    }
+
+    #[test]
+    fn apply_ignores_zero_idle_timeout() {
+        let applied = limits().apply(&preferences(Some(0), None));
+        assert_eq!(applied.idle_timeout_millis, Some(30_000));
+    }
+
+    #[test]
+    fn apply_does_not_panic_on_inverted_limits() {
+        let limits = TransportLimits {
+            min_uni_streams: 512,
+            ..limits()
+        };
+        let applied = limits.apply(&preferences(None, Some(100)));
+        assert_eq!(applied.max_uni_streams, Some(256));
+    }
+}
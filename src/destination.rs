@@ -0,0 +1,112 @@
+//! Destination servers named by clients.
+//!
+//! A client names its destination either by socket address or by host
+//! name. Host names are resolved by the gateway as the vanilla client
+//! would: through the `_minecraft._tcp` SRV record if no port is given,
+//! then through A and AAAA records.
+
+use anyhow::{bail, Context};
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+/// Port of a destination named without one and without an SRV record.
+pub const DEFAULT_PORT: u16 = 25565;
+
+/// Destination server a client proxies to.
+///
+/// Written as a socket address, or as a host name with an optional
+/// port, e.g. `mc.example.net` or `mc.example.net:25566`. A bare IP
+/// address is taken to be on the default port.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Address(SocketAddr),
+    Host {
+        /// Lowercase, without a trailing dot.
+        host: String,
+        /// If `None`, the SRV record or the default port is used.
+        port: Option<u16>,
+    },
+}
+
+impl Destination {
+    /// Names a destination by host name.
+    pub fn host(host: &str, port: Option<u16>) -> anyhow::Result<Self> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host.is_empty() || host.len() > 253 {
+            bail!("invalid host name length");
+        }
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Self::Address(SocketAddr::new(
+                ip,
+                port.unwrap_or(DEFAULT_PORT),
+            )));
+        }
+        if !host
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= 63)
+            || !host
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+        {
+            bail!("invalid host name '{host}'");
+        }
+        Ok(Self::Host { host, port })
+    }
+
+    /// Gets the address, if the destination is named by one.
+    pub fn address(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Address(address) => Some(*address),
+            Self::Host { .. } => None,
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(address: SocketAddr) -> Self {
+        Self::Address(address)
+    }
+}
+
+impl FromStr for Destination {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(Self::Address(address));
+        }
+        // A bracketed IPv6 address without a port.
+        let unbracketed = s
+            .strip_prefix('[')
+            .and_then(|s| s.strip_suffix(']'))
+            .unwrap_or(s);
+        if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            return Ok(Self::Address(SocketAddr::new(ip, DEFAULT_PORT)));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port
+                    .parse()
+                    .with_context(|| format!("invalid port in destination '{s}'"))?;
+                Self::host(host, Some(port))
+            }
+            None => Self::host(s, None),
+        }
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Address(address) => write!(f, "{address}"),
+            Self::Host {
+                host,
+                port: Some(port),
+            } => write!(f, "{host}:{port}"),
+            Self::Host { host, port: None } => write!(f, "{host}"),
+        }
+    }
+}
@@ -0,0 +1,247 @@
+//! A minimal offline-mode Minecraft server for development,
+//! also used as the destination of synthetic load test clients.
+//!
+//! It answers status requests and logs clients in without authentication
+//! into a small flat world, then answers pings and sends keepalives.
+//! Nothing is simulated: blocks cannot be changed and no entities exist.
+
+use crate::{
+    protocol::{
+        packet::{
+            client, client::handshake::NextState, server, server::play::RelativeFlags, side, state,
+        },
+        Decoder, Encoder, PROTOCOL_VERSION,
+    },
+    proxy::{PacketIo, VanillaPacketIo},
+};
+use anyhow::{bail, Context};
+use std::time::Duration;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select, task,
+    time::interval,
+};
+use world::{DIMENSION_NAME, DIMENSION_TYPE, SURFACE_Y};
+
+mod nbt;
+mod world;
+
+/// Interval between keepalives sent to each client.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+/// Radius of the square of chunks around the spawn point sent to clients.
+const VIEW_DISTANCE: i32 = 4;
+
+/// Accepts connections on the listener until an error occurs.
+///
+/// Must be called within a `LocalSet`.
+pub async fn serve(listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        task::spawn_local(async move {
+            if let Err(e) = handle_connection(stream).await {
+                tracing::debug!("Dev server connection from {address} ended: {e:#}");
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: TcpStream) -> anyhow::Result<()> {
+    stream.set_nodelay(true)?;
+    let conn: VanillaPacketIo<side::Server, state::Handshake> = VanillaPacketIo::new(stream)?;
+    let client::handshake::Packet::Handshake(handshake) = conn.recv_packet().await?;
+    if let NextState::Status = handshake.next_state {
+        return status(conn.switch_state::<state::Status>()).await;
+    }
+
+    let conn = conn.switch_state::<state::Login>();
+    let client::login::Packet::LoginStart(login_start) = conn.recv_packet().await? else {
+        bail!("expected LoginStart");
+    };
+    let mut decoder = Decoder::new(&login_start.ignored_data);
+    let username = decoder.read_string()?.to_owned();
+    let uuid = u128::from_be_bytes(decoder.consume::<16>()?);
+    conn.send_packet(server::login::Packet::LoginSuccess(
+        server::login::LoginSuccess {
+            ignored_data: encode(|encoder| {
+                encoder.write_slice(&uuid.to_be_bytes());
+                encoder.write_string(&username);
+                // No properties
+                encoder.write_var_int(0);
+            }),
+        },
+    ))
+    .await?;
+    loop {
+        if let client::login::Packet::LoginAcknowledged(_) = conn.recv_packet().await? {
+            break;
+        }
+    }
+
+    let conn = conn.switch_state::<state::Configuration>();
+    conn.send_packet(server::configuration::Packet::RegistryData(
+        server::configuration::RegistryData {
+            ignored_data: world::registry_data(),
+        },
+    ))
+    .await?;
+    conn.send_packet(server::configuration::Packet::FinishConfiguration(
+        server::configuration::FinishConfiguration {
+            ignored_data: Vec::new(),
+        },
+    ))
+    .await?;
+    loop {
+        if let client::configuration::Packet::FinishConfiguration(_) = conn.recv_packet().await? {
+            break;
+        }
+    }
+
+    let conn = conn.switch_state::<state::Play>();
+    join(&conn).await?;
+    play(&conn).await
+}
+
+async fn status(conn: VanillaPacketIo<side::Server, state::Status>) -> anyhow::Result<()> {
+    loop {
+        match conn.recv_packet().await? {
+            client::status::Packet::StatusRequest(_) => {
+                let status = serde_json::json!({
+                    "version": { "name": "1.20.4", "protocol": PROTOCOL_VERSION },
+                    "players": { "max": 20, "online": 0 },
+                    "description": { "text": "minecraft-quic-proxy dev server" },
+                });
+                conn.send_packet(server::status::Packet::StatusResponse(
+                    server::status::StatusResponse {
+                        ignored_data: encode(|encoder| encoder.write_string(&status.to_string())),
+                    },
+                ))
+                .await?;
+            }
+            client::status::Packet::PingRequest(ping) => {
+                conn.send_packet(server::status::Packet::PingResponse(
+                    server::status::PingResponse {
+                        ignored_data: ping.ignored_data,
+                    },
+                ))
+                .await?;
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Sends the packets needed for the client to spawn in the world.
+async fn join(conn: &VanillaPacketIo<side::Server, state::Play>) -> anyhow::Result<()> {
+    conn.send_packet(server::play::Packet::Login(server::play::Login {
+        ignored_data: encode(|encoder| {
+            // Entity ID
+            encoder.write_i32(1);
+            // Hardcore
+            encoder.write_bool(false);
+            encoder.write_var_int(1);
+            encoder.write_string(DIMENSION_NAME);
+            // Max players, view distance, simulation distance
+            encoder.write_var_int(20);
+            encoder.write_var_int(VIEW_DISTANCE);
+            encoder.write_var_int(VIEW_DISTANCE);
+            // Reduced debug info, respawn screen, limited crafting
+            encoder.write_bool(false);
+            encoder.write_bool(true);
+            encoder.write_bool(false);
+            encoder.write_string(DIMENSION_TYPE);
+            encoder.write_string(DIMENSION_NAME);
+            // Hashed seed
+            encoder.write_i64(0);
+            // Game mode (creative) and previous game mode (none)
+            encoder.write_u8(1);
+            encoder.write_i8(-1);
+            // Debug, flat
+            encoder.write_bool(false);
+            encoder.write_bool(true);
+            // Death location
+            encoder.write_bool(false);
+            // Portal cooldown
+            encoder.write_var_int(0);
+        }),
+    }))
+    .await?;
+
+    conn.send_packet(server::play::Packet::SynchronizePlayerPosition(
+        server::play::SynchronizePlayerPosition {
+            x: 8.5,
+            y: SURFACE_Y,
+            z: 8.5,
+            yaw: 0.0,
+            pitch: 0.0,
+            flags: RelativeFlags::empty(),
+            teleport_id: 1,
+        },
+    ))
+    .await?;
+
+    // "Start waiting for level chunks", without which the client
+    // never leaves the loading screen.
+    conn.send_packet(server::play::Packet::GameEvent(server::play::GameEvent {
+        ignored_data: encode(|encoder| {
+            encoder.write_u8(13);
+            encoder.write_f32(0.0);
+        }),
+    }))
+    .await?;
+
+    conn.send_packet(server::play::Packet::SetCenterChunk(
+        server::play::SetCenterChunk {
+            ignored_data: encode(|encoder| {
+                encoder.write_var_int(0);
+                encoder.write_var_int(0);
+            }),
+        },
+    ))
+    .await?;
+
+    let chunk_data = world::chunk_data();
+    for chunk_x in -VIEW_DISTANCE..=VIEW_DISTANCE {
+        for chunk_z in -VIEW_DISTANCE..=VIEW_DISTANCE {
+            conn.send_packet(server::play::Packet::ChunkAndLightData(
+                server::play::ChunkAndLightData {
+                    chunk_x,
+                    chunk_z,
+                    ignored_data: chunk_data.clone(),
+                },
+            ))
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn play(conn: &VanillaPacketIo<side::Server, state::Play>) -> anyhow::Result<()> {
+    let mut keepalive = interval(KEEPALIVE_INTERVAL);
+    let mut keepalive_id = 0i64;
+    loop {
+        select! {
+            packet = conn.recv_packet() => {
+                if let client::play::Packet::PingRequest(ping) = packet.context("receive")? {
+                    conn.send_packet(server::play::Packet::PingResponse(server::play::PingResponse {
+                        ignored_data: ping.ignored_data,
+                    }))
+                    .await?;
+                }
+            }
+            _ = keepalive.tick() => {
+                keepalive_id += 1;
+                conn.send_packet(server::play::Packet::KeepAlive(server::play::KeepAlive {
+                    ignored_data: keepalive_id.to_be_bytes().to_vec(),
+                }))
+                .await?;
+            }
+        }
+    }
+}
+
+/// Encodes packet fields that the protocol model does not parse.
+pub(crate) fn encode(f: impl FnOnce(&mut Encoder)) -> Vec<u8> {
+    let mut buf = Vec::new();
+    f(&mut Encoder::new(&mut buf));
+    buf
+}
@@ -0,0 +1,90 @@
+//! Just enough of an NBT encoder to build registry data and heightmaps.
+
+use crate::protocol::Encoder;
+
+/// An NBT tag.
+#[derive(Debug, Clone)]
+pub enum Nbt {
+    Byte(i8),
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    String(String),
+    List(Vec<Nbt>),
+    Compound(Vec<(String, Nbt)>),
+}
+
+impl Nbt {
+    /// Creates a compound from `(name, tag)` pairs.
+    pub fn compound<'a>(entries: impl IntoIterator<Item = (&'a str, Nbt)>) -> Self {
+        Self::Compound(
+            entries
+                .into_iter()
+                .map(|(name, tag)| (name.to_owned(), tag))
+                .collect(),
+        )
+    }
+
+    pub fn string(s: impl Into<String>) -> Self {
+        Self::String(s.into())
+    }
+
+    pub fn bool(b: bool) -> Self {
+        Self::Byte(b.into())
+    }
+
+    /// Encodes the tag in the network format used since 1.20.2,
+    /// where the root tag has a type but no name.
+    pub fn encode_network(&self, encoder: &mut Encoder) {
+        encoder.write_u8(self.id());
+        self.encode_payload(encoder);
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Nbt::Byte(_) => 1,
+            Nbt::Int(_) => 3,
+            Nbt::Float(_) => 5,
+            Nbt::Double(_) => 6,
+            Nbt::String(_) => 8,
+            Nbt::List(_) => 9,
+            Nbt::Compound(_) => 10,
+        }
+    }
+
+    fn encode_payload(&self, encoder: &mut Encoder) {
+        match self {
+            Nbt::Byte(x) => encoder.write_i8(*x),
+            Nbt::Int(x) => encoder.write_i32(*x),
+            Nbt::Float(x) => encoder.write_f32(*x),
+            Nbt::Double(x) => encoder.write_f64(*x),
+            Nbt::String(s) => write_nbt_string(encoder, s),
+            Nbt::List(elements) => {
+                // Empty lists have the End element type.
+                encoder.write_u8(elements.first().map_or(0, Nbt::id));
+                encoder.write_i32(elements.len() as i32);
+                for element in elements {
+                    element.encode_payload(encoder);
+                }
+            }
+            Nbt::Compound(entries) => {
+                for (name, tag) in entries {
+                    encoder.write_u8(tag.id());
+                    write_nbt_string(encoder, name);
+                    tag.encode_payload(encoder);
+                }
+                // End tag
+                encoder.write_u8(0);
+            }
+        }
+    }
+}
+
+/// Writes a string with a `u16` length prefix.
+///
+/// NBT uses modified UTF-8, which only differs from UTF-8
+/// for NUL and supplementary characters; neither occurs here.
+fn write_nbt_string(encoder: &mut Encoder, s: &str) {
+    encoder.write_u16(s.len() as u16);
+    encoder.write_slice(s.as_bytes());
+}
@@ -0,0 +1,230 @@
+//! The registries and flat world sent to joining clients.
+
+use super::nbt::Nbt;
+use crate::{dev_server::encode, protocol::Encoder};
+
+pub const DIMENSION_TYPE: &str = "minecraft:overworld";
+pub const DIMENSION_NAME: &str = "minecraft:overworld";
+
+/// Height of the world, starting at y = 0.
+const WORLD_HEIGHT: i32 = 256;
+const SECTIONS: i32 = WORLD_HEIGHT / 16;
+
+/// Block state ID of `minecraft:grass_block[snowy=false]` in 1.20.4.
+const GRASS_BLOCK: i32 = 9;
+/// Y coordinate of the surface. The lowest section is filled with grass.
+pub const SURFACE_Y: f64 = 16.0;
+
+/// Damage types that the 1.20.4 client looks up on joining.
+const DAMAGE_TYPES: &[&str] = &[
+    "arrow",
+    "bad_respawn_point",
+    "cactus",
+    "cramming",
+    "dragon_breath",
+    "drown",
+    "dry_out",
+    "explosion",
+    "fall",
+    "falling_anvil",
+    "falling_block",
+    "falling_stalactite",
+    "fireball",
+    "fireworks",
+    "fly_into_wall",
+    "freeze",
+    "generic",
+    "generic_kill",
+    "hot_floor",
+    "in_fire",
+    "in_wall",
+    "indirect_magic",
+    "lava",
+    "lightning_bolt",
+    "magic",
+    "mob_attack",
+    "mob_attack_no_aggro",
+    "mob_projectile",
+    "on_fire",
+    "out_of_world",
+    "outside_border",
+    "player_attack",
+    "player_explosion",
+    "sonic_boom",
+    "stalagmite",
+    "starve",
+    "sting",
+    "sweet_berry_bush",
+    "thorns",
+    "thrown",
+    "trident",
+    "unattributed_fireball",
+    "wither",
+    "wither_skull",
+];
+
+/// Encodes the payload of the RegistryData packet:
+/// the synchronized registries with the minimum entries a client needs.
+pub fn registry_data() -> Vec<u8> {
+    let registries = Nbt::compound([
+        (
+            "minecraft:dimension_type",
+            registry(
+                "minecraft:dimension_type",
+                [(DIMENSION_TYPE.to_owned(), overworld_dimension_type())],
+            ),
+        ),
+        (
+            "minecraft:worldgen/biome",
+            registry(
+                "minecraft:worldgen/biome",
+                [("minecraft:plains".to_owned(), plains_biome())],
+            ),
+        ),
+        (
+            "minecraft:chat_type",
+            registry(
+                "minecraft:chat_type",
+                [("minecraft:chat".to_owned(), chat_type())],
+            ),
+        ),
+        (
+            "minecraft:damage_type",
+            registry(
+                "minecraft:damage_type",
+                DAMAGE_TYPES
+                    .iter()
+                    .map(|name| (format!("minecraft:{name}"), damage_type(name))),
+            ),
+        ),
+        (
+            "minecraft:trim_pattern",
+            registry("minecraft:trim_pattern", []),
+        ),
+        (
+            "minecraft:trim_material",
+            registry("minecraft:trim_material", []),
+        ),
+    ]);
+    encode(|encoder| registries.encode_network(encoder))
+}
+
+fn registry(name: &str, entries: impl IntoIterator<Item = (String, Nbt)>) -> Nbt {
+    let entries = entries
+        .into_iter()
+        .enumerate()
+        .map(|(id, (name, element))| {
+            Nbt::compound([
+                ("name", Nbt::String(name)),
+                ("id", Nbt::Int(id as i32)),
+                ("element", element),
+            ])
+        })
+        .collect();
+    Nbt::compound([("type", Nbt::string(name)), ("value", Nbt::List(entries))])
+}
+
+fn overworld_dimension_type() -> Nbt {
+    Nbt::compound([
+        ("has_skylight", Nbt::bool(true)),
+        ("has_ceiling", Nbt::bool(false)),
+        ("ultrawarm", Nbt::bool(false)),
+        ("natural", Nbt::bool(true)),
+        ("coordinate_scale", Nbt::Double(1.0)),
+        ("bed_works", Nbt::bool(true)),
+        ("respawn_anchor_works", Nbt::bool(false)),
+        ("min_y", Nbt::Int(0)),
+        ("height", Nbt::Int(WORLD_HEIGHT)),
+        ("logical_height", Nbt::Int(WORLD_HEIGHT)),
+        ("infiniburn", Nbt::string("#minecraft:infiniburn_overworld")),
+        ("effects", Nbt::string("minecraft:overworld")),
+        ("ambient_light", Nbt::Float(0.0)),
+        ("piglin_safe", Nbt::bool(false)),
+        ("has_raids", Nbt::bool(false)),
+        ("monster_spawn_light_level", Nbt::Int(0)),
+        ("monster_spawn_block_light_limit", Nbt::Int(0)),
+    ])
+}
+
+fn plains_biome() -> Nbt {
+    Nbt::compound([
+        ("has_precipitation", Nbt::bool(true)),
+        ("temperature", Nbt::Float(0.8)),
+        ("downfall", Nbt::Float(0.4)),
+        (
+            "effects",
+            Nbt::compound([
+                ("fog_color", Nbt::Int(12638463)),
+                ("water_color", Nbt::Int(4159204)),
+                ("water_fog_color", Nbt::Int(329011)),
+                ("sky_color", Nbt::Int(7907327)),
+            ]),
+        ),
+    ])
+}
+
+fn chat_type() -> Nbt {
+    let decoration = |translation_key: &str| {
+        Nbt::compound([
+            ("translation_key", Nbt::string(translation_key)),
+            (
+                "parameters",
+                Nbt::List(vec![Nbt::string("sender"), Nbt::string("content")]),
+            ),
+        ])
+    };
+    Nbt::compound([
+        ("chat", decoration("chat.type.text")),
+        ("narration", decoration("chat.type.text.narrate")),
+    ])
+}
+
+fn damage_type(name: &str) -> Nbt {
+    Nbt::compound([
+        ("message_id", Nbt::string(name)),
+        ("scaling", Nbt::string("never")),
+        ("exhaustion", Nbt::Float(0.0)),
+    ])
+}
+
+/// Encodes the ChunkAndLightData fields after the chunk coordinates
+/// for a chunk of the flat world. No light data is sent.
+pub fn chunk_data() -> Vec<u8> {
+    let sections = encode(|encoder| {
+        for section in 0..SECTIONS {
+            if section == 0 {
+                encoder.write_i16(4096);
+                write_single_valued_container(encoder, GRASS_BLOCK);
+            } else {
+                encoder.write_i16(0);
+                write_single_valued_container(encoder, 0);
+            }
+            // Biomes: plains, the only registered biome
+            write_single_valued_container(encoder, 0);
+        }
+    });
+    encode(|encoder| {
+        // Heightmaps
+        Nbt::compound([]).encode_network(encoder);
+        encoder.write_var_int(sections.len() as i32);
+        encoder.write_slice(&sections);
+        // Block entities
+        encoder.write_var_int(0);
+        // Sky light, block light, empty sky light and empty block light masks
+        for _ in 0..4 {
+            encoder.write_var_int(0);
+        }
+        // Sky light and block light arrays
+        encoder.write_var_int(0);
+        encoder.write_var_int(0);
+    })
+}
+
+/// Writes a paletted container holding a single value.
+fn write_single_valued_container(encoder: &mut Encoder, value: i32) {
+    // Bits per entry
+    encoder.write_u8(0);
+    encoder.write_var_int(value);
+    // Data array length
+    encoder.write_var_int(0);
+}
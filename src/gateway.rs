@@ -1,21 +1,133 @@
 //! Implements the gateway server. This translates
 //! from QUIC packets from the client to TCP sent to the destination server.
+//!
+//! A session stays with the destination it was dialed for. The gateway
+//! does not handle the 1.20.5 `Transfer` packet by dialing the new
+//! destination over the same QUIC connection, since the protocol model
+//! stops at 1.20.4 (see the `protocol` module).
 
 use crate::{
+    bandwidth::{BandwidthQuota, QuotaExceeded},
+    build_info::BuildInfo,
+    clock,
+    clock::SharedClock,
     control_stream,
-    control_stream::EnableTerminalEncryption,
+    control_stream::{
+        ConnectedDestination, EnableTerminalEncryption, OpeningRequest, ReauthenticationAttempt,
+        ResumptionGrant, ResumptionToken, TransportLimits,
+    },
+    destination::Destination,
+    latency_budget::LatencyBudgets,
+    memory_budget::{MemoryBudget, MemoryBudgetExceeded},
+    packet_log,
+    packet_rate::{PacketRateExceeded, PacketRateLimit},
+    packet_translation::STRIP_LIGHT_CHANNEL,
     protocol::{
-        packet::{client, client::handshake::NextState, server, side, state},
+        optimized_codec::CodecVersion,
+        packet::{
+            client,
+            client::handshake::{Handshake, NextState},
+            client::login::LoginStart,
+            server,
+            server::login::LoginPluginRequest,
+            side, state,
+        },
         vanilla_codec::{CompressionThreshold, EncryptionKey},
     },
-    proxy::{PacketIo, Proxy, QuicPacketIo, SingleQuicPacketIo, VanillaPacketIo},
+    proxy::{
+        Interception, PacketIo, Proxy, QuicPacketIo, SingleQuicPacketIo, TcpDisconnected,
+        VanillaPacketIo,
+    },
+    stats::SessionReport,
     stream,
+    watchdog::Stalled,
 };
+use ahash::AHashMap;
 use anyhow::{anyhow, bail, Context};
 use argon2::{PasswordHash, PasswordVerifier};
-use quinn::{Connection, Endpoint};
-use std::{ops::ControlFlow, thread, time::Duration};
-use tokio::{net::TcpStream, runtime, task::LocalSet, time::timeout};
+use ban::Bans;
+use circuit_breaker::{CircuitBreakers, CircuitOpen};
+use config::{FloodAction, GatewayConfig, ProxyConfig, QuotaAction, QuotaScope};
+use dial::Dialer;
+use event_log::{ConnectionEvent, EventLog};
+use fallback::Fallbacks;
+use forwarding::VelocityForwarding;
+use futures::future;
+use keepalive::ConfigurationKeepAlive;
+use login_plugin::{LoginPluginResponder, VELOCITY_PLAYER_INFO_CHANNEL};
+use measurement::MeasurementEndpoints;
+use metrics::ClientMetricsAggregator;
+pub use metrics::MetricsHandle;
+use notifier::{Alert, BruteForceDetector, Notifier, SessionCountDetector};
+use policy::{Policies, PolicyPermit, PolicyViolation};
+use quinn::{Connection, Endpoint, VarInt};
+use rate_limit::RateLimiter;
+use resumption::{Registration, Resumption, Resumptions};
+use session::{IdentityMismatch, Session, SessionRegistry, SessionSummary};
+use session_webhook::SessionWebhooks;
+pub use shutdown::ShutdownHandle;
+use shutdown::{DrainDeadline, DrainDeadlinePassed};
+use socket2::{Domain, Socket, Type};
+use status::StatusResponder;
+use std::{
+    convert::Infallible,
+    iter,
+    net::{IpAddr, SocketAddr},
+    ops::ControlFlow,
+    sync::{Arc, Mutex, RwLock, Weak},
+    time::Duration,
+};
+use subtle::ConstantTimeEq;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpListener, TcpStream},
+    select,
+};
+use upstream::{Upstream, Upstreams};
+use usage::UsageStats;
+use virtual_host::VirtualHosts;
+
+pub(crate) mod acme;
+mod admin;
+pub(crate) mod ban;
+pub(crate) mod circuit_breaker;
+pub mod config;
+mod dial;
+mod event_log;
+mod fallback;
+mod forwarding;
+mod keepalive;
+mod login_plugin;
+mod measurement;
+mod metrics;
+pub(crate) mod notifier;
+pub(crate) mod policy;
+mod proxy_protocol;
+pub(crate) mod rate_limit;
+mod resumption;
+pub(crate) mod self_test;
+pub(crate) mod session;
+mod session_webhook;
+mod shutdown;
+mod status;
+pub(crate) mod tls;
+pub(crate) mod token;
+mod upstream;
+pub(crate) mod usage;
+mod vanilla;
+pub(crate) mod virtual_host;
+
+// Used by the command-line interface, but not part of the stable API (see `prelude`).
+#[doc(hidden)]
+pub use acme::AcmeOptions;
+#[doc(hidden)]
+pub use policy::{PolicyEvaluation, PolicyEvaluationRequest};
+#[doc(hidden)]
+pub use self_test::{CheckOutcome, SelfTest};
+#[doc(hidden)]
+pub use tls::ReloadableCertificates;
+#[doc(hidden)]
+pub use token::TokenClaims;
 
 #[derive(Debug, Clone)]
 pub enum AuthenticationKey {
@@ -24,9 +136,26 @@ pub enum AuthenticationKey {
 }
 
 impl AuthenticationKey {
+    /// Interprets a configured key as an Argon2 hash if it is one,
+    /// and as a plaintext key otherwise.
+    pub fn parse(key: String) -> Self {
+        if PasswordHash::new(&key).is_ok() {
+            Self::Hashed(key)
+        } else {
+            tracing::warn!("Using plaintext authentication key. This is likely to expose side channel vulnerabilities.");
+            Self::Plaintext(key)
+        }
+    }
+
+    pub fn is_plaintext(&self) -> bool {
+        matches!(self, Self::Plaintext(_))
+    }
+
     pub fn is_correct(&self, key: &str) -> anyhow::Result<bool> {
         match self {
-            Self::Plaintext(s) => Ok(s == key),
+            // Constant time, so the comparison does not reveal
+            // how long a prefix of the key a guess got right.
+            Self::Plaintext(s) => Ok(s.as_bytes().ct_eq(key.as_bytes()).into()),
             Self::Hashed(s) => Ok(argon2::Argon2::default()
                 .verify_password(
                     key.as_bytes(),
@@ -39,13 +168,512 @@ impl AuthenticationKey {
     }
 }
 
-/// Runs a gateway server on the given endpoint.
+/// A set of authentication keys, any of which is correct. Clones share
+/// the set, so that it can be replaced while the gateway runs, e.g. to
+/// rotate the main key without restarting.
+#[derive(Debug, Clone)]
+pub struct AuthenticationKeys(Arc<RwLock<Arc<[AuthenticationKey]>>>);
+
+impl AuthenticationKeys {
+    pub fn new(keys: Vec<AuthenticationKey>) -> Self {
+        Self(Arc::new(RwLock::new(keys.into())))
+    }
+
+    /// Replaces the keys. Sessions already authenticated are not affected.
+    pub fn replace(&self, keys: Vec<AuthenticationKey>) {
+        *self.0.write().unwrap() = keys.into();
+    }
+
+    pub fn has_plaintext(&self) -> bool {
+        self.keys().iter().any(AuthenticationKey::is_plaintext)
+    }
+
+    pub fn is_correct(&self, key: &str) -> anyhow::Result<bool> {
+        for candidate in self.keys().iter() {
+            if candidate.is_correct(key)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn keys(&self) -> Arc<[AuthenticationKey]> {
+        // Not held while verifying, which is slow for hashed keys.
+        Arc::clone(&self.0.read().unwrap())
+    }
+}
+
+impl From<AuthenticationKey> for AuthenticationKeys {
+    fn from(key: AuthenticationKey) -> Self {
+        Self::new(vec![key])
+    }
+}
+
+/// Name of the identity authenticated by the gateway's main authentication key.
+pub const DEFAULT_IDENTITY: &str = "default";
+
+/// Name of the listener configured on the command line.
+pub const DEFAULT_LISTENER: &str = "default";
+
+/// An authentication key that clients may authenticate as.
+struct Identity {
+    name: String,
+    authentication_keys: AuthenticationKeys,
+    /// Tenant the identity belongs to, if any.
+    tenant: Option<String>,
+}
+
+/// State shared between all connections of a gateway.
+struct Shared {
+    identities: Vec<Identity>,
+    policies: Policies,
+    config: GatewayConfig,
+    notifier: Notifier,
+    brute_force_detector: BruteForceDetector,
+    session_count_detector: SessionCountDetector,
+    circuit_breakers: CircuitBreakers,
+    dialer: Dialer,
+    /// Secret of Velocity modern forwarding, if enabled.
+    forwarding_secret: Option<Arc<[u8]>>,
+    /// Secret authentication tokens are signed with, if enabled.
+    token_secret: Option<Arc<[u8]>>,
+    virtual_hosts: Option<VirtualHosts>,
+    fallbacks: Fallbacks,
+    upstreams: Upstreams,
+    status: Option<StatusResponder>,
+    sessions: Arc<SessionRegistry>,
+    usage: Arc<UsageStats>,
+    client_metrics: ClientMetricsAggregator,
+    on_session_end: Option<SessionEndHook>,
+    latency_budgets: Arc<LatencyBudgets>,
+    measurement: Option<Arc<MeasurementEndpoints>>,
+    rate_limiter: Option<RateLimiter>,
+    bans: Option<Bans>,
+    /// Bandwidth quotas shared by the sessions of each identity,
+    /// if quotas apply per identity.
+    identity_quotas: Mutex<AHashMap<String, Weak<BandwidthQuota>>>,
+    resumptions: Resumptions,
+    drain_deadline: DrainDeadline,
+    clock: SharedClock,
+}
+
+/// A QUIC endpoint accepting client connections.
+///
+/// Several listeners may share a name, e.g. to listen on both IPv4
+/// and IPv6 with the same certificates and policy.
+pub struct Listener {
+    /// Name identifying the listener in logs and diagnostics.
+    pub name: String,
+    pub endpoint: Endpoint,
+}
+
+/// Binds a UDP socket for a listener endpoint on each of `addresses`.
+///
+/// An IPv6 socket accepts IPv4 connections too (as IPv4-mapped addresses),
+/// on every platform, unless an IPv4 address with the same port is also
+/// in `addresses`, in which case the IPv4 socket takes them.
+pub fn bind_udp(addresses: &[SocketAddr]) -> anyhow::Result<Vec<std::net::UdpSocket>> {
+    addresses
+        .iter()
+        .map(|&address| {
+            let bind = || -> std::io::Result<_> {
+                let socket = Socket::new(Domain::for_address(address), Type::DGRAM, None)?;
+                if address.is_ipv6() {
+                    let separate_ipv4 = addresses
+                        .iter()
+                        .any(|other| other.is_ipv4() && other.port() == address.port());
+                    socket.set_only_v6(separate_ipv4)?;
+                }
+                socket.bind(&address.into())?;
+                Ok(socket.into())
+            };
+            bind().with_context(|| format!("failed to bind UDP socket on {address}"))
+        })
+        .collect()
+}
+
+/// Called with the summary of a session and a report on it when it ends.
+pub type SessionEndHook = Arc<dyn Fn(SessionSummary, SessionReport) + Send + Sync>;
+
+/// Runs a gateway server on the given listeners.
+///
+/// Returns once all listeners' endpoints have been closed.
 pub async fn run(
-    endpoint: &Endpoint,
+    listeners: &[Listener],
+    authentication_key: &AuthenticationKey,
+    config: GatewayConfig,
+) -> anyhow::Result<()> {
+    Gateway::new(listeners, authentication_key, config)
+        .run()
+        .await
+}
+
+/// Like `run`, but measures timeouts, rate limits and
+/// idle expiry on the given clock.
+pub async fn run_with_clock(
+    listeners: &[Listener],
     authentication_key: &AuthenticationKey,
+    config: GatewayConfig,
+    clock: SharedClock,
 ) -> anyhow::Result<()> {
+    Gateway::new(listeners, authentication_key, config)
+        .with_clock(clock)
+        .run()
+        .await
+}
+
+/// A gateway server on a set of listeners, for embedders
+/// that need more control than `run` offers.
+pub struct Gateway<'a> {
+    listeners: &'a [Listener],
+    authentication_keys: AuthenticationKeys,
+    config: GatewayConfig,
+    clock: SharedClock,
+    on_session_end: Option<SessionEndHook>,
+    shutdown: ShutdownHandle,
+    metrics: MetricsHandle,
+}
+
+impl<'a> Gateway<'a> {
+    pub fn new(
+        listeners: &'a [Listener],
+        authentication_key: &AuthenticationKey,
+        config: GatewayConfig,
+    ) -> Self {
+        Self {
+            listeners,
+            authentication_keys: authentication_key.clone().into(),
+            config,
+            clock: clock::system(),
+            on_session_end: None,
+            shutdown: ShutdownHandle::new(),
+            metrics: MetricsHandle::default(),
+        }
+    }
+
+    /// Authenticates the default identity with any of `keys` instead of
+    /// the key given to `new`. Replacing the keys of the set takes effect
+    /// on the next connection.
+    pub fn with_authentication_keys(mut self, keys: AuthenticationKeys) -> Self {
+        self.authentication_keys = keys;
+        self
+    }
+
+    /// Measures timeouts, rate limits and idle expiry on the given clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Calls `hook` whenever a session ends, with its summary
+    /// (including unmasked addresses) and a report on it.
+    ///
+    /// The hook is called on a runtime worker thread, so it should not block.
+    pub fn with_session_end_hook(
+        mut self,
+        hook: impl Fn(SessionSummary, SessionReport) + Send + Sync + 'static,
+    ) -> Self {
+        self.on_session_end = Some(Arc::new(hook));
+        self
+    }
+
+    /// Gets a handle that shuts the gateway down gracefully
+    /// once it runs, draining its sessions.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Gets a handle that reads the gateway's metrics, active
+    /// sessions and usage while it runs, without the admin API.
+    pub fn metrics_handle(&self) -> MetricsHandle {
+        self.metrics.clone()
+    }
+
+    /// Runs the gateway. Returns once all listeners' endpoints have
+    /// been closed, or once the gateway has been shut down through
+    /// its `shutdown_handle` and its sessions have drained.
+    pub async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            listeners,
+            authentication_keys,
+            config,
+            clock,
+            on_session_end,
+            shutdown,
+            metrics,
+        } = self;
+        tracing::info!("Starting {}", BuildInfo::current());
+        let identities: Vec<_> = iter::once(Identity {
+            name: DEFAULT_IDENTITY.to_owned(),
+            authentication_keys,
+            tenant: None,
+        })
+        .chain(config.identities.iter().map(|identity| Identity {
+            name: identity.name.clone(),
+            authentication_keys: AuthenticationKey::parse(identity.auth_key.clone()).into(),
+            tenant: None,
+        }))
+        .chain(config.tenants.iter().flat_map(|tenant| {
+            tenant.identities.iter().map(|identity| Identity {
+                name: tenant.identity_name(identity),
+                authentication_keys: AuthenticationKey::parse(identity.auth_key.clone()).into(),
+                tenant: Some(tenant.name.clone()),
+            })
+        }))
+        .collect();
+        if config.require_hashed_keys {
+            if let Some(identity) = identities
+                .iter()
+                .find(|identity| identity.authentication_keys.has_plaintext())
+            {
+                bail!(
+                    "authentication key of identity {} is plaintext, but hashed keys are required",
+                    identity.name
+                );
+            }
+        }
+        if let Some(vanilla) = &config.vanilla_listener {
+            if config
+                .listeners
+                .iter()
+                .any(|listener| listener.name == vanilla.name)
+            {
+                bail!(
+                    "the vanilla listener and a QUIC listener are both named {}",
+                    vanilla.name
+                );
+            }
+        }
+        if config.transport.min_uni_streams > config.transport.max_uni_streams {
+            bail!(
+                "transport.min_uni_streams ({}) is greater than transport.max_uni_streams ({})",
+                config.transport.min_uni_streams,
+                config.transport.max_uni_streams
+            );
+        }
+        let forwarding_secret = config
+            .proxy
+            .velocity_forwarding
+            .as_ref()
+            .map(|forwarding| forwarding.load_secret().map(Arc::from))
+            .transpose()?;
+        let token_secret = config
+            .tokens
+            .as_ref()
+            .map(|tokens| tokens.load_secret().map(Arc::from))
+            .transpose()?;
+        let usage = Arc::new(UsageStats::new(&config.usage, Arc::clone(&clock)));
+        let event_log = config
+            .event_log
+            .as_ref()
+            .map(|event_log| EventLog::open(event_log).map(Arc::new))
+            .transpose()?;
+        let session_webhooks = config
+            .session_webhooks
+            .as_ref()
+            .map(|webhooks| SessionWebhooks::new(webhooks).map(Arc::new))
+            .transpose()?;
+        let shared = Arc::new(Shared {
+            identities,
+            policies: Policies::new(&config, Arc::clone(&clock)),
+            notifier: Notifier::new(&config.webhooks)?,
+            brute_force_detector: BruteForceDetector::new(&config.webhooks, Arc::clone(&clock)),
+            session_count_detector: SessionCountDetector::new(&config.webhooks),
+            circuit_breakers: CircuitBreakers::new(&config.circuit_breaker, Arc::clone(&clock)),
+            dialer: Dialer::new(
+                config.proxy.outbound_bind.clone(),
+                config.proxy.destination_tcp.clone(),
+                Arc::clone(&clock),
+            )?,
+            forwarding_secret,
+            token_secret,
+            virtual_hosts: config
+                .virtual_hosts
+                .as_ref()
+                .map(VirtualHosts::new)
+                .transpose()?,
+            fallbacks: Fallbacks::new(&config.fallbacks)?,
+            upstreams: Upstreams::new(&config.upstreams)?,
+            status: config
+                .status
+                .as_ref()
+                .map(|status| StatusResponder::new(status, Arc::clone(&clock)))
+                .transpose()?,
+            sessions: Arc::new(SessionRegistry::new(
+                Arc::clone(&clock),
+                config.strict.clone(),
+                Arc::clone(&usage),
+                event_log,
+                session_webhooks,
+            )),
+            usage,
+            client_metrics: ClientMetricsAggregator::new(),
+            on_session_end,
+            latency_budgets: Arc::default(),
+            measurement: config.measurement.as_ref().map(|measurement| {
+                Arc::new(MeasurementEndpoints::new(
+                    measurement.port,
+                    Arc::clone(&clock),
+                ))
+            }),
+            rate_limiter: config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| RateLimiter::new(rate_limit, Arc::clone(&clock))),
+            bans: config
+                .ban
+                .as_ref()
+                .map(|ban| Bans::new(ban, Arc::clone(&clock)))
+                .transpose()?,
+            identity_quotas: Mutex::default(),
+            resumptions: Resumptions::default(),
+            drain_deadline: DrainDeadline::new(),
+            config,
+            clock,
+        });
+        metrics.attach(&shared);
+        packet_log::set_filter(shared.config.packet_log.clone());
+        if let Some(address) = shared.config.admin.listen {
+            let shared = Arc::clone(&shared);
+            tokio::spawn(async move {
+                if let Err(e) = admin::serve(address, shared).await {
+                    tracing::error!("Admin API failed: {e:#}");
+                }
+            });
+        }
+        if let Some(vanilla) = &shared.config.vanilla_listener {
+            if shared.virtual_hosts.is_none() {
+                bail!("the vanilla listener routes by virtual host, but no virtual hosts are configured");
+            }
+            let listener = TcpListener::bind(vanilla.listen).await.with_context(|| {
+                format!("failed to bind vanilla listener on {}", vanilla.listen)
+            })?;
+            tracing::info!(
+                "Vanilla listener {} listening on {}",
+                vanilla.name,
+                vanilla.listen
+            );
+            let vanilla = vanilla.clone();
+            let shared = Arc::clone(&shared);
+            tokio::spawn(async move {
+                if let Err(e) = vanilla::serve(listener, vanilla, shared).await {
+                    tracing::error!("Vanilla listener failed: {e:#}");
+                }
+            });
+        }
+        if let Some(measurement) = &shared.measurement {
+            let port = measurement.port();
+            let listener = TcpListener::bind(("0.0.0.0", port))
+                .await
+                .with_context(|| format!("failed to bind measurement endpoint on port {port}"))?;
+            tracing::info!("Measurement endpoint listening on port {port}");
+            let measurement = Arc::clone(measurement);
+            tokio::spawn(async move {
+                if let Err(e) = measurement.serve(listener).await {
+                    tracing::error!("Measurement endpoint failed: {e:#}");
+                }
+            });
+        }
+        {
+            let sessions = Arc::clone(&shared.sessions);
+            tokio::spawn(
+                Arc::clone(&shared.usage)
+                    .save_periodically(shared.config.usage.clone(), move || {
+                        sessions.active_bytes()
+                    }),
+            );
+        }
+        shared.notifier.notify(Alert::GatewayStarted {
+            listen_addresses: listeners
+                .iter()
+                .map(|listener| listener.endpoint.local_addr())
+                .collect::<Result<_, _>>()?,
+        });
+
+        let accept_loops = future::try_join_all(
+            listeners
+                .iter()
+                .map(|listener| accept_loop(listener, &shared)),
+        );
+        let result = select! {
+            result = accept_loops => result.map(|_| "endpoint closed"),
+            () = shutdown.requested() => {
+                drain(listeners, &shared).await;
+                Ok("shut down")
+            }
+        };
+        shared.usage.save(shared.sessions.active_bytes());
+        let reason = match &result {
+            Ok(reason) => (*reason).to_owned(),
+            Err(e) => format!("{e:#}"),
+        };
+        shared
+            .notifier
+            .notify_and_wait(Alert::GatewayStopped { reason })
+            .await;
+        result.map(|_| ())
+    }
+}
+
+/// Stops accepting connections, then waits for the sessions
+/// to end until the drain timeout passes and closes the listeners.
+async fn drain(listeners: &[Listener], shared: &Shared) {
+    tracing::info!("Shutting down, draining {} sessions", shared.sessions.len());
+    for listener in listeners {
+        listener.endpoint.set_server_config(None);
+    }
+    let drain_timeout = shared.config.shutdown.drain_timeout();
+    shared
+        .drain_deadline
+        .start(shared.clock.now() + drain_timeout);
+    // Play sessions still running at the deadline take up to
+    // `SHUTDOWN_LINGER` more to deliver their disconnect message.
+    let idle = future::join_all(
+        listeners
+            .iter()
+            .map(|listener| listener.endpoint.wait_idle()),
+    );
+    if clock::timeout(&*shared.clock, drain_timeout + SHUTDOWN_LINGER, idle)
+        .await
+        .is_err()
+    {
+        tracing::warn!(
+            "Closing {} sessions still running after the drain timeout",
+            shared.sessions.len()
+        );
+    }
+    for listener in listeners {
+        listener
+            .endpoint
+            .close(ErrorCode::GatewayShutdown.into(), b"gateway shut down");
+    }
+}
+
+async fn accept_loop(listener: &Listener, shared: &Arc<Shared>) -> anyhow::Result<()> {
+    tracing::info!(
+        "Listener {} listening on {}",
+        listener.name,
+        listener.endpoint.local_addr()?
+    );
     loop {
-        let connection = match endpoint.accept().await.context("endpoint closed")?.await {
+        let Some(connecting) = listener.endpoint.accept().await else {
+            return Ok(());
+        };
+        if let Some(bans) = &shared.bans {
+            if let Err(e) = bans.check(connecting.remote_address().ip()) {
+                // Dropping the connection refuses it without completing the handshake.
+                tracing::debug!("Refusing connection: {e}");
+                continue;
+            }
+        }
+        if let Some(rate_limiter) = &shared.rate_limiter {
+            if let Err(e) = rate_limiter.admit_connection(connecting.remote_address().ip()) {
+                // Dropping the connection refuses it without completing the handshake.
+                tracing::warn!("Refusing connection: {e}");
+                continue;
+            }
+        }
+        let connection = match connecting.await {
             Ok(conn) => conn,
             Err(e) => {
                 tracing::warn!("Failed to accept connection: {e}");
@@ -53,87 +681,1185 @@ pub async fn run(
             }
         };
 
-        tracing::info!("Accepted connection from {}", connection.remote_address());
-        let authentication_key = authentication_key.clone();
-        let runtime = runtime::Handle::current();
-        thread::spawn(move || {
-            let local_set = LocalSet::new();
-            local_set.spawn_local(async move {
-                if let Err(e) = drive_connection(connection, &authentication_key).await {
-                    tracing::info!("Connection lost: {e:?}");
+        let session = match shared.sessions.register(
+            connection.clone(),
+            &listener.name,
+            &shared.config.session_limits,
+        ) {
+            Ok(session) => session,
+            Err(e) => {
+                tracing::warn!(
+                    "Refusing connection from {}: {e}",
+                    connection.remote_address()
+                );
+                tokio::spawn(refuse(connection, e.to_string(), shared.clock.clone()));
+                continue;
+            }
+        };
+        tracing::info!(
+            "Accepted connection from {} on listener {} (session {})",
+            connection.remote_address(),
+            listener.name,
+            session.session().id()
+        );
+        session.session().log(ConnectionEvent::Connect {
+            listener: &listener.name,
+        });
+        if let Some(alert) = shared.session_count_detector.record(shared.sessions.len()) {
+            shared.notifier.notify(alert);
+        }
+
+        let shared = Arc::clone(shared);
+        tokio::spawn(async move {
+            let result = drive_connection(connection.clone(), &shared, session.session()).await;
+            if let Err(e) = &result {
+                if e.is::<ReauthenticationAttempt>() || e.is::<IdentityMismatch>() {
+                    connection.close(ErrorCode::Reauthentication.into(), e.to_string().as_bytes());
                 }
-            });
-            runtime.block_on(local_set);
+                tracing::info!("Connection lost: {e:?}");
+                session
+                    .session()
+                    .record_event(format!("connection lost: {e:#}"));
+            }
+            session
+                .session()
+                .log_disconnect(result.as_ref().err().map(|e| format!("{e:#}")));
+            if let Some(on_session_end) = &shared.on_session_end {
+                let session = session.session();
+                let report = SessionReport::new(
+                    session.duration(),
+                    &connection,
+                    session.timeline(),
+                    &result,
+                );
+                on_session_end(session.summary(true), report);
+            }
+            drop(session);
         });
     }
 }
 
-const CONFIGURATION_TIMEOUT: Duration = Duration::from_secs(30);
+impl Shared {
+    /// Returns the identity the key authenticates as, if any.
+    ///
+    /// A key of the form `<identity>:<key>` is only verified against the
+    /// keys of the named identity, and any other key against those of the
+    /// default identity. Verifying a hashed key is slow, so an attempt must
+    /// not cost a verification for every identity configured.
+    fn authenticate(&self, key: &str) -> anyhow::Result<Option<&Identity>> {
+        let named = key
+            .split_once(':')
+            .and_then(|(name, key)| Some((self.identity(name)?, key)));
+        let (identity, key) = match named {
+            Some(named) => named,
+            // The default identity always comes first.
+            None => (&self.identities[0], key),
+        };
+        Ok(identity
+            .authentication_keys
+            .is_correct(key)?
+            .then_some(identity))
+    }
+
+    /// Renders the metrics served by the admin API
+    /// in the Prometheus text exposition format.
+    fn render_metrics(&self) -> String {
+        let mut out = self.client_metrics.render();
+        self.latency_budgets.render(&mut out);
+        self.sessions.render(&mut out);
+        self.usage.render(&mut out, self.sessions.active_bytes());
+        out
+    }
+
+    fn identity(&self, name: &str) -> Option<&Identity> {
+        self.identities
+            .iter()
+            .find(|identity| identity.name == name)
+    }
+
+    /// Gets the bandwidth quota of a new session
+    /// of the given identity, if quotas are enabled.
+    fn bandwidth_quota(&self, identity: &str) -> Option<Arc<BandwidthQuota>> {
+        let config = self.config.bandwidth_quota.as_ref()?;
+        let new_quota = || {
+            Arc::new(BandwidthQuota::new(
+                config.max_bytes_per_sec,
+                config.burst_bytes,
+                config.action == QuotaAction::Throttle,
+                Arc::clone(&self.clock),
+            ))
+        };
+        match config.scope {
+            QuotaScope::Session => Some(new_quota()),
+            QuotaScope::Identity => {
+                let mut quotas = self.identity_quotas.lock().unwrap();
+                quotas.retain(|_, quota| quota.strong_count() > 0);
+                if let Some(quota) = quotas.get(identity).and_then(Weak::upgrade) {
+                    return Some(quota);
+                }
+                let quota = new_quota();
+                quotas.insert(identity.to_owned(), Arc::downgrade(&quota));
+                Some(quota)
+            }
+        }
+    }
+}
+
+/// QUIC application error code the gateway closes a client connection with.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u32)]
+pub(crate) enum ErrorCode {
+    /// The destination's circuit breaker is open.
+    CircuitOpen = 1,
+    /// The client attempted to authenticate a second time.
+    Reauthentication = 2,
+    /// The session's anomaly score exceeded the maximum in strict mode.
+    AnomalyScore = 3,
+    /// The destination server closed its connection.
+    DestinationClosed = 4,
+    /// The client's IP address exceeded a rate limit.
+    RateLimited = 5,
+    /// The gateway shut down.
+    GatewayShutdown = 6,
+    /// The Play session stalled in one direction.
+    Stalled = 7,
+    /// The connection would exceed a limit on concurrent sessions.
+    SessionLimit = 8,
+    /// An operator disconnected the session through the admin API.
+    AdminDisconnect = 9,
+    /// The session exceeded its bandwidth quota.
+    QuotaExceeded = 10,
+    /// The client resumed the session on a new connection.
+    Resumed = 11,
+    /// The client asked to resume a session that cannot be resumed.
+    ResumptionRefused = 12,
+    /// The Play session exchanged no packets for the idle timeout.
+    PlayIdle = 13,
+    /// The session made the gateway buffer more than its memory budget.
+    MemoryBudget = 14,
+    /// The client sent more packets than flood protection allows.
+    Flooding = 15,
+}
+
+impl From<ErrorCode> for VarInt {
+    fn from(code: ErrorCode) -> Self {
+        VarInt::from_u32(code as u32)
+    }
+}
+
+/// Time a refused client has to read the reason before its connection is closed.
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Disconnect message shown to players whose session stalled.
+const STALLED_MESSAGE: &str = "Lost connection: the proxy stopped delivering packets.";
+/// Time the connection of a stalled Play session is kept open after
+/// disconnecting the player. Also bounds the time spent disconnecting
+/// them, since the stalled direction may be the one to the client.
+const STALL_LINGER: Duration = Duration::from_secs(2);
+
+/// Time the connection of a Play session still running at the drain
+/// deadline is kept open after disconnecting the player, unless the
+/// client closes it first. See `close_after_linger`.
+const SHUTDOWN_LINGER: Duration = Duration::from_secs(2);
+
+/// Tells the client why its connection is refused over the control
+/// stream, then closes the connection with the same reason.
+async fn refuse(connection: Connection, reason: String, clock: SharedClock) {
+    let notify = async {
+        let mut control_stream = control_stream::GatewaySide::accept(&connection).await?;
+        control_stream.refuse(&reason).await?;
+        // Give the client the chance to read the message and close first.
+        connection.closed().await;
+        anyhow::Ok(())
+    };
+    if let Ok(Err(e)) = clock::timeout(&*clock, REFUSAL_TIMEOUT, notify).await {
+        tracing::debug!("Failed to send refusal: {e}");
+    }
+    connection.close(ErrorCode::SessionLimit.into(), reason.as_bytes());
+}
+
+/// Hands a connection resuming a session over to the session. If it
+/// cannot be resumed, tells the client why and closes the connection.
+async fn hand_over(
+    connection: Connection,
+    control_stream: control_stream::GatewaySide,
+    token: ResumptionToken,
+    codec_versions: &[u8],
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<()> {
+    let resumption = Resumption {
+        connection: connection.clone(),
+        control_stream,
+    };
+    let (e, mut control_stream) = match shared.resumptions.claim(&token, codec_versions) {
+        Ok(sender) => match sender.send(resumption) {
+            Ok(()) => {
+                session.record_event("handed over to the session it resumes");
+                return Ok(());
+            }
+            Err(resumption) => (
+                anyhow!("session ended before it could be resumed"),
+                resumption.control_stream,
+            ),
+        },
+        Err(e) => (e, resumption.control_stream),
+    };
+    session.record_event(format!("failed to resume a session: {e}"));
+    let reason = e.to_string();
+    let refusal = async {
+        control_stream.refuse(&reason).await?;
+        // Give the client the chance to read the message and close first.
+        connection.closed().await;
+        anyhow::Ok(())
+    };
+    clock::timeout(&*shared.clock, REFUSAL_TIMEOUT, refusal)
+        .await
+        .ok();
+    connection.close(ErrorCode::ResumptionRefused.into(), reason.as_bytes());
+    Err(e)
+}
 
 /// Accepts a new connection from a client.
 async fn drive_connection(
-    connection: Connection,
-    authentication_key: &AuthenticationKey,
+    mut connection: Connection,
+    shared: &Shared,
+    session: &Session,
 ) -> anyhow::Result<()> {
-    let mut control_stream = control_stream::GatewaySide::accept(&connection).await?;
-    let connect_to = timeout(CONFIGURATION_TIMEOUT, control_stream.wait_for_connect_to()).await??;
+    let mut control_stream = control_stream::GatewaySide::accept(&connection)
+        .await?
+        .with_affinity_token(shared.config.affinity.token())
+        .with_measurement_issuer(
+            shared
+                .measurement
+                .as_ref()
+                .map(MeasurementEndpoints::issuer),
+        )
+        .with_resumption_grant(shared.config.resumption.as_ref().map(|resumption| {
+            ResumptionGrant {
+                token: Resumptions::generate_token(),
+                grace_millis: resumption
+                    .grace()
+                    .as_millis()
+                    .try_into()
+                    .unwrap_or(u64::MAX),
+            }
+        }))
+        .with_transport_limits(TransportLimits {
+            max_idle_timeout: shared.config.transport.max_idle_timeout(),
+            min_uni_streams: shared.config.transport.min_uni_streams,
+            max_uni_streams: shared.config.transport.max_uni_streams,
+        });
+    let request = clock::timeout(
+        &*shared.clock,
+        shared.config.timeouts.control_stream(),
+        control_stream.wait_for_opening_request(),
+    )
+    .await
+    .map_err(|_| anyhow!("client did not send its opening request in time"))??;
 
-    if !authentication_key.is_correct(&connect_to.authentication_key)? {
+    let address = connection.remote_address().ip();
+    if let Some(rate_limiter) = &shared.rate_limiter {
+        if let Err(e) = rate_limiter.admit_handshake(address) {
+            session.record_event(format!("rejected by rate limit: {e}"));
+            connection.close(ErrorCode::RateLimited.into(), e.to_string().as_bytes());
+            return Err(e.into());
+        }
+    }
+    let connect_to = match request {
+        OpeningRequest::Connect(connect_to) => connect_to,
+        OpeningRequest::Resume {
+            token,
+            codec_versions,
+        } => {
+            return hand_over(
+                connection,
+                control_stream,
+                token,
+                &codec_versions,
+                shared,
+                session,
+            )
+            .await;
+        }
+    };
+    let mut certificate_replaces_key = false;
+    if let Some(client_certificates) = &shared.config.client_certificates {
+        // Listeners whose TLS configuration does not verify client
+        // certificates (e.g. self-signed ones) must not let clients skip them.
+        if !has_client_certificate(&connection) {
+            session.record_event("no client certificate presented");
+            bail!("client presented no certificate, but client certificates are required");
+        }
+        session.record_event("client certificate verified");
+        certificate_replaces_key = client_certificates.replaces_auth_key;
+    }
+    let key = &connect_to.authentication_key;
+    // Claims of the token the client authenticated with, if any.
+    let mut token_claims = None;
+    let authenticated = match &shared.token_secret {
+        Some(secret) if token::is_token(key) => {
+            // With virtual hosts, the destination named by the client is
+            // ignored, so the token is checked against the routed one instead.
+            let destination =
+                Some(&connect_to.destination).filter(|_| shared.virtual_hosts.is_none());
+            match token::verify(secret, key, destination, shared.clock.system_time()) {
+                Ok(claims) => {
+                    session
+                        .record_event(format!("token valid until unix time {}", claims.expires_at));
+                    let identity = shared.identity(&claims.identity);
+                    token_claims = Some(claims);
+                    identity
+                }
+                Err(e) => {
+                    session.record_event(format!("invalid token: {e}"));
+                    None
+                }
+            }
+        }
+        _ => shared.authenticate(key)?,
+    };
+    let identity = match authenticated {
+        Some(identity) => Some(identity),
+        // The verified certificate alone authenticates the client.
+        None if certificate_replaces_key => shared.identity(DEFAULT_IDENTITY),
+        None => None,
+    };
+    let Some(identity) = identity else {
+        if let Some(alert) = shared.brute_force_detector.record_failure(address) {
+            shared.notifier.notify(alert);
+        }
+        if let Some(rate_limiter) = &shared.rate_limiter {
+            rate_limiter.record_auth_failure(address);
+        }
+        if let Some(alert) = shared
+            .bans
+            .as_ref()
+            .and_then(|bans| bans.record_auth_failure(address))
+        {
+            session.record_event("source address banned");
+            shared.notifier.notify(alert);
+        }
+        session.record_event("authentication failed");
+        session.log(ConnectionEvent::AuthFailure);
         bail!("client failed to present correct authentication key");
+    };
+    session.bind_identity(&identity.name, identity.tenant.as_deref())?;
+    session.record_event(format!("authenticated as identity {}", identity.name));
+    session.log(ConnectionEvent::AuthSuccess {
+        identity: &identity.name,
+        tenant: identity.tenant.as_deref(),
+    });
+    if let Some(quota) = shared.bandwidth_quota(&identity.name) {
+        session.set_quota(quota);
+    }
+    if let Some(memory_budget) = &shared.config.memory_budget {
+        session.set_memory_budget(MemoryBudget::new(memory_budget.max_bytes));
     }
 
-    tracing::info!(
-        "Connecting to destination server {}",
-        connect_to.destination_server
-    );
-    let server_connection = TcpStream::connect(connect_to.destination_server).await?;
-    tracing::info!(
-        "Connected to destination server {}",
-        connect_to.destination_server
-    );
-    let server_connection: VanillaPacketIo<side::Client, state::Handshake> =
-        VanillaPacketIo::new(server_connection)?;
-    control_stream.acknowledge_connect_to().await?;
+    let codec_version = CodecVersion::negotiate(&connect_to.codec_versions).with_context(|| {
+        format!(
+            "no common codec version (client supports {:?})",
+            connect_to.codec_versions
+        )
+    })?;
+    session.set_codec_version(codec_version);
+    session.record_event(format!(
+        "negotiated codec version {}",
+        codec_version.as_u8()
+    ));
+
+    // With virtual hosts, the destination is only known from the handshake,
+    // which the client sends once the connection is acknowledged. With
+    // status answered by the gateway, pings may need no connection at all.
+    let dial_after_handshake = shared.virtual_hosts.is_some() || shared.status.is_some();
+    let mut dialed = if dial_after_handshake {
+        None
+    } else {
+        Some(dial_destination(&connect_to.destination, Some(identity), shared, session).await?)
+    };
+    control_stream.set_connected_destination(dialed.as_ref().map(|dialed| ConnectedDestination {
+        destination: dialed.destination.to_string(),
+        address: dialed.address,
+        fallback: dialed.fallback,
+    }));
+    control_stream.acknowledge_connect_to(codec_version).await?;
+    clock::timeout(
+        &*shared.clock,
+        shared.config.timeouts.control_stream(),
+        control_stream.answer_time_sync(),
+    )
+    .await
+    .map_err(|_| anyhow!("client did not complete the time sync in time"))??;
+    if control_stream.measurement_enabled() {
+        session.record_event("measurement enabled");
+        tokio::spawn(measurement::echo_quic_probes(connection.clone()));
+    }
+    if let Some(preferences) = control_stream.transport_preferences() {
+        session.record_event(format!("applied transport preferences: {preferences:?}"));
+    }
+    if let Some(build_info) = control_stream.client_build_info() {
+        session.set_client_build_info(build_info.clone());
+        session.record_event(format!("client is {build_info}"));
+    }
 
     let client_connection: SingleQuicPacketIo<side::Server, state::Handshake> =
-        SingleQuicPacketIo::new(&connection).await?;
+        SingleQuicPacketIo::new(&connection, codec_version, Arc::clone(session.timeline())).await?;
+
+    let mut handshake = None;
+    let mut destination = connect_to.destination;
+    if dial_after_handshake {
+        let client::handshake::Packet::Handshake(received) = clock::timeout(
+            &*shared.clock,
+            shared.config.timeouts.login(),
+            client_connection.recv_packet(),
+        )
+        .await
+        .map_err(|_| anyhow!("game did not send its handshake in time"))??;
+        if let Some(virtual_hosts) = &shared.virtual_hosts {
+            destination = match virtual_hosts.route(&received) {
+                Ok(routed) => routed.clone(),
+                Err(e) => {
+                    session.record_event(format!("handshake not routed: {e}"));
+                    return Err(e.into());
+                }
+            };
+            session.record_event(format!(
+                "routed handshake for {:?} to {destination}",
+                received.server_address
+            ));
+            if let Some(claims) = &token_claims {
+                if let Err(e) = token::check_destination(claims, &destination) {
+                    session.record_event(format!("token rejected for routed destination: {e}"));
+                    return Err(e.into());
+                }
+            }
+        }
+        let status_response = shared
+            .status
+            .as_ref()
+            .filter(|_| received.next_state == NextState::Status)
+            .and_then(|status| {
+                status.response(
+                    &destination,
+                    received.protocol_version,
+                    shared.sessions.len(),
+                )
+            });
+        if let Some(response) = status_response {
+            session.record_event("answering status without connecting to the destination server");
+            session.set_state("Status");
+            let client_connection = client_connection.switch_state(&mut control_stream).await?;
+            clock::timeout(
+                &*shared.clock,
+                shared.config.timeouts.login(),
+                status::answer(client_connection, response),
+            )
+            .await
+            .map_err(|_| anyhow!("status ping did not complete in time"))??;
+            return Ok(());
+        }
+        dialed = Some(dial_destination(&destination, Some(identity), shared, session).await?);
+        handshake = Some(received);
+    }
+    let Dialed {
+        server_connection,
+        destination: dialed_destination,
+        address,
+        fallback: _,
+        policy_permit: _policy_permit,
+        mut upstream,
+    } = dialed.expect("destination dialed");
+    let mut server_connection: VanillaPacketIo<side::Client, state::Handshake> =
+        VanillaPacketIo::new(server_connection)?;
+    if let (Some(batching), None) = (&shared.config.proxy.write_batching, &upstream) {
+        server_connection =
+            server_connection.with_write_batching(batching.max_delay(), batching.max_bytes);
+    }
 
-    let (mut client_connection, mut server_connection) = match timeout(
-        CONFIGURATION_TIMEOUT,
-        configure_connection(server_connection, client_connection, &mut control_stream),
+    let (client_connection, server_connection) = match clock::timeout(
+        &*shared.clock,
+        shared.config.timeouts.login(),
+        configure_connection(
+            server_connection,
+            client_connection,
+            handshake,
+            &mut control_stream,
+            session,
+            match upstream {
+                Some(_) => upstream::handshake_address(&dialed_destination),
+                None => handshake_address(&dialed_destination, address),
+            },
+            upstream.as_mut(),
+            shared.status.as_ref().map(|status| (status, &destination)),
+            shared.forwarding_secret.as_ref().map(|secret| {
+                VelocityForwarding::new(Arc::clone(secret), connection.remote_address().ip())
+            }),
+            &shared.config.proxy,
+        ),
     )
-    .await??
+    .await
+    .map_err(|_| anyhow!("login did not complete in time"))??
     {
         Some(conns) => conns,
         None => return Ok(()),
     };
+    let (mut client_connection, mut server_connection) = clock::timeout(
+        &*shared.clock,
+        shared.config.timeouts.configuration(),
+        do_configuration(
+            client_connection,
+            server_connection,
+            &mut control_stream,
+            session,
+            &shared.config.proxy,
+            &shared.clock,
+        ),
+    )
+    .await
+    .map_err(|_| anyhow!("configuration did not complete in time"))??;
 
+    let resumption_token = control_stream.resumption_token();
+    if resumption_token.is_some() {
+        session.record_event("resumption granted");
+    }
     loop {
-        let mut proxy = Proxy::new(client_connection, server_connection);
-        proxy
-            .run(
-                |client_packet| {
-                    if let client::play::Packet::AcknowledgeConfiguration(_) = client_packet {
-                        ControlFlow::Break(())
-                    } else {
-                        ControlFlow::Continue(())
+        let mut registration =
+            resumption_token.map(|token| shared.resumptions.register(token, codec_version));
+        let mut proxy = Proxy::new(client_connection, server_connection)
+            .with_packet_flow(Arc::clone(session.packet_flow()))
+            .with_latency_budgets(Arc::clone(&shared.latency_budgets));
+        if let Some(memory_budget) = session.memory_budget() {
+            proxy = proxy.with_memory_budget(Arc::clone(memory_budget));
+        }
+        if let Some(flood_protection) = &shared.config.flood_protection {
+            proxy = proxy.with_packet_rate_limit(PacketRateLimit::new(
+                flood_protection.max_packets_per_sec,
+                flood_protection.burst_packets,
+                flood_protection.action == FloodAction::Throttle,
+                Arc::clone(&shared.clock),
+            ));
+        }
+        if let Some(stall_watchdog) = &shared.config.proxy.stall_watchdog {
+            proxy = proxy.with_stall_watchdog(Arc::clone(&shared.clock), stall_watchdog.timeout());
+        }
+        let mut server_sent_disconnect = false;
+        let run = proxy.run(
+            |client_packet| {
+                if let client::play::Packet::AcknowledgeConfiguration(_) = client_packet {
+                    ControlFlow::Break(())
+                } else {
+                    ControlFlow::Continue(())
+                }
+            },
+            |server_packet| {
+                if let server::play::Packet::Disconnect(_) = server_packet {
+                    server_sent_disconnect = true;
+                }
+                ControlFlow::<()>::Continue(())
+            },
+        );
+        let mut resumed = None;
+        let result = select! {
+            result = run => result,
+            result = serve_play_control_stream(&mut control_stream, shared, session) => {
+                result.map(|never| match never {})
+            }
+            result = watch_play_idle(shared.config.timeouts.play_idle(), shared, session) => {
+                result.map(|never| match never {})
+            }
+            resumption = wait_for_resumption(registration.as_mut()) => {
+                resumed = Some(resumption);
+                Err(anyhow!("client resumed the session on a new connection"))
+            }
+        };
+        if let (Err(e), Some(registration)) = (&result, registration.as_mut()) {
+            if resumed.is_some() || resumption::dropped(&connection) {
+                // Fails the sends still pending on the old connection.
+                connection.close(
+                    ErrorCode::Resumed.into(),
+                    b"session resumed on another connection",
+                );
+                proxy.finish_pending().await;
+                let (_, server) = proxy.into_parts();
+                let (resumption, buffered) = match resumed {
+                    Some(resumption) => (resumption, Vec::new()),
+                    None => {
+                        session.record_event(format!(
+                            "client connection dropped, waiting for it to resume: {e:#}"
+                        ));
+                        session.set_state("Resuming");
+                        let config = shared
+                            .config
+                            .resumption
+                            .as_ref()
+                            .expect("resumption granted without config");
+                        clock::timeout(
+                            &*shared.clock,
+                            config.grace(),
+                            registration.wait_after_drop(&server, config),
+                        )
+                        .await
+                        .map_err(|_| anyhow!("client did not resume the session in time"))??
                     }
-                },
-                |_| ControlFlow::<()>::Continue(()),
-            )
-            .await?;
+                };
+                connection = resumption.connection;
+                session.resume_on(connection.clone());
+                control_stream
+                    .take_over(resumption.control_stream, codec_version)
+                    .await?;
+                client_connection = play_client_connection(
+                    connection.clone(),
+                    codec_version,
+                    &control_stream,
+                    session,
+                    &shared.config.proxy,
+                )
+                .await?;
+                for packet in buffered {
+                    client_connection.send_packet(packet).await?;
+                }
+                server_connection = server;
+                tracing::info!("Session {} resumed", session.id());
+                session.record_event("resumed on a new connection");
+                session.set_state("Play");
+                continue;
+            }
+        }
+        match (result, &shared.config.proxy.destination_close) {
+            (Ok(()), _) => {}
+            (Err(e), _) if e.is::<DrainDeadlinePassed>() => {
+                session.record_event("disconnected after the drain timeout");
+                proxy.finish_pending().await;
+                proxy
+                    .inject_to_client(server::play::Packet::Disconnect(
+                        server::play::Disconnect::with_reason(&shared.config.shutdown.message),
+                    ))
+                    .await?;
+                close_after_linger(
+                    &connection,
+                    SHUTDOWN_LINGER,
+                    ErrorCode::GatewayShutdown,
+                    b"gateway shut down",
+                    &mut control_stream,
+                    shared,
+                    session,
+                )
+                .await;
+                return Ok(());
+            }
+            (Err(e), _) if e.is::<Stalled>() => {
+                session.record_event(format!("disconnected after a stall: {e}"));
+                let disconnect = proxy.inject_to_client(server::play::Packet::Disconnect(
+                    server::play::Disconnect::with_reason(STALLED_MESSAGE),
+                ));
+                if let Ok(Err(e)) = clock::timeout(&*shared.clock, STALL_LINGER, disconnect).await {
+                    tracing::debug!("Failed to disconnect stalled player: {e:#}");
+                }
+                close_after_linger(
+                    &connection,
+                    STALL_LINGER,
+                    ErrorCode::Stalled,
+                    b"session stalled",
+                    &mut control_stream,
+                    shared,
+                    session,
+                )
+                .await;
+                return Err(e);
+            }
+            (Err(e), _) if e.is::<PlayIdle>() => {
+                session.record_event(format!("disconnected: {e}"));
+                connection.close(ErrorCode::PlayIdle.into(), e.to_string().as_bytes());
+                return Err(e);
+            }
+            (Err(e), _) if e.chain().any(|cause| cause.is::<QuotaExceeded>()) => {
+                session.record_event(format!("disconnected: {e:#}"));
+                connection.close(ErrorCode::QuotaExceeded.into(), e.to_string().as_bytes());
+                return Err(e);
+            }
+            (Err(e), _) if e.chain().any(|cause| cause.is::<MemoryBudgetExceeded>()) => {
+                session.record_event(format!("disconnected: {e:#}"));
+                connection.close(ErrorCode::MemoryBudget.into(), e.to_string().as_bytes());
+                return Err(e);
+            }
+            (Err(e), _) if e.is::<PacketRateExceeded>() => {
+                session.record_event(format!("disconnected: {e}"));
+                connection.close(ErrorCode::Flooding.into(), e.to_string().as_bytes());
+                return Err(e);
+            }
+            (Err(e), Some(close_config)) if TcpDisconnected::is_cause_of(&e) => {
+                session.record_event(format!("destination server closed the connection: {e:#}"));
+                proxy.finish_pending().await;
+                if !server_sent_disconnect {
+                    proxy
+                        .inject_to_client(server::play::Packet::Disconnect(
+                            server::play::Disconnect::with_reason(&close_config.message),
+                        ))
+                        .await?;
+                }
+                close_after_linger(
+                    &connection,
+                    close_config.linger(),
+                    ErrorCode::DestinationClosed,
+                    b"destination server closed the connection",
+                    &mut control_stream,
+                    shared,
+                    session,
+                )
+                .await;
+                return Ok(());
+            }
+            (Err(e), _) => return Err(e),
+        }
 
         (client_connection, server_connection) = proxy.into_parts();
         control_stream
             .acknowledge_transition_play_to_config()
             .await?;
         tracing::debug!("Acknowledged transition to Configuration state");
-        let (send, recv) = stream::open_bi(client_connection.connection(), "configuration").await?;
-        let config_client_connection =
-            SingleQuicPacketIo::from_streams(client_connection.connection(), send, recv);
+        session.record_event("transition from Play to Configuration state");
+        session.set_state("Configuration");
+        let (send, recv) = stream::open_bi(
+            client_connection.connection(),
+            codec_version,
+            "configuration",
+        )
+        .await?;
+        let config_client_connection = SingleQuicPacketIo::from_streams(
+            client_connection.connection(),
+            codec_version,
+            Arc::clone(client_connection.timeline()),
+            send,
+            recv,
+        );
         let config_server_connection = server_connection.switch_state();
-        (client_connection, server_connection) =
-            do_configuration(config_client_connection, config_server_connection).await?;
+        (client_connection, server_connection) = do_configuration(
+            config_client_connection,
+            config_server_connection,
+            &mut control_stream,
+            session,
+            &shared.config.proxy,
+            &shared.clock,
+        )
+        .await?;
+    }
+}
+
+/// Keeps a connection whose session ended open until the client closes
+/// it or the linger time passes, so that the packets sent last and the
+/// client's final metrics report are delivered, then closes it.
+async fn close_after_linger(
+    connection: &Connection,
+    linger: Duration,
+    error_code: ErrorCode,
+    reason: &[u8],
+    control_stream: &mut control_stream::GatewaySide,
+    shared: &Shared,
+    session: &Session,
+) {
+    let linger = clock::timeout(&*shared.clock, linger, async {
+        select! {
+            _ = connection.closed() => {}
+            // Ends the linger early if the control stream fails.
+            _ = receive_client_metrics(control_stream, shared, session) => {}
+        }
+    });
+    linger.await.ok();
+    connection.close(error_code.into(), reason);
+}
+
+/// Serves the control stream during the Play state. Once the gateway
+/// shuts down, tells the client, then fails with `DrainDeadlinePassed`
+/// when the drain deadline passes.
+async fn serve_play_control_stream(
+    control_stream: &mut control_stream::GatewaySide,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Infallible> {
+    let deadline = select! {
+        deadline = shared.drain_deadline.wait() => deadline,
+        result = receive_client_metrics(control_stream, shared, session) => match result? {},
+    };
+    let drain = deadline.saturating_duration_since(shared.clock.now());
+    if control_stream.notify_shutdown(drain).await? {
+        session.record_event("told client of shutdown");
+    }
+    select! {
+        () = shared.clock.sleep_until(deadline) => Err(DrainDeadlinePassed.into()),
+        result = receive_client_metrics(control_stream, shared, session) => match result? {},
+    }
+}
+
+/// Error ending a Play session in which neither side sent packets
+/// for the idle timeout.
+#[derive(Debug, thiserror::Error)]
+#[error("no packets in either direction for {}s", .0.as_secs())]
+struct PlayIdle(Duration);
+
+/// Fails with `PlayIdle` once no Play packets were sent or
+/// received for `timeout`. Never completes if it is unset.
+async fn watch_play_idle(
+    timeout: Option<Duration>,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Infallible> {
+    let Some(timeout) = timeout else {
+        return future::pending().await;
+    };
+    let mut packets = session.play_packets();
+    let mut last_activity = shared.clock.now();
+    loop {
+        // Checking more often than the timeout bounds how late idleness is noticed.
+        shared
+            .clock
+            .sleep_until(shared.clock.now() + timeout / 4)
+            .await;
+        let now = shared.clock.now();
+        let current = session.play_packets();
+        if current != packets {
+            packets = current;
+            last_activity = now;
+        } else if now.duration_since(last_activity) >= timeout {
+            return Err(PlayIdle(timeout).into());
+        }
+    }
+}
+
+/// Aggregates metrics reports sent by the client during the Play state.
+async fn receive_client_metrics(
+    control_stream: &mut control_stream::GatewaySide,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Infallible> {
+    loop {
+        let metrics = control_stream.wait_for_metrics().await?;
+        shared.client_metrics.record(session.tenant(), &metrics);
+    }
+}
+
+/// Whether the client presented a certificate during the QUIC handshake.
+fn has_client_certificate(connection: &Connection) -> bool {
+    connection
+        .peer_identity()
+        .and_then(|identity| identity.downcast::<Vec<rustls::Certificate>>().ok())
+        .is_some_and(|certificates| !certificates.is_empty())
+}
+
+/// Connection to a destination server, made by `dial_destination`.
+struct Dialed {
+    server_connection: TcpStream,
+    /// The destination connected to, which is a fallback
+    /// of the one requested if `fallback` is set.
+    destination: Destination,
+    address: SocketAddr,
+    fallback: bool,
+    /// Held for the rest of the session.
+    policy_permit: PolicyPermit,
+    /// Client to the upstream gateway the destination is proxied
+    /// through, if any. Held for the rest of the session.
+    upstream: Option<crate::client::ClientHandle>,
+}
+
+/// Connects to `destination`, or to its fallbacks in order if it
+/// cannot be connected to within the dial timeout of its chain.
+async fn dial_destination(
+    destination: &Destination,
+    identity: Option<&Identity>,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Dialed> {
+    let result = match shared.fallbacks.get(destination) {
+        Some(chain) => {
+            let mut candidates = iter::once(destination).chain(&chain.fallbacks).peekable();
+            loop {
+                let candidate = candidates.next().expect("chain is never empty");
+                let is_fallback = candidate != destination;
+                if is_fallback {
+                    session.record_event(format!("falling back to {candidate}"));
+                }
+                let result = clock::timeout(
+                    &*shared.clock,
+                    chain.dial_timeout,
+                    dial_one(candidate, identity, shared, session),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(anyhow!(
+                        "connecting to {candidate} timed out after {}s",
+                        chain.dial_timeout.as_secs()
+                    ))
+                });
+                match result {
+                    Ok(mut dialed) => {
+                        dialed.fallback = is_fallback;
+                        if is_fallback {
+                            tracing::info!("Fell back from {destination} to {candidate}");
+                        }
+                        break Ok(dialed);
+                    }
+                    // Not a failure of the destination, so its fallbacks would be no better.
+                    Err(e) if e.is::<PolicyViolation>() => break Err(e),
+                    Err(e) if candidates.peek().is_none() => break Err(e),
+                    Err(e) => session
+                        .record_event(format!("{candidate} could not be connected to: {e:#}")),
+                }
+            }
+        }
+        None => {
+            let dial_timeout = shared.config.timeouts.dial();
+            clock::timeout(
+                &*shared.clock,
+                dial_timeout,
+                dial_one(destination, identity, shared, session),
+            )
+            .await
+            .unwrap_or_else(|_| {
+                Err(anyhow!(
+                    "connecting to {destination} timed out after {}s",
+                    dial_timeout.as_secs()
+                ))
+            })
+        }
+    };
+    if let Err(e) = &result {
+        if let Some(circuit_open) = e.downcast_ref::<CircuitOpen>() {
+            // Tell the client why, rather than letting the connection drop silently.
+            session.close(ErrorCode::CircuitOpen, &circuit_open.to_string());
+        }
+    }
+    result
+}
+
+/// Resolves `destination`, checks it against the policies of `identity`
+/// and the circuit breakers, then connects to it.
+///
+/// `identity` is `None` for vanilla connections, which cannot be proxied
+/// through an upstream gateway since it would need their encryption key.
+async fn dial_one(
+    destination: &Destination,
+    identity: Option<&Identity>,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Dialed> {
+    if let Some(upstream) = shared.upstreams.get(destination) {
+        let Some(identity) = identity else {
+            bail!("{destination} is proxied through an upstream gateway, which vanilla connections cannot be");
+        };
+        return dial_upstream(upstream, destination, identity, shared, session).await;
+    }
+    let destination_host = match destination {
+        Destination::Address(_) => None,
+        Destination::Host { host, .. } => Some(host.as_str()),
+    };
+    let addresses = match shared.dialer.resolve(destination).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            session.record_event(format!("destination could not be resolved: {e:#}"));
+            return Err(e);
+        }
+    };
+    if destination_host.is_some() {
+        session.record_event(format!(
+            "resolved {} to {}",
+            destination,
+            addresses
+                .iter()
+                .map(SocketAddr::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    let (policy_permit, mut addresses) = match shared
+        .policies
+        .admit(
+            session.listener(),
+            identity.and_then(|identity| identity.tenant.as_deref()),
+            identity.map(|identity| identity.name.as_str()),
+            &addresses,
+            destination_host,
+        )
+        .await
+    {
+        Ok(admitted) => admitted,
+        Err(violation) => {
+            session.record_event(format!("rejected by policy: {violation}"));
+            return Err(violation.into());
+        }
+    };
+
+    tracing::info!("Connecting to destination server {}", destination);
+    session.set_destination(addresses[0]);
+    let mut circuit_open = None;
+    addresses.retain(|&address| match shared.circuit_breakers.check(address) {
+        Ok(()) => true,
+        Err(e) => {
+            circuit_open.get_or_insert(e);
+            false
+        }
+    });
+    if let Some(e) = circuit_open.filter(|_| addresses.is_empty()) {
+        session.record_event(format!("rejected by circuit breaker: {e}"));
+        return Err(e.into());
+    }
+    session.record_event("connecting to destination server");
+    let requested_destination = destination.to_string();
+    let on_failure = |address: SocketAddr, e: &std::io::Error| {
+        session.record_event(format!("destination server {address} unreachable: {e}"));
+        session.log(ConnectionEvent::Dial {
+            destination: &requested_destination,
+            address,
+            error: Some(e.to_string()),
+        });
+        shared.notifier.notify(Alert::DestinationUnreachable {
+            destination: address,
+            error: e.to_string(),
+        });
+        if shared.circuit_breakers.record_failure(address) {
+            shared.notifier.notify(Alert::CircuitOpened {
+                destination: address,
+                open_secs: shared.config.circuit_breaker.open_secs,
+            });
+        }
+    };
+    let (mut server_connection, address) = shared.dialer.connect(&addresses, on_failure).await?;
+    shared.circuit_breakers.record_success(address);
+    session.set_destination(address);
+    tracing::info!("Connected to destination server {address}");
+    session.record_event("connected to destination server");
+    session.log(ConnectionEvent::Dial {
+        destination: &requested_destination,
+        address,
+        error: None,
+    });
+    if shared.config.proxy.proxy_protocol {
+        server_connection
+            .write_all(&proxy_protocol::header(session.client_address(), address))
+            .await?;
+        session.record_event("sent PROXY protocol header");
+    }
+    Ok(Dialed {
+        server_connection,
+        destination: destination.clone(),
+        address,
+        fallback: false,
+        policy_permit,
+        upstream: None,
+    })
+}
+
+/// Checks the upstream gateway `destination` is proxied through against
+/// the policies of `identity`, then opens a client to it.
+async fn dial_upstream(
+    upstream: &Upstream,
+    destination: &Destination,
+    identity: &Identity,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<Dialed> {
+    let addresses = match shared.dialer.resolve(&upstream.gateway).await {
+        Ok(addresses) => addresses,
+        Err(e) => {
+            session.record_event(format!("upstream gateway could not be resolved: {e:#}"));
+            return Err(e);
+        }
+    };
+    let (policy_permit, addresses) = match shared
+        .policies
+        .admit(
+            session.listener(),
+            identity.tenant.as_deref(),
+            Some(&identity.name),
+            &addresses,
+            match &upstream.gateway {
+                Destination::Address(_) => None,
+                Destination::Host { host, .. } => Some(host.as_str()),
+            },
+        )
+        .await
+    {
+        Ok(admitted) => admitted,
+        Err(violation) => {
+            session.record_event(format!("rejected by policy: {violation}"));
+            return Err(violation.into());
+        }
+    };
+    let address = addresses[0];
+    if let Err(e) = shared.circuit_breakers.check(address) {
+        session.record_event(format!("rejected by circuit breaker: {e}"));
+        return Err(e.into());
+    }
+
+    tracing::info!(
+        "Connecting to {destination} through upstream gateway {}",
+        upstream.gateway
+    );
+    session.set_destination(address);
+    session.record_event(format!(
+        "connecting through upstream gateway {}",
+        upstream.gateway
+    ));
+    let requested_destination = destination.to_string();
+    let (client, server_connection) = match upstream
+        .open(
+            destination,
+            &shared.config.proxy.destination_tcp,
+            &shared.clock,
+        )
+        .await
+    {
+        Ok(opened) => opened,
+        Err(e) => {
+            session.record_event(format!("upstream gateway {address} unreachable: {e:#}"));
+            session.log(ConnectionEvent::Dial {
+                destination: &requested_destination,
+                address,
+                error: Some(format!("{e:#}")),
+            });
+            if shared.circuit_breakers.record_failure(address) {
+                shared.notifier.notify(Alert::CircuitOpened {
+                    destination: address,
+                    open_secs: shared.config.circuit_breaker.open_secs,
+                });
+            }
+            return Err(e);
+        }
+    };
+    shared.circuit_breakers.record_success(address);
+    tracing::info!("Connected to {destination} through upstream gateway {address}");
+    session.record_event("connected through upstream gateway");
+    session.log(ConnectionEvent::Dial {
+        destination: &requested_destination,
+        address,
+        error: None,
+    });
+    Ok(Dialed {
+        server_connection,
+        destination: destination.clone(),
+        address,
+        fallback: false,
+        policy_permit,
+        upstream: Some(client),
+    })
+}
+
+/// Gets the host and port the handshake names after rewriting: the host
+/// name the client named the destination by, if any, or its address.
+fn handshake_address(destination: &Destination, address: SocketAddr) -> (String, u16) {
+    match destination {
+        Destination::Host { host, .. } => (host.clone(), address.port()),
+        Destination::Address(_) => (address.ip().to_string(), address.port()),
+    }
+}
+
+/// Whether a handshake is addressed to a loopback host, as when the game
+/// connects to the client's local listener.
+fn addresses_loopback(handshake: &Handshake) -> bool {
+    match handshake.server_address.host() {
+        Some("localhost") => true,
+        Some(host) => host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback()),
+        None => false,
     }
 }
 
@@ -142,15 +1868,40 @@ type PlayConnections = (
     VanillaPacketIo<side::Client, state::Play>,
 );
 
-/// Performs handling for a connection until it arrives in the Play state.
-/// Returns `None` if the connection was a status connection and is therefore
-/// now terminated.
+type ConfigurationConnections = (
+    SingleQuicPacketIo<side::Server, state::Configuration>,
+    VanillaPacketIo<side::Client, state::Configuration>,
+);
+
+/// Performs handling for a connection until it arrives in the Configuration
+/// state. Returns `None` if the connection was a status connection and is
+/// therefore now terminated.
+///
+/// `handshake` is the client's handshake if it has already been received.
 async fn configure_connection(
     server_connection: VanillaPacketIo<side::Client, state::Handshake>,
     client_connection: SingleQuicPacketIo<side::Server, state::Handshake>,
+    handshake: Option<Handshake>,
     control_stream: &mut control_stream::GatewaySide,
-) -> anyhow::Result<Option<PlayConnections>> {
-    let client::handshake::Packet::Handshake(handshake) = client_connection.recv_packet().await?;
+    session: &Session,
+    (destination_host, destination_port): (String, u16),
+    mut upstream: Option<&mut crate::client::ClientHandle>,
+    status_cache: Option<(&StatusResponder, &Destination)>,
+    forwarding: Option<VelocityForwarding>,
+    proxy_config: &ProxyConfig,
+) -> anyhow::Result<Option<ConfigurationConnections>> {
+    let mut handshake = match handshake {
+        Some(handshake) => handshake,
+        None => {
+            let client::handshake::Packet::Handshake(handshake) =
+                client_connection.recv_packet().await?;
+            handshake
+        }
+    };
+    if proxy_config.rewrite_handshake_address && addresses_loopback(&handshake) {
+        handshake.rewrite_address(&destination_host, destination_port);
+        session.record_event("rewrote handshake address to destination");
+    }
     server_connection
         .send_packet(client::handshake::Packet::Handshake(handshake.clone()))
         .await?;
@@ -158,17 +1909,26 @@ async fn configure_connection(
     match handshake.next_state {
         NextState::Status => {
             tracing::debug!("Transition to Status state");
+            session.record_event("transition to Status state");
+            session.set_state("Status");
             handle_status(
                 server_connection.switch_state(),
-                client_connection.switch_state().await?,
+                client_connection.switch_state(control_stream).await?,
+                status_cache
+                    .map(|(status, destination)| (status, destination, handshake.protocol_version)),
+                session.client_address(),
             )
             .await?;
             Ok(None)
         }
         NextState::Login => {
             tracing::debug!("Transition to Login state");
+            session.record_event("transition to Login state");
+            session.set_state("Login");
             let (client_connection, server_connection) = (
-                client_connection.switch_state::<state::Login>().await?,
+                client_connection
+                    .switch_state::<state::Login>(control_stream)
+                    .await?,
                 server_connection.switch_state::<state::Login>(),
             );
 
@@ -176,33 +1936,63 @@ async fn configure_connection(
             enum Status {
                 EnableEncryption,
                 EnableCompression(CompressionThreshold),
+                AnswerLoginPluginRequest(LoginPluginRequest),
+                ForwardPlayerInfo(LoginStart),
                 FinishLogin,
             }
 
-            let mut proxy = Proxy::new(client_connection, server_connection);
+            let mut login_plugin_responder = LoginPluginResponder::default();
+
+            let mut proxy = Proxy::new(client_connection, server_connection)
+                .with_packet_flow(Arc::clone(session.packet_flow()));
+            if let Some(memory_budget) = session.memory_budget() {
+                proxy = proxy.with_memory_budget(Arc::clone(memory_budget));
+            }
             loop {
                 let status = proxy
-                    .run(
+                    .run_intercepting(
                         |client_packet| {
                             if let client::login::Packet::LoginAcknowledged(_) = client_packet {
-                                ControlFlow::Break(Status::FinishLogin)
+                                Interception::Break(Status::FinishLogin)
                             } else if let client::login::Packet::EncryptionResponse(_) =
                                 client_packet
                             {
-                                ControlFlow::Break(Status::EnableEncryption)
+                                Interception::Break(Status::EnableEncryption)
+                            } else if let (
+                                client::login::Packet::LoginStart(login_start),
+                                Some(_),
+                            ) = (&*client_packet, &forwarding)
+                            {
+                                Interception::Break(Status::ForwardPlayerInfo(login_start.clone()))
                             } else {
-                                ControlFlow::Continue(())
+                                Interception::Continue
                             }
                         },
-                        |server_packet| {
-                            if let server::login::Packet::SetCompression(packet) = server_packet {
-                                if let Ok(threshold) = usize::try_from(packet.threshold) {
-                                    return ControlFlow::Break(Status::EnableCompression(
-                                        CompressionThreshold::new(threshold),
-                                    ));
+                        |server_packet| match server_packet {
+                            server::login::Packet::SetCompression(packet) => {
+                                match usize::try_from(packet.threshold) {
+                                    Ok(threshold) => {
+                                        Interception::Break(Status::EnableCompression(
+                                            CompressionThreshold::new(threshold),
+                                        ))
+                                    }
+                                    Err(_) => Interception::Continue,
+                                }
+                            }
+                            server::login::Packet::LoginPluginRequest(request) => {
+                                tracing::debug!(
+                                    "Login plugin request on channel {}",
+                                    request.channel
+                                );
+                                if login_plugin_responder.handles(&request.channel) {
+                                    Interception::Withhold(Status::AnswerLoginPluginRequest(
+                                        request.clone(),
+                                    ))
+                                } else {
+                                    Interception::Continue
                                 }
                             }
-                            ControlFlow::Continue(())
+                            _ => Interception::Continue,
                         },
                     )
                     .await?;
@@ -212,25 +2002,51 @@ async fn configure_connection(
                     Status::EnableEncryption => {
                         let EnableTerminalEncryption { key } =
                             control_stream.wait_for_terminal_encryption().await?;
-                        proxy
-                            .server_mut()
-                            .enable_encryption(EncryptionKey::new(key));
+                        match upstream.as_deref_mut() {
+                            // Encrypted by the upstream gateway instead.
+                            Some(upstream) => upstream.set_encryption_key(key),
+                            None => proxy
+                                .server_mut()
+                                .enable_encryption(EncryptionKey::new(key)),
+                        }
                         control_stream.acknowledge_terminal_encryption().await?;
+                        session.record_event("enabled encryption");
                     }
+                    // Compressed by the upstream gateway instead.
+                    Status::EnableCompression(_) if upstream.is_some() => {}
                     Status::EnableCompression(threshold) => {
                         proxy.server_mut().enable_compression(threshold);
+                        session.record_event(format!("enabled compression ({threshold:?})"));
+                    }
+                    Status::AnswerLoginPluginRequest(request) => {
+                        let response = login_plugin_responder
+                            .respond(&request)
+                            .expect("responder handles the channel");
+                        proxy
+                            .inject_to_server(client::login::Packet::LoginPluginResponse(response))
+                            .await?;
+                        session.record_event(format!(
+                            "answered login plugin request on channel {}",
+                            request.channel
+                        ));
+                    }
+                    Status::ForwardPlayerInfo(login_start) => {
+                        let forwarding = forwarding.as_ref().expect("forwarding is enabled");
+                        login_plugin_responder.register(
+                            VELOCITY_PLAYER_INFO_CHANNEL,
+                            forwarding.handler(&login_start)?,
+                        );
+                        session.record_event("forwarding player info to destination server");
                     }
                     Status::FinishLogin => break,
                 }
             }
 
             let (client_connection, server_connection) = proxy.into_parts();
-            do_configuration(
-                client_connection.switch_state().await?,
+            Ok(Some((
+                client_connection.switch_state(control_stream).await?,
                 server_connection.switch_state(),
-            )
-            .await
-            .map(Some)
+            )))
         }
     }
 }
@@ -238,42 +2054,185 @@ async fn configure_connection(
 async fn do_configuration(
     client_connection: SingleQuicPacketIo<side::Server, state::Configuration>,
     server_connection: VanillaPacketIo<side::Client, state::Configuration>,
+    control_stream: &mut control_stream::GatewaySide,
+    session: &Session,
+    proxy_config: &ProxyConfig,
+    clock: &SharedClock,
 ) -> anyhow::Result<PlayConnections> {
     tracing::debug!("Transition to Configuration state");
-    let mut proxy = Proxy::new(client_connection, server_connection);
+    session.record_event("transition to Configuration state");
+    session.set_state("Configuration");
+    let mut proxy = Proxy::new(client_connection, server_connection)
+        .with_packet_flow(Arc::clone(session.packet_flow()));
+    if let Some(memory_budget) = session.memory_budget() {
+        proxy = proxy.with_memory_budget(Arc::clone(memory_budget));
+    }
+    let injector = proxy.injector();
+    let keepalive = proxy_config
+        .configuration_keepalive
+        .as_ref()
+        .map(|config| ConfigurationKeepAlive::new(config.idle(), Arc::clone(clock)));
 
-    proxy
-        .run(
-            |packet| {
-                if let client::configuration::Packet::FinishConfiguration(_) = packet {
-                    ControlFlow::Break(())
-                } else {
-                    ControlFlow::Continue(())
+    enum Status {
+        FinishConfiguration,
+        WithheldKeepAliveReply,
+        StripLightRequest,
+    }
+
+    loop {
+        let run = proxy.run_intercepting(
+            |packet| match packet {
+                client::configuration::Packet::FinishConfiguration(_) => {
+                    Interception::Break(Status::FinishConfiguration)
                 }
+                client::configuration::Packet::KeepAlive(reply)
+                    if keepalive
+                        .as_ref()
+                        .is_some_and(|keepalive| keepalive.is_own_reply(reply)) =>
+                {
+                    Interception::Withhold(Status::WithheldKeepAliveReply)
+                }
+                // Meant for the gateway, not the server.
+                client::configuration::Packet::PluginMessage(message)
+                    if message.channel == STRIP_LIGHT_CHANNEL =>
+                {
+                    Interception::Withhold(Status::StripLightRequest)
+                }
+                _ => Interception::Continue,
             },
-            |_| ControlFlow::Continue(()),
-        )
-        .await?;
+            |_| {
+                if let Some(keepalive) = &keepalive {
+                    keepalive.record_server_packet();
+                }
+                Interception::Continue
+            },
+        );
+        let send_keepalives = async {
+            match &keepalive {
+                Some(keepalive) => keepalive.run(&injector).await,
+                None => future::pending().await,
+            }
+        };
+        let status = select! {
+            status = run => status?,
+            never = send_keepalives => match never {},
+        };
+        match status {
+            Status::FinishConfiguration => break,
+            Status::WithheldKeepAliveReply => {}
+            Status::StripLightRequest if proxy_config.strip_light => {
+                if !session.strips_light() {
+                    session.enable_light_stripping();
+                    session.record_event("light stripping enabled");
+                }
+                injector.inject_to_client(server::configuration::Packet::PluginMessage(
+                    server::configuration::PluginMessage {
+                        channel: STRIP_LIGHT_CHANNEL.to_owned(),
+                        data: Vec::new(),
+                    },
+                ));
+            }
+            Status::StripLightRequest => {
+                session.record_event("light stripping requested, but not enabled");
+            }
+        }
+    }
 
     let (client_connection, server_connection) = proxy.into_parts();
 
-    let new_client_connection =
-        QuicPacketIo::<side::Server>::new(client_connection.connection().clone()).await?;
+    let connection = client_connection.connection().clone();
+    let codec_version = client_connection.codec_version();
+    client_connection.switch_to_play(control_stream).await?;
+    let new_client_connection = play_client_connection(
+        connection,
+        codec_version,
+        control_stream,
+        session,
+        proxy_config,
+    )
+    .await?;
 
     tracing::debug!("Transition to Play state");
+    session.record_event("transition to Play state");
+    session.set_state("Play");
     Ok((new_client_connection, server_connection.switch_state()))
 }
 
+/// Creates the client side of the Play state on `connection`.
+async fn play_client_connection(
+    connection: Connection,
+    codec_version: CodecVersion,
+    control_stream: &control_stream::GatewaySide,
+    session: &Session,
+    proxy_config: &ProxyConfig,
+) -> anyhow::Result<QuicPacketIo<side::Server>> {
+    let mut client_connection = QuicPacketIo::<side::Server>::with_instrumentation(
+        connection,
+        codec_version,
+        session.instrumentation(),
+    )
+    .await?;
+    if proxy_config.remap_reused_entity_ids {
+        client_connection = client_connection.with_entity_id_remapping();
+    }
+    if let Some(coalescing) = &proxy_config.velocity_coalescing {
+        client_connection = client_connection.with_velocity_coalescing(coalescing.window());
+    }
+    if control_stream.redundancy_enabled() {
+        client_connection = client_connection.with_redundancy();
+    }
+    if let Some(preferences) = control_stream.transport_preferences() {
+        if let Some(max_uni_streams) = preferences.max_uni_streams {
+            client_connection
+                .connection()
+                .set_max_concurrent_uni_streams(VarInt::from_u32(max_uni_streams));
+        }
+        if !preferences.datagrams {
+            client_connection = client_connection.without_datagrams();
+        }
+    }
+    if session.strips_light() {
+        client_connection = client_connection.with_light_stripping();
+    }
+    Ok(client_connection)
+}
+
+/// Waits for a client to resume the session, if it may be
+/// resumed. Cancel safe.
+async fn wait_for_resumption(registration: Option<&mut Registration<'_>>) -> Resumption {
+    match registration {
+        Some(registration) => registration.wait().await,
+        None => future::pending().await,
+    }
+}
+
+/// Proxies pings to the destination server, caching its
+/// status response if `cache` is set.
 async fn handle_status(
     server_connection: VanillaPacketIo<side::Client, state::Status>,
     client_connection: SingleQuicPacketIo<side::Server, state::Status>,
+    cache: Option<(&StatusResponder, &Destination, u32)>,
+    peer: SocketAddr,
 ) -> anyhow::Result<()> {
-    Proxy::new(client_connection, server_connection)
+    // Either side usually ends the exchange by closing the connection,
+    // so an error here is expected and only of interest when debugging.
+    if let Err(e) = Proxy::new(client_connection, server_connection)
         .run(
             |_| ControlFlow::<()>::Continue(()),
-            |_| ControlFlow::Continue(()),
+            |server_packet| {
+                if let (
+                    server::status::Packet::StatusResponse(response),
+                    Some((status, destination, protocol_version)),
+                ) = (server_packet, cache)
+                {
+                    status.cache(destination, protocol_version, response);
+                }
+                ControlFlow::Continue(())
+            },
         )
         .await
-        .ok();
+    {
+        tracing::debug!("Status exchange with {peer} ended: {e:#}");
+    }
     Ok(())
 }
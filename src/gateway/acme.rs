@@ -0,0 +1,503 @@
+//! Provisioning of certificates from an ACME certificate authority
+//! (e.g. Let's Encrypt), as an alternative to managing certificate files.
+//!
+//! Implements the parts of RFC 8555 needed to order a certificate for a
+//! set of domains with the `http-01` challenge. Validation requests are
+//! plain HTTP on TCP port 80 and cannot reach a QUIC listener, so they
+//! are answered by a temporary listener that only runs during an order.
+//!
+//! The account key, the certificate and its private key are kept in a
+//! cache directory. The certificate is loaded from there as
+//! `ReloadableCertificates`, so renewals apply to new connections
+//! without a restart.
+
+use crate::gateway::{config::CertificateConfig, tls::ReloadableCertificates};
+use ahash::AHashMap;
+use anyhow::{anyhow, bail, Context};
+use axum::{
+    extract::{Path as UrlPath, State},
+    http::StatusCode,
+    routing::get,
+    Router,
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use reqwest::header;
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use std::{
+    fmt::{self, Display},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpListener;
+
+/// Interval at which pending authorizations and orders are polled.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+/// Maximum number of polls before an order is abandoned.
+const MAX_POLLS: u32 = 60;
+/// Interval at which `AcmeOptions::renew_periodically` checks whether renewal is due.
+const RENEWAL_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Time after which a failed renewal is retried.
+const RENEWAL_RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct AcmeOptions {
+    /// Domains the certificate is for. The first one is the fallback
+    /// for clients that send no or an unknown server name.
+    pub domains: Vec<String>,
+    /// Contact email of the account, for expiry notices.
+    pub contact_email: Option<String>,
+    pub directory_url: String,
+    /// Directory where the account key and certificate are kept.
+    pub cache_dir: PathBuf,
+    /// Address of the temporary HTTP listener answering challenges.
+    /// Port 80 of each domain must reach it.
+    pub challenge_address: SocketAddr,
+    /// Age of the certificate after which it is renewed.
+    pub renew_after: Duration,
+}
+
+impl AcmeOptions {
+    /// Directory of the Let's Encrypt production environment.
+    pub const LETS_ENCRYPT_DIRECTORY: &'static str =
+        "https://acme-v02.api.letsencrypt.org/directory";
+    /// Certificate age after which it is renewed by default: two thirds of
+    /// the validity of Let's Encrypt certificates, as they recommend.
+    pub const DEFAULT_RENEW_AFTER: Duration = Duration::from_secs(60 * 24 * 60 * 60);
+
+    /// Gets the certificate configuration pointing at the cached certificate.
+    pub fn certificate_config(&self) -> CertificateConfig {
+        CertificateConfig {
+            server_names: self.domains.clone(),
+            cert: self.cache_dir.join("cert.pem"),
+            priv_key: self.cache_dir.join("key.pem"),
+        }
+    }
+
+    fn domains_path(&self) -> PathBuf {
+        self.cache_dir.join("domains")
+    }
+
+    /// Whether the cached certificate is missing, for other
+    /// domains, or older than `renew_after`.
+    fn renewal_due(&self) -> bool {
+        let cached_domains = fs_err::read_to_string(self.domains_path()).unwrap_or_default();
+        if cached_domains != self.domains.join("\n") {
+            return true;
+        }
+        let issued = fs_err::metadata(self.certificate_config().cert)
+            .and_then(|metadata| metadata.modified());
+        match issued {
+            Ok(issued) => SystemTime::now()
+                .duration_since(issued)
+                .is_ok_and(|age| age >= self.renew_after),
+            Err(_) => true,
+        }
+    }
+
+    /// Obtains a certificate unless the cache holds one that is not due
+    /// for renewal, returning whether one was obtained.
+    pub async fn ensure_certificate(&self) -> anyhow::Result<bool> {
+        if !self.renewal_due() {
+            return Ok(false);
+        }
+        fs_err::create_dir_all(&self.cache_dir)?;
+        tracing::info!("Ordering certificate for {}", self.domains.join(", "));
+        let mut account = Account::open(self).await?;
+        let (cert_pem, key_pem) = account.order_certificate(self).await?;
+
+        // Written via renames so that a reload never sees a partial file;
+        // `ReloadableCertificates` rejects the pair until both are replaced.
+        let config = self.certificate_config();
+        write_atomically(&config.priv_key, key_pem.as_bytes())?;
+        write_atomically(&config.cert, cert_pem.as_bytes())?;
+        write_atomically(&self.domains_path(), self.domains.join("\n").as_bytes())?;
+        tracing::info!("Obtained certificate for {}", self.domains.join(", "));
+        Ok(true)
+    }
+
+    /// Renews the certificate whenever it is due, reloading `certificates`
+    /// afterwards. Failures are logged and retried later. Never returns.
+    pub async fn renew_periodically(self, certificates: Arc<ReloadableCertificates>) {
+        loop {
+            let delay = match self.ensure_certificate().await {
+                Ok(true) => match certificates.reload() {
+                    Ok(()) => RENEWAL_CHECK_INTERVAL,
+                    Err(e) => {
+                        tracing::error!("Failed to load renewed certificate: {e:#}");
+                        RENEWAL_RETRY_INTERVAL
+                    }
+                },
+                Ok(false) => RENEWAL_CHECK_INTERVAL,
+                Err(e) => {
+                    tracing::error!("Failed to renew certificate: {e:#}");
+                    RENEWAL_RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let temporary = path.with_extension("tmp");
+    fs_err::write(&temporary, contents)?;
+    fs_err::rename(&temporary, path)?;
+    Ok(())
+}
+
+fn base64url(data: impl AsRef<[u8]>) -> String {
+    URL_SAFE_NO_PAD.encode(data)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: Option<String>,
+}
+
+/// Error document returned by the certificate authority.
+#[derive(Debug, Default, Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.detail, self.kind)
+    }
+}
+
+/// An account with the certificate authority, identified by its key.
+struct Account {
+    http: reqwest::Client,
+    directory: Directory,
+    rng: SystemRandom,
+    key: EcdsaKeyPair,
+    /// Account URL, used as the key ID once registered.
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Account {
+    /// Registers the account, or finds the existing one for the
+    /// cached account key.
+    async fn open(options: &AcmeOptions) -> anyhow::Result<Self> {
+        let http = reqwest::Client::new();
+        let directory = http
+            .get(&options.directory_url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .context("failed to fetch ACME directory")?;
+        let rng = SystemRandom::new();
+        let key = load_or_generate_account_key(&options.cache_dir.join("account.key"), &rng)?;
+        let mut account = Self {
+            http,
+            directory,
+            rng,
+            key,
+            kid: None,
+            nonce: None,
+        };
+
+        let contact: Vec<_> = options
+            .contact_email
+            .iter()
+            .map(|email| format!("mailto:{email}"))
+            .collect();
+        let new_account = account.directory.new_account.clone();
+        let response = account
+            .post(
+                &new_account,
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        account.kid = Some(location(&response)?);
+        Ok(account)
+    }
+
+    /// Orders a certificate, answering its challenges,
+    /// and returns the certificate chain and private key in PEM.
+    async fn order_certificate(
+        &mut self,
+        options: &AcmeOptions,
+    ) -> anyhow::Result<(String, String)> {
+        let identifiers: Vec<_> = options
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let response = self
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Order = response.json().await?;
+
+        let tokens = Arc::new(Mutex::new(AHashMap::new()));
+        let challenge_server = serve_challenges(options.challenge_address, Arc::clone(&tokens))
+            .await
+            .context("failed to start ACME challenge listener")?;
+        let result = self.authorize(&order, &tokens).await;
+        challenge_server.abort();
+        result?;
+
+        let mut params = rcgen::CertificateParams::new(options.domains.clone());
+        params.distinguished_name = rcgen::DistinguishedName::new();
+        let certificate = rcgen::Certificate::from_params(params)?;
+        let csr = certificate.serialize_request_der()?;
+        self.post(&order.finalize, Some(json!({ "csr": base64url(csr) })))
+            .await?;
+
+        let order: Order = self
+            .poll(&order_url, |order: &Order| {
+                matches!(order.status.as_str(), "valid" | "invalid")
+            })
+            .await?;
+        let certificate_url = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(url)) => url,
+            (status, _) => bail!("order ended with status {status}"),
+        };
+        let chain = self.post(&certificate_url, None).await?.text().await?;
+        Ok((chain, certificate.serialize_private_key_pem()))
+    }
+
+    /// Completes the `http-01` challenge of each pending authorization.
+    async fn authorize(
+        &mut self,
+        order: &Order,
+        tokens: &Mutex<AHashMap<String, String>>,
+    ) -> anyhow::Result<()> {
+        for url in &order.authorizations {
+            let authorization: Authorization = self.post(url, None).await?.json().await?;
+            if authorization.status == "valid" {
+                continue;
+            }
+            let domain = authorization.identifier.value;
+            let (challenge_url, token) = authorization
+                .challenges
+                .into_iter()
+                .find_map(
+                    |challenge| match (challenge.kind.as_str(), challenge.token) {
+                        ("http-01", Some(token)) => Some((challenge.url, token)),
+                        _ => None,
+                    },
+                )
+                .with_context(|| format!("no http-01 challenge offered for {domain}"))?;
+            let key_authorization = format!("{token}.{}", self.thumbprint());
+            tokens.lock().unwrap().insert(token, key_authorization);
+
+            self.post(&challenge_url, Some(json!({}))).await?;
+            let authorization: Authorization = self
+                .poll(url, |authorization: &Authorization| {
+                    authorization.status != "pending"
+                })
+                .await?;
+            if authorization.status != "valid" {
+                bail!(
+                    "authorization of {domain} ended with status {}",
+                    authorization.status
+                );
+            }
+            tracing::debug!("Authorized ACME order for {domain}");
+        }
+        Ok(())
+    }
+
+    /// Fetches `url` until `done` returns true.
+    async fn poll<T: DeserializeOwned>(
+        &mut self,
+        url: &str,
+        done: impl Fn(&T) -> bool,
+    ) -> anyhow::Result<T> {
+        for _ in 0..MAX_POLLS {
+            let value: T = self.post(url, None).await?.json().await?;
+            if done(&value) {
+                return Ok(value);
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        bail!("timed out waiting for {url}")
+    }
+
+    fn jwk(&self) -> serde_json::Value {
+        // Uncompressed point: 0x04, then the X and Y coordinates.
+        let point = self.key.public_key().as_ref();
+        json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64url(&point[1..33]),
+            "y": base64url(&point[33..65]),
+        })
+    }
+
+    /// Gets the JWK thumbprint (RFC 7638) of the account key.
+    fn thumbprint(&self) -> String {
+        let jwk = self.jwk();
+        // Members in lexicographic order, without whitespace.
+        let canonical = format!(
+            r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+            jwk["x"], jwk["y"]
+        );
+        base64url(ring::digest::digest(
+            &ring::digest::SHA256,
+            canonical.as_bytes(),
+        ))
+    }
+
+    async fn new_nonce(&self) -> anyhow::Result<String> {
+        let response = self.http.head(&self.directory.new_nonce).send().await?;
+        replay_nonce(&response).context("no nonce returned")
+    }
+
+    /// Sends a signed request, with an empty payload (POST-as-GET)
+    /// if `payload` is `None`. Retried once if the nonce is rejected.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<serde_json::Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        let payload = payload.map_or_else(String::new, |payload| base64url(payload.to_string()));
+        let mut retried = false;
+        loop {
+            let nonce = match self.nonce.take() {
+                Some(nonce) => nonce,
+                None => self.new_nonce().await?,
+            };
+            let mut protected = json!({ "alg": "ES256", "nonce": nonce, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk(),
+            }
+            let protected = base64url(protected.to_string());
+            let signature = self
+                .key
+                .sign(&self.rng, format!("{protected}.{payload}").as_bytes())
+                .map_err(|_| anyhow!("failed to sign ACME request"))?;
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": base64url(signature),
+            });
+
+            let response = self
+                .http
+                .post(url)
+                .header(header::CONTENT_TYPE, "application/jose+json")
+                .body(body.to_string())
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let problem: Problem = response.json().await.unwrap_or_default();
+            if problem.kind == "urn:ietf:params:acme:error:badNonce" && !retried {
+                retried = true;
+                continue;
+            }
+            bail!("ACME request to {url} failed: {problem}");
+        }
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")
+        .and_then(|nonce| nonce.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn location(response: &reqwest::Response) -> anyhow::Result<String> {
+    response
+        .headers()
+        .get(header::LOCATION)
+        .and_then(|location| location.to_str().ok())
+        .map(str::to_owned)
+        .context("no location returned")
+}
+
+fn load_or_generate_account_key(path: &Path, rng: &SystemRandom) -> anyhow::Result<EcdsaKeyPair> {
+    let pkcs8 = match fs_err::read(path) {
+        Ok(pkcs8) => pkcs8,
+        Err(_) => {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, rng)
+                .map_err(|_| anyhow!("failed to generate ACME account key"))?;
+            write_atomically(path, pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        }
+    };
+    EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, rng)
+        .map_err(|e| anyhow!("invalid ACME account key {}: {e}", path.display()))
+}
+
+/// Serves the key authorizations of `http-01` challenges until aborted.
+async fn serve_challenges(
+    address: SocketAddr,
+    tokens: Arc<Mutex<AHashMap<String, String>>>,
+) -> anyhow::Result<tokio::task::JoinHandle<()>> {
+    let listener = TcpListener::bind(address).await?;
+    let app = Router::new()
+        .route("/.well-known/acme-challenge/:token", get(challenge))
+        .with_state(tokens);
+    Ok(tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("ACME challenge listener failed: {e}");
+        }
+    }))
+}
+
+async fn challenge(
+    State(tokens): State<Arc<Mutex<AHashMap<String, String>>>>,
+    UrlPath(token): UrlPath<String>,
+) -> Result<String, StatusCode> {
+    tokens
+        .lock()
+        .unwrap()
+        .get(&token)
+        .cloned()
+        .ok_or(StatusCode::NOT_FOUND)
+}
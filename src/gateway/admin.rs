@@ -0,0 +1,243 @@
+//! Admin HTTP API for operators.
+//!
+//! `GET /sessions` lists the active sessions with their identity, client
+//! address, destination, protocol state, uptime and byte counts. Pass
+//! `?include_addresses=true` to include unmasked addresses.
+//!
+//! `DELETE /sessions/:id` forcibly disconnects a session. Pass `?reason=`
+//! to set the reason the client is given (default `disconnected by an
+//! operator`).
+//!
+//! `GET /sessions/:id/diagnostics` exports a redacted diagnostics bundle
+//! for one session. Pass `?include_addresses=true` to include unmasked
+//! client and destination addresses.
+//!
+//! `GET /metrics` exposes the aggregated client metrics, the latency
+//! added by the gateway to each packet type and the packets and bytes
+//! proxied by each active session in the Prometheus format.
+//!
+//! `GET /usage` reports the gateway's usage totals across restarts
+//! (see the `usage` module), which are also included in `/metrics`.
+//!
+//! `GET /packet-log` and `PUT /packet-log` get and replace the packet log filter.
+//!
+//! `GET /sessions/:id/observe` attaches a read-only observer to a session,
+//! streaming a summary of its packet flow (packet names, counts, sizes and
+//! stream allocations; never contents) as server-sent events. Pass
+//! `?interval_millis=` to change the summary interval (default 1000).
+//!
+//! `GET /bans` lists the source IPs banned after repeated authentication
+//! failures (see the `ban` module), and `DELETE /bans/:address` lifts a ban.
+//!
+//! `POST /policy/evaluate` checks a hypothetical connection against the
+//! policies without making it or counting it towards quotas, reporting the
+//! outcome of each policy that applies. The body is a JSON object with
+//! `identity`, `destination` and optionally `listener` (default `default`).
+
+use crate::{
+    gateway::{
+        ban::{Ban, Bans},
+        policy::{PolicyEvaluation, PolicyEvaluationRequest},
+        session::{Diagnostics, SessionId, SessionSummary},
+        usage::UsageSnapshot,
+        Shared,
+    },
+    packet_log,
+    packet_log::PacketLogFilter,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, StatusCode},
+    response::{
+        sse::{self, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post},
+    Json, Router,
+};
+use futures::{stream, Stream};
+use serde::Deserialize;
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{net::TcpListener, time::interval};
+
+/// Bounds for the observer summary interval.
+const MIN_OBSERVE_INTERVAL: Duration = Duration::from_millis(100);
+const MAX_OBSERVE_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(super) async fn serve(address: SocketAddr, shared: Arc<Shared>) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/sessions", get(sessions))
+        .route("/sessions/:id", delete(disconnect))
+        .route("/sessions/:id/diagnostics", get(diagnostics))
+        .route("/sessions/:id/observe", get(observe))
+        .route("/metrics", get(metrics))
+        .route("/usage", get(usage))
+        .route("/policy/evaluate", post(evaluate_policy))
+        .route("/bans", get(bans))
+        .route("/bans/:address", delete(unban))
+        .route(
+            "/packet-log",
+            get(packet_log_filter).put(set_packet_log_filter),
+        )
+        .with_state(shared);
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("Serving admin API on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct DiagnosticsParams {
+    #[serde(default)]
+    include_addresses: bool,
+}
+
+async fn sessions(
+    State(shared): State<Arc<Shared>>,
+    Query(params): Query<DiagnosticsParams>,
+) -> Json<Vec<SessionSummary>> {
+    let mut sessions: Vec<_> = shared
+        .sessions
+        .list()
+        .iter()
+        .map(|session| session.summary(params.include_addresses))
+        .collect();
+    sessions.sort_by_key(|session| session.started_at_millis);
+    Json(sessions)
+}
+
+#[derive(Debug, Deserialize)]
+struct DisconnectParams {
+    reason: Option<String>,
+}
+
+async fn disconnect(
+    State(shared): State<Arc<Shared>>,
+    Path(id): Path<SessionId>,
+    Query(params): Query<DisconnectParams>,
+) -> StatusCode {
+    let Some(session) = shared.sessions.get(id) else {
+        return StatusCode::NOT_FOUND;
+    };
+    session.disconnect(
+        params
+            .reason
+            .as_deref()
+            .unwrap_or("disconnected by an operator"),
+    );
+    StatusCode::NO_CONTENT
+}
+
+async fn diagnostics(
+    State(shared): State<Arc<Shared>>,
+    Path(id): Path<SessionId>,
+    Query(params): Query<DiagnosticsParams>,
+) -> Result<Json<Diagnostics>, StatusCode> {
+    let session = shared.sessions.get(id).ok_or(StatusCode::NOT_FOUND)?;
+    let diagnostics = session.diagnostics(&shared.config, params.include_addresses);
+    tracing::info!("Exported diagnostics for session {id}");
+    Ok(Json(diagnostics))
+}
+
+#[derive(Debug, Deserialize)]
+struct ObserveParams {
+    interval_millis: Option<u64>,
+}
+
+async fn observe(
+    State(shared): State<Arc<Shared>>,
+    Path(id): Path<SessionId>,
+    Query(params): Query<ObserveParams>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, StatusCode> {
+    let session = shared.sessions.get(id).ok_or(StatusCode::NOT_FOUND)?;
+    let period = params
+        .interval_millis
+        .map_or(Duration::from_secs(1), Duration::from_millis)
+        .clamp(MIN_OBSERVE_INTERVAL, MAX_OBSERVE_INTERVAL);
+    tracing::info!("Observer attached to session {id}");
+
+    // Holding only a weak reference ends the stream when the session ends.
+    let weak_session = Arc::downgrade(&session);
+    let observer = session.observe();
+    drop(session);
+    let mut interval = interval(period);
+    // The first tick completes immediately.
+    interval.tick().await;
+    let events = stream::unfold(
+        (observer, interval, weak_session),
+        |(mut observer, mut interval, weak_session)| async move {
+            interval.tick().await;
+            weak_session.upgrade()?;
+            let event = sse::Event::default()
+                .json_data(observer.summary())
+                .expect("summary is serializable");
+            Some((Ok(event), (observer, interval, weak_session)))
+        },
+    );
+    Ok(Sse::new(events).keep_alive(sse::KeepAlive::default()))
+}
+
+async fn metrics(State(shared): State<Arc<Shared>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        shared.render_metrics(),
+    )
+}
+
+async fn usage(State(shared): State<Arc<Shared>>) -> Json<UsageSnapshot> {
+    Json(shared.usage.snapshot(shared.sessions.active_bytes()))
+}
+
+async fn evaluate_policy(
+    State(shared): State<Arc<Shared>>,
+    Json(request): Json<PolicyEvaluationRequest>,
+) -> Result<Json<PolicyEvaluation>, (StatusCode, String)> {
+    let identity = shared
+        .identities
+        .iter()
+        .find(|identity| identity.name == request.identity)
+        .ok_or_else(|| {
+            (
+                StatusCode::NOT_FOUND,
+                format!("unknown identity {}", request.identity),
+            )
+        })?;
+    let evaluation = shared
+        .policies
+        .evaluate(
+            &request.listener,
+            identity.tenant.as_deref(),
+            &identity.name,
+            request.destination,
+        )
+        .await;
+    Ok(Json(evaluation))
+}
+
+async fn bans(State(shared): State<Arc<Shared>>) -> Json<Vec<Ban>> {
+    Json(shared.bans.as_ref().map(Bans::bans).unwrap_or_default())
+}
+
+async fn unban(State(shared): State<Arc<Shared>>, Path(address): Path<IpAddr>) -> StatusCode {
+    match &shared.bans {
+        Some(bans) if bans.unban(address) => {
+            tracing::info!("Lifted the ban of {address}");
+            StatusCode::NO_CONTENT
+        }
+        _ => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn packet_log_filter() -> Json<PacketLogFilter> {
+    Json(packet_log::filter())
+}
+
+async fn set_packet_log_filter(Json(filter): Json<PacketLogFilter>) -> StatusCode {
+    packet_log::set_filter(filter);
+    StatusCode::NO_CONTENT
+}
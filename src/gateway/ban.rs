@@ -0,0 +1,290 @@
+//! Banning of source IPs, fail2ban style.
+//!
+//! An address with `max_auth_failures` failed authentication attempts
+//! within the window is banned for `ban_secs`. Addresses and networks in
+//! the blocklist file are refused for as long as they are listed. Both are
+//! checked when a connection arrives, so refused connections are dropped
+//! before their QUIC handshake completes.
+//!
+//! Unlike the rate limiter, which only gates handshakes while failures are
+//! within its window, a ban refuses all connections until it expires.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    gateway::{
+        config::{prefix_matches, BanConfig},
+        notifier::Alert,
+    },
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+/// Minimum time between checks of whether the blocklist file changed.
+const BLOCKLIST_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Returned when a connection is refused because its address is banned.
+#[derive(Debug, thiserror::Error)]
+pub enum Refused {
+    #[error("{address} is on the blocklist")]
+    Blocklisted { address: IpAddr },
+    #[error(
+        "{address} is banned after repeated authentication failures; retrying in {}s",
+        retry_in.as_secs().max(1)
+    )]
+    Banned { address: IpAddr, retry_in: Duration },
+}
+
+/// A banned address, as listed by the admin API.
+#[derive(Debug, Clone, Serialize)]
+pub struct Ban {
+    pub address: IpAddr,
+    /// Time left until the ban expires.
+    pub remaining_secs: u64,
+}
+
+pub(crate) struct Bans {
+    config: BanConfig,
+    /// Times of recent authentication failures, per address.
+    failures: Mutex<AHashMap<IpAddr, VecDeque<Instant>>>,
+    /// Time each banned address is banned until.
+    banned: Mutex<AHashMap<IpAddr, Instant>>,
+    blocklist: Option<Blocklist>,
+    clock: SharedClock,
+}
+
+impl Bans {
+    /// Fails if the blocklist file cannot be loaded.
+    pub fn new(config: &BanConfig, clock: SharedClock) -> anyhow::Result<Self> {
+        let blocklist = config
+            .blocklist_file
+            .as_ref()
+            .map(|path| Blocklist::load(path.clone(), clock.now()))
+            .transpose()?;
+        Ok(Self {
+            config: config.clone(),
+            failures: Mutex::default(),
+            banned: Mutex::default(),
+            blocklist,
+            clock,
+        })
+    }
+
+    /// Checks whether connections from `address` may be accepted.
+    pub fn check(&self, address: IpAddr) -> Result<(), Refused> {
+        let address = address.to_canonical();
+        let now = self.clock.now();
+        if let Some(blocklist) = &self.blocklist {
+            if blocklist.contains(address, now) {
+                return Err(Refused::Blocklisted { address });
+            }
+        }
+        let mut banned = self.banned.lock().unwrap();
+        match banned.get(&address) {
+            Some(&until) if now < until => Err(Refused::Banned {
+                address,
+                retry_in: until - now,
+            }),
+            Some(_) => {
+                tracing::info!("Ban of {address} expired");
+                banned.remove(&address);
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Records a failed authentication from `address`.
+    /// Returns an alert if the address is banned because of it.
+    pub fn record_auth_failure(&self, address: IpAddr) -> Option<Alert> {
+        if self.config.max_auth_failures == 0 {
+            return None;
+        }
+        let address = address.to_canonical();
+        let now = self.clock.now();
+        let window = self.config.window();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, times| {
+            times.retain(|&time| now.duration_since(time) < window);
+            !times.is_empty()
+        });
+        let times = failures.entry(address).or_default();
+        times.push_back(now);
+        if times.len() < self.config.max_auth_failures {
+            return None;
+        }
+        let count = times.len();
+        failures.remove(&address);
+        drop(failures);
+
+        self.banned
+            .lock()
+            .unwrap()
+            .insert(address, now + self.config.ban_duration());
+        Some(Alert::AddressBanned {
+            address,
+            failures: count,
+            ban_secs: self.config.ban_secs,
+        })
+    }
+
+    /// Gets the current bans.
+    pub fn bans(&self) -> Vec<Ban> {
+        let now = self.clock.now();
+        self.banned
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, &until)| now < until)
+            .map(|(&address, &until)| Ban {
+                address,
+                remaining_secs: (until - now).as_secs().max(1),
+            })
+            .collect()
+    }
+
+    /// Lifts the ban of `address`. Returns whether it was banned.
+    pub fn unban(&self, address: IpAddr) -> bool {
+        self.banned
+            .lock()
+            .unwrap()
+            .remove(&address.to_canonical())
+            .is_some()
+    }
+}
+
+/// An address or network in the blocklist.
+#[derive(Debug, Clone, Copy)]
+struct BlockedNetwork {
+    address: IpAddr,
+    prefix_len: u8,
+}
+
+impl BlockedNetwork {
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let address: IpAddr = address
+            .parse()
+            .with_context(|| format!("invalid address '{s}'"))?;
+        let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse()
+                .with_context(|| format!("invalid prefix length in '{s}'"))?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            bail!("prefix length of '{s}' exceeds {max_prefix_len}");
+        }
+        Ok(Self {
+            address,
+            prefix_len,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The blocklist file, reloaded when it changes.
+struct Blocklist {
+    path: PathBuf,
+    entries: RwLock<Vec<BlockedNetwork>>,
+    /// When the file was last checked for changes,
+    /// and its modification time then.
+    checked: Mutex<(Instant, Option<SystemTime>)>,
+}
+
+impl Blocklist {
+    fn load(path: PathBuf, now: Instant) -> anyhow::Result<Self> {
+        let modified = modified(&path);
+        let entries = parse_blocklist(&path)?;
+        tracing::info!(
+            "Loaded {} blocklist entries from {}",
+            entries.len(),
+            path.display()
+        );
+        Ok(Self {
+            path,
+            entries: RwLock::new(entries),
+            checked: Mutex::new((now, modified)),
+        })
+    }
+
+    fn contains(&self, address: IpAddr, now: Instant) -> bool {
+        self.reload_if_modified(now);
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .any(|network| network.contains(address))
+    }
+
+    /// Reloads the file if it changed, keeping the current
+    /// entries if it has become invalid.
+    fn reload_if_modified(&self, now: Instant) {
+        let mut checked = self.checked.lock().unwrap();
+        if now.duration_since(checked.0) < BLOCKLIST_CHECK_INTERVAL {
+            return;
+        }
+        let modified = modified(&self.path);
+        let changed = modified != checked.1;
+        *checked = (now, modified);
+        drop(checked);
+        if !changed {
+            return;
+        }
+        match parse_blocklist(&self.path) {
+            Ok(entries) => {
+                tracing::info!(
+                    "Reloaded {} blocklist entries from {}",
+                    entries.len(),
+                    self.path.display()
+                );
+                *self.entries.write().unwrap() = entries;
+            }
+            Err(e) => tracing::warn!("Keeping the previous blocklist: {e:#}"),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs_err::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+fn parse_blocklist(path: &Path) -> anyhow::Result<Vec<BlockedNetwork>> {
+    let contents = fs_err::read_to_string(path).context("failed to read the blocklist")?;
+    contents
+        .lines()
+        .enumerate()
+        .filter_map(|(i, line)| {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            (!line.is_empty()).then_some((i, line))
+        })
+        .map(|(i, line)| {
+            BlockedNetwork::parse(line)
+                .with_context(|| format!("{} line {}", path.display(), i + 1))
+        })
+        .collect()
+}
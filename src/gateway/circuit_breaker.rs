@@ -0,0 +1,130 @@
+//! Per-destination circuit breakers, so that clients fail fast while a
+//! destination server is down instead of each waiting for a TCP timeout.
+//!
+//! After `failure_threshold` consecutive failed connection attempts to a
+//! destination, its breaker opens and connections to it are rejected for
+//! `open_secs`. Once that has elapsed, a single connection is let through
+//! as a probe: if it succeeds, the breaker closes again; if it fails,
+//! the breaker reopens.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    gateway::config::CircuitBreakerConfig,
+};
+use ahash::AHashMap;
+use std::{net::SocketAddr, sync::Mutex, time::Duration};
+
+/// Returned when a connection is rejected because the destination's breaker is open.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "destination {destination} is unavailable after repeated connection failures; \
+     retrying in {}s",
+    retry_in.as_secs().max(1)
+)]
+pub struct CircuitOpen {
+    pub destination: SocketAddr,
+    pub retry_in: Duration,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    Closed {
+        consecutive_failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A probe connection is in progress. If it never reports back,
+    /// another probe is allowed after `open_duration`.
+    Probing {
+        since: Instant,
+    },
+}
+
+pub(crate) struct CircuitBreakers {
+    failure_threshold: u32,
+    open_duration: Duration,
+    destinations: Mutex<AHashMap<SocketAddr, BreakerState>>,
+    clock: SharedClock,
+}
+
+impl CircuitBreakers {
+    pub fn new(config: &CircuitBreakerConfig, clock: SharedClock) -> Self {
+        Self {
+            failure_threshold: config.failure_threshold,
+            open_duration: Duration::from_secs(config.open_secs),
+            destinations: Mutex::new(AHashMap::new()),
+            clock,
+        }
+    }
+
+    /// Checks whether a connection to the destination may be attempted.
+    /// Its outcome must then be reported with `record_success` or `record_failure`.
+    pub fn check(&self, destination: SocketAddr) -> Result<(), CircuitOpen> {
+        if self.failure_threshold == 0 {
+            return Ok(());
+        }
+        let now = self.clock.now();
+        let mut destinations = self.destinations.lock().unwrap();
+        let Some(state) = destinations.get_mut(&destination) else {
+            return Ok(());
+        };
+        match *state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if now < until => Err(CircuitOpen {
+                destination,
+                retry_in: until - now,
+            }),
+            BreakerState::Probing { since } if now.duration_since(since) < self.open_duration => {
+                Err(CircuitOpen {
+                    destination,
+                    retry_in: self.open_duration - now.duration_since(since),
+                })
+            }
+            BreakerState::Open { .. } | BreakerState::Probing { .. } => {
+                tracing::info!("Probing destination {destination} for recovery");
+                *state = BreakerState::Probing { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub fn record_success(&self, destination: SocketAddr) {
+        if let Some(BreakerState::Probing { .. }) =
+            self.destinations.lock().unwrap().remove(&destination)
+        {
+            tracing::info!("Destination {destination} recovered");
+        }
+    }
+
+    /// Records a failed connection attempt. Returns whether the breaker opened.
+    pub fn record_failure(&self, destination: SocketAddr) -> bool {
+        if self.failure_threshold == 0 {
+            return false;
+        }
+        let mut destinations = self.destinations.lock().unwrap();
+        let state = destinations
+            .entry(destination)
+            .or_insert(BreakerState::Closed {
+                consecutive_failures: 0,
+            });
+        let consecutive_failures = match *state {
+            BreakerState::Closed {
+                consecutive_failures,
+            } => consecutive_failures + 1,
+            // A failed probe reopens the breaker immediately.
+            BreakerState::Open { .. } | BreakerState::Probing { .. } => self.failure_threshold,
+        };
+        if consecutive_failures >= self.failure_threshold {
+            *state = BreakerState::Open {
+                until: self.clock.now() + self.open_duration,
+            };
+            true
+        } else {
+            *state = BreakerState::Closed {
+                consecutive_failures,
+            };
+            false
+        }
+    }
+}
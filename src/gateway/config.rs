@@ -0,0 +1,1625 @@
+//! Gateway configuration, loaded from a TOML file.
+
+use crate::{affinity::AffinityToken, packet_log::PacketLogFilter, tcp_options::TcpOptions};
+use anyhow::{bail, Context};
+use schemars::{gen::SchemaGenerator, schema::Schema, JsonSchema};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+mod validation;
+
+/// Top-level gateway configuration.
+///
+/// Every field has a default, so an empty file (or no file at all)
+/// yields a working configuration.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// Additional listeners, besides the one configured on the command line.
+    pub listeners: Vec<ListenerConfig>,
+    /// Additional identities that clients may authenticate as, besides
+    /// the default identity using the key given on the command line.
+    pub identities: Vec<IdentityConfig>,
+    /// Customers served by the gateway, each with their own identities and policy.
+    pub tenants: Vec<TenantConfig>,
+    /// Policy applying to all connections.
+    pub policy: PolicyConfig,
+    /// Destinations in loopback, private and link-local ranges are refused
+    /// unless allowed here, so that a leaked authentication key cannot be
+    /// used to probe the network of the gateway's host.
+    pub private_destinations: PrivateDestinationsConfig,
+    pub webhooks: WebhookConfig,
+    pub admin: AdminConfig,
+    /// Initial packet log filter. Can be changed at runtime through the admin API.
+    pub packet_log: PacketLogFilter,
+    pub proxy: ProxyConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub affinity: AffinityConfig,
+    pub strict: StrictConfig,
+    pub usage: UsageConfig,
+    /// Serves the TCP side of dual transport measurement, for clients
+    /// that enable it. Disabled if unset.
+    pub measurement: Option<MeasurementConfig>,
+    /// Per source IP limits on new connections, handshakes and failed
+    /// authentication attempts. Disabled if unset.
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Validates client addresses with a QUIC Retry before any handshake
+    /// crypto, so that floods of Initial packets with spoofed sources cost
+    /// the gateway a stateless reply each rather than a handshake, and
+    /// per-IP limits apply to real addresses. Costs legitimate clients a
+    /// round trip when connecting. Disabled if unset.
+    pub retry: Option<RetryConfig>,
+    /// Limits on concurrent sessions, in total and per source IP.
+    pub session_limits: SessionLimitsConfig,
+    /// Time limits on each phase of a connection.
+    pub timeouts: TimeoutsConfig,
+    /// Bounds on the transport settings clients may ask for.
+    pub transport: TransportLimitsConfig,
+    /// Keeps the connection to the destination server open for a while
+    /// when a client's connection drops during the Play state, so that
+    /// clients that asked for it can reconnect and continue the session
+    /// without being kicked from the server. Disabled if unset.
+    pub resumption: Option<ResumptionConfig>,
+    /// Chooses the destination server by the host name in the game's
+    /// handshake rather than by the destination the client names.
+    /// Disabled if unset.
+    pub virtual_hosts: Option<VirtualHostsConfig>,
+    /// Destination servers to connect to instead
+    /// when others cannot be connected to.
+    pub fallbacks: Vec<FallbackConfig>,
+    /// Other gateways that destinations are proxied through
+    /// over QUIC, rather than connected to over TCP.
+    pub upstreams: Vec<UpstreamConfig>,
+    /// Accepts connections from unmodified games over plain TCP,
+    /// routed by virtual host. Disabled if unset.
+    pub vanilla_listener: Option<VanillaListenerConfig>,
+    /// Answers server list pings from a cache of the destination server's
+    /// status, or with a configured MOTD, rather than connecting to the
+    /// destination server for every ping. Disabled if unset.
+    pub status: Option<StatusConfig>,
+    /// Limits the bandwidth of each session or identity. Disabled if unset.
+    pub bandwidth_quota: Option<BandwidthQuotaConfig>,
+    /// Closes connections that make the gateway buffer too many bytes,
+    /// e.g. a client that floods it while the destination server reads
+    /// slowly, or the reverse. Disabled if unset.
+    pub memory_budget: Option<MemoryBudgetConfig>,
+    /// Limits the rate of packets each client sends in the Play state,
+    /// against modified clients spamming packets. Disabled if unset.
+    pub flood_protection: Option<FloodProtectionConfig>,
+    /// Writes connection events as JSON lines to a file,
+    /// for log pipelines. Disabled if unset.
+    pub event_log: Option<EventLogConfig>,
+    /// POSTs session lifecycle events (connects, authentication failures,
+    /// disconnects) as JSON to webhooks. Disabled if unset.
+    pub session_webhooks: Option<SessionWebhookConfig>,
+    /// Temporarily bans source IPs with repeated authentication failures,
+    /// and refuses connections from a static blocklist. Disabled if unset.
+    pub ban: Option<BanConfig>,
+    /// Refuses to start if any authentication key is plaintext
+    /// rather than an Argon2 hash.
+    pub require_hashed_keys: bool,
+    pub shutdown: ShutdownConfig,
+    /// Periodically reloads the certificates of all listeners from disk
+    /// if their files changed, e.g. after a renewal. Disabled if unset;
+    /// on Unix, SIGHUP reloads them regardless.
+    pub certificate_reload: Option<CertificateReloadConfig>,
+    /// Requires clients to present a TLS certificate signed by a CA,
+    /// verified during the QUIC handshake. Disabled if unset.
+    pub client_certificates: Option<ClientCertificateConfig>,
+    /// Accepts short-lived tokens, issued with `issue-token`, as
+    /// authentication keys. Disabled if unset.
+    pub tokens: Option<TokenConfig>,
+}
+
+impl GatewayConfig {
+    /// Loads the configuration from a TOML file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs_err::read_to_string(path)?;
+        Self::parse(&contents).with_context(|| format!("invalid gateway config {}", path.display()))
+    }
+
+    /// Parses the configuration from TOML.
+    ///
+    /// Unlike plain deserialization, keys that match no option are errors
+    /// (with a suggestion of the option likely meant), rather than
+    /// being silently ignored.
+    pub fn parse(contents: &str) -> anyhow::Result<Self> {
+        let mut unknown_keys = Vec::new();
+        let config = serde_ignored::deserialize(toml::Deserializer::new(contents), |path| {
+            unknown_keys.push(validation::KeyPath::from(&path))
+        })?;
+        validation::reject_unknown_keys(&unknown_keys)?;
+        Ok(config)
+    }
+
+    /// Gets the JSON schema of the TOML configuration,
+    /// for validation and completion in editors.
+    pub fn schema() -> serde_json::Value {
+        serde_json::to_value(validation::root_schema()).expect("schema is serializable")
+    }
+
+    /// Returns a copy of the configuration with secrets removed,
+    /// safe to include in diagnostics.
+    pub fn redacted(&self) -> Self {
+        let mut config = self.clone();
+        for url in config.webhooks.urls.iter_mut().chain(
+            config
+                .session_webhooks
+                .iter_mut()
+                .flat_map(|webhooks| &mut webhooks.urls),
+        ) {
+            *url = "<redacted>".to_owned();
+        }
+        for identity in config.identities.iter_mut().chain(
+            config
+                .tenants
+                .iter_mut()
+                .flat_map(|tenant| &mut tenant.identities),
+        ) {
+            identity.auth_key = "<redacted>".to_owned();
+        }
+        for upstream in &mut config.upstreams {
+            upstream.authentication_key = "<redacted>".to_owned();
+        }
+        config
+    }
+}
+
+/// A QUIC listener with its own certificates.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Name identifying the listener in logs and diagnostics.
+    pub name: String,
+    /// Address or list of addresses to listen on, e.g.
+    /// `["0.0.0.0:6666", "[::]:6666"]` for IPv4 and IPv6.
+    pub listen: ListenAddresses,
+    /// Certificates to present, selected by the server name (SNI)
+    /// requested by the client. The first one is the fallback.
+    pub certificates: Vec<CertificateConfig>,
+    /// Policy applying to connections accepted on this listener.
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// One or several socket addresses a listener listens on.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum ListenAddresses {
+    One(SocketAddr),
+    Many(Vec<SocketAddr>),
+}
+
+impl ListenAddresses {
+    pub fn addresses(&self) -> &[SocketAddr] {
+        match self {
+            Self::One(address) => std::slice::from_ref(address),
+            Self::Many(addresses) => addresses,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct CertificateConfig {
+    /// Server names this certificate is presented for.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+    /// Certificate chain, in PEM or DER (`.der`) format.
+    pub cert: PathBuf,
+    /// Private key, in PEM or DER (`.der`) format.
+    pub priv_key: PathBuf,
+}
+
+/// A named authentication key with its own policy.
+///
+/// Clients authenticate as the identity by sending `<name>:<auth_key>`
+/// as their key; a key without a known name authenticates as the default
+/// identity.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct IdentityConfig {
+    pub name: String,
+    /// Authentication key, either plaintext or an Argon2 hash.
+    pub auth_key: String,
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+/// A customer of the gateway. Connections authenticated as one of its
+/// identities are subject to its policy, and the metrics they report
+/// are labelled with its name.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct TenantConfig {
+    pub name: String,
+    /// Identities of the tenant. Their names only need to be unique
+    /// within the tenant; see `TenantConfig::identity_name`.
+    pub identities: Vec<IdentityConfig>,
+    /// Policy applying to all connections of the tenant.
+    #[serde(default)]
+    pub policy: PolicyConfig,
+}
+
+impl TenantConfig {
+    /// Gets the gateway-wide name of one of the tenant's
+    /// identities, as `<tenant>/<identity>`.
+    pub fn identity_name(&self, identity: &IdentityConfig) -> String {
+        format!("{}/{}", self.name, identity.name)
+    }
+}
+
+/// Restrictions on the connections a scope may make.
+/// Unset fields impose no restriction.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct PolicyConfig {
+    /// Destination servers that may be connected to.
+    pub allowed_destinations: Option<Vec<DestinationRule>>,
+    /// Maximum number of concurrent sessions.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of new sessions per minute.
+    pub max_connections_per_minute: Option<usize>,
+}
+
+/// Exceptions to the refusal of private destinations. See `policy`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct PrivateDestinationsConfig {
+    /// Allows all private destinations, e.g. for a gateway in
+    /// front of servers on its own network.
+    pub allow_all: bool,
+    /// Private addresses and networks that may be connected to, written
+    /// like allowlist rules, e.g. `10.0.0.5:25565` or `10.0.1.0/24`.
+    /// Host name rules never match.
+    pub allowed: Vec<DestinationRule>,
+}
+
+/// Options for how packets are proxied.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct ProxyConfig {
+    /// Gives entities whose ID the server reuses shortly after removing the
+    /// previous entity their own streams and sequences, so that packets still
+    /// in flight for the old entity cannot delay or supersede the new one's.
+    pub remap_reused_entity_ids: bool,
+    /// Rewrites a handshake addressed to a loopback host (i.e. the local
+    /// listener of the client) to the destination server's IP and port, for
+    /// servers that validate the address. Clients that know the address the
+    /// player entered should rewrite the handshake themselves instead.
+    pub rewrite_handshake_address: bool,
+    /// Sends a PROXY protocol v2 header at the start of the connection to
+    /// the destination server, so that it sees the player's address instead
+    /// of the gateway's. The server must expect the header, since it
+    /// otherwise fails to parse the handshake.
+    pub proxy_protocol: bool,
+    /// Local addresses to make the TCP connections to destination servers
+    /// from, e.g. on hosts with several addresses, at most one per address
+    /// family. A destination is dialed from the address of its family, and
+    /// never if there is none. The system chooses if empty.
+    pub outbound_bind: Vec<IpAddr>,
+    /// Socket options of the TCP connections to destination servers.
+    pub destination_tcp: TcpOptions,
+    /// Strips light data from chunks sent to clients whose mod asks for it
+    /// on the `quic-proxy:strip_light` plugin channel, because they
+    /// recompute lighting themselves.
+    pub strip_light: bool,
+    /// Combines packets written to the destination server into fewer
+    /// writes. Disabled if unset.
+    pub write_batching: Option<WriteBatchingConfig>,
+    /// Sends at most one velocity update per entity and window, the
+    /// latest one, reducing datagrams for projectiles and explosions.
+    /// Disabled if unset.
+    pub velocity_coalescing: Option<VelocityCoalescingConfig>,
+    /// Tells the player why when the destination server closes the
+    /// connection in the Play state, rather than dropping the connection
+    /// with a generic error. Disabled if unset.
+    pub destination_close: Option<DestinationCloseConfig>,
+    /// Sends the player keepalives in the Configuration state while the
+    /// destination server sends nothing, so that the game does not time
+    /// out while a server (e.g. a heavily modded one) is slow to send its
+    /// configuration. Disabled if unset.
+    pub configuration_keepalive: Option<ConfigurationKeepAliveConfig>,
+    /// Detects Play sessions in which one direction stops delivering
+    /// packets while the other stays active, e.g. because a stream is
+    /// wedged, and recovers or disconnects the player instead of leaving
+    /// them in a frozen world. Disabled if unset.
+    pub stall_watchdog: Option<StallWatchdogConfig>,
+    /// Answers Velocity modern forwarding requests of destination servers
+    /// with the player's address, name and UUID. The gateway does not
+    /// authenticate players, so the name and UUID are the ones the game
+    /// claims. Disabled if unset.
+    pub velocity_forwarding: Option<VelocityForwardingConfig>,
+}
+
+/// Write combining on the TCP connection to the destination server.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct WriteBatchingConfig {
+    /// Maximum time a packet is held back waiting for further packets.
+    pub max_delay_micros: u64,
+    /// Size of buffered packets at which they are written immediately.
+    pub max_bytes: usize,
+}
+
+impl WriteBatchingConfig {
+    pub fn max_delay(&self) -> Duration {
+        Duration::from_micros(self.max_delay_micros)
+    }
+}
+
+impl Default for WriteBatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_delay_micros: 1000,
+            max_bytes: 16 * 1024,
+        }
+    }
+}
+
+/// Coalescing of velocity updates sent to the client.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct VelocityCoalescingConfig {
+    /// Minimum time between velocity updates of an entity.
+    pub window_millis: u64,
+}
+
+impl VelocityCoalescingConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_millis(self.window_millis)
+    }
+}
+
+impl Default for VelocityCoalescingConfig {
+    fn default() -> Self {
+        // One game tick.
+        Self { window_millis: 50 }
+    }
+}
+
+/// Keepalives sent by the gateway in the Configuration state.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct ConfigurationKeepAliveConfig {
+    /// Time without packets from the destination server after which a
+    /// keepalive is sent, and then again while it stays silent. Must be
+    /// well below the game's 30-second read timeout.
+    pub idle_secs: u64,
+}
+
+impl ConfigurationKeepAliveConfig {
+    pub fn idle(&self) -> Duration {
+        Duration::from_secs(self.idle_secs.max(1))
+    }
+}
+
+impl Default for ConfigurationKeepAliveConfig {
+    fn default() -> Self {
+        Self { idle_secs: 15 }
+    }
+}
+
+/// Detection of one-directional stalls in the Play state.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct StallWatchdogConfig {
+    /// Time one direction may deliver no packets while the other is
+    /// active before the misc stream is reopened and, if that does not
+    /// help within the same time, the player is disconnected.
+    pub timeout_secs: u64,
+}
+
+impl StallWatchdogConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs.max(1))
+    }
+}
+
+impl Default for StallWatchdogConfig {
+    fn default() -> Self {
+        Self { timeout_secs: 20 }
+    }
+}
+
+/// Velocity modern forwarding to destination servers.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct VelocityForwardingConfig {
+    /// File holding the secret shared with the destination servers,
+    /// like Velocity's `forwarding-secret-file`.
+    pub secret_file: PathBuf,
+}
+
+impl VelocityForwardingConfig {
+    /// Reads the secret, ignoring surrounding whitespace.
+    pub fn load_secret(&self) -> anyhow::Result<Vec<u8>> {
+        let secret = fs_err::read_to_string(&self.secret_file)
+            .context("failed to read the forwarding secret")?;
+        let secret = secret.trim();
+        if secret.is_empty() {
+            bail!(
+                "forwarding secret in {} is empty",
+                self.secret_file.display()
+            );
+        }
+        Ok(secret.as_bytes().to_vec())
+    }
+}
+
+impl Default for VelocityForwardingConfig {
+    fn default() -> Self {
+        Self {
+            secret_file: PathBuf::from("forwarding.secret"),
+        }
+    }
+}
+
+/// Handling of the destination server closing the connection.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct DestinationCloseConfig {
+    /// Disconnect message shown to the player,
+    /// unless the server sent its own before closing.
+    pub message: String,
+    /// Time the connection to the client is kept open afterwards, so that
+    /// the message and final diagnostics on the control stream are
+    /// delivered, unless the client closes it first.
+    pub linger_millis: u64,
+}
+
+impl DestinationCloseConfig {
+    pub fn linger(&self) -> Duration {
+        Duration::from_millis(self.linger_millis)
+    }
+}
+
+impl Default for DestinationCloseConfig {
+    fn default() -> Self {
+        Self {
+            message: "The server closed the connection.".to_owned(),
+            linger_millis: 2000,
+        }
+    }
+}
+
+/// Graceful shutdown of the gateway. See `gateway::ShutdownHandle`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /// Maximum time sessions are given to end by themselves once the
+    /// gateway stops accepting connections.
+    pub drain_timeout_secs: u64,
+    /// Disconnect message shown to players still connected
+    /// when the drain timeout passes.
+    pub message: String,
+}
+
+impl ShutdownConfig {
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout_secs)
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            drain_timeout_secs: 30,
+            message: "The proxy is shutting down.".to_owned(),
+        }
+    }
+}
+
+/// Matches destination servers by IP address, network or host name,
+/// optionally restricted to a port.
+///
+/// Written as `10.0.0.5`, `10.0.0.0/8`, `play.example.net` or
+/// `*.example.net`, each optionally followed by `:<port>`
+/// (with IPv6 addresses in brackets, e.g. `[2001:db8::5]:25565`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct DestinationRule {
+    pub target: DestinationTarget,
+    pub port: Option<u16>,
+}
+
+/// What a `DestinationRule` matches, besides the port.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DestinationTarget {
+    Address(IpAddr),
+    /// All addresses sharing the first `prefix_len` bits with `address`.
+    Network {
+        address: IpAddr,
+        prefix_len: u8,
+    },
+    /// A host name, lowercase without a trailing dot. A leading `*.`
+    /// matches any number of labels, but not the name without them.
+    ///
+    /// Matches destinations whose IP address the name resolves to at
+    /// admission (see the `policy` module).
+    Host(String),
+}
+
+impl DestinationRule {
+    /// Checks whether the rule matches `destination`, known by `host_names`.
+    pub fn matches(&self, destination: SocketAddr, host_names: &[String]) -> bool {
+        self.port.is_none_or(|port| port == destination.port())
+            && self.target.matches(destination.ip(), host_names)
+    }
+}
+
+impl DestinationTarget {
+    fn matches(&self, ip: IpAddr, host_names: &[String]) -> bool {
+        // An IPv4-mapped IPv6 address is matched as the IPv4 address it maps.
+        let ip = ip.to_canonical();
+        match self {
+            Self::Address(address) => address.to_canonical() == ip,
+            Self::Network {
+                address,
+                prefix_len,
+            } => match (address, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    prefix_matches(&network.octets(), &ip.octets(), *prefix_len)
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    prefix_matches(&network.octets(), &ip.octets(), *prefix_len)
+                }
+                (IpAddr::V6(network), IpAddr::V4(ip)) => prefix_matches(
+                    &network.octets(),
+                    &ip.to_ipv6_mapped().octets(),
+                    *prefix_len,
+                ),
+                _ => false,
+            },
+            Self::Host(pattern) => host_names.iter().any(|name| host_matches(pattern, name)),
+        }
+    }
+}
+
+pub(super) fn prefix_matches(network: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let full_bytes = usize::from(prefix_len / 8);
+    let remaining_bits = prefix_len % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = u8::MAX << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// Checks whether a host name `pattern` matches `name`.
+fn host_matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => name
+            .strip_suffix(domain)
+            .is_some_and(|labels| labels.len() > 1 && labels.ends_with('.')),
+        None => pattern == name,
+    }
+}
+
+impl FromStr for DestinationRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid destination rule '{s}'");
+        if let Ok(address) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                target: DestinationTarget::Address(address.ip()),
+                port: Some(address.port()),
+            });
+        }
+        if let Ok(ip) = s.parse() {
+            return Ok(Self {
+                target: DestinationTarget::Address(ip),
+                port: None,
+            });
+        }
+        if let Some((address, rest)) = s.split_once('/') {
+            let (prefix_len, port) = match rest.split_once(':') {
+                Some((prefix_len, port)) => (prefix_len, Some(port)),
+                None => (rest, None),
+            };
+            let address: IpAddr = address
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .with_context(invalid)?;
+            let prefix_len: u8 = prefix_len.parse().with_context(invalid)?;
+            let max_prefix_len = if address.is_ipv4() { 32 } else { 128 };
+            if prefix_len > max_prefix_len {
+                bail!("{}: prefix length exceeds {max_prefix_len}", invalid());
+            }
+            return Ok(Self {
+                target: DestinationTarget::Network {
+                    address,
+                    prefix_len,
+                },
+                port: port.map(str::parse).transpose().with_context(invalid)?,
+            });
+        }
+        let (host, port) = match s.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse().with_context(invalid)?)),
+            None => (s, None),
+        };
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let name = host.strip_prefix("*.").unwrap_or(&host);
+        let valid_label = |label: &str| {
+            !label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        };
+        if !name.split('.').all(valid_label) {
+            bail!("{}: not an IP address, network or host name", invalid());
+        }
+        Ok(Self {
+            target: DestinationTarget::Host(host),
+            port,
+        })
+    }
+}
+
+impl TryFrom<String> for DestinationRule {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl JsonSchema for DestinationRule {
+    fn schema_name() -> String {
+        "DestinationRule".to_owned()
+    }
+
+    fn json_schema(gen: &mut SchemaGenerator) -> Schema {
+        let mut schema = String::json_schema(gen).into_object();
+        schema.metadata().description = Some(
+            "IP address, network or host name (`*.` matching any subdomain), optionally \
+             with a port, e.g. `10.0.0.5`, `10.0.0.0/8`, `*.example.net:25565`."
+                .to_owned(),
+        );
+        schema.into()
+    }
+}
+
+impl From<DestinationRule> for String {
+    fn from(rule: DestinationRule) -> Self {
+        rule.to_string()
+    }
+}
+
+impl Display for DestinationRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.target, self.port) {
+            (DestinationTarget::Address(ip), Some(port)) => {
+                write!(f, "{}", SocketAddr::new(*ip, port))
+            }
+            (DestinationTarget::Address(ip), None) => write!(f, "{ip}"),
+            (
+                DestinationTarget::Network {
+                    address,
+                    prefix_len,
+                },
+                port,
+            ) => {
+                match address {
+                    IpAddr::V4(address) => write!(f, "{address}/{prefix_len}")?,
+                    IpAddr::V6(address) => write!(f, "[{address}]/{prefix_len}")?,
+                }
+                match port {
+                    Some(port) => write!(f, ":{port}"),
+                    None => Ok(()),
+                }
+            }
+            (DestinationTarget::Host(host), Some(port)) => write!(f, "{host}:{port}"),
+            (DestinationTarget::Host(host), None) => write!(f, "{host}"),
+        }
+    }
+}
+
+/// Alerting via webhooks (Discord/Slack-compatible).
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// URLs that alerts are POSTed to as JSON.
+    pub urls: Vec<String>,
+    /// Send an alert when the number of concurrent sessions reaches this value.
+    /// It alerts again once the number has fallen below 90% of it in between.
+    pub session_count_threshold: Option<usize>,
+    /// Number of failed authentication attempts from a single IP
+    /// within `auth_failure_window_secs` that counts as a brute-force attempt.
+    pub auth_failure_threshold: usize,
+    pub auth_failure_window_secs: u64,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            session_count_threshold: None,
+            auth_failure_threshold: 10,
+            auth_failure_window_secs: 60,
+        }
+    }
+}
+
+/// Fast-failing of connections to destinations that are down.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Number of consecutive failed connection attempts to a destination
+    /// after which connections to it are rejected. 0 disables the breaker.
+    pub failure_threshold: u32,
+    /// Time to reject connections for before probing the destination again.
+    pub open_secs: u64,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 30,
+        }
+    }
+}
+
+/// The admin HTTP API.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Address to serve the admin API on. Disabled if unset.
+    ///
+    /// The API is unauthenticated, so this should only be bound
+    /// to a loopback or otherwise private address.
+    pub listen: Option<SocketAddr>,
+}
+
+/// Session affinity within a pool of gateways behind one DNS name.
+/// See the `affinity` module.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct AffinityConfig {
+    /// ID of this gateway, unique within its pool.
+    /// No affinity tokens are issued if unset.
+    pub gateway_id: Option<String>,
+    /// Address, as `host:port`, at which clients can reach this
+    /// gateway directly, bypassing the pool's load balancer.
+    pub address: Option<String>,
+}
+
+impl AffinityConfig {
+    /// Gets the token issued to clients that request affinity.
+    pub fn token(&self) -> Option<AffinityToken> {
+        Some(AffinityToken {
+            gateway_id: self.gateway_id.clone()?,
+            address: self.address.clone(),
+        })
+    }
+}
+
+/// Strict mode: scoring each session's protocol anomalies (see the
+/// `anomaly` module) and acting on sessions whose score gets too high.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct StrictConfig {
+    /// Anomaly score above which `action` is taken on a session.
+    /// Strict mode is disabled if unset.
+    pub max_anomaly_score: Option<u64>,
+    pub action: StrictAction,
+}
+
+/// What to do with a session exceeding the maximum anomaly score.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum StrictAction {
+    /// Close the connection.
+    #[default]
+    Disconnect,
+    /// Keep the connection, but log a warning and
+    /// mark the session in its diagnostics.
+    Flag,
+}
+
+/// Client certificate authentication (mutual TLS).
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct ClientCertificateConfig {
+    /// Certificates of the CAs client certificates must be signed by,
+    /// in PEM or DER (`.der`) format.
+    pub ca_file: PathBuf,
+    /// Accepts clients with a valid certificate without an authentication
+    /// key. Such clients authenticate as the identity whose key they
+    /// present, if any, and as the default identity otherwise. If unset,
+    /// clients need both a certificate and a key.
+    #[serde(default)]
+    pub replaces_auth_key: bool,
+}
+
+/// Short-lived authentication tokens. See `token`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct TokenConfig {
+    /// File holding the secret tokens are signed with. Anyone
+    /// who can read it can issue tokens for any identity.
+    pub secret_file: PathBuf,
+}
+
+impl TokenConfig {
+    /// Reads the secret, ignoring surrounding whitespace.
+    pub fn load_secret(&self) -> anyhow::Result<Vec<u8>> {
+        let secret =
+            fs_err::read_to_string(&self.secret_file).context("failed to read the token secret")?;
+        let secret = secret.trim();
+        if secret.is_empty() {
+            bail!("token secret in {} is empty", self.secret_file.display());
+        }
+        Ok(secret.as_bytes().to_vec())
+    }
+}
+
+impl Default for TokenConfig {
+    fn default() -> Self {
+        Self {
+            secret_file: PathBuf::from("token.secret"),
+        }
+    }
+}
+
+/// Periodic reloading of certificates. See `tls::ReloadableCertificates`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct CertificateReloadConfig {
+    /// Interval at which the modification times of the
+    /// certificate and private key files are checked.
+    pub check_interval_secs: u64,
+}
+
+impl CertificateReloadConfig {
+    pub fn check_interval(&self) -> Duration {
+        Duration::from_secs(self.check_interval_secs.max(1))
+    }
+}
+
+impl Default for CertificateReloadConfig {
+    fn default() -> Self {
+        Self {
+            check_interval_secs: 60,
+        }
+    }
+}
+
+/// Dual transport measurement. See the `measurement` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct MeasurementConfig {
+    /// TCP port of the measurement endpoint, on all interfaces.
+    pub port: u16,
+}
+
+impl Default for MeasurementConfig {
+    fn default() -> Self {
+        Self { port: 6667 }
+    }
+}
+
+/// Per source IP rate limits. See the `rate_limit` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// Length of the sliding window the limits apply to.
+    pub window_secs: u64,
+    /// Maximum number of QUIC connections accepted from one IP within the window.
+    pub max_connections: usize,
+    /// Maximum number of control stream handshakes accepted from one IP within the window.
+    pub max_handshakes: usize,
+    /// Number of failed authentication attempts from one IP within
+    /// the window after which its handshakes are rejected.
+    pub max_auth_failures: usize,
+}
+
+impl RateLimitConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            window_secs: 60,
+            max_connections: 30,
+            max_handshakes: 30,
+            max_auth_failures: 5,
+        }
+    }
+}
+
+/// QUIC address validation with Retry packets.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Time a client has to come back with the token of a Retry.
+    pub token_lifetime_secs: u64,
+}
+
+impl RetryConfig {
+    pub fn token_lifetime(&self) -> Duration {
+        Duration::from_secs(self.token_lifetime_secs.max(1))
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            token_lifetime_secs: 15,
+        }
+    }
+}
+
+/// Resumption of sessions whose connection dropped. See `resumption`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct ResumptionConfig {
+    /// Time a session waits for its client to reconnect. The game itself
+    /// gives up after 30 seconds without packets.
+    pub grace_secs: u64,
+    /// Maximum number of packets from the destination server held for
+    /// the client meanwhile. The session ends if the server sends more.
+    pub max_buffered_packets: usize,
+}
+
+impl ResumptionConfig {
+    pub fn grace(&self) -> Duration {
+        Duration::from_secs(self.grace_secs)
+    }
+}
+
+impl Default for ResumptionConfig {
+    fn default() -> Self {
+        Self {
+            grace_secs: 15,
+            max_buffered_packets: 4096,
+        }
+    }
+}
+
+/// Routing by the host name in the handshake. See `virtual_host`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct VirtualHostsConfig {
+    /// Destination server of each host name, e.g.
+    /// `"survival.example.net" = "10.0.0.5:25565"`. Host names
+    /// are matched case-insensitively.
+    pub hosts: BTreeMap<String, String>,
+    /// Destination server of handshakes naming no configured host.
+    /// Such connections are closed if unset.
+    pub default: Option<String>,
+}
+
+/// Plain TCP listener for unmodified games. See `vanilla`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct VanillaListenerConfig {
+    /// Name identifying the listener in logs and diagnostics.
+    /// Must differ from the names of the QUIC listeners.
+    pub name: String,
+    pub listen: SocketAddr,
+    /// Policy applying to connections accepted on this listener.
+    /// No identity policy applies to them, since they do not authenticate.
+    pub policy: PolicyConfig,
+}
+
+impl Default for VanillaListenerConfig {
+    fn default() -> Self {
+        Self {
+            name: "vanilla".to_owned(),
+            listen: SocketAddr::from(([0, 0, 0, 0], 25565)),
+            policy: PolicyConfig::default(),
+        }
+    }
+}
+
+/// Destinations tried in order when `destination` cannot be connected to.
+/// See `fallback`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct FallbackConfig {
+    /// Destination as named by clients or virtual hosts,
+    /// e.g. `lobby.example.net` or `10.0.0.5:25565`.
+    pub destination: String,
+    /// Destinations tried in order if it cannot be connected to.
+    pub fallbacks: Vec<String>,
+    /// Time after which connecting to a destination of
+    /// the chain is given up on, moving to the next one.
+    pub dial_timeout_secs: u64,
+}
+
+impl FallbackConfig {
+    pub fn dial_timeout(&self) -> Duration {
+        Duration::from_secs(self.dial_timeout_secs)
+    }
+}
+
+impl Default for FallbackConfig {
+    fn default() -> Self {
+        Self {
+            destination: String::new(),
+            fallbacks: Vec::new(),
+            dial_timeout_secs: 5,
+        }
+    }
+}
+
+/// Another gateway that destinations are proxied through. See `upstream`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Address of the upstream gateway, as `host:port`. Its certificate
+    /// is verified against the host.
+    pub gateway: String,
+    /// Authentication key presented to the upstream gateway.
+    pub authentication_key: String,
+    /// Destinations proxied through the upstream gateway, as named by
+    /// clients or virtual hosts, e.g. `lobby.internal` or `10.0.0.5:25565`.
+    /// The upstream gateway resolves them.
+    pub destinations: Vec<String>,
+    /// Local address the upstream gateway is connected to from.
+    /// Must be of the same address family as the upstream gateway.
+    pub bind: SocketAddr,
+    /// Certificates of the CAs the upstream gateway's certificate must be
+    /// signed by, in PEM or DER (`.der`) format, e.g. its self-signed
+    /// certificate. The system's roots if unset.
+    pub ca_file: Option<PathBuf>,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            gateway: String::new(),
+            authentication_key: String::new(),
+            destinations: Vec::new(),
+            bind: SocketAddr::from(([0, 0, 0, 0], 0)),
+            ca_file: None,
+        }
+    }
+}
+
+/// Server list pings answered by the gateway. See `status`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct StatusConfig {
+    /// Time the status of a destination server is reused
+    /// for further pings of it. Not cached if 0.
+    pub cache_secs: u64,
+    /// If set, pings are answered with this description rather than
+    /// the destination server's status, e.g. `"§aA Minecraft Server"`.
+    pub motd: Option<String>,
+    /// PNG image of 64x64 pixels shown with the MOTD.
+    pub favicon_file: Option<PathBuf>,
+    /// Version name shown with the MOTD.
+    pub version_name: String,
+    /// Maximum player count shown with the MOTD. The online
+    /// count shown is the number of sessions of the gateway.
+    pub max_players: u32,
+}
+
+impl StatusConfig {
+    pub fn cache_duration(&self) -> Duration {
+        Duration::from_secs(self.cache_secs)
+    }
+}
+
+impl Default for StatusConfig {
+    fn default() -> Self {
+        Self {
+            cache_secs: 5,
+            motd: None,
+            favicon_file: None,
+            version_name: "minecraft-quic-proxy".to_owned(),
+            max_players: 100,
+        }
+    }
+}
+
+/// Bounds on the transport settings clients ask for with
+/// `ClientOptions::transport`. Clients that ask for none get the maximums.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct TransportLimitsConfig {
+    /// Longest idle timeout clients may ask for. Connections use the
+    /// shorter of it and the client's own, which is 30 seconds unless
+    /// the client asks for another.
+    pub max_idle_timeout_secs: u64,
+    /// Fewest unidirectional streams clients may limit each side
+    /// to. Must not be greater than `max_uni_streams`.
+    pub min_uni_streams: u32,
+    /// Most unidirectional streams each side may have open at once.
+    pub max_uni_streams: u32,
+}
+
+impl TransportLimitsConfig {
+    pub fn max_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.max_idle_timeout_secs)
+    }
+}
+
+impl Default for TransportLimitsConfig {
+    fn default() -> Self {
+        Self {
+            max_idle_timeout_secs: 30,
+            min_uni_streams: 64,
+            max_uni_streams: 16384,
+        }
+    }
+}
+
+/// Time limits on the phases of a connection, after which it is closed.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// Time the client has to send its `ConnectTo` once its connection
+    /// is accepted, and to complete the time sync once acknowledged.
+    pub control_stream_secs: u64,
+    /// Time resolving and connecting to a destination server may take,
+    /// unless its fallback chain sets its own dial timeout.
+    pub dial_secs: u64,
+    /// Time the game has to send its handshake, and the Status
+    /// and Login states may take afterward.
+    pub login_secs: u64,
+    /// Time the Configuration state may take when joining.
+    pub configuration_secs: u64,
+    /// Time after which a Play session in which neither side sent any
+    /// packets is closed. Servers send keepalives every 15 seconds, so
+    /// this only closes sessions whose destination server went silent.
+    /// Never closed if unset.
+    pub play_idle_secs: Option<u64>,
+}
+
+impl TimeoutsConfig {
+    pub fn control_stream(&self) -> Duration {
+        Duration::from_secs(self.control_stream_secs)
+    }
+
+    pub fn dial(&self) -> Duration {
+        Duration::from_secs(self.dial_secs)
+    }
+
+    pub fn login(&self) -> Duration {
+        Duration::from_secs(self.login_secs)
+    }
+
+    pub fn configuration(&self) -> Duration {
+        Duration::from_secs(self.configuration_secs)
+    }
+
+    pub fn play_idle(&self) -> Option<Duration> {
+        self.play_idle_secs.map(Duration::from_secs)
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        Self {
+            control_stream_secs: 30,
+            dial_secs: 30,
+            login_secs: 30,
+            configuration_secs: 30,
+            play_idle_secs: None,
+        }
+    }
+}
+
+/// Limits on concurrent sessions, checked when a connection arrives.
+/// Connections over a limit are closed with the reason.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct SessionLimitsConfig {
+    /// Maximum number of sessions on the gateway. Unlimited if unset.
+    pub max_sessions: Option<usize>,
+    /// Maximum number of sessions from one source IP. Unlimited if unset.
+    pub max_sessions_per_ip: Option<usize>,
+}
+
+/// Limits the bytes each connection may make the gateway buffer: the
+/// packets received on its streams but not decoded yet, and those
+/// decoded but not yet sent on to the other side.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct MemoryBudgetConfig {
+    /// Bytes buffered at most, as received on the wire (i.e. before
+    /// decompression). Must leave room for the largest packets, of up
+    /// to 8 MiB.
+    pub max_bytes: usize,
+}
+
+impl Default for MemoryBudgetConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes: 64 * 1024 * 1024,
+        }
+    }
+}
+
+/// Limits the rate of Play packets each client sends towards the
+/// destination server. See the `packet_rate` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct FloodProtectionConfig {
+    /// Average rate of packets allowed.
+    pub max_packets_per_sec: u32,
+    /// Packets that may be sent in a burst above the average rate.
+    pub burst_packets: u32,
+    pub action: FloodAction,
+}
+
+impl Default for FloodProtectionConfig {
+    fn default() -> Self {
+        Self {
+            max_packets_per_sec: 500,
+            burst_packets: 1000,
+            action: FloodAction::default(),
+        }
+    }
+}
+
+/// What to do with a client exceeding its packet rate.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum FloodAction {
+    /// Stop reading the client's packets until the rate allows more.
+    Throttle,
+    /// Close the connection.
+    #[default]
+    Disconnect,
+}
+
+/// Bandwidth quota of sessions. Counts the Play packets proxied in both
+/// directions, as sent over QUIC (i.e. after compression).
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct BandwidthQuotaConfig {
+    /// Average rate allowed.
+    pub max_bytes_per_sec: u64,
+    /// Bytes that may be proxied in a burst above the average rate.
+    pub burst_bytes: u64,
+    pub scope: QuotaScope,
+    pub action: QuotaAction,
+}
+
+impl Default for BandwidthQuotaConfig {
+    fn default() -> Self {
+        Self {
+            max_bytes_per_sec: 1_000_000,
+            burst_bytes: 4_000_000,
+            scope: QuotaScope::default(),
+            action: QuotaAction::default(),
+        }
+    }
+}
+
+/// What a bandwidth quota applies to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaScope {
+    /// Each session has a quota of its own.
+    #[default]
+    Session,
+    /// The sessions of an identity (i.e. authentication key) share a quota.
+    Identity,
+}
+
+/// What to do with a session exceeding its bandwidth quota.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum QuotaAction {
+    /// Delay packets until the quota refills.
+    #[default]
+    Throttle,
+    /// Close the connection.
+    Disconnect,
+}
+
+/// The structured event log. See the `event_log` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[schemars(deny_unknown_fields)]
+pub struct EventLogConfig {
+    /// File the events are appended to. Created if it does not exist.
+    pub file: PathBuf,
+}
+
+/// Webhooks notified of session events. See `session_webhook`.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct SessionWebhookConfig {
+    /// URLs the events are POSTed to as JSON.
+    pub urls: Vec<String>,
+    /// Events that are sent.
+    pub events: Vec<SessionEventKind>,
+    /// Sends client addresses in full rather than masked to their
+    /// network, e.g. if the webhook is not a shared chat channel.
+    pub include_addresses: bool,
+}
+
+impl Default for SessionWebhookConfig {
+    fn default() -> Self {
+        Self {
+            urls: Vec::new(),
+            events: vec![
+                SessionEventKind::Connect,
+                SessionEventKind::AuthFailure,
+                SessionEventKind::Disconnect,
+            ],
+            include_addresses: false,
+        }
+    }
+}
+
+/// A kind of session event, as named in the event log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionEventKind {
+    Connect,
+    AuthSuccess,
+    AuthFailure,
+    Dial,
+    StateTransition,
+    Disconnect,
+}
+
+/// Banning of source IPs. See the `ban` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct BanConfig {
+    /// Number of failed authentication attempts from one IP within
+    /// `window_secs` after which it is banned. 0 disables banning.
+    pub max_auth_failures: usize,
+    pub window_secs: u64,
+    /// Time a ban lasts.
+    pub ban_secs: u64,
+    /// File listing addresses and networks (e.g. `203.0.113.0/24`) to
+    /// refuse connections from, one per line, with `#` starting comments.
+    /// Reloaded when it changes.
+    pub blocklist_file: Option<PathBuf>,
+}
+
+impl BanConfig {
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+
+    pub fn ban_duration(&self) -> Duration {
+        Duration::from_secs(self.ban_secs)
+    }
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            max_auth_failures: 10,
+            window_secs: 600,
+            ban_secs: 3600,
+            blocklist_file: None,
+        }
+    }
+}
+
+/// Persistence of the gateway's usage totals. See the `usage` module.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(default)]
+#[schemars(deny_unknown_fields)]
+pub struct UsageConfig {
+    /// JSON file the totals are kept in across restarts.
+    /// The totals only cover the current run if unset.
+    pub state_file: Option<PathBuf>,
+    pub save_interval_secs: u64,
+}
+
+impl UsageConfig {
+    pub fn save_interval(&self) -> Duration {
+        Duration::from_secs(self.save_interval_secs.max(1))
+    }
+}
+
+impl Default for UsageConfig {
+    fn default() -> Self {
+        Self {
+            state_file: None,
+            save_interval_secs: 60,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(rule: &str) -> DestinationRule {
+        rule.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_destination_rules() {
+        let cases = [
+            (
+                "10.0.0.5",
+                DestinationTarget::Address([10, 0, 0, 5].into()),
+                None,
+            ),
+            (
+                "10.0.0.5:25565",
+                DestinationTarget::Address([10, 0, 0, 5].into()),
+                Some(25565),
+            ),
+            (
+                "[2001:db8::5]:25565",
+                DestinationTarget::Address("2001:db8::5".parse().unwrap()),
+                Some(25565),
+            ),
+            (
+                "10.0.0.0/8",
+                DestinationTarget::Network {
+                    address: [10, 0, 0, 0].into(),
+                    prefix_len: 8,
+                },
+                None,
+            ),
+            (
+                "[2001:db8::]/32:25565",
+                DestinationTarget::Network {
+                    address: "2001:db8::".parse().unwrap(),
+                    prefix_len: 32,
+                },
+                Some(25565),
+            ),
+            (
+                "Play.Example.NET.",
+                DestinationTarget::Host("play.example.net".to_owned()),
+                None,
+            ),
+            (
+                "*.example.net:25566",
+                DestinationTarget::Host("*.example.net".to_owned()),
+                Some(25566),
+            ),
+        ];
+        for (input, target, port) in cases {
+            assert_eq!(rule(input), DestinationRule { target, port }, "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_destination_rules() {
+        for input in [
+            "",
+            "10.0.0.0/33",
+            "[2001:db8::]/129",
+            "10.0.0.0/x",
+            "play.example.net:x",
+            "play..example.net",
+            "*.",
+            "play.example.net/8",
+        ] {
+            assert!(input.parse::<DestinationRule>().is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn matches_addresses_and_networks() {
+        let cases = [
+            ("10.0.0.5", "10.0.0.5:25565", true),
+            ("10.0.0.5", "10.0.0.6:25565", false),
+            ("10.0.0.5:25565", "10.0.0.5:25565", true),
+            ("10.0.0.5:25565", "10.0.0.5:25566", false),
+            ("0.0.0.0/0", "203.0.113.7:25565", true),
+            ("0.0.0.0/0", "[2001:db8::5]:25565", false),
+            ("10.0.0.0/8", "10.255.255.255:25565", true),
+            ("10.0.0.0/8", "11.0.0.0:25565", false),
+            ("192.168.0.0/23", "192.168.1.200:25565", true),
+            ("192.168.0.0/23", "192.168.2.1:25565", false),
+            ("10.0.0.5/32", "10.0.0.5:25565", true),
+            ("10.0.0.5/32", "10.0.0.4:25565", false),
+            ("[::]/0", "[2001:db8::5]:25565", true),
+            ("[2001:db8::]/32", "[2001:db8:ffff::1]:25565", true),
+            ("[2001:db8::]/32", "[2001:db9::1]:25565", false),
+            ("[2001:db8::5]/128", "[2001:db8::5]:25565", true),
+            ("[2001:db8::5]/128", "[2001:db8::4]:25565", false),
+            ("[2001:db8::]/32:25565", "[2001:db8::1]:25566", false),
+            // IPv4-mapped IPv6 addresses match as the IPv4 address they map.
+            ("10.0.0.5", "[::ffff:10.0.0.5]:25565", true),
+            ("10.0.0.0/8", "[::ffff:10.1.2.3]:25565", true),
+            ("10.0.0.0/8", "[::ffff:11.1.2.3]:25565", false),
+            ("::ffff:10.0.0.5", "10.0.0.5:25565", true),
+            ("[::ffff:0:0]/96", "10.0.0.5:25565", true),
+            ("[::ffff:10.0.0.0]/104", "10.0.0.5:25565", true),
+            ("[::ffff:10.0.0.0]/104", "11.0.0.5:25565", false),
+        ];
+        for (input, destination, expected) in cases {
+            assert_eq!(
+                rule(input).matches(destination.parse().unwrap(), &[]),
+                expected,
+                "{input} against {destination}"
+            );
+        }
+    }
+
+    #[test]
+    fn matches_host_names() {
+        let cases = [
+            ("play.example.net", "play.example.net", true),
+            ("play.example.net", "example.net", false),
+            ("play.example.net", "other.play.example.net", false),
+            ("*.example.net", "play.example.net", true),
+            ("*.example.net", "a.b.example.net", true),
+            ("*.example.net", "example.net", false),
+            ("*.example.net", "badexample.net", false),
+            ("*.example.net", "play.example.net.evil", false),
+            ("*.example.net:25565", "play.example.net", true),
+        ];
+        let destination = "203.0.113.7:25565".parse().unwrap();
+        for (input, name, expected) in cases {
+            assert_eq!(
+                rule(input).matches(destination, &[name.to_owned()]),
+                expected,
+                "{input} against {name}"
+            );
+        }
+        assert!(!rule("*.example.net:25566").matches(destination, &["play.example.net".to_owned()]));
+        assert!(!rule("play.example.net").matches(destination, &[]));
+    }
+
+    #[test]
+    fn prefix_matches_partial_bytes() {
+        let network = [0b1010_0000, 0, 0, 0];
+        assert!(prefix_matches(&network, &[0b1010_1111, 1, 2, 3], 4));
+        assert!(!prefix_matches(&network, &[0b1011_0000, 0, 0, 0], 4));
+        assert!(prefix_matches(&network, &[0b1011_0000, 0, 0, 0], 3));
+        assert!(prefix_matches(&network, &[0xff, 0xff, 0xff, 0xff], 0));
+        assert!(prefix_matches(&network, &network, 32));
+        assert!(!prefix_matches(&network, &[0b1010_0000, 0, 0, 1], 32));
+    }
+}
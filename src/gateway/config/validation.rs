@@ -0,0 +1,168 @@
+//! Strict validation of the configuration file.
+//!
+//! Serde ignores keys that match no field, so a misspelled option would
+//! silently fall back to its default. Instead, unknown keys are collected
+//! during deserialization and rejected, with the closest option at the
+//! same position in the config schema suggested as a correction.
+
+use super::GatewayConfig;
+use anyhow::bail;
+use schemars::{
+    gen::SchemaSettings,
+    schema::{RootSchema, Schema, SchemaObject, SingleOrVec},
+};
+use std::fmt::{self, Display, Write};
+
+/// Minimum Jaro-Winkler similarity of a suggested key to the unknown one.
+const MIN_SUGGESTION_SIMILARITY: f64 = 0.8;
+
+/// Generates the schema of the configuration file.
+pub fn root_schema() -> RootSchema {
+    SchemaSettings::draft07()
+        .into_generator()
+        .into_root_schema_for::<GatewayConfig>()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+/// Location of a key in the configuration file, e.g. `listeners[0].policy`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPath(Vec<Segment>);
+
+impl From<&serde_ignored::Path<'_>> for KeyPath {
+    fn from(path: &serde_ignored::Path) -> Self {
+        let mut segments = Vec::new();
+        let mut path = path;
+        loop {
+            path = match path {
+                serde_ignored::Path::Root => break,
+                serde_ignored::Path::Map { parent, key } => {
+                    segments.push(Segment::Key(key.clone()));
+                    parent
+                }
+                serde_ignored::Path::Seq { parent, index } => {
+                    segments.push(Segment::Index(*index));
+                    parent
+                }
+                serde_ignored::Path::Some { parent }
+                | serde_ignored::Path::NewtypeStruct { parent }
+                | serde_ignored::Path::NewtypeVariant { parent } => parent,
+            };
+        }
+        segments.reverse();
+        Self(segments)
+    }
+}
+
+impl Display for KeyPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.0.iter().enumerate() {
+            match segment {
+                Segment::Key(key) if i == 0 => write!(f, "{key}")?,
+                Segment::Key(key) => write!(f, ".{key}")?,
+                Segment::Index(index) => write!(f, "[{index}]")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails with a description of each unknown key, if there are any.
+pub fn reject_unknown_keys(unknown_keys: &[KeyPath]) -> anyhow::Result<()> {
+    if unknown_keys.is_empty() {
+        return Ok(());
+    }
+    let schema = root_schema();
+    let mut message = String::new();
+    for (i, path) in unknown_keys.iter().enumerate() {
+        if i > 0 {
+            message.push('\n');
+        }
+        write!(message, "unknown key `{path}`").unwrap();
+        let Some((Segment::Key(key), parent)) = path.0.split_last() else {
+            continue;
+        };
+        let known_keys = known_keys(&schema, parent);
+        match suggestion(key, &known_keys) {
+            Some(suggestion) => write!(message, ", did you mean `{suggestion}`?").unwrap(),
+            None if !known_keys.is_empty() => {
+                write!(message, " (expected one of `{}`)", known_keys.join("`, `")).unwrap()
+            }
+            None => {}
+        }
+    }
+    bail!(message)
+}
+
+fn suggestion<'a>(key: &str, known_keys: &'a [String]) -> Option<&'a str> {
+    known_keys
+        .iter()
+        .map(|known| (known, strsim::jaro_winkler(key, known)))
+        .filter(|(_, similarity)| *similarity >= MIN_SUGGESTION_SIMILARITY)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(known, _)| known.as_str())
+}
+
+/// Gets the keys the schema allows in the table at `path`.
+fn known_keys(root: &RootSchema, path: &[Segment]) -> Vec<String> {
+    let mut schemas = resolve(root, &root.schema);
+    for segment in path {
+        schemas = schemas
+            .into_iter()
+            .filter_map(|schema| child(schema, segment))
+            .flat_map(|child| match child {
+                Schema::Object(child) => resolve(root, child),
+                Schema::Bool(_) => Vec::new(),
+            })
+            .collect();
+    }
+    let mut keys: Vec<String> = schemas
+        .into_iter()
+        .filter_map(|schema| schema.object.as_ref())
+        .flat_map(|object| object.properties.keys().cloned())
+        .collect();
+    keys.dedup();
+    keys
+}
+
+/// Follows references and expands `anyOf` (as used for `Option`)
+/// and `allOf` (as used for documented references).
+fn resolve<'a>(root: &'a RootSchema, schema: &'a SchemaObject) -> Vec<&'a SchemaObject> {
+    if let Some(reference) = &schema.reference {
+        return reference
+            .strip_prefix("#/definitions/")
+            .and_then(|name| root.definitions.get(name))
+            .map(|definition| match definition {
+                Schema::Object(definition) => resolve(root, definition),
+                Schema::Bool(_) => Vec::new(),
+            })
+            .unwrap_or_default();
+    }
+    let mut schemas = vec![schema];
+    if let Some(subschemas) = &schema.subschemas {
+        for subschema in [&subschemas.any_of, &subschemas.all_of, &subschemas.one_of]
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            if let Schema::Object(subschema) = subschema {
+                schemas.extend(resolve(root, subschema));
+            }
+        }
+    }
+    schemas
+}
+
+fn child<'a>(schema: &'a SchemaObject, segment: &Segment) -> Option<&'a Schema> {
+    match segment {
+        Segment::Key(key) => schema.object.as_ref()?.properties.get(key),
+        Segment::Index(_) => match schema.array.as_ref()?.items.as_ref()? {
+            SingleOrVec::Single(items) => Some(items),
+            SingleOrVec::Vec(items) => items.first(),
+        },
+    }
+}
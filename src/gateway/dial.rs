@@ -0,0 +1,242 @@
+//! Resolution and dialing of destination servers.
+//!
+//! Destinations named by host name are resolved as the vanilla client
+//! resolves them: through the `_minecraft._tcp` SRV record if the client
+//! gave no port, then through A and AAAA records. The addresses found are
+//! dialed Happy Eyeballs style (RFC 8305): alternating between IPv6 and
+//! IPv4, each attempt starts once the previous one has failed or has been
+//! pending for `ATTEMPT_DELAY`, and the first to connect wins.
+//!
+//! If outbound bind addresses are configured, each connection is made
+//! from the one of its destination's address family. Connections get the
+//! configured socket options.
+
+use crate::{
+    clock,
+    clock::SharedClock,
+    destination::{Destination, DEFAULT_PORT},
+    tcp_options::TcpOptions,
+};
+use anyhow::{bail, Context};
+use futures::{stream::FuturesUnordered, StreamExt};
+use hickory_resolver::{
+    config::LookupIpStrategy, error::ResolveErrorKind, system_conf, TokioAsyncResolver,
+};
+use once_cell::sync::OnceCell;
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    select,
+};
+
+/// Time after which the next address is dialed
+/// while earlier attempts are still pending.
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// Time after which resolving a destination is given up on.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves and dials destination servers.
+pub(crate) struct Dialer {
+    /// Created on first use, since only host name destinations need it.
+    /// `None` if the system's DNS configuration could not be read, in
+    /// which case the system resolver is used without SRV lookups.
+    resolver: OnceCell<Option<TokioAsyncResolver>>,
+    /// Local addresses to connect from, at most one per address family.
+    outbound_bind: Vec<IpAddr>,
+    tcp_options: TcpOptions,
+    clock: SharedClock,
+}
+
+impl Dialer {
+    /// Fails if `outbound_bind` has several addresses of one family.
+    pub fn new(
+        outbound_bind: Vec<IpAddr>,
+        tcp_options: TcpOptions,
+        clock: SharedClock,
+    ) -> anyhow::Result<Self> {
+        for family in [IpAddr::is_ipv4, IpAddr::is_ipv6] {
+            if outbound_bind.iter().filter(|ip| family(ip)).count() > 1 {
+                bail!("at most one outbound bind address per address family is allowed");
+            }
+        }
+        Ok(Self {
+            resolver: OnceCell::new(),
+            outbound_bind,
+            tcp_options,
+            clock,
+        })
+    }
+
+    /// Gets the addresses of `destination`, in the order to dial them.
+    /// Never empty.
+    pub async fn resolve(&self, destination: &Destination) -> anyhow::Result<Vec<SocketAddr>> {
+        let (host, port) = match destination {
+            Destination::Address(address) => return Ok(vec![*address]),
+            Destination::Host { host, port } => (host, *port),
+        };
+        let addresses = clock::timeout(&*self.clock, RESOLVE_TIMEOUT, self.lookup(host, port))
+            .await
+            .with_context(|| format!("timed out resolving {destination}"))??;
+        if addresses.is_empty() {
+            bail!("{destination} has no addresses");
+        }
+        Ok(addresses)
+    }
+
+    async fn lookup(&self, host: &str, port: Option<u16>) -> anyhow::Result<Vec<SocketAddr>> {
+        let resolver = self.resolver.get_or_init(|| {
+            let (config, mut opts) = system_conf::read_system_conf()
+                .inspect_err(|e| {
+                    tracing::warn!("SRV records of destinations cannot be looked up: {e}");
+                })
+                .ok()?;
+            opts.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            Some(TokioAsyncResolver::tokio(config, opts))
+        });
+        let Some(resolver) = resolver else {
+            let port = port.unwrap_or(DEFAULT_PORT);
+            let addresses = tokio::net::lookup_host((host, port))
+                .await
+                .with_context(|| format!("failed to resolve {host}"))?;
+            return Ok(interleave_families(addresses.collect()));
+        };
+
+        if port.is_none() {
+            let addresses = self.lookup_srv(resolver, host).await;
+            if !addresses.is_empty() {
+                return Ok(addresses);
+            }
+        }
+        let port = port.unwrap_or(DEFAULT_PORT);
+        match resolver.lookup_ip(host).await {
+            Ok(ips) => Ok(interleave_families(
+                ips.iter().map(|ip| SocketAddr::new(ip, port)).collect(),
+            )),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                bail!("host {host} does not exist")
+            }
+            Err(e) => Err(e).with_context(|| format!("failed to resolve {host}")),
+        }
+    }
+
+    /// Gets the addresses of the targets of the `_minecraft._tcp` SRV
+    /// record of `host`, in order of priority. Empty if it has none.
+    async fn lookup_srv(&self, resolver: &TokioAsyncResolver, host: &str) -> Vec<SocketAddr> {
+        let records = match resolver
+            .srv_lookup(format!("_minecraft._tcp.{host}."))
+            .await
+        {
+            Ok(lookup) => {
+                let mut records: Vec<_> = lookup.iter().cloned().collect();
+                records.sort_by_key(|record| (record.priority(), u16::MAX - record.weight()));
+                records
+            }
+            Err(e) => {
+                if !matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) {
+                    tracing::debug!("SRV lookup for {host} failed: {e}");
+                }
+                return Vec::new();
+            }
+        };
+
+        let mut addresses = Vec::new();
+        for record in records {
+            let target = record.target().to_string();
+            match resolver.lookup_ip(target.as_str()).await {
+                Ok(ips) => addresses.extend(interleave_families(
+                    ips.iter()
+                        .map(|ip| SocketAddr::new(ip, record.port()))
+                        .collect(),
+                )),
+                Err(e) => tracing::debug!("Failed to resolve SRV target {target} of {host}: {e}"),
+            }
+        }
+        addresses
+    }
+
+    /// Connects to the first of `addresses` to accept, as described in the
+    /// module docs. `on_failure` is called for each address that fails.
+    ///
+    /// Returns the error of the last address to fail if none connects.
+    pub async fn connect(
+        &self,
+        addresses: &[SocketAddr],
+        mut on_failure: impl FnMut(SocketAddr, &io::Error),
+    ) -> io::Result<(TcpStream, SocketAddr)> {
+        let mut attempts = FuturesUnordered::new();
+        let mut last_error = None;
+        for &address in addresses {
+            attempts.push(async move { (address, self.dial(address).await) });
+            let mut delay = self.clock.sleep_until(self.clock.now() + ATTEMPT_DELAY);
+            select! {
+                Some((address, result)) = attempts.next() => match result {
+                    Ok(stream) => return Ok((stream, address)),
+                    Err(e) => {
+                        on_failure(address, &e);
+                        last_error = Some(e);
+                    }
+                },
+                () = &mut delay => {}
+            }
+        }
+        while let Some((address, result)) = attempts.next().await {
+            match result {
+                Ok(stream) => return Ok((stream, address)),
+                Err(e) => {
+                    on_failure(address, &e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error
+            .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to dial")))
+    }
+
+    /// Connects to `address`, from the outbound bind address of its family if any are configured.
+    async fn dial(&self, address: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        self.tcp_options.configure_socket(&socket)?;
+        if !self.outbound_bind.is_empty() {
+            let Some(&local_ip) = self
+                .outbound_bind
+                .iter()
+                .find(|ip| ip.is_ipv4() == address.is_ipv4())
+            else {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "no outbound bind address of the destination's address family",
+                ));
+            };
+            socket.bind(SocketAddr::new(local_ip, 0))?;
+        }
+        let stream = socket.connect(address).await?;
+        self.tcp_options.configure_stream(&stream)?;
+        Ok(stream)
+    }
+}
+
+/// Orders addresses alternating between IPv6 and IPv4, starting
+/// with IPv6, keeping their order within each family.
+fn interleave_families(addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (Vec<_>, Vec<_>) = addresses
+        .into_iter()
+        .partition(|address| matches!(address.ip(), IpAddr::V6(_)));
+    v6.dedup();
+    v4.dedup();
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut interleaved = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return interleaved,
+            (a, b) => interleaved.extend(a.into_iter().chain(b)),
+        }
+    }
+}
@@ -0,0 +1,113 @@
+//! Structured log of connection events, written as JSON lines to a file
+//! for log pipelines, separately from the human-readable tracing output.
+//!
+//! Each line is one event of one session, e.g.
+//! `{"timestamp_millis":1700000000000,"session":3,"client_address":"203.0.113.7:50123","event":"auth_success","identity":"default","tenant":null}`.
+//! Unlike session summaries served by the admin API, addresses
+//! are not masked, since the file is only readable by the operator.
+
+use crate::{
+    bandwidth::BandwidthTotals,
+    gateway::{config::EventLogConfig, session::SessionId},
+};
+use anyhow::Context;
+use serde::Serialize;
+use std::{
+    io::Write,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// An event in the life of a connection.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnectionEvent<'a> {
+    /// The connection was accepted.
+    Connect {
+        listener: &'a str,
+    },
+    AuthSuccess {
+        identity: &'a str,
+        tenant: Option<&'a str>,
+    },
+    AuthFailure,
+    /// A destination server address was dialed.
+    Dial {
+        /// Destination as named by the client.
+        destination: &'a str,
+        address: SocketAddr,
+        /// Why the dial failed, if it did.
+        error: Option<String>,
+    },
+    /// The session switched protocol state.
+    StateTransition {
+        from: &'a str,
+        to: &'a str,
+    },
+    Disconnect {
+        duration_secs: f64,
+        /// UDP bytes sent and received on the client connection,
+        /// including QUIC overhead.
+        bytes_sent: u64,
+        bytes_received: u64,
+        /// Play packets and their bytes, excluding QUIC overhead.
+        play_traffic: BandwidthTotals,
+        /// Why the session ended, if it failed.
+        error: Option<String>,
+    },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    timestamp_millis: u64,
+    session: SessionId,
+    client_address: SocketAddr,
+    #[serde(flatten)]
+    event: &'a ConnectionEvent<'a>,
+}
+
+/// The event log file, appended to by all sessions.
+pub(crate) struct EventLog {
+    file: Mutex<fs_err::File>,
+}
+
+impl EventLog {
+    /// Opens the log file for appending, creating it if needed.
+    pub fn open(config: &EventLogConfig) -> anyhow::Result<Self> {
+        let file = fs_err::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.file)
+            .context("failed to open the event log")?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Appends an event of the given session. Failures to
+    /// write are logged rather than failing the session.
+    pub fn write(&self, session: SessionId, client_address: SocketAddr, event: &ConnectionEvent) {
+        let line = Line {
+            timestamp_millis: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            session,
+            client_address,
+            event,
+        };
+        let mut line = match serde_json::to_vec(&line) {
+            Ok(line) => line,
+            Err(e) => {
+                tracing::warn!("Failed to serialize event: {e}");
+                return;
+            }
+        };
+        line.push(b'\n');
+        // One write per line, so that lines of concurrent sessions don't interleave.
+        if let Err(e) = self.file.lock().unwrap().write_all(&line) {
+            tracing::warn!("Failed to write to the event log: {e}");
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! Fallback destination servers.
+//!
+//! A destination may have a chain of fallbacks. If it cannot be resolved or
+//! connected to within the dial timeout, or its circuit breaker is open,
+//! the fallbacks are tried in order. A destination rejected by policy is
+//! not fallen back from, since that is no failure of the destination.
+//!
+//! Clients learn which destination the gateway connected to
+//! with `ClientSide::query_destination`.
+
+use crate::{destination::Destination, gateway::config::FallbackConfig};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use std::time::Duration;
+
+/// The fallbacks of a destination.
+pub(crate) struct FallbackChain {
+    pub fallbacks: Vec<Destination>,
+    pub dial_timeout: Duration,
+}
+
+/// Fallback chains, by the destination they are for.
+#[derive(Default)]
+pub(crate) struct Fallbacks {
+    chains: AHashMap<Destination, FallbackChain>,
+}
+
+impl Fallbacks {
+    /// Fails if a destination is invalid or has several chains.
+    pub fn new(configs: &[FallbackConfig]) -> anyhow::Result<Self> {
+        let mut chains = AHashMap::new();
+        for config in configs {
+            let destination: Destination = config.destination.parse().with_context(|| {
+                format!("invalid fallback destination '{}'", config.destination)
+            })?;
+            let fallbacks = config
+                .fallbacks
+                .iter()
+                .map(|fallback| {
+                    fallback
+                        .parse()
+                        .with_context(|| format!("invalid fallback '{fallback}' of {destination}"))
+                })
+                .collect::<anyhow::Result<_>>()?;
+            let chain = FallbackChain {
+                fallbacks,
+                dial_timeout: config.dial_timeout(),
+            };
+            if chains.insert(destination.clone(), chain).is_some() {
+                bail!("{destination} has several fallback chains");
+            }
+        }
+        Ok(Self { chains })
+    }
+
+    /// Gets the fallbacks of `destination`, if it has any.
+    pub fn get(&self, destination: &Destination) -> Option<&FallbackChain> {
+        self.chains.get(destination)
+    }
+}
@@ -0,0 +1,128 @@
+//! Velocity's modern player information forwarding.
+//!
+//! Destination servers set up for Velocity (e.g. Paper with
+//! `velocity.enabled`) run in offline mode and ask the proxy for the
+//! player's address, UUID and name with a login plugin request on
+//! `velocity:player_info`. The gateway answers it with the player's real
+//! address and the name and UUID from their `LoginStart`, signed with the
+//! secret shared with the server.
+//!
+//! The gateway does not authenticate players with Mojang, so the name and
+//! UUID are the ones the game claims. Only enable forwarding for
+//! destinations reached by trusted identities.
+
+use crate::protocol::{
+    packet::{client::login::LoginStart, server::login::LoginPluginRequest},
+    Decoder, Encoder,
+};
+use anyhow::Context;
+use ring::hmac;
+use std::{net::IpAddr, sync::Arc};
+
+/// Forwarding version sent: `MODERN_DEFAULT`, without player keys,
+/// which every server supporting modern forwarding accepts.
+const MODERN_DEFAULT: i32 = 1;
+
+/// Forwarding of the information of one connection's player.
+#[derive(Clone)]
+pub(crate) struct VelocityForwarding {
+    secret: Arc<[u8]>,
+    /// Address of the player's connection to the gateway.
+    address: IpAddr,
+}
+
+impl VelocityForwarding {
+    pub fn new(secret: Arc<[u8]>, address: IpAddr) -> Self {
+        Self {
+            secret,
+            address: address.to_canonical(),
+        }
+    }
+
+    /// Gets a handler answering `velocity:player_info` requests
+    /// for the player that sent `login_start`.
+    pub fn handler(
+        &self,
+        login_start: &LoginStart,
+    ) -> anyhow::Result<impl Fn(&LoginPluginRequest) -> Vec<u8> + Send + Sync + 'static> {
+        let mut decoder = Decoder::new(&login_start.ignored_data);
+        let name = decoder
+            .read_string()
+            .context("malformed LoginStart")?
+            .to_owned();
+        let uuid = u128::from_be_bytes(decoder.consume::<16>().context("malformed LoginStart")?);
+
+        let mut payload = Vec::new();
+        let mut encoder = Encoder::new(&mut payload);
+        encoder.write_var_int(MODERN_DEFAULT);
+        encoder.write_string(&self.address.to_string());
+        encoder.write_slice(&uuid.to_be_bytes());
+        encoder.write_string(&name);
+        // No properties (i.e. no skin), since the player is not authenticated.
+        encoder.write_var_int(0);
+
+        let signature = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &self.secret), &payload);
+        let response = [signature.as_ref(), &payload].concat();
+        // The version the server asks for is at least `MODERN_DEFAULT`.
+        Ok(move |_: &LoginPluginRequest| response.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn signs_player_info() {
+        let forwarding = VelocityForwarding::new(
+            Arc::from(&b"velocity secret"[..]),
+            "::ffff:203.0.113.7".parse().unwrap(),
+        );
+        let mut login_start = vec![5];
+        login_start.extend_from_slice(b"Notch");
+        login_start.extend_from_slice(&from_hex("069a79f444e94726a5befca90e38aaf5"));
+        let handler = forwarding
+            .handler(&LoginStart {
+                ignored_data: login_start,
+            })
+            .unwrap();
+
+        let response = handler(&LoginPluginRequest {
+            message_id: 1,
+            channel: "velocity:player_info".to_owned(),
+            data: vec![1],
+        });
+        // HMAC-SHA256 under the secret, then: version 1, the address
+        // "203.0.113.7", the UUID, the name "Notch" and no properties.
+        let expected = from_hex(concat!(
+            "54b89abfc732913b4122fe111d84421c00d3f3c4ec5c5262062ebdf08a5c456e",
+            "010b3230332e302e3131332e37",
+            "069a79f444e94726a5befca90e38aaf5",
+            "054e6f74636800",
+        ));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn rejects_truncated_login_start() {
+        let forwarding = VelocityForwarding::new(
+            Arc::from(&b"velocity secret"[..]),
+            "203.0.113.7".parse().unwrap(),
+        );
+        let mut login_start = vec![5];
+        login_start.extend_from_slice(b"Notch");
+        login_start.extend_from_slice(&[0; 15]);
+        assert!(forwarding
+            .handler(&LoginStart {
+                ignored_data: login_start,
+            })
+            .is_err());
+    }
+}
@@ -0,0 +1,101 @@
+//! Keepalives sent by the gateway in the Configuration state.
+//!
+//! The game closes its connection if it receives nothing for 30 seconds,
+//! which a destination server can exceed while preparing a large
+//! configuration. While the server is silent, the gateway sends keepalives
+//! of its own, and withholds the game's replies to them, since the server
+//! would disconnect the player for answering a keepalive it never sent.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    protocol::packet::{client, server, state},
+    proxy::Injector,
+};
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+/// Set in the IDs of keepalives sent by the gateway. Vanilla servers
+/// use millisecond timestamps as IDs, which never have it set.
+const ID_MARKER: u64 = 0x5155_4943 << 32;
+/// Maximum number of unanswered keepalives remembered.
+const MAX_PENDING: usize = 16;
+
+/// Keepalives for one Configuration state.
+pub struct ConfigurationKeepAlive {
+    idle: Duration,
+    clock: SharedClock,
+    /// Time of the last packet from the server or keepalive sent.
+    last_activity: Mutex<Instant>,
+    next_id: AtomicU64,
+    pending: Mutex<VecDeque<u64>>,
+}
+
+impl ConfigurationKeepAlive {
+    pub fn new(idle: Duration, clock: SharedClock) -> Self {
+        Self {
+            idle,
+            last_activity: Mutex::new(clock.now()),
+            clock,
+            next_id: AtomicU64::new(0),
+            pending: Mutex::default(),
+        }
+    }
+
+    pub fn record_server_packet(&self) {
+        *self.last_activity.lock().unwrap() = self.clock.now();
+    }
+
+    /// Checks whether `packet` answers a keepalive sent by the gateway,
+    /// in which case it must be withheld from the server.
+    pub fn is_own_reply(&self, packet: &client::configuration::KeepAlive) -> bool {
+        let Ok(id) = <[u8; 8]>::try_from(packet.ignored_data.as_slice()) else {
+            return false;
+        };
+        let id = u64::from_be_bytes(id);
+        let mut pending = self.pending.lock().unwrap();
+        let position = pending.iter().position(|&pending_id| pending_id == id);
+        if let Some(position) = position {
+            pending.remove(position);
+        }
+        position.is_some()
+    }
+
+    /// Sends keepalives through `injector` whenever the server has been
+    /// silent for the idle time. Never returns; drop it to stop.
+    pub async fn run(&self, injector: &Injector<state::Configuration>) -> Infallible {
+        loop {
+            let deadline = self.last_activity() + self.idle;
+            self.clock.sleep_until(deadline).await;
+            if self.last_activity() + self.idle > self.clock.now() {
+                // The server sent something in the meantime.
+                continue;
+            }
+            let id = ID_MARKER | self.next_id.fetch_add(1, Ordering::Relaxed);
+            {
+                let mut pending = self.pending.lock().unwrap();
+                if pending.len() == MAX_PENDING {
+                    pending.pop_front();
+                }
+                pending.push_back(id);
+            }
+            tracing::debug!("Destination server is silent, sending keepalive to the client");
+            injector.inject_to_client(server::configuration::Packet::KeepAlive(
+                server::configuration::KeepAlive {
+                    ignored_data: id.to_be_bytes().to_vec(),
+                },
+            ));
+            *self.last_activity.lock().unwrap() = self.clock.now();
+        }
+    }
+
+    fn last_activity(&self) -> Instant {
+        *self.last_activity.lock().unwrap()
+    }
+}
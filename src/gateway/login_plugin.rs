@@ -0,0 +1,49 @@
+//! Login plugin requests ("custom queries") sent by the destination server.
+//!
+//! Requests on channels the gateway implements itself (such as
+//! forwarding of player information) are answered by the gateway and never
+//! reach the client. All other requests are passed through to the client verbatim.
+
+use crate::protocol::packet::{
+    client::login::LoginPluginResponse, server::login::LoginPluginRequest,
+};
+use ahash::AHashMap;
+
+/// Channel used by Velocity's modern forwarding.
+pub const VELOCITY_PLAYER_INFO_CHANNEL: &str = "velocity:player_info";
+
+type Handler = Box<dyn Fn(&LoginPluginRequest) -> Vec<u8> + Send + Sync>;
+
+/// Answers login plugin requests on behalf of the client
+/// for the channels it has handlers for.
+#[derive(Default)]
+pub(crate) struct LoginPluginResponder {
+    handlers: AHashMap<String, Handler>,
+}
+
+impl LoginPluginResponder {
+    /// Registers a handler computing the response data
+    /// for requests on `channel`.
+    pub fn register(
+        &mut self,
+        channel: impl Into<String>,
+        handler: impl Fn(&LoginPluginRequest) -> Vec<u8> + Send + Sync + 'static,
+    ) {
+        self.handlers.insert(channel.into(), Box::new(handler));
+    }
+
+    /// Whether requests on the channel are answered by the gateway.
+    pub fn handles(&self, channel: &str) -> bool {
+        self.handlers.contains_key(channel)
+    }
+
+    /// Answers a request, if the gateway handles its channel.
+    pub fn respond(&self, request: &LoginPluginRequest) -> Option<LoginPluginResponse> {
+        let handler = self.handlers.get(&request.channel)?;
+        Some(LoginPluginResponse {
+            message_id: request.message_id,
+            successful: true,
+            data: handler(request),
+        })
+    }
+}
@@ -0,0 +1,115 @@
+//! The gateway's TCP measurement endpoint. See the `measurement` module.
+//!
+//! Each session that enables measurement is issued a single-use token,
+//! which admits one TCP connection. The connection then only echoes
+//! probes back, so it cannot be used to reach anything else.
+
+use crate::{
+    clock,
+    clock::{Instant, SharedClock},
+    measurement::{self, MeasurementEndpoint, MeasurementIssuer, MeasurementToken},
+};
+use ahash::AHashMap;
+use anyhow::bail;
+use quinn::Connection;
+use rand::RngCore;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+
+/// Time after issuing within which a token must be redeemed.
+const TOKEN_TTL: Duration = Duration::from_secs(30);
+/// Time a new connection has to send its token.
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Tokens issued for the measurement endpoint and not yet redeemed.
+#[derive(Debug)]
+pub struct MeasurementEndpoints {
+    port: u16,
+    tokens: Mutex<AHashMap<MeasurementToken, Instant>>,
+    clock: SharedClock,
+}
+
+impl MeasurementEndpoints {
+    pub fn new(port: u16, clock: SharedClock) -> Self {
+        Self {
+            port,
+            tokens: Mutex::default(),
+            clock,
+        }
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Issues a new token, forgetting expired ones.
+    pub fn issue(&self) -> MeasurementEndpoint {
+        let mut token = MeasurementToken::default();
+        rand::thread_rng().fill_bytes(&mut token);
+        let now = self.clock.now();
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.retain(|_, issued| now.duration_since(*issued) < TOKEN_TTL);
+        tokens.insert(token, now);
+        MeasurementEndpoint {
+            port: self.port,
+            token,
+        }
+    }
+
+    pub fn issuer(self: &Arc<Self>) -> MeasurementIssuer {
+        let endpoints = Arc::clone(self);
+        Arc::new(move || endpoints.issue())
+    }
+
+    /// Consumes `token`, returning whether it was issued and has not expired.
+    pub fn redeem(&self, token: &MeasurementToken) -> bool {
+        self.tokens
+            .lock()
+            .unwrap()
+            .remove(token)
+            .is_some_and(|issued| self.clock.now().duration_since(issued) < TOKEN_TTL)
+    }
+
+    /// Accepts connections on `listener`, echoing probes on
+    /// those that present a valid token.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) -> anyhow::Result<()> {
+        loop {
+            let (stream, address) = listener.accept().await?;
+            let endpoints = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = endpoints.serve_connection(stream).await {
+                    tracing::debug!("Measurement connection from {address} failed: {e:#}");
+                }
+            });
+        }
+    }
+
+    async fn serve_connection(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        stream.set_nodelay(true)?;
+        let mut token = MeasurementToken::default();
+        clock::timeout(&*self.clock, TOKEN_TIMEOUT, stream.read_exact(&mut token)).await??;
+        if !self.redeem(&token) {
+            bail!("invalid measurement token");
+        }
+        let (reader, writer) = stream.split();
+        measurement::echo(reader, writer).await
+    }
+}
+
+/// Echoes the QUIC probes of a session that enabled measurement, on
+/// the next bidirectional stream the client opens after the time sync.
+pub async fn echo_quic_probes(connection: Connection) {
+    let result = match connection.accept_bi().await {
+        Ok((send, recv)) => measurement::echo(recv, send).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        tracing::debug!("Measurement stream failed: {e:#}");
+    }
+}
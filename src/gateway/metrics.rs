@@ -0,0 +1,204 @@
+//! Aggregation of the quality metrics reported by clients,
+//! exposed in the Prometheus text format.
+//!
+//! Metrics of connections authenticated as a tenant's identity are
+//! aggregated separately, in series labelled with the tenant's name.
+
+use super::{session::SessionSummary, usage::UsageSnapshot, Shared};
+use crate::{
+    control_stream::ClientMetrics,
+    histogram::{self, Histogram},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex, OnceLock, Weak},
+};
+
+const RTT_BUCKETS_MILLIS: &[f64] = &[
+    5.0, 10.0, 25.0, 50.0, 75.0, 100.0, 150.0, 200.0, 300.0, 500.0, 1000.0,
+];
+const LOSS_RATIO_BUCKETS: &[f64] = &[0.0, 0.001, 0.005, 0.01, 0.02, 0.05, 0.1, 0.2, 0.5];
+
+/// Aggregates client metrics reports across all sessions.
+pub(crate) struct ClientMetricsAggregator {
+    /// Series by tenant. `None` for connections outside of any tenant.
+    series: Mutex<BTreeMap<Option<String>, Series>>,
+}
+
+/// Aggregated reports of one tenant.
+struct Series {
+    reports: u64,
+    rtt_millis: Histogram,
+    loss_ratio: Histogram,
+    dropped_datagrams: u64,
+    reconfigurations: u64,
+}
+
+impl Series {
+    fn new() -> Self {
+        Self {
+            reports: 0,
+            rtt_millis: Histogram::new(RTT_BUCKETS_MILLIS),
+            loss_ratio: Histogram::new(LOSS_RATIO_BUCKETS),
+            dropped_datagrams: 0,
+            reconfigurations: 0,
+        }
+    }
+}
+
+impl ClientMetricsAggregator {
+    pub fn new() -> Self {
+        Self {
+            series: Mutex::new(BTreeMap::from([(None, Series::new())])),
+        }
+    }
+
+    pub fn record(&self, tenant: Option<&str>, metrics: &ClientMetrics) {
+        let mut series = self.series.lock().unwrap();
+        let series = series
+            .entry(tenant.map(str::to_owned))
+            .or_insert_with(Series::new);
+        series.reports += 1;
+        series.rtt_millis.observe(f64::from(metrics.rtt_millis));
+        if metrics.sent_packets > 0 {
+            series
+                .loss_ratio
+                .observe(metrics.lost_packets as f64 / metrics.sent_packets as f64);
+        }
+        series.dropped_datagrams += metrics.dropped_datagrams;
+        series.reconfigurations += u64::from(metrics.reconfigurations);
+    }
+
+    /// Renders the aggregated metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let series = self.series.lock().unwrap();
+        let labelled = || {
+            series.iter().map(|(tenant, series)| {
+                let labels = match tenant {
+                    Some(tenant) => vec![("tenant", tenant.as_str())],
+                    None => Vec::new(),
+                };
+                (labels, series)
+            })
+        };
+
+        let mut out = String::new();
+        write_counter(
+            &mut out,
+            "quic_proxy_client_reports_total",
+            "Metrics reports received from clients.",
+            labelled().map(|(labels, series)| (labels, series.reports)),
+        );
+        histogram::write_header(
+            &mut out,
+            "quic_proxy_client_rtt_milliseconds",
+            "Round-trip time between clients and the gateway, as reported by clients.",
+        );
+        for (labels, series) in labelled() {
+            series.rtt_millis.render_series(
+                &mut out,
+                "quic_proxy_client_rtt_milliseconds",
+                &labels,
+            );
+        }
+        histogram::write_header(
+            &mut out,
+            "quic_proxy_client_packet_loss_ratio",
+            "Fraction of QUIC packets sent by clients that were lost, per report.",
+        );
+        for (labels, series) in labelled() {
+            series.loss_ratio.render_series(
+                &mut out,
+                "quic_proxy_client_packet_loss_ratio",
+                &labels,
+            );
+        }
+        write_counter(
+            &mut out,
+            "quic_proxy_client_dropped_datagrams_total",
+            "Out-of-date sequenced datagrams dropped by clients.",
+            labelled().map(|(labels, series)| (labels, series.dropped_datagrams)),
+        );
+        write_counter(
+            &mut out,
+            "quic_proxy_client_reconfigurations_total",
+            "Switches from the Play to the Configuration state reported by clients.",
+            labelled().map(|(labels, series)| (labels, series.reconfigurations)),
+        );
+        out
+    }
+}
+
+pub(super) fn write_counter<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    values: impl Iterator<Item = (Vec<(&'a str, &'a str)>, u64)>,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} counter").unwrap();
+    for (labels, value) in values {
+        let labels: Vec<String> = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\"", histogram::escape_label_value(value)))
+            .collect();
+        if labels.is_empty() {
+            writeln!(out, "{name} {value}").unwrap();
+        } else {
+            writeln!(out, "{name}{{{}}} {value}", labels.join(",")).unwrap();
+        }
+    }
+}
+
+/// Reads the metrics of a gateway, for embedders that expose them
+/// their own way rather than through the admin API. Obtained from
+/// `Gateway::metrics_handle`.
+///
+/// Until the gateway runs, and after it has stopped, there is nothing
+/// to read: `render` returns an empty string, `sessions` no sessions
+/// and `usage` `None`.
+#[derive(Clone, Default)]
+pub struct MetricsHandle {
+    shared: Arc<OnceLock<Weak<Shared>>>,
+}
+
+impl MetricsHandle {
+    pub(super) fn attach(&self, shared: &Arc<Shared>) {
+        self.shared.set(Arc::downgrade(shared)).ok();
+    }
+
+    fn shared(&self) -> Option<Arc<Shared>> {
+        self.shared.get().and_then(Weak::upgrade)
+    }
+
+    /// Renders the metrics in the Prometheus text exposition
+    /// format, as served by the admin API's `GET /metrics`.
+    pub fn render(&self) -> String {
+        self.shared()
+            .map(|shared| shared.render_metrics())
+            .unwrap_or_default()
+    }
+
+    /// Gets summaries of the active sessions, oldest first. Addresses
+    /// are masked unless `include_addresses` is set.
+    pub fn sessions(&self, include_addresses: bool) -> Vec<SessionSummary> {
+        let Some(shared) = self.shared() else {
+            return Vec::new();
+        };
+        let mut sessions: Vec<_> = shared
+            .sessions
+            .list()
+            .iter()
+            .map(|session| session.summary(include_addresses))
+            .collect();
+        sessions.sort_by_key(|session| session.started_at_millis);
+        sessions
+    }
+
+    /// Gets the bytes transferred by each identity and tenant.
+    pub fn usage(&self) -> Option<UsageSnapshot> {
+        let shared = self.shared()?;
+        Some(shared.usage.snapshot(shared.sessions.active_bytes()))
+    }
+}
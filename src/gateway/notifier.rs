@@ -0,0 +1,307 @@
+//! Alerting hooks. Notable gateway events are POSTed as JSON
+//! to the configured webhook URLs.
+//!
+//! The payload contains both a `content` (Discord) and a `text` (Slack)
+//! field with a human-readable message, plus the structured `alert`.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    gateway::config::WebhookConfig,
+};
+use ahash::AHashMap;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// A notable event worth alerting an operator about.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Alert {
+    GatewayStarted {
+        listen_addresses: Vec<SocketAddr>,
+    },
+    GatewayStopped {
+        reason: String,
+    },
+    AuthBruteForce {
+        address: IpAddr,
+        failures: usize,
+        window_secs: u64,
+    },
+    DestinationUnreachable {
+        destination: SocketAddr,
+        error: String,
+    },
+    SessionCountThreshold {
+        sessions: usize,
+        threshold: usize,
+    },
+    CircuitOpened {
+        destination: SocketAddr,
+        open_secs: u64,
+    },
+    AddressBanned {
+        address: IpAddr,
+        failures: usize,
+        ban_secs: u64,
+    },
+}
+
+impl Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Alert::GatewayStarted { listen_addresses } => {
+                write!(f, "Gateway started on ")?;
+                for (i, address) in listen_addresses.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{address}")?;
+                }
+                Ok(())
+            }
+            Alert::GatewayStopped { reason } => write!(f, "Gateway stopped: {reason}"),
+            Alert::AuthBruteForce {
+                address,
+                failures,
+                window_secs,
+            } => write!(
+                f,
+                "Possible brute-force attempt: {failures} failed authentications from {address} in {window_secs}s"
+            ),
+            Alert::DestinationUnreachable { destination, error } => {
+                write!(f, "Destination server {destination} is unreachable: {error}")
+            }
+            Alert::SessionCountThreshold {
+                sessions,
+                threshold,
+            } => write!(
+                f,
+                "{sessions} concurrent sessions (alert threshold is {threshold})"
+            ),
+            Alert::CircuitOpened {
+                destination,
+                open_secs,
+            } => write!(
+                f,
+                "Destination server {destination} keeps failing; rejecting connections to it for {open_secs}s"
+            ),
+            Alert::AddressBanned {
+                address,
+                failures,
+                ban_secs,
+            } => write!(
+                f,
+                "Banned {address} for {ban_secs}s after {failures} failed authentications"
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    content: String,
+    text: String,
+    alert: &'a Alert,
+}
+
+/// Sends alerts to webhooks. Cheap to clone.
+///
+/// If no webhook URLs are configured, alerts are only logged.
+#[derive(Clone)]
+pub(crate) struct Notifier {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    client: reqwest::Client,
+    urls: Vec<String>,
+}
+
+/// Timeout for a single webhook delivery.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Notifier {
+    pub fn new(config: &WebhookConfig) -> anyhow::Result<Self> {
+        if config.urls.is_empty() {
+            return Ok(Self::disabled());
+        }
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            inner: Some(Arc::new(Inner {
+                client,
+                urls: config.urls.clone(),
+            })),
+        })
+    }
+
+    pub fn disabled() -> Self {
+        Self { inner: None }
+    }
+
+    /// Sends an alert in the background.
+    pub fn notify(&self, alert: Alert) {
+        let this = self.clone();
+        tokio::spawn(async move { this.notify_and_wait(alert).await });
+    }
+
+    /// Sends an alert, returning once all webhooks have been attempted.
+    ///
+    /// Delivery failures are logged, not returned.
+    pub async fn notify_and_wait(&self, alert: Alert) {
+        tracing::info!("Alert: {alert}");
+        let Some(inner) = &self.inner else {
+            return;
+        };
+
+        let message = alert.to_string();
+        let payload = Payload {
+            content: message.clone(),
+            text: message,
+            alert: &alert,
+        };
+        for url in &inner.urls {
+            let result = inner
+                .client
+                .post(url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                tracing::warn!("Failed to deliver alert to webhook: {e}");
+            }
+        }
+    }
+}
+
+/// Detects brute-force authentication attempts by counting
+/// failures per source IP over a sliding window.
+pub(crate) struct BruteForceDetector {
+    threshold: usize,
+    window: Duration,
+    failures: Mutex<AHashMap<IpAddr, VecDeque<Instant>>>,
+    clock: SharedClock,
+}
+
+impl BruteForceDetector {
+    pub fn new(config: &WebhookConfig, clock: SharedClock) -> Self {
+        Self {
+            threshold: config.auth_failure_threshold,
+            window: Duration::from_secs(config.auth_failure_window_secs),
+            failures: Mutex::new(AHashMap::new()),
+            clock,
+        }
+    }
+
+    /// Records a failed authentication. Returns an alert
+    /// the first time the address reaches the threshold within the window.
+    pub fn record_failure(&self, address: IpAddr) -> Option<Alert> {
+        let now = self.clock.now();
+        let mut failures = self.failures.lock().unwrap();
+        failures.retain(|_, times| {
+            times.retain(|&time| now.duration_since(time) < self.window);
+            !times.is_empty()
+        });
+
+        let times = failures.entry(address).or_default();
+        times.push_back(now);
+        (times.len() == self.threshold).then_some(Alert::AuthBruteForce {
+            address,
+            failures: times.len(),
+            window_secs: self.window.as_secs(),
+        })
+    }
+}
+
+/// Detects the number of concurrent sessions reaching the alert threshold.
+///
+/// Alerts once per crossing: after an alert, the count has to fall below
+/// 90% of the threshold before it alerts again, so that a count hovering
+/// around the threshold does not flood the webhooks.
+pub(crate) struct SessionCountDetector {
+    threshold: Option<usize>,
+    alerted: AtomicBool,
+}
+
+impl SessionCountDetector {
+    pub fn new(config: &WebhookConfig) -> Self {
+        Self {
+            threshold: config.session_count_threshold,
+            alerted: AtomicBool::new(false),
+        }
+    }
+
+    /// Records the current number of sessions. Returns an alert
+    /// if it reached the threshold since the detector was re-armed.
+    pub fn record(&self, sessions: usize) -> Option<Alert> {
+        let threshold = self.threshold?;
+        if sessions >= threshold {
+            let alerted = self.alerted.swap(true, Ordering::Relaxed);
+            (!alerted).then_some(Alert::SessionCountThreshold {
+                sessions,
+                threshold,
+            })
+        } else {
+            if sessions.saturating_mul(10) < threshold.saturating_mul(9) {
+                self.alerted.store(false, Ordering::Relaxed);
+            }
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn detector(threshold: usize) -> SessionCountDetector {
+        SessionCountDetector::new(&WebhookConfig {
+            session_count_threshold: Some(threshold),
+            ..WebhookConfig::default()
+        })
+    }
+
+    #[test]
+    fn session_count_alerts_once_until_rearmed() {
+        let detector = detector(100);
+        assert!(detector.record(99).is_none());
+        assert!(detector.record(100).is_some());
+        assert!(detector.record(101).is_none());
+        // Oscillating around the threshold does not alert again.
+        assert!(detector.record(99).is_none());
+        assert!(detector.record(90).is_none());
+        assert!(detector.record(100).is_none());
+        // Falling below 90% of the threshold re-arms it.
+        assert!(detector.record(89).is_none());
+        assert!(detector.record(100).is_some());
+    }
+
+    #[test]
+    fn session_count_alerts_when_jumping_past_threshold() {
+        let detector = detector(100);
+        assert!(matches!(
+            detector.record(120),
+            Some(Alert::SessionCountThreshold {
+                sessions: 120,
+                threshold: 100
+            })
+        ));
+    }
+
+    #[test]
+    fn session_count_never_alerts_without_threshold() {
+        let detector = SessionCountDetector::new(&WebhookConfig::default());
+        assert!(detector.record(usize::MAX).is_none());
+    }
+}
@@ -0,0 +1,572 @@
+//! Destination policies: allowlists, session quotas and connection rate limits.
+//!
+//! A policy can be scoped globally, to a listener, to a tenant, or to an
+//! authenticated identity. A connection must satisfy every policy that
+//! applies to it.
+//!
+//! Regardless of policies, destinations in ranges internal to the gateway's
+//! host or network (loopback, private, link-local, shared and unspecified
+//! addresses) are refused, unless allowed in `private_destinations`.
+//!
+//! Host name rules in allowlists are matched against the host name the
+//! client named the destination by, if any, and against the names the
+//! gateway finds for its address at admission: exact names by resolving
+//! them, and wildcards by a reverse lookup confirmed by resolving the name
+//! it returns.
+
+use crate::{
+    clock,
+    clock::{Instant, SharedClock},
+    gateway::{
+        config::{
+            DestinationRule, DestinationTarget, GatewayConfig, PolicyConfig,
+            PrivateDestinationsConfig,
+        },
+        DEFAULT_LISTENER,
+    },
+};
+use ahash::AHashMap;
+use futures::future;
+use hickory_resolver::TokioAsyncResolver;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    iter,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+/// Window over which `max_connections_per_minute` is enforced.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+/// Time after which host names not yet found for a destination are
+/// given up on, so that its host name rules do not match.
+const HOST_NAMES_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a policy applies to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyScope {
+    Global,
+    Listener(String),
+    Tenant(String),
+    Identity(String),
+}
+
+impl Display for PolicyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyScope::Global => write!(f, "global policy"),
+            PolicyScope::Listener(name) => write!(f, "policy of listener {name}"),
+            PolicyScope::Tenant(name) => write!(f, "policy of tenant {name}"),
+            PolicyScope::Identity(name) => write!(f, "policy of identity {name}"),
+        }
+    }
+}
+
+/// Reason a connection was rejected by a policy.
+#[derive(Debug, thiserror::Error)]
+pub enum PolicyViolation {
+    #[error("destination {destination} is not allowed by {scope}")]
+    DestinationNotAllowed {
+        scope: PolicyScope,
+        destination: SocketAddr,
+    },
+    #[error("session quota of {limit} reached for {scope}")]
+    SessionQuotaExceeded { scope: PolicyScope, limit: usize },
+    #[error("rate limit of {limit} connections per minute reached for {scope}")]
+    RateLimited { scope: PolicyScope, limit: usize },
+    #[error("destination {destination} is in a private address range")]
+    PrivateDestination { destination: SocketAddr },
+}
+
+/// A hypothetical connection to check against the policies.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyEvaluationRequest {
+    /// Name of the identity the connection authenticates as.
+    pub identity: String,
+    pub destination: SocketAddr,
+    /// Listener the connection arrives on.
+    #[serde(default = "default_listener")]
+    pub listener: String,
+}
+
+fn default_listener() -> String {
+    DEFAULT_LISTENER.to_owned()
+}
+
+/// Outcome of checking a hypothetical connection against the policies,
+/// without admitting it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyEvaluation {
+    pub allowed: bool,
+    /// Host names found for the destination, if any policy has host name rules.
+    pub host_names: Vec<String>,
+    /// Each policy that applies, in the order they are checked.
+    pub policies: Vec<PolicyCheck>,
+}
+
+/// Outcome of one policy in a `PolicyEvaluation`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PolicyCheck {
+    /// The policy's scope, e.g. "policy of tenant acme".
+    pub scope: String,
+    /// Allowlist rule matching the destination. `None` if
+    /// the policy has no allowlist or no rule matches.
+    pub matched_rule: Option<DestinationRule>,
+    /// Why the policy would reject the connection, if it would.
+    pub violation: Option<String>,
+}
+
+impl Display for PolicyEvaluation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", if self.allowed { "ALLOWED" } else { "DENIED" })?;
+        if !self.host_names.is_empty() {
+            writeln!(f, "Destination host names: {}", self.host_names.join(", "))?;
+        }
+        for check in &self.policies {
+            match (&check.violation, &check.matched_rule) {
+                (Some(violation), _) => writeln!(f, "  {}: denied: {violation}", check.scope)?,
+                (None, Some(rule)) => writeln!(f, "  {}: allowed by rule {rule}", check.scope)?,
+                (None, None) => writeln!(f, "  {}: allowed", check.scope)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A policy together with its usage state.
+struct ScopedPolicy {
+    scope: PolicyScope,
+    config: PolicyConfig,
+    active_sessions: AtomicUsize,
+    recent_connections: Mutex<VecDeque<Instant>>,
+}
+
+impl ScopedPolicy {
+    fn new(scope: PolicyScope, config: PolicyConfig) -> Arc<Self> {
+        Arc::new(Self {
+            scope,
+            config,
+            active_sessions: AtomicUsize::new(0),
+            recent_connections: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    /// Gets the host name patterns in the policy's allowlist.
+    fn host_patterns(&self) -> impl Iterator<Item = &str> {
+        self.config
+            .allowed_destinations
+            .iter()
+            .flatten()
+            .filter_map(|rule| match &rule.target {
+                DestinationTarget::Host(pattern) => Some(pattern.as_str()),
+                _ => None,
+            })
+    }
+
+    /// Gets the first allowlist rule matching `destination`.
+    fn matching_rule(
+        &self,
+        destination: SocketAddr,
+        host_names: &[String],
+    ) -> Option<&DestinationRule> {
+        self.config
+            .allowed_destinations
+            .as_ref()?
+            .iter()
+            .find(|rule| rule.matches(destination, host_names))
+    }
+
+    /// Whether the policy's allowlist, if any, allows `destination`.
+    fn allows(&self, destination: SocketAddr, host_names: &[String]) -> bool {
+        self.config.allowed_destinations.is_none()
+            || self.matching_rule(destination, host_names).is_some()
+    }
+
+    fn check(
+        &self,
+        destination: SocketAddr,
+        host_names: &[String],
+        now: Instant,
+    ) -> Result<(), PolicyViolation> {
+        if !self.allows(destination, host_names) {
+            return Err(PolicyViolation::DestinationNotAllowed {
+                scope: self.scope.clone(),
+                destination,
+            });
+        }
+
+        if let Some(limit) = self.config.max_sessions {
+            if self.active_sessions.load(Ordering::Relaxed) >= limit {
+                return Err(PolicyViolation::SessionQuotaExceeded {
+                    scope: self.scope.clone(),
+                    limit,
+                });
+            }
+        }
+
+        if let Some(limit) = self.config.max_connections_per_minute {
+            let mut recent = self.recent_connections.lock().unwrap();
+            while recent
+                .front()
+                .is_some_and(|&time| now.duration_since(time) >= RATE_LIMIT_WINDOW)
+            {
+                recent.pop_front();
+            }
+            if recent.len() >= limit {
+                return Err(PolicyViolation::RateLimited {
+                    scope: self.scope.clone(),
+                    limit,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    fn record_admission(&self, now: Instant) {
+        self.active_sessions.fetch_add(1, Ordering::Relaxed);
+        if self.config.max_connections_per_minute.is_some() {
+            self.recent_connections.lock().unwrap().push_back(now);
+        }
+    }
+}
+
+/// All policies of a gateway.
+pub(crate) struct Policies {
+    global: Arc<ScopedPolicy>,
+    listeners: AHashMap<String, Arc<ScopedPolicy>>,
+    tenants: AHashMap<String, Arc<ScopedPolicy>>,
+    identities: AHashMap<String, Arc<ScopedPolicy>>,
+    private_destinations: PrivateDestinationsConfig,
+    /// Serializes admission so that concurrent connections
+    /// cannot together exceed a quota.
+    admission_lock: Mutex<()>,
+    host_names: HostNames,
+    clock: SharedClock,
+}
+
+impl Policies {
+    pub fn new(config: &GatewayConfig, clock: SharedClock) -> Self {
+        Self {
+            global: ScopedPolicy::new(PolicyScope::Global, config.policy.clone()),
+            listeners: config
+                .listeners
+                .iter()
+                .map(|listener| (&listener.name, &listener.policy))
+                .chain(
+                    config
+                        .vanilla_listener
+                        .iter()
+                        .map(|listener| (&listener.name, &listener.policy)),
+                )
+                .map(|(name, policy)| {
+                    (
+                        name.clone(),
+                        ScopedPolicy::new(PolicyScope::Listener(name.clone()), policy.clone()),
+                    )
+                })
+                .collect(),
+            tenants: config
+                .tenants
+                .iter()
+                .map(|tenant| {
+                    (
+                        tenant.name.clone(),
+                        ScopedPolicy::new(
+                            PolicyScope::Tenant(tenant.name.clone()),
+                            tenant.policy.clone(),
+                        ),
+                    )
+                })
+                .collect(),
+            identities: config
+                .identities
+                .iter()
+                .map(|identity| (identity.name.clone(), identity))
+                .chain(config.tenants.iter().flat_map(|tenant| {
+                    tenant
+                        .identities
+                        .iter()
+                        .map(|identity| (tenant.identity_name(identity), identity))
+                }))
+                .map(|(name, identity)| {
+                    (
+                        name.clone(),
+                        ScopedPolicy::new(PolicyScope::Identity(name), identity.policy.clone()),
+                    )
+                })
+                .collect(),
+            private_destinations: config.private_destinations.clone(),
+            admission_lock: Mutex::new(()),
+            host_names: HostNames::default(),
+            clock,
+        }
+    }
+
+    /// Checks whether a connection on `listener`, authenticated as `identity`
+    /// (of `tenant`, if any), may proxy to one of `addresses`, the addresses
+    /// of its destination (named `host` by the client, if by host name).
+    /// Vanilla connections do not authenticate, so have no identity.
+    ///
+    /// On success, returns the addresses that may be dialed, in their
+    /// original order, and a permit that counts towards session quotas
+    /// until it is dropped.
+    pub async fn admit(
+        &self,
+        listener: &str,
+        tenant: Option<&str>,
+        identity: Option<&str>,
+        addresses: &[SocketAddr],
+        host: Option<&str>,
+    ) -> Result<(PolicyPermit, Vec<SocketAddr>), PolicyViolation> {
+        let policies = self.applicable(listener, tenant, identity);
+        let host_names = future::join_all(
+            addresses
+                .iter()
+                .map(|&address| self.host_names_of(address, host, &policies)),
+        )
+        .await;
+
+        let now = self.clock.now();
+        let _guard = self.admission_lock.lock().unwrap();
+        let allowed: Vec<SocketAddr> = addresses
+            .iter()
+            .zip(&host_names)
+            .filter(|(address, host_names)| {
+                self.check_private(**address).is_ok()
+                    && policies
+                        .iter()
+                        .all(|policy| policy.allows(**address, host_names))
+            })
+            .map(|(&address, _)| address)
+            .collect();
+        // If none is allowed, the first address reports the violation.
+        let checked = addresses
+            .iter()
+            .position(|address| allowed.first() == Some(address))
+            .unwrap_or(0);
+        self.check_private(addresses[checked])?;
+        for policy in &policies {
+            policy.check(addresses[checked], &host_names[checked], now)?;
+        }
+        for policy in &policies {
+            policy.record_admission(now);
+        }
+        Ok((PolicyPermit { policies }, allowed))
+    }
+
+    /// Checks a connection like `admit`, but without admitting it,
+    /// reporting the outcome of each policy that applies.
+    pub async fn evaluate(
+        &self,
+        listener: &str,
+        tenant: Option<&str>,
+        identity: &str,
+        destination: SocketAddr,
+    ) -> PolicyEvaluation {
+        let policies = self.applicable(listener, tenant, Some(identity));
+        let host_names = self.host_names_of(destination, None, &policies).await;
+        let now = self.clock.now();
+        let private_check = PolicyCheck {
+            scope: "private destination guard".to_owned(),
+            matched_rule: None,
+            violation: self
+                .check_private(destination)
+                .err()
+                .map(|violation| violation.to_string()),
+        };
+        let checks: Vec<_> = iter::once(private_check)
+            .chain(policies.iter().map(|policy| {
+                PolicyCheck {
+                    scope: policy.scope.to_string(),
+                    matched_rule: policy.matching_rule(destination, &host_names).cloned(),
+                    violation: policy
+                        .check(destination, &host_names, now)
+                        .err()
+                        .map(|violation| violation.to_string()),
+                }
+            }))
+            .collect();
+        PolicyEvaluation {
+            allowed: checks.iter().all(|check| check.violation.is_none()),
+            host_names,
+            policies: checks,
+        }
+    }
+
+    /// Refuses `destination` if it is private and not allowed.
+    fn check_private(&self, destination: SocketAddr) -> Result<(), PolicyViolation> {
+        let allowed = &self.private_destinations;
+        if !is_private(destination.ip())
+            || allowed.allow_all
+            || allowed
+                .allowed
+                .iter()
+                .any(|rule| rule.matches(destination, &[]))
+        {
+            return Ok(());
+        }
+        Err(PolicyViolation::PrivateDestination { destination })
+    }
+
+    fn applicable(
+        &self,
+        listener: &str,
+        tenant: Option<&str>,
+        identity: Option<&str>,
+    ) -> Vec<Arc<ScopedPolicy>> {
+        [
+            Some(&self.global),
+            self.listeners.get(listener),
+            tenant.and_then(|tenant| self.tenants.get(tenant)),
+            identity.and_then(|identity| self.identities.get(identity)),
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect()
+    }
+
+    /// Finds the host names of `destination` that the host name rules of
+    /// `policies` could match, starting with `host`, the name the gateway
+    /// resolved to it, if any. Empty if they have none.
+    async fn host_names_of(
+        &self,
+        destination: SocketAddr,
+        host: Option<&str>,
+        policies: &[Arc<ScopedPolicy>],
+    ) -> Vec<String> {
+        let patterns: Vec<&str> = policies
+            .iter()
+            .flat_map(|policy| policy.host_patterns())
+            .collect();
+        if patterns.is_empty() {
+            return Vec::new();
+        }
+        let found = clock::timeout(
+            &*self.clock,
+            HOST_NAMES_TIMEOUT,
+            self.host_names.find(destination.ip(), &patterns),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            tracing::warn!("Timed out finding the host names of {destination}");
+            Vec::new()
+        });
+        host.map(str::to_owned)
+            .into_iter()
+            .chain(found.into_iter().filter(|name| Some(name.as_str()) != host))
+            .collect()
+    }
+}
+
+/// Finds the host names of destinations, for matching host name rules.
+#[derive(Default)]
+struct HostNames {
+    /// Created on first use, since only wildcard rules need it.
+    /// `None` if the system's DNS configuration could not be read.
+    reverse_resolver: OnceCell<Option<TokioAsyncResolver>>,
+}
+
+impl HostNames {
+    /// Gets the names among `patterns` that resolve to `ip`, and, if there
+    /// are wildcard patterns, the names `ip` reverse resolves to that
+    /// resolve back to it.
+    async fn find(&self, ip: IpAddr, patterns: &[&str]) -> Vec<String> {
+        let exact = patterns
+            .iter()
+            .filter(|pattern| !pattern.starts_with("*."))
+            .map(|&name| async move { resolves_to(name, ip).await.then(|| name.to_owned()) });
+        let mut names: Vec<String> = future::join_all(exact)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        if patterns.iter().any(|pattern| pattern.starts_with("*.")) {
+            names.extend(self.reverse_lookup(ip).await);
+        }
+        names
+    }
+
+    async fn reverse_lookup(&self, ip: IpAddr) -> Vec<String> {
+        let resolver = self.reverse_resolver.get_or_init(|| {
+            TokioAsyncResolver::tokio_from_system_conf()
+                .inspect_err(|e| {
+                    tracing::warn!("Wildcard destination rules cannot match: {e}");
+                })
+                .ok()
+        });
+        let Some(resolver) = resolver else {
+            return Vec::new();
+        };
+        let lookup = match resolver.reverse_lookup(ip).await {
+            Ok(lookup) => lookup,
+            Err(e) => {
+                tracing::debug!("Reverse lookup of {ip} failed: {e}");
+                return Vec::new();
+            }
+        };
+        let candidates = lookup.iter().map(|name| {
+            let name = name.to_string().trim_end_matches('.').to_ascii_lowercase();
+            async move { resolves_to(&name, ip).await.then_some(name) }
+        });
+        future::join_all(candidates)
+            .await
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+}
+
+/// Checks whether `name` resolves to `ip` with the system resolver.
+async fn resolves_to(name: &str, ip: IpAddr) -> bool {
+    match tokio::net::lookup_host((name, 0)).await {
+        Ok(mut addresses) => addresses.any(|address| address.ip() == ip),
+        Err(e) => {
+            tracing::debug!("Failed to resolve {name}: {e}");
+            false
+        }
+    }
+}
+
+/// Holds a connection's place in the session quotas of its policies.
+pub(crate) struct PolicyPermit {
+    policies: Vec<Arc<ScopedPolicy>>,
+}
+
+impl Drop for PolicyPermit {
+    fn drop(&mut self) {
+        for policy in &self.policies {
+            policy.active_sessions.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Whether `ip` is in a range internal to a host or network: loopback,
+/// private (RFC 1918, unique local), link-local, shared (RFC 6598),
+/// broadcast or unspecified.
+fn is_private(ip: IpAddr) -> bool {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || a == 0
+                || (a == 100 && (b & 0xc0) == 64)
+        }
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+        }
+    }
+}
@@ -0,0 +1,131 @@
+//! PROXY protocol v2 headers, sent at the start of the TCP connection to
+//! the destination server so that it sees the player's address rather
+//! than the gateway's (e.g. Paper and Velocity with `proxy-protocol`
+//! enabled).
+//!
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, SocketAddr};
+
+const SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Version 2, PROXY command.
+const VERSION_COMMAND: u8 = 0x21;
+/// Address families with the stream transport.
+const TCP_OVER_IPV4: u8 = 0x11;
+const TCP_OVER_IPV6: u8 = 0x21;
+
+/// Builds the header of a connection proxied from `source`, the player,
+/// to `destination`.
+///
+/// If only one of the addresses is IPv6, the other is
+/// written as an IPv4-mapped IPv6 address.
+pub fn header(source: SocketAddr, destination: SocketAddr) -> Vec<u8> {
+    let source_ip = source.ip().to_canonical();
+    let destination_ip = destination.ip().to_canonical();
+
+    let mut header = SIGNATURE.to_vec();
+    header.push(VERSION_COMMAND);
+    match (source_ip, destination_ip) {
+        (IpAddr::V4(source_ip), IpAddr::V4(destination_ip)) => {
+            header.push(TCP_OVER_IPV4);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&source_ip.octets());
+            header.extend_from_slice(&destination_ip.octets());
+        }
+        (source_ip, destination_ip) => {
+            let to_v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            header.push(TCP_OVER_IPV6);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_v6(source_ip).octets());
+            header.extend_from_slice(&to_v6(destination_ip).octets());
+        }
+    }
+    header.extend_from_slice(&source.port().to_be_bytes());
+    header.extend_from_slice(&destination.port().to_be_bytes());
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The signature every v2 header starts with, from the specification.
+    const SPEC_SIGNATURE: [u8; 12] = [
+        0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a,
+    ];
+
+    fn expected(family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut expected = SPEC_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, family]);
+        expected.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        expected.extend_from_slice(addresses);
+        expected
+    }
+
+    #[test]
+    fn encodes_ipv4_header() {
+        let header = header(
+            "203.0.113.7:51234".parse().unwrap(),
+            "10.0.0.5:25565".parse().unwrap(),
+        );
+        #[rustfmt::skip]
+        let addresses = [
+            203, 0, 113, 7,
+            10, 0, 0, 5,
+            0xc8, 0x22,
+            0x63, 0xdd,
+        ];
+        assert_eq!(header, expected(0x11, &addresses));
+        assert_eq!(header.len(), 16 + 12);
+    }
+
+    #[test]
+    fn encodes_ipv6_header() {
+        let header = header(
+            "[2001:db8::7]:51234".parse().unwrap(),
+            "[2001:db8:1::5]:25565".parse().unwrap(),
+        );
+        #[rustfmt::skip]
+        let addresses = [
+            0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x07,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05,
+            0xc8, 0x22,
+            0x63, 0xdd,
+        ];
+        assert_eq!(header, expected(0x21, &addresses));
+        assert_eq!(header.len(), 16 + 36);
+    }
+
+    #[test]
+    fn maps_ipv4_address_when_mixed_with_ipv6() {
+        let header = header(
+            "203.0.113.7:51234".parse().unwrap(),
+            "[2001:db8:1::5]:25565".parse().unwrap(),
+        );
+        #[rustfmt::skip]
+        let addresses = [
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 203, 0, 113, 7,
+            0x20, 0x01, 0x0d, 0xb8, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x05,
+            0xc8, 0x22,
+            0x63, 0xdd,
+        ];
+        assert_eq!(header, expected(0x21, &addresses));
+    }
+
+    #[test]
+    fn unmaps_ipv4_mapped_addresses() {
+        assert_eq!(
+            header(
+                "[::ffff:203.0.113.7]:51234".parse().unwrap(),
+                "10.0.0.5:25565".parse().unwrap(),
+            ),
+            header(
+                "203.0.113.7:51234".parse().unwrap(),
+                "10.0.0.5:25565".parse().unwrap(),
+            )
+        );
+    }
+}
@@ -0,0 +1,172 @@
+//! Per source IP rate limiting of new connections, so that a single
+//! address cannot exhaust the gateway or use it to hammer destinations.
+//!
+//! Three events are counted per IP over a sliding window: accepted QUIC
+//! connections, control stream handshakes (`ConnectTo` messages) and failed
+//! authentication attempts. Connections over the limit are refused before
+//! their QUIC handshake completes. Handshakes over the limit, or from an
+//! address with too many recent authentication failures, are rejected
+//! before the key is verified and before the destination is dialed.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    gateway::config::RateLimitConfig,
+};
+use ahash::AHashMap;
+use std::{collections::VecDeque, fmt, net::IpAddr, sync::Mutex, time::Duration};
+
+/// An event limited per source IP.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    Connections,
+    Handshakes,
+    AuthFailures,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Connections => "connections",
+            Limit::Handshakes => "handshakes",
+            Limit::AuthFailures => "failed authentication attempts",
+        })
+    }
+}
+
+/// Returned when an address has exceeded one of its limits.
+#[derive(Debug, thiserror::Error)]
+#[error(
+    "too many {limit} from {address}; retrying in {}s",
+    retry_in.as_secs().max(1)
+)]
+pub struct RateLimited {
+    pub address: IpAddr,
+    pub limit: Limit,
+    pub retry_in: Duration,
+}
+
+/// Times of the events of one kind within the window, per address.
+#[derive(Default)]
+struct Window {
+    events: AHashMap<IpAddr, VecDeque<Instant>>,
+}
+
+impl Window {
+    /// Forgets events that have left the window.
+    fn expire(&mut self, now: Instant, window: Duration) {
+        self.events.retain(|_, times| {
+            while times
+                .front()
+                .is_some_and(|&time| now.duration_since(time) >= window)
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+    }
+
+    /// Gets the time until the address is below `max` events again,
+    /// or `None` if it already is.
+    fn exceeded(
+        &self,
+        address: IpAddr,
+        max: usize,
+        now: Instant,
+        window: Duration,
+    ) -> Option<Duration> {
+        let times = self.events.get(&address)?;
+        if times.len() < max {
+            return None;
+        }
+        let oldest = times[times.len() - max];
+        Some(window.saturating_sub(now.duration_since(oldest)))
+    }
+
+    fn record(&mut self, address: IpAddr, now: Instant) {
+        self.events.entry(address).or_default().push_back(now);
+    }
+}
+
+#[derive(Default)]
+struct Windows {
+    connections: Window,
+    handshakes: Window,
+    auth_failures: Window,
+}
+
+impl Windows {
+    fn get_mut(&mut self, limit: Limit) -> &mut Window {
+        match limit {
+            Limit::Connections => &mut self.connections,
+            Limit::Handshakes => &mut self.handshakes,
+            Limit::AuthFailures => &mut self.auth_failures,
+        }
+    }
+}
+
+pub(crate) struct RateLimiter {
+    config: RateLimitConfig,
+    windows: Mutex<Windows>,
+    clock: SharedClock,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig, clock: SharedClock) -> Self {
+        Self {
+            config: config.clone(),
+            windows: Mutex::default(),
+            clock,
+        }
+    }
+
+    /// Checks whether a new connection from `address` may be accepted,
+    /// counting it if so.
+    pub fn admit_connection(&self, address: IpAddr) -> Result<(), RateLimited> {
+        self.admit(
+            address,
+            &[(Limit::Connections, self.config.max_connections)],
+        )
+    }
+
+    /// Checks whether a control stream handshake from `address` may
+    /// proceed to authentication, counting it if so.
+    pub fn admit_handshake(&self, address: IpAddr) -> Result<(), RateLimited> {
+        self.admit(
+            address,
+            &[
+                (Limit::AuthFailures, self.config.max_auth_failures),
+                (Limit::Handshakes, self.config.max_handshakes),
+            ],
+        )
+    }
+
+    pub fn record_auth_failure(&self, address: IpAddr) {
+        let now = self.clock.now();
+        let mut windows = self.windows.lock().unwrap();
+        windows.auth_failures.expire(now, self.config.window());
+        windows.auth_failures.record(address, now);
+    }
+
+    /// Checks `limits` in order, and counts the event against the last
+    /// of them if none is exceeded. The others only gate it.
+    fn admit(&self, address: IpAddr, limits: &[(Limit, usize)]) -> Result<(), RateLimited> {
+        let now = self.clock.now();
+        let window = self.config.window();
+        let mut windows = self.windows.lock().unwrap();
+        for &(limit, max) in limits {
+            let events = windows.get_mut(limit);
+            events.expire(now, window);
+            if let Some(retry_in) = events.exceeded(address, max, now, window) {
+                return Err(RateLimited {
+                    address,
+                    limit,
+                    retry_in,
+                });
+            }
+        }
+        if let Some(&(limit, _)) = limits.last() {
+            windows.get_mut(limit).record(address, now);
+        }
+        Ok(())
+    }
+}
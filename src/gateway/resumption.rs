@@ -0,0 +1,167 @@
+//! Resumption of Play sessions whose client connection dropped.
+//!
+//! Clients that request it are granted a token when they connect. While
+//! the session is in the Play state, the token is registered here. If the
+//! client connection drops, the session keeps the connection to the
+//! destination server open for the grace time, answering its keepalives
+//! and holding its other packets. A client that reconnects in time and
+//! presents the token takes the session over on its new connection, and
+//! receives the held packets.
+//!
+//! Packets in flight on the dropped connection are lost, which the game
+//! usually tolerates for movement and entity updates, but not always for
+//! chunks. A client may also present the token before the gateway noticed
+//! the drop, in which case the old connection is closed in favor of the new.
+
+use crate::{
+    control_stream::{self, ResumptionToken},
+    gateway::config::ResumptionConfig,
+    hold::HeldPackets,
+    protocol::{
+        optimized_codec::CodecVersion,
+        packet::{client, server, side, state},
+    },
+    proxy::{PacketIo, VanillaPacketIo},
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use quinn::{Connection, ConnectionError};
+use rand::RngCore;
+use std::sync::Mutex;
+use tokio::{select, sync::oneshot};
+
+/// A new connection taking over a session.
+pub(crate) struct Resumption {
+    pub connection: Connection,
+    pub control_stream: control_stream::GatewaySide,
+}
+
+struct Resumable {
+    codec_version: CodecVersion,
+    sender: oneshot::Sender<Resumption>,
+}
+
+/// Sessions that may be resumed, by token.
+#[derive(Default)]
+pub(crate) struct Resumptions {
+    sessions: Mutex<AHashMap<ResumptionToken, Resumable>>,
+}
+
+impl Resumptions {
+    pub fn generate_token() -> ResumptionToken {
+        let mut token = ResumptionToken::default();
+        rand::thread_rng().fill_bytes(&mut token);
+        token
+    }
+
+    /// Lets a session in the Play state be resumed
+    /// with `token` until the registration is dropped.
+    pub fn register(&self, token: ResumptionToken, codec_version: CodecVersion) -> Registration {
+        let (sender, receiver) = oneshot::channel();
+        self.sessions.lock().unwrap().insert(
+            token,
+            Resumable {
+                codec_version,
+                sender,
+            },
+        );
+        Registration {
+            resumptions: self,
+            token,
+            receiver,
+        }
+    }
+
+    /// Claims the session registered with `token`, returning the sender
+    /// to hand the new connection over with. Fails if there is none or it
+    /// uses another codec version than the client supports.
+    pub fn claim(
+        &self,
+        token: &ResumptionToken,
+        codec_versions: &[u8],
+    ) -> anyhow::Result<oneshot::Sender<Resumption>> {
+        let Some(resumable) = self.sessions.lock().unwrap().remove(token) else {
+            bail!("no session to resume with this token");
+        };
+        if !codec_versions.contains(&resumable.codec_version.as_u8()) {
+            bail!(
+                "session uses codec version {}, which the client does not support",
+                resumable.codec_version.as_u8()
+            );
+        }
+        Ok(resumable.sender)
+    }
+}
+
+/// Registration of a session that may be resumed.
+pub(crate) struct Registration<'a> {
+    resumptions: &'a Resumptions,
+    token: ResumptionToken,
+    receiver: oneshot::Receiver<Resumption>,
+}
+
+impl Registration<'_> {
+    /// Waits for a client to resume the session. Cancel safe.
+    pub async fn wait(&mut self) -> Resumption {
+        match (&mut self.receiver).await {
+            Ok(resumption) => resumption,
+            // The sender is only dropped along with the registration,
+            // or after sending.
+            Err(_) => std::future::pending().await,
+        }
+    }
+
+    /// Waits for the client to resume the session after its connection
+    /// dropped, answering the keepalives of the destination server in the
+    /// meantime and buffering its other packets.
+    ///
+    /// Fails if the server disconnects the player or sends more packets than
+    /// may be buffered. The caller bounds the wait by the grace time.
+    pub async fn wait_after_drop(
+        &mut self,
+        server: &VanillaPacketIo<side::Client, state::Play>,
+        config: &ResumptionConfig,
+    ) -> anyhow::Result<(Resumption, Vec<server::play::Packet>)> {
+        let mut held = HeldPackets::new(config.max_buffered_packets);
+        loop {
+            let packet = select! {
+                resumption = self.wait() => return Ok((resumption, held.release().collect())),
+                packet = server.recv_packet() => packet?,
+            };
+            match packet {
+                server::play::Packet::KeepAlive(keepalive) => {
+                    server
+                        .send_packet(client::play::Packet::KeepAlive(client::play::KeepAlive {
+                            ignored_data: keepalive.ignored_data,
+                        }))
+                        .await?;
+                }
+                server::play::Packet::Disconnect(_) => {
+                    bail!("destination server disconnected the player before the client resumed")
+                }
+                packet => held.hold(packet).context(
+                    "destination server sent too many packets before the client resumed",
+                )?,
+            }
+        }
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.resumptions
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.token);
+    }
+}
+
+/// Whether a connection dropped, rather than being closed by either
+/// side, so that its session may be resumed.
+pub(crate) fn dropped(connection: &Connection) -> bool {
+    matches!(
+        connection.close_reason(),
+        Some(ConnectionError::TimedOut | ConnectionError::Reset)
+    )
+}
@@ -0,0 +1,239 @@
+//! Validation of the gateway setup before it starts accepting connections,
+//! so that mistakes are reported up front with an actionable message
+//! instead of failing at the first connection.
+
+use crate::gateway::{config::GatewayConfig, tls};
+use anyhow::{bail, Context};
+use argon2::PasswordHash;
+use rustls::{sign::any_supported_type, SignatureScheme};
+use std::{
+    fmt::{self, Display},
+    net::{SocketAddr, TcpListener, UdpSocket},
+    path::Path,
+};
+
+/// Receive/send buffer size below which UDP throughput may suffer
+/// under load. Matches the recommendation of other QUIC implementations.
+const RECOMMENDED_UDP_BUFFER_SIZE: u64 = 7_500_000;
+
+#[derive(Debug)]
+pub enum CheckOutcome {
+    Passed,
+    /// The gateway can start, but probably not as intended.
+    Warning(String),
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: String,
+    pub outcome: CheckOutcome,
+}
+
+/// Results of the checks run so far.
+#[derive(Debug, Default)]
+pub struct SelfTest {
+    checks: Vec<Check>,
+}
+
+impl SelfTest {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs all checks that only depend on the configuration file.
+    pub fn for_config(config: &GatewayConfig) -> Self {
+        let mut self_test = Self::new();
+        for listener in &config.listeners {
+            for certificate in &listener.certificates {
+                self_test.certificate_pair(
+                    &format!(
+                        "certificate {} of listener {}",
+                        certificate.cert.display(),
+                        listener.name
+                    ),
+                    &certificate.cert,
+                    &certificate.priv_key,
+                );
+            }
+            for &address in listener.listen.addresses() {
+                self_test
+                    .udp_bindable(&format!("listener {} on {address}", listener.name), address);
+            }
+        }
+        for identity in &config.identities {
+            self_test.authentication_key(
+                &format!("authentication key of identity {}", identity.name),
+                &identity.auth_key,
+                config.require_hashed_keys,
+            );
+        }
+        for tenant in &config.tenants {
+            for identity in &tenant.identities {
+                self_test.authentication_key(
+                    &format!(
+                        "authentication key of identity {}",
+                        tenant.identity_name(identity)
+                    ),
+                    &identity.auth_key,
+                    config.require_hashed_keys,
+                );
+            }
+        }
+        if let Some(address) = config.admin.listen {
+            self_test.tcp_bindable("admin API", address);
+        }
+        self_test.udp_buffer_sizes();
+        self_test
+    }
+
+    pub fn checks(&self) -> &[Check] {
+        &self.checks
+    }
+
+    pub fn failed(&self) -> bool {
+        self.checks
+            .iter()
+            .any(|check| matches!(check.outcome, CheckOutcome::Failed(_)))
+    }
+
+    fn record(&mut self, name: &str, result: anyhow::Result<Option<String>>) {
+        let outcome = match result {
+            Ok(None) => CheckOutcome::Passed,
+            Ok(Some(warning)) => CheckOutcome::Warning(warning),
+            Err(e) => CheckOutcome::Failed(format!("{e:#}")),
+        };
+        self.checks.push(Check {
+            name: name.to_owned(),
+            outcome,
+        });
+    }
+
+    /// Checks that the certificate chain and private key load,
+    /// and that the key belongs to the (first) certificate.
+    pub fn certificate_pair(&mut self, name: &str, cert: &Path, priv_key: &Path) {
+        self.record(name, check_certificate_pair(cert, priv_key).map(|()| None));
+    }
+
+    /// Checks that a key meant to be an Argon2 hash actually parses as one.
+    /// Plaintext keys fail if `require_hashed` is set.
+    pub fn authentication_key(&mut self, name: &str, key: &str, require_hashed: bool) {
+        self.record(name, check_authentication_key(key, require_hashed));
+    }
+
+    pub fn udp_bindable(&mut self, name: &str, address: SocketAddr) {
+        let result = UdpSocket::bind(address).map(|_| None).with_context(|| {
+            format!("cannot bind UDP port {address}; is another process using it?")
+        });
+        self.record(name, result);
+    }
+
+    pub fn tcp_bindable(&mut self, name: &str, address: SocketAddr) {
+        let result = TcpListener::bind(address).map(|_| None).with_context(|| {
+            format!("cannot bind TCP port {address}; is another process using it?")
+        });
+        self.record(name, result);
+    }
+
+    /// Checks the maximum UDP socket buffer sizes allowed by the OS.
+    pub fn udp_buffer_sizes(&mut self) {
+        self.record("UDP buffer sizes", check_udp_buffer_sizes());
+    }
+}
+
+impl Display for SelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for check in &self.checks {
+            match &check.outcome {
+                CheckOutcome::Passed => writeln!(f, "[ ok ] {}", check.name)?,
+                CheckOutcome::Warning(warning) => writeln!(f, "[warn] {}: {warning}", check.name)?,
+                CheckOutcome::Failed(error) => writeln!(f, "[FAIL] {}: {error}", check.name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(super) fn check_certificate_pair(cert: &Path, priv_key: &Path) -> anyhow::Result<()> {
+    let cert_chain = tls::load_cert_chain(cert)?;
+    let Some(end_entity) = cert_chain.first() else {
+        bail!("no certificates found in {}", cert.display());
+    };
+    let key = tls::load_private_key(priv_key)?;
+    let key = any_supported_type(&key).context("unsupported private key type")?;
+
+    // Sign a message with the key and verify it with the certificate.
+    let signer = key
+        .choose_scheme(&[
+            SignatureScheme::ED25519,
+            SignatureScheme::ECDSA_NISTP256_SHA256,
+            SignatureScheme::ECDSA_NISTP384_SHA384,
+            SignatureScheme::RSA_PSS_SHA256,
+            SignatureScheme::RSA_PKCS1_SHA256,
+        ])
+        .context("private key supports no usable signature scheme")?;
+    let algorithm = match signer.scheme() {
+        SignatureScheme::ED25519 => &webpki::ED25519,
+        SignatureScheme::ECDSA_NISTP256_SHA256 => &webpki::ECDSA_P256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384 => &webpki::ECDSA_P384_SHA384,
+        SignatureScheme::RSA_PSS_SHA256 => &webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+        _ => &webpki::RSA_PKCS1_2048_8192_SHA256,
+    };
+    let message = b"minecraft-quic-proxy self test";
+    let signature = signer.sign(message)?;
+    let end_entity = webpki::EndEntityCert::try_from(end_entity.0.as_slice())
+        .map_err(|e| anyhow::anyhow!("invalid certificate: {e:?}"))?;
+    if end_entity
+        .verify_signature(algorithm, message, &signature)
+        .is_err()
+    {
+        bail!(
+            "private key {} does not belong to certificate {}",
+            priv_key.display(),
+            cert.display()
+        );
+    }
+    Ok(())
+}
+
+fn check_authentication_key(key: &str, require_hashed: bool) -> anyhow::Result<Option<String>> {
+    if !key.starts_with("$argon2") {
+        if require_hashed {
+            bail!("plaintext key, but hashed keys are required");
+        }
+        return Ok(Some(
+            "plaintext key; consider configuring an Argon2 hash instead".to_owned(),
+        ));
+    }
+    let hash = PasswordHash::new(key)
+        .map_err(|e| anyhow::anyhow!("looks like an Argon2 hash but does not parse: {e}"))?;
+    argon2::Params::try_from(&hash)
+        .map_err(|e| anyhow::anyhow!("Argon2 hash has invalid parameters: {e}"))?;
+    Ok(None)
+}
+
+#[cfg(target_os = "linux")]
+fn check_udp_buffer_sizes() -> anyhow::Result<Option<String>> {
+    let mut too_small = Vec::new();
+    for name in ["rmem_max", "wmem_max"] {
+        let path = format!("/proc/sys/net/core/{name}");
+        let size: u64 = fs_err::read_to_string(&path)?.trim().parse()?;
+        if size < RECOMMENDED_UDP_BUFFER_SIZE {
+            too_small.push(format!("net.core.{name}={size}"));
+        }
+    }
+    if too_small.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(format!(
+            "{} is below the recommended {RECOMMENDED_UDP_BUFFER_SIZE}; \
+             raise with `sysctl -w net.core.rmem_max={RECOMMENDED_UDP_BUFFER_SIZE} net.core.wmem_max={RECOMMENDED_UDP_BUFFER_SIZE}`",
+            too_small.join(", ")
+        )))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn check_udp_buffer_sizes() -> anyhow::Result<Option<String>> {
+    Ok(None)
+}
@@ -0,0 +1,818 @@
+//! Tracks the sessions (proxied connections) active on a gateway,
+//! keeping enough history about each one to export a diagnostics bundle.
+
+use crate::{
+    anomaly::{AnomalyCollector, AnomalySummary},
+    bandwidth::{BandwidthMeter, BandwidthQuota, BandwidthTotals},
+    build_info::BuildInfo,
+    clock,
+    clock::SharedClock,
+    gateway::{
+        config::{GatewayConfig, SessionLimitsConfig, StrictAction, StrictConfig},
+        event_log::{ConnectionEvent, EventLog},
+        metrics::write_counter,
+        session_webhook::SessionWebhooks,
+        usage::UsageStats,
+        ErrorCode,
+    },
+    memory_budget::MemoryBudget,
+    packet_flow::{PacketFlow, PacketFlowObserver},
+    protocol::optimized_codec::CodecVersion,
+    proxy::Instrumentation,
+    stats::{NegotiatedParameters, TransportStats},
+    stream_allocation::{AllocationCounters, AllocationSummary},
+    timeline::{Timeline, TimelineEvent, TimelineSource},
+};
+use ahash::AHashMap;
+use quinn::Connection;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Display},
+    mem,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock, Weak,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio_util::sync::CancellationToken;
+
+/// Maximum number of events retained per session.
+const MAX_EVENTS: usize = 64;
+/// Interval between samples of a session's transport statistics.
+const STATS_SAMPLE_INTERVAL: Duration = Duration::from_secs(5);
+/// Maximum number of statistics samples retained per session
+/// (five minutes at the sampling interval).
+const MAX_STATS_SAMPLES: usize = 60;
+
+/// Identifies a session within a gateway process.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SessionId(u64);
+
+impl Display for SessionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Returned when a connection is refused because
+/// it would exceed a limit on concurrent sessions.
+#[derive(Debug, thiserror::Error)]
+pub enum SessionLimitReached {
+    #[error("the gateway is at its limit of {limit} sessions")]
+    Global { limit: usize },
+    #[error("{address} is at its limit of {limit} sessions")]
+    PerAddress { address: IpAddr, limit: usize },
+}
+
+/// Set of active sessions.
+pub(crate) struct SessionRegistry {
+    next_id: AtomicU64,
+    sessions: Mutex<AHashMap<SessionId, Arc<Session>>>,
+    clock: SharedClock,
+    strict: StrictConfig,
+    usage: Arc<UsageStats>,
+    event_log: Option<Arc<EventLog>>,
+    webhooks: Option<Arc<SessionWebhooks>>,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        clock: SharedClock,
+        strict: StrictConfig,
+        usage: Arc<UsageStats>,
+        event_log: Option<Arc<EventLog>>,
+        webhooks: Option<Arc<SessionWebhooks>>,
+    ) -> Self {
+        Self {
+            next_id: AtomicU64::new(0),
+            sessions: Mutex::new(AHashMap::new()),
+            clock,
+            strict,
+            usage,
+            event_log,
+            webhooks,
+        }
+    }
+
+    /// Registers a new session for the given connection,
+    /// unless that would exceed one of `limits`.
+    ///
+    /// The returned guard unregisters the session when dropped.
+    pub fn register(
+        self: &Arc<Self>,
+        connection: Connection,
+        listener: &str,
+        limits: &SessionLimitsConfig,
+    ) -> Result<SessionGuard, SessionLimitReached> {
+        self.register_client(Client::Quic(connection), listener, limits)
+    }
+
+    /// Registers a new session for a game connected to the vanilla
+    /// listener, like `register`. `closed` is cancelled when the
+    /// session is closed, e.g. through the admin API.
+    pub fn register_vanilla(
+        self: &Arc<Self>,
+        address: SocketAddr,
+        closed: CancellationToken,
+        listener: &str,
+        limits: &SessionLimitsConfig,
+    ) -> Result<SessionGuard, SessionLimitReached> {
+        let client = VanillaClient {
+            address,
+            bytes: Mutex::default(),
+            closed,
+        };
+        self.register_client(Client::Vanilla(Arc::new(client)), listener, limits)
+    }
+
+    fn register_client(
+        self: &Arc<Self>,
+        client: Client,
+        listener: &str,
+        limits: &SessionLimitsConfig,
+    ) -> Result<SessionGuard, SessionLimitReached> {
+        let address = client.remote_address().ip().to_canonical();
+        let (session, active_sessions) = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if let Some(limit) = limits.max_sessions {
+                if sessions.len() >= limit {
+                    return Err(SessionLimitReached::Global { limit });
+                }
+            }
+            if let Some(limit) = limits.max_sessions_per_ip {
+                let from_address = sessions
+                    .values()
+                    .filter(|session| session.client_address().ip().to_canonical() == address)
+                    .count();
+                if from_address >= limit {
+                    return Err(SessionLimitReached::PerAddress { address, limit });
+                }
+            }
+            let id = SessionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+            let session = Arc::new(Session::new(
+                id,
+                client,
+                listener.to_owned(),
+                self.event_log.clone(),
+                self.webhooks.clone(),
+                Arc::clone(&self.clock),
+            ));
+            sessions.insert(id, Arc::clone(&session));
+            (session, sessions.len())
+        };
+        self.usage.record_session(active_sessions);
+        session.spawn_stats_sampler(self.strict.clone());
+        Ok(SessionGuard {
+            registry: Arc::clone(self),
+            session,
+        })
+    }
+
+    pub fn get(&self, id: SessionId) -> Option<Arc<Session>> {
+        self.sessions.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Gets the active sessions, in no particular order.
+    pub fn list(&self) -> Vec<Arc<Session>> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Renders the Play traffic of each active session
+    /// in the Prometheus text exposition format.
+    pub fn render(&self, out: &mut String) {
+        let sessions: Vec<_> = self
+            .list()
+            .into_iter()
+            .map(|session| {
+                let id = session.id.to_string();
+                let identity = session.identity().unwrap_or_default().to_owned();
+                (id, identity, session.bandwidth.totals())
+            })
+            .collect();
+        let series = |value: fn(&BandwidthTotals) -> (u64, u64)| {
+            sessions.iter().flat_map(move |(id, identity, totals)| {
+                let (serverbound, clientbound) = value(totals);
+                [("serverbound", serverbound), ("clientbound", clientbound)].map(
+                    |(direction, value)| {
+                        (
+                            vec![
+                                ("session", id.as_str()),
+                                ("identity", identity.as_str()),
+                                ("direction", direction),
+                            ],
+                            value,
+                        )
+                    },
+                )
+            })
+        };
+        write_counter(
+            out,
+            "quic_proxy_session_packets_total",
+            "Play packets proxied by each active session.",
+            series(|totals| (totals.received_packets, totals.sent_packets)),
+        );
+        write_counter(
+            out,
+            "quic_proxy_session_bytes_total",
+            "Bytes of Play packets proxied by each active session, as sent over QUIC.",
+            series(|totals| (totals.received_bytes, totals.sent_bytes)),
+        );
+    }
+
+    /// Gets the bytes transferred so far by the active sessions.
+    pub fn active_bytes(&self) -> u64 {
+        self.sessions
+            .lock()
+            .unwrap()
+            .values()
+            .map(|session| session.transferred_bytes())
+            .sum()
+    }
+}
+
+/// Keeps a session registered for as long as it is alive.
+pub(crate) struct SessionGuard {
+    registry: Arc<SessionRegistry>,
+    session: Arc<Session>,
+}
+
+impl SessionGuard {
+    pub fn session(&self) -> &Arc<Session> {
+        &self.session
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.registry
+            .sessions
+            .lock()
+            .unwrap()
+            .remove(&self.session.id);
+        self.registry
+            .usage
+            .record_bytes(self.session.transferred_bytes());
+    }
+}
+
+/// The connection to the client of a session.
+#[derive(Clone)]
+enum Client {
+    Quic(Connection),
+    Vanilla(Arc<VanillaClient>),
+}
+
+impl Client {
+    fn remote_address(&self) -> SocketAddr {
+        match self {
+            Client::Quic(connection) => connection.remote_address(),
+            Client::Vanilla(client) => client.address,
+        }
+    }
+
+    /// Gets the bytes sent and received on the connection.
+    fn bytes(&self) -> (u64, u64) {
+        match self {
+            Client::Quic(connection) => {
+                let stats = connection.stats();
+                (stats.udp_tx.bytes, stats.udp_rx.bytes)
+            }
+            Client::Vanilla(client) => *client.bytes.lock().unwrap(),
+        }
+    }
+
+    fn transport_stats(&self) -> TransportStats {
+        match self {
+            Client::Quic(connection) => TransportStats::from_connection(connection),
+            Client::Vanilla(_) => TransportStats::default(),
+        }
+    }
+
+    fn close(&self, code: ErrorCode, reason: &str) {
+        match self {
+            Client::Quic(connection) => connection.close(code.into(), reason.as_bytes()),
+            Client::Vanilla(client) => client.closed.cancel(),
+        }
+    }
+}
+
+/// A game connected to the vanilla listener over plain TCP.
+struct VanillaClient {
+    address: SocketAddr,
+    /// TCP bytes sent to and received from the game,
+    /// counted once the connection has ended.
+    bytes: Mutex<(u64, u64)>,
+    closed: CancellationToken,
+}
+
+/// A single proxied connection.
+pub(crate) struct Session {
+    id: SessionId,
+    /// The client connection, replaced when the session is resumed.
+    client: Mutex<Client>,
+    /// UDP bytes sent and received on connections replaced by resumption.
+    resumed_bytes: Mutex<(u64, u64)>,
+    /// Name of the listener the connection was accepted on.
+    listener: String,
+    started_at: SystemTime,
+    /// Identity the connection authenticated as. Set once.
+    identity: OnceLock<String>,
+    /// Tenant of the identity, if any. Set with the identity.
+    tenant: OnceLock<String>,
+    destination: Mutex<Option<SocketAddr>>,
+    codec_version: Mutex<Option<CodecVersion>>,
+    /// Protocol state the session is in, e.g. `Login`.
+    state: Mutex<&'static str>,
+    /// Build reported by the client, if it supports build reports.
+    client_build_info: OnceLock<BuildInfo>,
+    events: Mutex<VecDeque<Event>>,
+    stats_history: Mutex<VecDeque<StatsSample>>,
+    allocation_counters: Arc<AllocationCounters>,
+    anomalies: Arc<AnomalyCollector>,
+    /// Datagrams from the client dropped as outdated, over all Play states.
+    dropped_datagrams: Arc<AtomicU64>,
+    timeline: Arc<Timeline>,
+    packet_flow: Arc<PacketFlow>,
+    /// Play packets received from and sent to the client.
+    bandwidth: Arc<BandwidthMeter>,
+    /// Bandwidth quota, set once the session has authenticated.
+    quota: OnceLock<Arc<BandwidthQuota>>,
+    /// Limit on the bytes the connection buffers, if configured.
+    memory_budget: OnceLock<Arc<MemoryBudget>>,
+    /// Set once the anomaly score exceeds the maximum in strict mode.
+    flagged: AtomicBool,
+    /// Set once the client's mod has asked for light data to be
+    /// stripped and the gateway agreed. See `STRIP_LIGHT_CHANNEL`.
+    strip_light: AtomicBool,
+    event_log: Option<Arc<EventLog>>,
+    webhooks: Option<Arc<SessionWebhooks>>,
+    clock: SharedClock,
+}
+
+impl Session {
+    fn new(
+        id: SessionId,
+        client: Client,
+        listener: String,
+        event_log: Option<Arc<EventLog>>,
+        webhooks: Option<Arc<SessionWebhooks>>,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            id,
+            client: Mutex::new(client),
+            resumed_bytes: Mutex::default(),
+            listener,
+            started_at: SystemTime::now(),
+            identity: OnceLock::new(),
+            tenant: OnceLock::new(),
+            destination: Mutex::new(None),
+            codec_version: Mutex::new(None),
+            state: Mutex::new("Handshake"),
+            client_build_info: OnceLock::new(),
+            events: Mutex::new(VecDeque::new()),
+            stats_history: Mutex::new(VecDeque::new()),
+            allocation_counters: Arc::default(),
+            anomalies: Arc::new(AnomalyCollector::new(format!("session {id}"))),
+            dropped_datagrams: Arc::default(),
+            timeline: Arc::new(Timeline::new(TimelineSource::Gateway)),
+            packet_flow: Arc::default(),
+            bandwidth: BandwidthMeter::new(Arc::clone(&clock)),
+            quota: OnceLock::new(),
+            memory_budget: OnceLock::new(),
+            flagged: AtomicBool::new(false),
+            strip_light: AtomicBool::new(false),
+            event_log,
+            webhooks,
+            clock,
+        }
+    }
+
+    pub fn id(&self) -> SessionId {
+        self.id
+    }
+
+    pub fn listener(&self) -> &str {
+        &self.listener
+    }
+
+    pub fn client_address(&self) -> SocketAddr {
+        self.client().remote_address()
+    }
+
+    fn client(&self) -> Client {
+        self.client.lock().unwrap().clone()
+    }
+
+    /// Moves the session to the connection that resumed it.
+    pub fn resume_on(&self, connection: Connection) {
+        let previous = mem::replace(&mut *self.client.lock().unwrap(), Client::Quic(connection));
+        let (sent, received) = previous.bytes();
+        let mut resumed_bytes = self.resumed_bytes.lock().unwrap();
+        resumed_bytes.0 += sent;
+        resumed_bytes.1 += received;
+    }
+
+    /// Records the bytes a vanilla connection sent and received,
+    /// once it has ended.
+    pub fn record_vanilla_bytes(&self, sent: u64, received: u64) {
+        if let Client::Vanilla(client) = self.client() {
+            *client.bytes.lock().unwrap() = (sent, received);
+        }
+    }
+
+    /// Closes the connection to the client.
+    pub fn close(&self, code: ErrorCode, reason: &str) {
+        self.client().close(code, reason);
+    }
+
+    /// Gets the bytes sent and received on the session's connections
+    /// so far, including those it was resumed from: UDP bytes for QUIC
+    /// connections, and TCP bytes for vanilla ones.
+    fn client_bytes(&self) -> (u64, u64) {
+        let (sent, received) = self.client().bytes();
+        let (resumed_sent, resumed_received) = *self.resumed_bytes.lock().unwrap();
+        (sent + resumed_sent, received + resumed_received)
+    }
+
+    /// Gets the number of Play packets sent and received.
+    pub fn play_packets(&self) -> u64 {
+        let totals = self.bandwidth.totals();
+        totals.sent_packets + totals.received_packets
+    }
+
+    /// Gets the bytes sent and received on the connection.
+    pub fn transferred_bytes(&self) -> u64 {
+        let (sent, received) = self.client_bytes();
+        sent + received
+    }
+
+    /// Counters that the session's `QuicPacketIo`s should record into.
+    pub fn instrumentation(&self) -> Instrumentation {
+        Instrumentation {
+            allocation_counters: Arc::clone(&self.allocation_counters),
+            anomalies: Arc::clone(&self.anomalies),
+            timeline: Arc::clone(&self.timeline),
+            clock: Arc::clone(&self.clock),
+            bandwidth: Some(Arc::clone(&self.bandwidth)),
+            quota: self.quota.get().cloned(),
+            memory_budget: self.memory_budget.get().cloned(),
+            dropped_datagrams: Arc::clone(&self.dropped_datagrams),
+        }
+    }
+
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
+    pub fn packet_flow(&self) -> &Arc<PacketFlow> {
+        &self.packet_flow
+    }
+
+    /// Attaches an observer to the session's packet flow.
+    pub fn observe(&self) -> PacketFlowObserver {
+        self.packet_flow
+            .observe(Arc::clone(&self.allocation_counters))
+    }
+
+    /// Binds the session to the identity its connection authenticated as,
+    /// and the tenant the identity belongs to.
+    ///
+    /// Fails if the session is already bound to a different identity.
+    pub fn bind_identity(
+        &self,
+        identity: &str,
+        tenant: Option<&str>,
+    ) -> Result<(), IdentityMismatch> {
+        let bound = self.identity.get_or_init(|| {
+            if let Some(tenant) = tenant {
+                self.tenant.set(tenant.to_owned()).ok();
+            }
+            identity.to_owned()
+        });
+        if bound == identity {
+            Ok(())
+        } else {
+            Err(IdentityMismatch {
+                bound: bound.clone(),
+                attempted: identity.to_owned(),
+            })
+        }
+    }
+
+    pub fn identity(&self) -> Option<&str> {
+        self.identity.get().map(String::as_str)
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.get().map(String::as_str)
+    }
+
+    pub fn set_destination(&self, destination: SocketAddr) {
+        *self.destination.lock().unwrap() = Some(destination);
+    }
+
+    /// Limits the session's bandwidth from now on. Only the first quota set applies.
+    pub fn set_quota(&self, quota: Arc<BandwidthQuota>) {
+        self.quota.set(quota).ok();
+    }
+
+    /// Limits the bytes the connection buffers from now on.
+    /// Only the first budget set applies.
+    pub fn set_memory_budget(&self, budget: Arc<MemoryBudget>) {
+        self.memory_budget.set(budget).ok();
+    }
+
+    pub fn memory_budget(&self) -> Option<&Arc<MemoryBudget>> {
+        self.memory_budget.get()
+    }
+
+    pub fn set_codec_version(&self, codec_version: CodecVersion) {
+        *self.codec_version.lock().unwrap() = Some(codec_version);
+    }
+
+    /// Records that the session switched to the given protocol state.
+    pub fn set_state(&self, state: &'static str) {
+        let from = mem::replace(&mut *self.state.lock().unwrap(), state);
+        self.log(ConnectionEvent::StateTransition { from, to: state });
+    }
+
+    pub fn enable_light_stripping(&self) {
+        self.strip_light.store(true, Ordering::Relaxed);
+    }
+
+    /// Whether light data is stripped from the chunks sent to the client.
+    pub fn strips_light(&self) -> bool {
+        self.strip_light.load(Ordering::Relaxed)
+    }
+
+    pub fn set_client_build_info(&self, build_info: BuildInfo) {
+        self.client_build_info.set(build_info).ok();
+    }
+
+    /// Records a notable event in the session's history.
+    ///
+    /// Events must not contain packet contents or secrets.
+    pub fn record_event(&self, message: impl Into<String>) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            timestamp_millis: unix_millis(SystemTime::now()),
+            message: message.into(),
+        });
+    }
+
+    /// Writes an event to the event log and sends it to session
+    /// webhooks, if configured.
+    pub fn log(&self, event: ConnectionEvent) {
+        if let Some(event_log) = &self.event_log {
+            event_log.write(self.id, self.client_address(), &event);
+        }
+        if let Some(webhooks) = &self.webhooks {
+            webhooks.notify(self.id, self.client_address(), &event);
+        }
+    }
+
+    /// Logs the end of the session, with its duration and byte counts.
+    pub fn log_disconnect(&self, error: Option<String>) {
+        let (bytes_sent, bytes_received) = self.client_bytes();
+        self.log(ConnectionEvent::Disconnect {
+            duration_secs: self.duration().as_secs_f64(),
+            bytes_sent,
+            bytes_received,
+            play_traffic: self.bandwidth.totals(),
+            error,
+        });
+    }
+
+    /// Periodically samples the transport statistics, and checks
+    /// the anomaly score in strict mode, until the session is dropped.
+    fn spawn_stats_sampler(self: &Arc<Self>, strict: StrictConfig) {
+        let session = Arc::downgrade(self);
+        tokio::spawn(sample_stats(session, Arc::clone(&self.clock), strict));
+    }
+
+    /// Takes the configured action if the session's anomaly
+    /// score exceeds the maximum. Acts at most once.
+    fn check_anomaly_score(&self, strict: &StrictConfig) {
+        let Some(max_score) = strict.max_anomaly_score else {
+            return;
+        };
+        let score = self.anomalies.score();
+        if score <= max_score || self.flagged.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let reason = format!("anomaly score {score} exceeds the maximum of {max_score}");
+        match strict.action {
+            StrictAction::Disconnect => {
+                tracing::warn!("Session {}: {reason}, disconnecting", self.id);
+                self.record_event(format!("disconnected: {reason}"));
+                self.close(ErrorCode::AnomalyScore, &reason);
+            }
+            StrictAction::Flag => {
+                tracing::warn!("Session {}: {reason}, flagging", self.id);
+                self.record_event(format!("flagged: {reason}"));
+            }
+        }
+    }
+
+    /// Closes the session's connection on behalf of an operator.
+    pub fn disconnect(&self, reason: &str) {
+        tracing::info!(
+            "Session {}: disconnected through the admin API: {reason}",
+            self.id
+        );
+        self.record_event(format!("disconnected through the admin API: {reason}"));
+        self.close(ErrorCode::AdminDisconnect, reason);
+    }
+
+    /// Gets the time since the session started.
+    pub fn duration(&self) -> Duration {
+        self.started_at.elapsed().unwrap_or_default()
+    }
+
+    /// Summarizes the session. Addresses are masked unless `include_addresses` is set.
+    pub fn summary(&self, include_addresses: bool) -> SessionSummary {
+        let mask = |address: SocketAddr| {
+            if include_addresses {
+                address.to_string()
+            } else {
+                mask_address(address.ip())
+            }
+        };
+        let (bytes_sent, bytes_received) = self.client_bytes();
+        SessionSummary {
+            id: self.id,
+            listener: self.listener.clone(),
+            identity: self.identity.get().cloned(),
+            tenant: self.tenant.get().cloned(),
+            client_address: mask(self.client_address()),
+            destination: self.destination.lock().unwrap().map(mask),
+            state: *self.state.lock().unwrap(),
+            started_at_millis: unix_millis(self.started_at),
+            duration_secs: self.duration().as_secs(),
+            bytes_sent,
+            bytes_received,
+            play_traffic: self.bandwidth.totals(),
+            flagged: self.flagged.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Builds a diagnostics bundle for this session.
+    ///
+    /// Addresses are masked unless `include_addresses` is set,
+    /// and the configuration is redacted. No packet contents are included.
+    pub fn diagnostics(&self, config: &GatewayConfig, include_addresses: bool) -> Diagnostics {
+        Diagnostics {
+            generated_at_millis: unix_millis(SystemTime::now()),
+            gateway_version: env!("CARGO_PKG_VERSION"),
+            gateway_build: BuildInfo::current(),
+            client_build: self.client_build_info.get().cloned(),
+            config: config.redacted(),
+            session: self.summary(include_addresses),
+            negotiated: match (self.client(), *self.codec_version.lock().unwrap()) {
+                (Client::Quic(connection), Some(codec_version)) => Some(
+                    NegotiatedParameters::from_connection(&connection, codec_version.as_u8()),
+                ),
+                _ => None,
+            },
+            transport: self.client().transport_stats(),
+            stats_history: self.stats_history.lock().unwrap().iter().copied().collect(),
+            events: self.events.lock().unwrap().iter().cloned().collect(),
+            allocations: self.allocation_counters.summary(),
+            dropped_packets: self.allocation_counters.dropped_summary(),
+            anomalies: self.anomalies.summary(),
+            anomaly_score: self.anomalies.score(),
+            timeline: self.timeline.events(),
+        }
+    }
+}
+
+async fn sample_stats(session: Weak<Session>, clock: SharedClock, strict: StrictConfig) {
+    let mut interval = clock::Interval::new(clock, STATS_SAMPLE_INTERVAL);
+    while let Some(session) = session.upgrade() {
+        session.check_anomaly_score(&strict);
+        let sample = StatsSample {
+            timestamp_millis: unix_millis(SystemTime::now()),
+            stats: session.client().transport_stats(),
+        };
+        {
+            let mut history = session.stats_history.lock().unwrap();
+            if history.len() == MAX_STATS_SAMPLES {
+                history.pop_front();
+            }
+            history.push_back(sample);
+        }
+        // Don't keep the session alive while waiting.
+        drop(session);
+        interval.tick().await;
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Returned when a session bound to one identity attempts to authenticate as another.
+#[derive(Debug, thiserror::Error)]
+#[error("session is bound to identity {bound}, but attempted to authenticate as {attempted}")]
+pub struct IdentityMismatch {
+    pub bound: String,
+    pub attempted: String,
+}
+
+/// Masks the host part of an address, keeping the network
+/// (/24 for IPv4, /48 for IPv6) for coarse identification.
+pub(crate) fn mask_address(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            format!("{a}.{b}.{c}.x")
+        }
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            format!("{:x}:{:x}:{:x}::x", segments[0], segments[1], segments[2])
+        }
+    }
+}
+
+/// A redacted diagnostics bundle for a single session,
+/// suitable for attaching to bug reports.
+#[derive(Debug, Clone, Serialize)]
+pub struct Diagnostics {
+    pub generated_at_millis: u64,
+    pub gateway_version: &'static str,
+    pub gateway_build: BuildInfo,
+    /// Build reported by the client, if it supports build reports.
+    pub client_build: Option<BuildInfo>,
+    pub config: GatewayConfig,
+    pub session: SessionSummary,
+    /// Connection parameters, once the client has connected to a destination.
+    pub negotiated: Option<NegotiatedParameters>,
+    /// Current transport statistics.
+    pub transport: TransportStats,
+    /// Transport statistics sampled periodically, oldest first.
+    pub stats_history: Vec<StatsSample>,
+    /// Recent events, oldest first.
+    pub events: Vec<Event>,
+    pub allocations: AllocationSummary,
+    /// Packets dropped because sending them failed, by allocation class.
+    pub dropped_packets: AllocationSummary,
+    pub anomalies: AnomalySummary,
+    pub anomaly_score: u64,
+    /// Recent packet and state switch events on the gateway's QUIC side,
+    /// oldest first. Can be merged with the client's timeline.
+    pub timeline: Vec<TimelineEvent>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionSummary {
+    pub id: SessionId,
+    pub listener: String,
+    pub identity: Option<String>,
+    pub tenant: Option<String>,
+    pub client_address: String,
+    pub destination: Option<String>,
+    /// Protocol state, e.g. `Play`.
+    pub state: &'static str,
+    pub started_at_millis: u64,
+    pub duration_secs: u64,
+    /// UDP bytes sent and received on the client connection,
+    /// including QUIC overhead, or TCP bytes for vanilla connections.
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Play packets received from the client (serverbound) and sent to
+    /// it (clientbound), with their sizes as sent over QUIC.
+    pub play_traffic: BandwidthTotals,
+    /// Whether the anomaly score exceeded the maximum in strict mode.
+    pub flagged: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StatsSample {
+    pub timestamp_millis: u64,
+    pub stats: TransportStats,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Event {
+    pub timestamp_millis: u64,
+    pub message: String,
+}
@@ -0,0 +1,175 @@
+//! Session lifecycle events POSTed as JSON to webhooks, so that operators
+//! can follow connections in a chat channel or monitoring system.
+//!
+//! The payload holds the same fields as a line of the event log, plus a
+//! `content` (Discord) and a `text` (Slack) field with a human-readable
+//! message, as alerts do. Unless configured otherwise, client addresses
+//! are masked to their network, like in session summaries.
+//!
+//! Events are delivered in order by a background task. If webhooks fall
+//! behind, events are dropped rather than queued without bound.
+
+use crate::gateway::{
+    config::{SessionEventKind, SessionWebhookConfig},
+    event_log::ConnectionEvent,
+    session::{mask_address, SessionId},
+};
+use serde::Serialize;
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc;
+
+/// Timeout for a single webhook delivery.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of events waiting for delivery after which further ones are dropped.
+const MAX_QUEUED_EVENTS: usize = 1024;
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    content: String,
+    text: String,
+    timestamp_millis: u64,
+    session: SessionId,
+    client_address: String,
+    #[serde(flatten)]
+    event: &'a ConnectionEvent<'a>,
+}
+
+/// Sends session events to the configured webhooks.
+pub(crate) struct SessionWebhooks {
+    events: Vec<SessionEventKind>,
+    include_addresses: bool,
+    sender: mpsc::Sender<serde_json::Value>,
+}
+
+impl SessionWebhooks {
+    /// Spawns the task delivering events. Must be called within the runtime.
+    pub fn new(config: &SessionWebhookConfig) -> anyhow::Result<Self> {
+        let client = reqwest::Client::builder()
+            .timeout(WEBHOOK_TIMEOUT)
+            .build()?;
+        let (sender, receiver) = mpsc::channel(MAX_QUEUED_EVENTS);
+        tokio::spawn(deliver(client, config.urls.clone(), receiver));
+        Ok(Self {
+            events: config.events.clone(),
+            include_addresses: config.include_addresses,
+            sender,
+        })
+    }
+
+    /// Queues an event of the given session for
+    /// delivery, if its kind is configured.
+    pub fn notify(&self, session: SessionId, client_address: SocketAddr, event: &ConnectionEvent) {
+        if !self.events.contains(&kind(event)) {
+            return;
+        }
+        let client_address = if self.include_addresses {
+            client_address.to_string()
+        } else {
+            mask_address(client_address.ip())
+        };
+        let message = describe(session, &client_address, event);
+        let payload = Payload {
+            content: message.clone(),
+            text: message,
+            timestamp_millis: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64,
+            session,
+            client_address,
+            event,
+        };
+        let payload = match serde_json::to_value(&payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!("Failed to serialize session event: {e}");
+                return;
+            }
+        };
+        if self.sender.try_send(payload).is_err() {
+            tracing::warn!("Session webhooks are falling behind; dropping an event");
+        }
+    }
+}
+
+async fn deliver(
+    client: reqwest::Client,
+    urls: Vec<String>,
+    mut receiver: mpsc::Receiver<serde_json::Value>,
+) {
+    while let Some(payload) = receiver.recv().await {
+        for url in &urls {
+            let result = client
+                .post(url)
+                .json(&payload)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(e) = result {
+                tracing::warn!("Failed to deliver session event to webhook: {e}");
+            }
+        }
+    }
+}
+
+fn kind(event: &ConnectionEvent) -> SessionEventKind {
+    match event {
+        ConnectionEvent::Connect { .. } => SessionEventKind::Connect,
+        ConnectionEvent::AuthSuccess { .. } => SessionEventKind::AuthSuccess,
+        ConnectionEvent::AuthFailure => SessionEventKind::AuthFailure,
+        ConnectionEvent::Dial { .. } => SessionEventKind::Dial,
+        ConnectionEvent::StateTransition { .. } => SessionEventKind::StateTransition,
+        ConnectionEvent::Disconnect { .. } => SessionEventKind::Disconnect,
+    }
+}
+
+fn describe(session: SessionId, client_address: &str, event: &ConnectionEvent) -> String {
+    let mut message = format!("Session {session} from {client_address} ");
+    // Writing to a string cannot fail.
+    let _ = match event {
+        ConnectionEvent::Connect { listener } => {
+            write!(message, "connected on listener {listener}")
+        }
+        ConnectionEvent::AuthSuccess { identity, .. } => {
+            write!(message, "authenticated as {identity}")
+        }
+        ConnectionEvent::AuthFailure => write!(message, "failed to authenticate"),
+        ConnectionEvent::Dial {
+            destination,
+            address,
+            error: None,
+        } => write!(message, "connected to {destination} ({address})"),
+        ConnectionEvent::Dial {
+            destination,
+            address,
+            error: Some(error),
+        } => write!(
+            message,
+            "failed to connect to {destination} ({address}): {error}"
+        ),
+        ConnectionEvent::StateTransition { from, to } => {
+            write!(message, "switched from {from} to {to}")
+        }
+        ConnectionEvent::Disconnect {
+            duration_secs,
+            bytes_sent,
+            bytes_received,
+            error,
+            ..
+        } => {
+            let _ = write!(
+                message,
+                "disconnected after {duration_secs:.0}s ({bytes_sent} bytes sent, {bytes_received} received)"
+            );
+            match error {
+                Some(error) => write!(message, ": {error}"),
+                None => Ok(()),
+            }
+        }
+    };
+    message
+}
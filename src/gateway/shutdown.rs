@@ -0,0 +1,74 @@
+//! Graceful shutdown of the gateway.
+//!
+//! Once shut down, the gateway stops accepting connections and tells the
+//! clients of sessions in the Play state, over the control stream, how long
+//! they have left. Sessions are then given until the end of the drain
+//! timeout to end by themselves. Play sessions still running at that point
+//! have their pending packets flushed and the player disconnected with a
+//! message; any other connections are closed.
+
+use crate::clock::Instant;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Shuts down a running gateway. Obtained from `Gateway::shutdown_handle`.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<watch::Sender<bool>>,
+}
+
+impl ShutdownHandle {
+    pub(super) fn new() -> Self {
+        Self {
+            requested: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Starts shutting down the gateway. `Gateway::run` returns
+    /// once its sessions have drained or the drain timeout has passed.
+    ///
+    /// Does nothing if the gateway is already shutting down.
+    pub fn shutdown(&self) {
+        self.requested.send_replace(true);
+    }
+
+    /// Waits until `shutdown` is called.
+    pub(super) async fn requested(&self) {
+        let mut requested = self.requested.subscribe();
+        // The sender is never dropped while `self` exists.
+        requested.wait_for(|&requested| requested).await.ok();
+    }
+}
+
+/// Deadline by which sessions must have ended, once the gateway shuts down.
+pub(super) struct DrainDeadline {
+    deadline: watch::Sender<Option<Instant>>,
+}
+
+impl DrainDeadline {
+    pub fn new() -> Self {
+        Self {
+            deadline: watch::Sender::new(None),
+        }
+    }
+
+    pub fn start(&self, deadline: Instant) {
+        self.deadline.send_replace(Some(deadline));
+    }
+
+    /// Waits until the gateway shuts down, returning the deadline. Cancel safe.
+    pub async fn wait(&self) -> Instant {
+        let deadline = *self
+            .deadline
+            .subscribe()
+            .wait_for(Option::is_some)
+            .await
+            .expect("sender is owned by self");
+        deadline.expect("waited for deadline")
+    }
+}
+
+/// Error ending a Play session still running at the drain deadline.
+#[derive(Debug, thiserror::Error)]
+#[error("gateway shut down")]
+pub struct DrainDeadlinePassed;
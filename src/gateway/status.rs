@@ -0,0 +1,146 @@
+//! Server list pings answered by the gateway.
+//!
+//! The game pings each server in its list every time the list is shown,
+//! each ping costing the gateway a connection to the destination server.
+//! Status responses of destination servers are cached for a few seconds,
+//! keyed by the destination and protocol version of the ping, and further
+//! pings within that time are answered from the cache. With a MOTD
+//! configured, all pings are answered with it instead, and destination
+//! servers are not connected to for pings at all.
+//!
+//! Pings can only be answered this way if the handshake is received
+//! before connecting to the destination server, so with this enabled,
+//! the gateway connects once the game has sent its handshake, as with
+//! virtual hosts.
+
+use crate::{
+    clock::{Instant, SharedClock},
+    destination::Destination,
+    gateway::config::StatusConfig,
+    protocol::{
+        packet::{client, server, side, state},
+        Encoder,
+    },
+    proxy::{PacketIo, SingleQuicPacketIo},
+};
+use ahash::AHashMap;
+use anyhow::Context;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{sync::Mutex, time::Duration};
+
+/// Answers pings from the cache or with the configured MOTD.
+pub(crate) struct StatusResponder {
+    config: StatusConfig,
+    /// Data URL of the favicon, if configured.
+    favicon: Option<String>,
+    /// Status responses of destination servers, by destination and
+    /// protocol version, with the time they were received.
+    cache: Mutex<AHashMap<(Destination, u32), (Instant, server::status::StatusResponse)>>,
+    clock: SharedClock,
+}
+
+impl StatusResponder {
+    /// Fails if the favicon cannot be read.
+    pub fn new(config: &StatusConfig, clock: SharedClock) -> anyhow::Result<Self> {
+        let favicon = config
+            .favicon_file
+            .as_ref()
+            .map(|path| {
+                fs_err::read(path)
+                    .context("failed to read the favicon")
+                    .map(|png| format!("data:image/png;base64,{}", STANDARD.encode(png)))
+            })
+            .transpose()?;
+        Ok(Self {
+            config: config.clone(),
+            favicon,
+            cache: Mutex::default(),
+            clock,
+        })
+    }
+
+    /// Gets the response to a ping of `destination` from a game speaking
+    /// `protocol_version`, if it can be answered without connecting to the
+    /// destination server. `sessions` is shown as the online player count.
+    pub fn response(
+        &self,
+        destination: &Destination,
+        protocol_version: u32,
+        sessions: usize,
+    ) -> Option<server::status::StatusResponse> {
+        if let Some(motd) = &self.config.motd {
+            return Some(self.motd_response(motd, protocol_version, sessions));
+        }
+        let cache = self.cache.lock().unwrap();
+        let (received, response) = cache.get(&(destination.clone(), protocol_version))?;
+        (self.clock.now().duration_since(*received) < self.config.cache_duration())
+            .then(|| response.clone())
+    }
+
+    /// Caches the status response of `destination` to a
+    /// game speaking `protocol_version`.
+    pub fn cache(
+        &self,
+        destination: &Destination,
+        protocol_version: u32,
+        response: &server::status::StatusResponse,
+    ) {
+        let ttl = self.config.cache_duration();
+        if ttl == Duration::ZERO {
+            return;
+        }
+        let now = self.clock.now();
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (received, _)| now.duration_since(*received) < ttl);
+        cache.insert(
+            (destination.clone(), protocol_version),
+            (now, response.clone()),
+        );
+    }
+
+    fn motd_response(
+        &self,
+        motd: &str,
+        protocol_version: u32,
+        sessions: usize,
+    ) -> server::status::StatusResponse {
+        let mut status = serde_json::json!({
+            // The game's own version, so that it does not show the server as incompatible.
+            "version": { "name": self.config.version_name, "protocol": protocol_version },
+            "players": { "max": self.config.max_players, "online": sessions },
+            "description": { "text": motd },
+        });
+        if let Some(favicon) = &self.favicon {
+            status["favicon"] = favicon.as_str().into();
+        }
+        let mut ignored_data = Vec::new();
+        Encoder::new(&mut ignored_data).write_string(&status.to_string());
+        server::status::StatusResponse { ignored_data }
+    }
+}
+
+/// Answers the pings of the game with `response`.
+pub(crate) async fn answer(
+    client_connection: SingleQuicPacketIo<side::Server, state::Status>,
+    response: server::status::StatusResponse,
+) -> anyhow::Result<()> {
+    loop {
+        match client_connection.recv_packet().await? {
+            client::status::Packet::StatusRequest(_) => {
+                client_connection
+                    .send_packet(server::status::Packet::StatusResponse(response.clone()))
+                    .await?;
+            }
+            client::status::Packet::PingRequest(ping) => {
+                client_connection
+                    .send_packet(server::status::Packet::PingResponse(
+                        server::status::PingResponse {
+                            ignored_data: ping.ignored_data,
+                        },
+                    ))
+                    .await?;
+                return Ok(());
+            }
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Loading of TLS certificates for gateway listeners.
+
+use crate::gateway::{
+    config::{CertificateConfig, ClientCertificateConfig},
+    self_test,
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use quinn::ServerConfig;
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+    Certificate, PrivateKey, RootCertStore,
+};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::SystemTime,
+};
+
+/// Loads a private key in PEM (PKCS#8 or PKCS#1) or DER format.
+pub fn load_private_key(path: &Path) -> anyhow::Result<PrivateKey> {
+    // Code adapted from Quinn examples
+    let key = fs_err::read(path).context("failed to read private key")?;
+    let mut key = key.as_slice();
+    if path.extension().is_some_and(|x| x == "der") {
+        return Ok(PrivateKey(key.to_vec()));
+    }
+
+    let mut pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut key);
+    match pkcs8.next() {
+        Some(x) => Ok(PrivateKey(x?.secret_pkcs8_der().to_vec())),
+        None => {
+            drop(pkcs8);
+            let rsa = rustls_pemfile::rsa_private_keys(&mut key);
+            match rsa.into_iter().next() {
+                Some(x) => Ok(PrivateKey(x?.secret_pkcs1_der().to_vec())),
+                None => bail!("no private keys found"),
+            }
+        }
+    }
+}
+
+/// Loads a certificate chain in PEM or DER format.
+pub fn load_cert_chain(path: &Path) -> anyhow::Result<Vec<Certificate>> {
+    let cert_chain = fs_err::read(path).context("failed to read certificate chain")?;
+    if path.extension().is_some_and(|x| x == "der") {
+        Ok(vec![Certificate(cert_chain)])
+    } else {
+        Ok(rustls_pemfile::certs(&mut &*cert_chain)
+            .map(|cert| cert.map(|der| Certificate(der.to_vec())))
+            .collect::<Result<Vec<_>, std::io::Error>>()?)
+    }
+}
+
+/// Certificates of a listener, presented by the server name (SNI) the
+/// client connects with and reloadable from disk while the gateway runs,
+/// so that short-lived certificates can be rotated without a restart.
+///
+/// The first certificate is used for clients that send no
+/// or an unknown server name.
+pub struct ReloadableCertificates {
+    certificates: Vec<CertificateConfig>,
+    resolver: RwLock<Arc<SniResolver>>,
+    /// Modification times of the files, as of the last (re)load.
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl ReloadableCertificates {
+    pub fn load(certificates: Vec<CertificateConfig>) -> anyhow::Result<Arc<Self>> {
+        let modified = modification_times(&certificates);
+        let resolver = SniResolver::load(&certificates)?;
+        Ok(Arc::new(Self {
+            certificates,
+            resolver: RwLock::new(Arc::new(resolver)),
+            modified: Mutex::new(modified),
+        }))
+    }
+
+    /// Creates a server config presenting these certificates,
+    /// requiring client certificates if configured.
+    pub fn server_config(
+        self: &Arc<Self>,
+        client_certificates: Option<&ClientCertificateConfig>,
+    ) -> anyhow::Result<ServerConfig> {
+        let builder = rustls::ServerConfig::builder()
+            .with_safe_default_cipher_suites()
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&[&rustls::version::TLS13])?;
+        let builder = match client_certificates {
+            Some(config) => builder.with_client_cert_verifier(
+                AllowAnyAuthenticatedClient::new(load_client_cas(config)?).boxed(),
+            ),
+            None => builder.with_no_client_auth(),
+        };
+        let mut crypto =
+            builder.with_cert_resolver(Arc::clone(self) as Arc<dyn ResolvesServerCert>);
+        crypto.max_early_data_size = u32::MAX;
+        Ok(crate::server_config_with_crypto(crypto))
+    }
+
+    /// Reloads the certificates from disk. Each certificate must match its
+    /// private key; otherwise, e.g. if only one of them has been replaced
+    /// yet, the previous certificates stay in use and an error is returned.
+    ///
+    /// Connections already established are unaffected.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let modified = modification_times(&self.certificates);
+        for certificate in &self.certificates {
+            self_test::check_certificate_pair(&certificate.cert, &certificate.priv_key)
+                .with_context(|| format!("invalid certificate {}", certificate.cert.display()))?;
+        }
+        let resolver = SniResolver::load(&self.certificates)?;
+        *self.resolver.write().unwrap() = Arc::new(resolver);
+        *self.modified.lock().unwrap() = modified;
+        Ok(())
+    }
+
+    /// Reloads the certificates if any of their files was modified
+    /// since the last (re)load, returning whether they were.
+    pub fn reload_if_modified(&self) -> anyhow::Result<bool> {
+        if modification_times(&self.certificates) == *self.modified.lock().unwrap() {
+            return Ok(false);
+        }
+        self.reload().map(|()| true)
+    }
+}
+
+impl ResolvesServerCert for ReloadableCertificates {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let resolver = Arc::clone(&self.resolver.read().unwrap());
+        resolver.resolve(client_hello)
+    }
+}
+
+/// Gets the modification times of the certificate and key files,
+/// `None` for files that cannot be read.
+fn modification_times(certificates: &[CertificateConfig]) -> Vec<Option<SystemTime>> {
+    certificates
+        .iter()
+        .flat_map(|certificate| [&certificate.cert, &certificate.priv_key])
+        .map(|path| {
+            fs_err::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Creates a server config that presents one of several certificates,
+/// selected by the server name (SNI) the client connects with.
+///
+/// The first certificate is used for clients that send no
+/// or an unknown server name. See `ReloadableCertificates`
+/// for certificates that can be reloaded.
+pub fn sni_server_config(certificates: &[CertificateConfig]) -> anyhow::Result<ServerConfig> {
+    ReloadableCertificates::load(certificates.to_vec())?.server_config(None)
+}
+
+/// Loads the CAs client certificates must be signed by.
+fn load_client_cas(config: &ClientCertificateConfig) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for certificate in load_cert_chain(&config.ca_file)? {
+        roots.add(&certificate).with_context(|| {
+            format!(
+                "invalid client CA certificate in {}",
+                config.ca_file.display()
+            )
+        })?;
+    }
+    if roots.is_empty() {
+        bail!("no client CA certificates in {}", config.ca_file.display());
+    }
+    Ok(roots)
+}
+
+struct SniResolver {
+    by_name: AHashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+}
+
+impl SniResolver {
+    fn load(certificates: &[CertificateConfig]) -> anyhow::Result<Self> {
+        let mut resolver = SniResolver {
+            by_name: AHashMap::new(),
+            default: None,
+        };
+        for certificate in certificates {
+            let key = load_private_key(&certificate.priv_key)?;
+            let key = rustls::sign::any_supported_type(&key).with_context(|| {
+                format!("unsupported private key {}", certificate.priv_key.display())
+            })?;
+            let certified_key =
+                Arc::new(CertifiedKey::new(load_cert_chain(&certificate.cert)?, key));
+            for name in &certificate.server_names {
+                resolver
+                    .by_name
+                    .insert(name.to_ascii_lowercase(), Arc::clone(&certified_key));
+            }
+            resolver.default.get_or_insert(certified_key);
+        }
+        if resolver.default.is_none() {
+            bail!("listener has no certificates");
+        }
+        Ok(resolver)
+    }
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        client_hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .or(self.default.as_ref())
+            .cloned()
+    }
+}
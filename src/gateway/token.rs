@@ -0,0 +1,285 @@
+//! Short-lived authentication tokens, which server owners issue to players
+//! instead of sharing a long-term authentication key with them.
+//!
+//! A token is `mqp1.<claims>.<signature>`: the claims as JSON and their
+//! HMAC-SHA256 under the secret shared with the gateway, both base64url
+//! encoded. Clients present a token as their authentication key. It
+//! authenticates as the identity it names until it expires, optionally
+//! only for one destination.
+
+use crate::destination::Destination;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Prefix distinguishing tokens from plain authentication keys.
+const PREFIX: &str = "mqp1.";
+
+/// What a token grants.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenClaims {
+    /// Identity the token authenticates as.
+    pub identity: String,
+    /// Unix time in seconds at which the token expires.
+    pub expires_at: u64,
+    /// Destination the token is limited to, as named by the client
+    /// (e.g. `mc.example.net` or `203.0.113.7:25565`), or with virtual
+    /// hosts, as the handshake is routed to. Any if unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+}
+
+impl TokenClaims {
+    /// Issues a token granting the claims, signed with `secret`.
+    pub fn issue(&self, secret: &[u8]) -> String {
+        let claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).expect("claims serialize"));
+        let signature = hmac::sign(&key(secret), claims.as_bytes());
+        format!(
+            "{PREFIX}{claims}.{}",
+            URL_SAFE_NO_PAD.encode(signature.as_ref())
+        )
+    }
+}
+
+/// Reason a token is rejected.
+#[derive(Debug, thiserror::Error)]
+pub enum TokenError {
+    #[error("malformed token")]
+    Malformed,
+    #[error("token signature is invalid")]
+    InvalidSignature,
+    #[error("token expired {expired_secs_ago}s ago")]
+    Expired { expired_secs_ago: u64 },
+    #[error("token is limited to destination {allowed}")]
+    WrongDestination { allowed: String },
+}
+
+/// Whether an authentication key is a token rather than a plain key.
+pub fn is_token(key: &str) -> bool {
+    key.starts_with(PREFIX)
+}
+
+/// Verifies a token presented for `destination` at time `now`. If the
+/// destination is not known yet, pass `None` and call `check_destination`
+/// once it is.
+pub fn verify(
+    secret: &[u8],
+    token: &str,
+    destination: Option<&Destination>,
+    now: SystemTime,
+) -> Result<TokenClaims, TokenError> {
+    let (claims, signature) = token
+        .strip_prefix(PREFIX)
+        .and_then(|token| token.split_once('.'))
+        .ok_or(TokenError::Malformed)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?;
+    // Checked before parsing, so that only tokens issued
+    // with the secret are ever parsed.
+    hmac::verify(&key(secret), claims.as_bytes(), &signature)
+        .map_err(|_| TokenError::InvalidSignature)?;
+    let claims: TokenClaims = URL_SAFE_NO_PAD
+        .decode(claims)
+        .ok()
+        .and_then(|claims| serde_json::from_slice(&claims).ok())
+        .ok_or(TokenError::Malformed)?;
+
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    if now >= claims.expires_at {
+        return Err(TokenError::Expired {
+            expired_secs_ago: now - claims.expires_at,
+        });
+    }
+    if let Some(destination) = destination {
+        check_destination(&claims, destination)?;
+    }
+    Ok(claims)
+}
+
+/// Checks that `claims` allow connecting to `destination`.
+pub fn check_destination(
+    claims: &TokenClaims,
+    destination: &Destination,
+) -> Result<(), TokenError> {
+    if let Some(allowed) = &claims.destination {
+        if allowed.parse::<Destination>().ok().as_ref() != Some(destination) {
+            return Err(TokenError::WrongDestination {
+                allowed: allowed.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn key(secret: &[u8]) -> hmac::Key {
+    hmac::Key::new(hmac::HMAC_SHA256, secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECRET: &[u8] = b"token test secret";
+    const EXPIRES_AT: u64 = 1_700_000_000;
+
+    fn claims(destination: Option<&str>) -> TokenClaims {
+        TokenClaims {
+            identity: "players".to_owned(),
+            expires_at: EXPIRES_AT,
+            destination: destination.map(str::to_owned),
+        }
+    }
+
+    fn at(unix_secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(unix_secs)
+    }
+
+    fn destination(destination: &str) -> Destination {
+        destination.parse().unwrap()
+    }
+
+    #[test]
+    fn issued_token_verifies() {
+        let claims = claims(Some("mc.example.net"));
+        let token = claims.issue(SECRET);
+        assert!(is_token(&token));
+
+        let verified = verify(
+            SECRET,
+            &token,
+            Some(&destination("MC.example.net.")),
+            at(EXPIRES_AT - 1),
+        )
+        .unwrap();
+        assert_eq!(verified, claims);
+        assert_eq!(
+            verify(SECRET, &token, None, at(EXPIRES_AT - 1)).unwrap(),
+            claims
+        );
+    }
+
+    #[test]
+    fn rejects_other_secret() {
+        let token = claims(None).issue(SECRET);
+        assert!(matches!(
+            verify(b"other secret", &token, None, at(0)),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_claims() {
+        let token = claims(None).issue(SECRET);
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let mut forged = claims(None);
+        forged.identity = "admins".to_owned();
+        let forged_claims = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&forged).unwrap());
+
+        let tampered = format!("{PREFIX}{forged_claims}.{signature}");
+        assert!(matches!(
+            verify(SECRET, &tampered, None, at(0)),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn rejects_tampered_signature() {
+        let token = claims(None).issue(SECRET);
+        let (claims, signature) = token.rsplit_once('.').unwrap();
+        let mut signature = URL_SAFE_NO_PAD.decode(signature).unwrap();
+        signature[0] ^= 1;
+
+        let tampered = format!("{claims}.{}", URL_SAFE_NO_PAD.encode(signature));
+        assert!(matches!(
+            verify(SECRET, &tampered, None, at(0)),
+            Err(TokenError::InvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn expires_at_expiry_time() {
+        let token = claims(None).issue(SECRET);
+        assert!(verify(SECRET, &token, None, at(EXPIRES_AT - 1)).is_ok());
+        assert!(matches!(
+            verify(SECRET, &token, None, at(EXPIRES_AT)),
+            Err(TokenError::Expired {
+                expired_secs_ago: 0
+            })
+        ));
+        assert!(matches!(
+            verify(SECRET, &token, None, at(EXPIRES_AT + 30)),
+            Err(TokenError::Expired {
+                expired_secs_ago: 30
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_tokens() {
+        let token = claims(None).issue(SECRET);
+        let (claims, signature) = token.strip_prefix(PREFIX).unwrap().split_once('.').unwrap();
+        let malformed = [
+            String::new(),
+            format!("{claims}.{signature}"),
+            format!("mqp2.{claims}.{signature}"),
+            format!("{PREFIX}{claims}"),
+            format!("{PREFIX}{claims}.{signature}!"),
+            format!("{PREFIX}{claims}.{signature}="),
+        ];
+        for token in &malformed {
+            assert!(
+                matches!(
+                    verify(SECRET, token, None, at(0)),
+                    Err(TokenError::Malformed)
+                ),
+                "{token:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_signed_claims_that_do_not_parse() {
+        for payload in ["not base64!", "bm90IGpzb24"] {
+            let signature = hmac::sign(&key(SECRET), payload.as_bytes());
+            let token = format!(
+                "{PREFIX}{payload}.{}",
+                URL_SAFE_NO_PAD.encode(signature.as_ref())
+            );
+            assert!(
+                matches!(
+                    verify(SECRET, &token, None, at(0)),
+                    Err(TokenError::Malformed)
+                ),
+                "{payload:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_other_destination() {
+        let token = claims(Some("mc.example.net")).issue(SECRET);
+        for other in ["other.example.net", "mc.example.net:25566", "203.0.113.7"] {
+            assert!(
+                matches!(
+                    verify(SECRET, &token, Some(&destination(other)), at(0)),
+                    Err(TokenError::WrongDestination { allowed }) if allowed == "mc.example.net"
+                ),
+                "{other}"
+            );
+        }
+    }
+
+    #[test]
+    fn check_destination_compares_addresses() {
+        let limited = claims(Some("203.0.113.7:25565"));
+        assert!(check_destination(&limited, &destination("203.0.113.7")).is_ok());
+        assert!(matches!(
+            check_destination(&limited, &destination("203.0.113.8:25565")),
+            Err(TokenError::WrongDestination { .. })
+        ));
+        assert!(check_destination(&claims(None), &destination("203.0.113.8")).is_ok());
+    }
+}
@@ -0,0 +1,168 @@
+//! Chaining to upstream gateways.
+//!
+//! Destinations may be configured to be proxied through another gateway,
+//! e.g. one next to the destination servers, so that traffic crosses the
+//! long-haul link over QUIC and only the final LAN hop over TCP. For each
+//! session to such a destination, the gateway opens a client to the
+//! upstream gateway, as the game's client would, and connects to its local
+//! listener in place of the destination server.
+//!
+//! Like the game's connection to a client, the connection to the local
+//! listener is neither compressed nor encrypted: the encryption key the
+//! client sends is passed on to the upstream gateway, which encrypts its
+//! connection to the destination server.
+//!
+//! Policies and circuit breakers apply to the upstream gateway's address;
+//! the upstream gateway applies its own to the destination server.
+
+use crate::{
+    client::{ClientHandle, ClientOptions},
+    clock::SharedClock,
+    destination::{Destination, DEFAULT_PORT},
+    gateway::{config::UpstreamConfig, tls},
+    tcp_options::TcpOptions,
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use quinn::{ClientConfig, Endpoint};
+use rustls::RootCertStore;
+use std::{net::SocketAddr, sync::Arc};
+use tokio::net::TcpStream;
+
+/// An upstream gateway.
+pub(crate) struct Upstream {
+    /// The upstream gateway, as a destination so that it
+    /// is resolved like destination servers are.
+    pub gateway: Destination,
+    host: String,
+    port: u16,
+    authentication_key: String,
+    endpoint: Endpoint,
+    client_config: ClientConfig,
+}
+
+impl Upstream {
+    fn new(config: &UpstreamConfig) -> anyhow::Result<Self> {
+        let (host, port) = config
+            .gateway
+            .rsplit_once(':')
+            .context("upstream gateway must be given as host:port")?;
+        let port: u16 = port
+            .parse()
+            .with_context(|| format!("invalid port of upstream gateway '{}'", config.gateway))?;
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let gateway = Destination::host(&host, Some(port))?;
+        let client_config = match &config.ca_file {
+            Some(ca_file) => {
+                let mut roots = RootCertStore::empty();
+                for certificate in tls::load_cert_chain(ca_file)? {
+                    roots.add(&certificate).with_context(|| {
+                        format!("invalid upstream CA certificate in {}", ca_file.display())
+                    })?;
+                }
+                let crypto = rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots)
+                    .with_no_client_auth();
+                crate::client_config_with_crypto(crypto)
+            }
+            None => crate::client_config()?,
+        };
+        let endpoint = Endpoint::client(config.bind).with_context(|| {
+            format!(
+                "failed to bind endpoint for upstream gateway {}",
+                config.gateway
+            )
+        })?;
+        Ok(Self {
+            gateway,
+            host,
+            port,
+            authentication_key: config.authentication_key.clone(),
+            endpoint,
+            client_config,
+        })
+    }
+
+    /// Opens a client proxying to `destination` through the upstream
+    /// gateway, and connects to its local listener.
+    pub async fn open(
+        &self,
+        destination: &Destination,
+        tcp_options: &TcpOptions,
+        clock: &SharedClock,
+    ) -> anyhow::Result<(ClientHandle, TcpStream)> {
+        let options = ClientOptions {
+            client_config: Some(self.client_config.clone()),
+            clock: Some(Arc::clone(clock)),
+            game_tcp: tcp_options.clone(),
+            ..Default::default()
+        };
+        let client = ClientHandle::open_with_options(
+            &self.endpoint,
+            &self.host,
+            self.port,
+            destination.clone(),
+            &self.authentication_key,
+            &options,
+        )
+        .await?;
+        let stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], client.bound_port())))
+            .await
+            .context("failed to connect to the client of the upstream gateway")?;
+        tcp_options.configure_stream(&stream)?;
+        Ok((client, stream))
+    }
+}
+
+/// Upstream gateways, by the destinations proxied through them.
+#[derive(Default)]
+pub(crate) struct Upstreams {
+    by_destination: AHashMap<Destination, Arc<Upstream>>,
+}
+
+impl Upstreams {
+    /// Fails if a destination is invalid or has several upstream
+    /// gateways, or if an upstream gateway cannot be set up.
+    /// Must be called within the runtime.
+    pub fn new(configs: &[UpstreamConfig]) -> anyhow::Result<Self> {
+        let mut by_destination = AHashMap::new();
+        for config in configs {
+            let upstream = Arc::new(
+                Upstream::new(config)
+                    .with_context(|| format!("invalid upstream gateway '{}'", config.gateway))?,
+            );
+            for destination in &config.destinations {
+                let destination: Destination = destination.parse().with_context(|| {
+                    format!("invalid destination '{destination}' of upstream gateway")
+                })?;
+                if by_destination
+                    .insert(destination.clone(), Arc::clone(&upstream))
+                    .is_some()
+                {
+                    bail!("{destination} has several upstream gateways");
+                }
+            }
+        }
+        Ok(Self { by_destination })
+    }
+
+    /// Gets the upstream gateway `destination` is proxied through, if any.
+    pub fn get(&self, destination: &Destination) -> Option<&Upstream> {
+        self.by_destination
+            .get(destination)
+            .map(|upstream| &**upstream)
+    }
+}
+
+/// Gets the host and port to put in the handshake sent to `destination`
+/// through an upstream gateway, whose address is unknown to this one.
+pub(crate) fn handshake_address(destination: &Destination) -> (String, u16) {
+    match destination {
+        Destination::Address(address) => (address.ip().to_string(), address.port()),
+        Destination::Host { host, port } => (host.clone(), port.unwrap_or(DEFAULT_PORT)),
+    }
+}
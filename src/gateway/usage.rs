@@ -0,0 +1,219 @@
+//! Gateway-wide usage totals: sessions served, bytes proxied, uptime
+//! and peak concurrency.
+//!
+//! If a state file is configured, the totals are loaded from it on
+//! startup and saved to it periodically and on shutdown, so they
+//! accumulate across restarts. At most the usage since the last save
+//! is lost if the gateway is killed.
+
+use crate::{clock, clock::SharedClock, gateway::config::UsageConfig};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// Totals as persisted in the state file.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct UsageState {
+    sessions_served: u64,
+    bytes_proxied: u64,
+    uptime_secs: u64,
+    peak_sessions: u64,
+    /// When the totals were first recorded.
+    first_started_at_millis: u64,
+}
+
+/// Usage totals across all runs of the gateway.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UsageSnapshot {
+    pub sessions_served: u64,
+    /// UDP bytes sent and received on client connections,
+    /// including QUIC overhead.
+    pub bytes_proxied: u64,
+    /// Time the gateway has been running since it was last started.
+    pub uptime_secs: u64,
+    /// Time the gateway has been running, summed over all runs.
+    pub total_uptime_secs: u64,
+    /// Highest number of concurrent sessions.
+    pub peak_sessions: u64,
+    pub first_started_at_millis: u64,
+}
+
+/// Accumulates usage on top of the totals of previous runs.
+pub(crate) struct UsageStats {
+    state_file: Option<PathBuf>,
+    /// Totals of previous runs.
+    previous: UsageState,
+    sessions_served: AtomicU64,
+    /// Bytes of sessions that have ended during this run.
+    bytes_proxied: AtomicU64,
+    peak_sessions: AtomicU64,
+    started_at: Instant,
+    clock: SharedClock,
+}
+
+impl UsageStats {
+    pub fn new(config: &UsageConfig, clock: SharedClock) -> Self {
+        let mut previous = match &config.state_file {
+            Some(path) => match load(path) {
+                Ok(state) => state,
+                Err(e) => {
+                    tracing::warn!("Ignoring usage state file {}: {e:#}", path.display());
+                    UsageState::default()
+                }
+            },
+            None => UsageState::default(),
+        };
+        if previous.first_started_at_millis == 0 {
+            previous.first_started_at_millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+        }
+        Self {
+            state_file: config.state_file.clone(),
+            previous,
+            sessions_served: AtomicU64::new(0),
+            bytes_proxied: AtomicU64::new(0),
+            peak_sessions: AtomicU64::new(0),
+            started_at: clock.now(),
+            clock,
+        }
+    }
+
+    /// Records a new session, with `active_sessions` now running.
+    pub fn record_session(&self, active_sessions: usize) {
+        self.sessions_served.fetch_add(1, Ordering::Relaxed);
+        self.peak_sessions
+            .fetch_max(active_sessions as u64, Ordering::Relaxed);
+    }
+
+    /// Records the bytes transferred by a session that ended.
+    pub fn record_bytes(&self, bytes: u64) {
+        self.bytes_proxied.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Gets the totals, counting `active_bytes` transferred
+    /// so far by sessions that are still running.
+    pub fn snapshot(&self, active_bytes: u64) -> UsageSnapshot {
+        let uptime_secs = self
+            .clock
+            .now()
+            .saturating_duration_since(self.started_at)
+            .as_secs();
+        UsageSnapshot {
+            sessions_served: self.previous.sessions_served
+                + self.sessions_served.load(Ordering::Relaxed),
+            bytes_proxied: self.previous.bytes_proxied
+                + self.bytes_proxied.load(Ordering::Relaxed)
+                + active_bytes,
+            uptime_secs,
+            total_uptime_secs: self.previous.uptime_secs + uptime_secs,
+            peak_sessions: self
+                .previous
+                .peak_sessions
+                .max(self.peak_sessions.load(Ordering::Relaxed)),
+            first_started_at_millis: self.previous.first_started_at_millis,
+        }
+    }
+
+    /// Saves the totals to the state file, if one is configured.
+    pub fn save(&self, active_bytes: u64) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let snapshot = self.snapshot(active_bytes);
+        let state = UsageState {
+            sessions_served: snapshot.sessions_served,
+            bytes_proxied: snapshot.bytes_proxied,
+            uptime_secs: snapshot.total_uptime_secs,
+            peak_sessions: snapshot.peak_sessions,
+            first_started_at_millis: snapshot.first_started_at_millis,
+        };
+        if let Err(e) = save(path, &state) {
+            tracing::warn!("Failed to save usage state file {}: {e:#}", path.display());
+        }
+    }
+
+    /// Periodically saves the totals, as long as the gateway is running.
+    pub async fn save_periodically(
+        self: Arc<Self>,
+        config: UsageConfig,
+        active_bytes: impl Fn() -> u64,
+    ) {
+        if self.state_file.is_none() {
+            return;
+        }
+        let mut interval = clock::Interval::new(Arc::clone(&self.clock), config.save_interval());
+        loop {
+            interval.tick().await;
+            self.save(active_bytes());
+        }
+    }
+
+    /// Renders the totals in the Prometheus text exposition format.
+    pub fn render(&self, out: &mut String, active_bytes: u64) {
+        let snapshot = self.snapshot(active_bytes);
+        let metrics = [
+            (
+                "quic_proxy_sessions_served_total",
+                "counter",
+                "Sessions accepted by the gateway, over all runs.",
+                snapshot.sessions_served,
+            ),
+            (
+                "quic_proxy_bytes_proxied_total",
+                "counter",
+                "UDP bytes sent and received on client connections, over all runs.",
+                snapshot.bytes_proxied,
+            ),
+            (
+                "quic_proxy_uptime_seconds",
+                "gauge",
+                "Time since the gateway was started.",
+                snapshot.uptime_secs,
+            ),
+            (
+                "quic_proxy_total_uptime_seconds",
+                "counter",
+                "Time the gateway has been running, over all runs.",
+                snapshot.total_uptime_secs,
+            ),
+            (
+                "quic_proxy_peak_sessions",
+                "gauge",
+                "Highest number of concurrent sessions, over all runs.",
+                snapshot.peak_sessions,
+            ),
+        ];
+        for (name, kind, help, value) in metrics {
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} {kind}").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        }
+    }
+}
+
+fn load(path: &Path) -> anyhow::Result<UsageState> {
+    if !path.exists() {
+        return Ok(UsageState::default());
+    }
+    let contents = fs_err::read(path)?;
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+/// Writes to a temporary file first, so an interrupted
+/// save cannot leave a truncated state file behind.
+fn save(path: &Path, state: &UsageState) -> anyhow::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    fs_err::write(&temp_path, serde_json::to_vec_pretty(state)?)?;
+    fs_err::rename(&temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,155 @@
+//! Plain TCP listener for unmodified games, so that one gateway serves
+//! both modded clients over QUIC and vanilla clients.
+//!
+//! A vanilla connection is routed by the host name in its handshake, as
+//! with virtual hosts, which must be configured. It is a session like a
+//! QUIC connection, subject to the same session limits, and is dialed
+//! the same way, with its destination's fallbacks, the policies and the
+//! circuit breakers. Once connected, bytes are copied as they are: the
+//! game encrypts its connection to the destination server end to end, so
+//! the gateway cannot read the packets after the handshake.
+//!
+//! Vanilla connections do not authenticate, so only the global policy and
+//! that of their listener apply to them. They cannot be proxied through
+//! upstream gateways, which would need the encryption key.
+
+use crate::{
+    clock,
+    gateway::{
+        config::VanillaListenerConfig, dial_destination, event_log::ConnectionEvent,
+        session::Session, Dialed, Shared,
+    },
+    protocol::{
+        packet::{client, side, state},
+        vanilla_codec::VanillaCodec,
+    },
+};
+use anyhow::{anyhow, bail};
+use std::sync::Arc;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    select,
+};
+use tokio_util::sync::CancellationToken;
+
+/// Accepts vanilla connections on `listener`.
+pub(crate) async fn serve(
+    listener: TcpListener,
+    config: VanillaListenerConfig,
+    shared: Arc<Shared>,
+) -> anyhow::Result<()> {
+    loop {
+        let (stream, address) = listener.accept().await?;
+        if let Some(bans) = &shared.bans {
+            if let Err(e) = bans.check(address.ip()) {
+                tracing::debug!("Refusing vanilla connection: {e}");
+                continue;
+            }
+        }
+        if let Some(rate_limiter) = &shared.rate_limiter {
+            if let Err(e) = rate_limiter.admit_connection(address.ip()) {
+                tracing::warn!("Refusing vanilla connection: {e}");
+                continue;
+            }
+        }
+        let closed = CancellationToken::new();
+        let session = match shared.sessions.register_vanilla(
+            address,
+            closed.clone(),
+            &config.name,
+            &shared.config.session_limits,
+        ) {
+            Ok(session) => session,
+            Err(e) => {
+                // The game cannot be told why before its handshake.
+                tracing::warn!("Refusing vanilla connection from {address}: {e}");
+                continue;
+            }
+        };
+        tracing::info!(
+            "Accepted vanilla connection from {address} on listener {} (session {})",
+            config.name,
+            session.session().id()
+        );
+        session.session().log(ConnectionEvent::Connect {
+            listener: &config.name,
+        });
+        let shared = Arc::clone(&shared);
+        tokio::spawn(async move {
+            let result = select! {
+                result = serve_connection(stream, &shared, session.session()) => result,
+                _ = closed.cancelled() => Err(anyhow!("closed by the gateway")),
+            };
+            match &result {
+                Ok(()) => tracing::info!("Vanilla connection from {address} closed"),
+                Err(e) => {
+                    tracing::warn!("Vanilla connection from {address} failed: {e:#}");
+                    session
+                        .session()
+                        .record_event(format!("connection lost: {e:#}"));
+                }
+            }
+            session
+                .session()
+                .log_disconnect(result.err().map(|e| format!("{e:#}")));
+        });
+    }
+}
+
+async fn serve_connection(
+    mut client: TcpStream,
+    shared: &Shared,
+    session: &Session,
+) -> anyhow::Result<()> {
+    client.set_nodelay(true)?;
+    let mut codec = VanillaCodec::<side::Server, state::Handshake>::new();
+    let receive_handshake = async {
+        let mut buffer = [0; 512];
+        loop {
+            if let Some(client::handshake::Packet::Handshake(handshake)) = codec.decode_packet()? {
+                return anyhow::Ok(handshake);
+            }
+            let read = client.read(&mut buffer).await?;
+            if read == 0 {
+                bail!("closed before sending its handshake");
+            }
+            codec.give_data(&mut buffer[..read]);
+        }
+    };
+    let handshake = clock::timeout(
+        &*shared.clock,
+        shared.config.timeouts.login(),
+        receive_handshake,
+    )
+    .await
+    .map_err(|_| anyhow!("game did not send its handshake in time"))??;
+    // The bytes the game sent after its handshake, e.g. its login start.
+    let received = codec.into_read_buffer();
+
+    let virtual_hosts = shared
+        .virtual_hosts
+        .as_ref()
+        .expect("vanilla listener requires virtual hosts");
+    let destination = virtual_hosts.route(&handshake)?.clone();
+    session.record_event(format!(
+        "routed handshake for {:?} to {destination}",
+        handshake.server_address
+    ));
+    let Dialed {
+        server_connection: mut server,
+        policy_permit: _policy_permit,
+        ..
+    } = dial_destination(&destination, None, shared, session).await?;
+
+    let mut handshake_bytes = VanillaCodec::<side::Client, state::Handshake>::new()
+        .encode_packet(&client::handshake::Packet::Handshake(handshake))?;
+    handshake_bytes.extend_from_slice(&received);
+    server.write_all(&handshake_bytes).await?;
+    let copied = tokio::io::copy_bidirectional(&mut client, &mut server).await;
+    if let Ok((received, sent)) = copied {
+        session.record_vanilla_bytes(sent, received);
+    }
+    copied?;
+    Ok(())
+}
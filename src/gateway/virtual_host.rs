@@ -0,0 +1,68 @@
+//! Routing of connections by the host name in their handshake, so that one
+//! gateway can front many destination servers, like a vanilla proxy.
+//!
+//! The host is the `server_address` the game puts in its handshake, i.e.
+//! the address the player entered (see `ClientOptions::handshake_address`).
+//! Since the handshake only arrives after the connection is set up, the
+//! gateway acknowledges the connection before dialing the destination
+//! server, and the destination named by the client is ignored.
+
+use crate::{
+    destination::Destination, gateway::config::VirtualHostsConfig,
+    protocol::packet::client::handshake::Handshake,
+};
+use ahash::AHashMap;
+use anyhow::Context;
+
+/// Error of a handshake naming a host that has no destination.
+#[derive(Debug, thiserror::Error)]
+#[error("no destination server for host '{host}'")]
+pub struct UnknownHost {
+    pub host: String,
+}
+
+pub(crate) struct VirtualHosts {
+    /// Destinations by lowercase host name, without a trailing dot.
+    hosts: AHashMap<String, Destination>,
+    default: Option<Destination>,
+}
+
+impl VirtualHosts {
+    /// Fails if a destination is invalid.
+    pub fn new(config: &VirtualHostsConfig) -> anyhow::Result<Self> {
+        let hosts = config
+            .hosts
+            .iter()
+            .map(|(host, destination)| {
+                let destination = destination
+                    .parse()
+                    .with_context(|| format!("invalid destination of virtual host '{host}'"))?;
+                Ok((normalize(host), destination))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let default = config
+            .default
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .context("invalid default destination of virtual hosts")?;
+        Ok(Self { hosts, default })
+    }
+
+    /// Gets the destination server of the host named by `handshake`.
+    pub fn route(&self, handshake: &Handshake) -> Result<&Destination, UnknownHost> {
+        let host = handshake
+            .server_address
+            .host()
+            .map(normalize)
+            .unwrap_or_default();
+        self.hosts
+            .get(&host)
+            .or(self.default.as_ref())
+            .ok_or(UnknownHost { host })
+    }
+}
+
+fn normalize(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
@@ -0,0 +1,83 @@
+//! Histograms rendered in the Prometheus text exposition format.
+
+use std::{fmt::Write, sync::Mutex};
+
+/// A Prometheus histogram with fixed bucket bounds.
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    /// Non-cumulative count for each bucket, plus the `+Inf` bucket.
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            state: Mutex::new(HistogramState {
+                buckets: vec![0; bounds.len() + 1],
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|&bound| value <= bound)
+            .unwrap_or(self.bounds.len());
+        let mut state = self.state.lock().unwrap();
+        state.buckets[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+
+    pub fn render(&self, out: &mut String, name: &str, help: &str) {
+        write_header(out, name, help);
+        self.render_series(out, name, &[]);
+    }
+
+    /// Renders the samples of one labelled series, without the
+    /// `HELP` and `TYPE` lines shared by all series (see `write_header`).
+    pub fn render_series(&self, out: &mut String, name: &str, labels: &[(&str, &str)]) {
+        let labels: String = labels
+            .iter()
+            .map(|(label, value)| format!("{label}=\"{}\",", escape_label_value(value)))
+            .collect();
+        let state = self.state.lock().unwrap();
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&state.buckets) {
+            cumulative += count;
+            writeln!(out, "{name}_bucket{{{labels}le=\"{bound}\"}} {cumulative}").unwrap();
+        }
+        writeln!(out, "{name}_bucket{{{labels}le=\"+Inf\"}} {}", state.count).unwrap();
+        let labels = match labels.trim_end_matches(',') {
+            "" => String::new(),
+            labels => format!("{{{labels}}}"),
+        };
+        writeln!(out, "{name}_sum{labels} {}", state.sum).unwrap();
+        writeln!(out, "{name}_count{labels} {}", state.count).unwrap();
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a histogram.
+pub(crate) fn write_header(out: &mut String, name: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+}
+
+pub(crate) fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
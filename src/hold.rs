@@ -0,0 +1,162 @@
+//! Holding of Play packets while a connection is re-established, to
+//! release them once it is rather than dropping them.
+//!
+//! Both sides of session resumption hold packets this way: the client
+//! those the game sends while it reconnects to the gateway, the gateway
+//! those the destination server sends until the client is back.
+//!
+//! The other side may keep sending at full rate for the whole wait, so
+//! the number of held packets is bounded. Keepalives have a bound of
+//! their own and are released first, since a late keepalive response
+//! gets the player kicked, while other packets only need their order kept.
+
+use crate::protocol::packet::{client, server};
+
+/// Maximum number of keepalives held, besides the other packets.
+const MAX_HELD_KEEPALIVES: usize = 16;
+
+/// A packet that may be held.
+pub trait HoldablePacket {
+    fn is_keepalive(&self) -> bool;
+}
+
+impl HoldablePacket for client::play::Packet {
+    fn is_keepalive(&self) -> bool {
+        matches!(self, Self::KeepAlive(_))
+    }
+}
+
+impl HoldablePacket for server::play::Packet {
+    fn is_keepalive(&self) -> bool {
+        matches!(self, Self::KeepAlive(_))
+    }
+}
+
+/// Error returned when holding more packets than allowed.
+#[derive(Debug, thiserror::Error)]
+#[error("held more than {max_packets} packets")]
+pub struct HoldOverflow {
+    pub max_packets: usize,
+}
+
+/// Packets held until they can be released.
+#[derive(Debug)]
+pub struct HeldPackets<P> {
+    keepalives: Vec<P>,
+    packets: Vec<P>,
+    max_packets: usize,
+}
+
+impl<P: HoldablePacket> HeldPackets<P> {
+    /// Holds at most `max_packets` packets other than keepalives.
+    pub fn new(max_packets: usize) -> Self {
+        Self {
+            keepalives: Vec::new(),
+            packets: Vec::new(),
+            max_packets,
+        }
+    }
+
+    /// Holds a packet, failing if as many are held as allowed.
+    pub fn hold(&mut self, packet: P) -> Result<(), HoldOverflow> {
+        let (held, max_packets) = if packet.is_keepalive() {
+            (&mut self.keepalives, MAX_HELD_KEEPALIVES)
+        } else {
+            (&mut self.packets, self.max_packets)
+        };
+        if held.len() == max_packets {
+            return Err(HoldOverflow { max_packets });
+        }
+        held.push(packet);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keepalives.len() + self.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Releases the held packets: the keepalives, then the
+    /// other packets, each in the order they were held.
+    pub fn release(self) -> impl Iterator<Item = P> {
+        self.keepalives.into_iter().chain(self.packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Eq)]
+    enum Packet {
+        KeepAlive(u64),
+        Other(u32),
+    }
+
+    impl HoldablePacket for Packet {
+        fn is_keepalive(&self) -> bool {
+            matches!(self, Self::KeepAlive(_))
+        }
+    }
+
+    #[test]
+    fn releases_packets_in_order() {
+        let mut held = HeldPackets::new(8);
+        for i in 0..8 {
+            held.hold(Packet::Other(i)).unwrap();
+        }
+        assert_eq!(held.len(), 8);
+        assert_eq!(
+            held.release().collect::<Vec<_>>(),
+            (0..8).map(Packet::Other).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn enforces_cap() {
+        let mut held = HeldPackets::new(3);
+        for i in 0..3 {
+            held.hold(Packet::Other(i)).unwrap();
+        }
+        let error = held.hold(Packet::Other(3)).unwrap_err();
+        assert_eq!(error.max_packets, 3);
+        // The packets held before are kept.
+        assert_eq!(
+            held.release().collect::<Vec<_>>(),
+            [Packet::Other(0), Packet::Other(1), Packet::Other(2)]
+        );
+    }
+
+    #[test]
+    fn holds_keepalives_beyond_cap_and_releases_them_first() {
+        let mut held = HeldPackets::new(2);
+        held.hold(Packet::Other(0)).unwrap();
+        held.hold(Packet::KeepAlive(10)).unwrap();
+        held.hold(Packet::Other(1)).unwrap();
+        assert!(held.hold(Packet::Other(2)).is_err());
+        held.hold(Packet::KeepAlive(11)).unwrap();
+        assert_eq!(
+            held.release().collect::<Vec<_>>(),
+            [
+                Packet::KeepAlive(10),
+                Packet::KeepAlive(11),
+                Packet::Other(0),
+                Packet::Other(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn enforces_keepalive_cap() {
+        let mut held = HeldPackets::new(0);
+        for i in 0..MAX_HELD_KEEPALIVES as u64 {
+            held.hold(Packet::KeepAlive(i)).unwrap();
+        }
+        assert!(held.hold(Packet::KeepAlive(0)).is_err());
+        assert!(held.hold(Packet::Other(0)).is_err());
+        assert_eq!(held.len(), MAX_HELD_KEEPALIVES);
+    }
+}
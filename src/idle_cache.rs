@@ -0,0 +1,142 @@
+//! A map whose entries expire after going unused for some time.
+
+use crate::clock::{Instant, SharedClock};
+use ahash::AHashMap;
+use std::{hash::Hash, sync::Mutex, time::Duration};
+
+/// Map that drops entries not accessed within `time_to_idle`,
+/// measured on the given clock.
+///
+/// Expired entries are never returned. They are freed by a sweep
+/// over the whole map, done on insertion at most once per `time_to_idle`.
+pub struct IdleCache<K, V> {
+    clock: SharedClock,
+    time_to_idle: Duration,
+    inner: Mutex<Inner<K, V>>,
+}
+
+struct Inner<K, V> {
+    entries: AHashMap<K, Entry<V>>,
+    next_sweep: Instant,
+}
+
+struct Entry<V> {
+    value: V,
+    last_used: Instant,
+}
+
+impl<K, V> IdleCache<K, V>
+where
+    K: Hash + Eq,
+    V: Clone,
+{
+    pub fn new(clock: SharedClock, time_to_idle: Duration) -> Self {
+        let next_sweep = clock.now() + time_to_idle;
+        Self {
+            clock,
+            time_to_idle,
+            inner: Mutex::new(Inner {
+                entries: AHashMap::new(),
+                next_sweep,
+            }),
+        }
+    }
+
+    /// Gets the value for `key`, marking it as used.
+    pub fn get(&self, key: &K) -> Option<V> {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get_mut(key)?;
+        if now.duration_since(entry.last_used) >= self.time_to_idle {
+            inner.entries.remove(key);
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.value.clone())
+    }
+
+    /// Removes the entry for `key`, returning its value if it had not expired.
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let now = self.clock.now();
+        let entry = self.inner.lock().unwrap().entries.remove(key)?;
+        (now.duration_since(entry.last_used) < self.time_to_idle).then_some(entry.value)
+    }
+
+    /// Removes the entries for which `keep` returns false.
+    pub fn retain(&self, mut keep: impl FnMut(&K, &V) -> bool) {
+        self.inner
+            .lock()
+            .unwrap()
+            .entries
+            .retain(|key, entry| keep(key, &entry.value));
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let now = self.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        if now >= inner.next_sweep {
+            inner
+                .entries
+                .retain(|_, entry| now.duration_since(entry.last_used) < self.time_to_idle);
+            inner.next_sweep = now + self.time_to_idle;
+        }
+        inner.entries.insert(
+            key,
+            Entry {
+                value,
+                last_used: now,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use std::sync::Arc;
+
+    const TIME_TO_IDLE: Duration = Duration::from_secs(60);
+
+    fn cache() -> (ManualClock, IdleCache<u32, &'static str>) {
+        let clock = ManualClock::new();
+        let cache = IdleCache::new(Arc::new(clock.clone()), TIME_TO_IDLE);
+        (clock, cache)
+    }
+
+    #[test]
+    fn entries_expire_after_idle_time() {
+        let (clock, cache) = cache();
+        cache.insert(1, "a");
+
+        clock.advance(TIME_TO_IDLE - Duration::from_millis(1));
+        assert_eq!(cache.remove(&1), Some("a"));
+
+        cache.insert(1, "a");
+        clock.advance(TIME_TO_IDLE);
+        assert_eq!(cache.get(&1), None);
+        assert_eq!(cache.remove(&1), None);
+    }
+
+    #[test]
+    fn get_refreshes_entries() {
+        let (clock, cache) = cache();
+        cache.insert(1, "a");
+        for _ in 0..3 {
+            clock.advance(TIME_TO_IDLE / 2);
+            assert_eq!(cache.get(&1), Some("a"));
+        }
+        clock.advance(TIME_TO_IDLE);
+        assert_eq!(cache.get(&1), None);
+    }
+
+    #[test]
+    fn insert_sweeps_expired_entries() {
+        let (clock, cache) = cache();
+        cache.insert(1, "a");
+        clock.advance(TIME_TO_IDLE);
+        cache.insert(2, "b");
+        assert_eq!(cache.inner.lock().unwrap().entries.len(), 1);
+        assert_eq!(cache.get(&2), Some("b"));
+    }
+}
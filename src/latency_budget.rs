@@ -0,0 +1,88 @@
+//! Latency added by the proxy to each packet: the time from a packet's
+//! receipt on one leg to the completion of its write on the other,
+//! bucketed by packet type and allocation class.
+//!
+//! For packets written to QUIC, "write completion" means the packet was
+//! accepted by the stream or queued as a datagram, so this measures
+//! head-of-line blocking within the proxy (e.g. a movement update stuck
+//! behind chunk data), not the network round trip.
+
+use crate::{
+    histogram::{self, Histogram},
+    packet_flow::Direction,
+    stream_allocation::AllocationClass,
+};
+use ahash::AHashMap;
+use std::{sync::Mutex, time::Duration};
+
+const LATENCY_BUCKETS_MILLIS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0,
+];
+
+/// Allocation class label of packets written to TCP.
+const TCP_CLASS: &str = "tcp";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct SeriesKey {
+    direction: Direction,
+    packet: String,
+    class: &'static str,
+}
+
+/// Latency histograms, one per direction, packet type and allocation class.
+#[derive(Debug, Default)]
+pub struct LatencyBudgets {
+    series: Mutex<AHashMap<SeriesKey, Histogram>>,
+}
+
+impl LatencyBudgets {
+    /// Records the time a packet spent in the proxy. `class` is `None`
+    /// for packets written to TCP, which has no allocation.
+    pub fn record(
+        &self,
+        direction: Direction,
+        packet: &str,
+        class: Option<AllocationClass>,
+        latency: Duration,
+    ) {
+        let key = SeriesKey {
+            direction,
+            packet: packet.to_owned(),
+            class: class.map_or(TCP_CLASS, |class| class.into()),
+        };
+        self.series
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| Histogram::new(LATENCY_BUCKETS_MILLIS))
+            .observe(latency.as_secs_f64() * 1000.0);
+    }
+
+    /// Renders the histograms in the Prometheus text exposition format.
+    pub fn render(&self, out: &mut String) {
+        const NAME: &str = "quic_proxy_packet_latency_milliseconds";
+        let series = self.series.lock().unwrap();
+        if series.is_empty() {
+            return;
+        }
+        histogram::write_header(
+            out,
+            NAME,
+            "Time from a packet's receipt on one leg of the proxy to the completion \
+             of its write on the other.",
+        );
+        let mut keys: Vec<&SeriesKey> = series.keys().collect();
+        keys.sort();
+        for key in keys {
+            series[key].render_series(
+                out,
+                NAME,
+                &[
+                    ("direction", key.direction.as_ref()),
+                    ("packet", &key.packet),
+                    ("allocation", key.class),
+                ],
+            );
+        }
+    }
+}
@@ -8,7 +8,8 @@
 //! connection into QUIC.
 //!
 //! # Proxying process
-//! A newly opened connection first contacts the gateway server over QUIC.
+//! A newly opened connection first contacts the gateway server over QUIC,
+//! negotiating the revision of the protocol with ALPN (see `ALPN`).
 //! It opens a single bidirectional stream, called the _control stream_,
 //! which is used to transmit metadata related to the proxying process (i.e. not Minecraft packets).
 //! The client sends a message over the control stream indicating the destination server it wishes to connect to.
@@ -24,35 +25,175 @@
 //! In this case, it sends a message over the control stream indicating the encryption key.
 //! Note that Minecraft encryption is only applied between the gateway and the destination. Over QUIC,
 //! the much more secure TLS built into QUIC is used instead.
+//!
+//! # API stability
+//! The supported public API is re-exported from [`prelude`]. Internal modules
+//! such as the proxy loop and stream allocation are private and may change freely.
+//!
+//! # Cargo features
+//! * `client` enables the `client` module, the side of the proxy embedded in the Minecraft client.
+//! * `gateway` enables the `gateway` module along with its server-side dependencies,
+//!   and `client`, through which it chains to upstream gateways.
+//! * `cli` (default) builds the binary and enables both of the above.
+//! * `proxy` enables the QUIC runtime shared by both sides, and is enabled by each of them.
+//!
+//! The JNI library only depends on `client`, so it does not pull in gateway dependencies.
+//! Without any features, only [`protocol`] (with [`phase`] and [`position`]) is built,
+//! without quinn or tokio, so that other tools can reuse the packet definitions.
 
 #![feature(error_generic_member_access)]
 #![allow(dead_code)]
 
+#[cfg(feature = "proxy")]
+mod affinity;
+#[cfg(feature = "proxy")]
+mod anomaly;
+#[cfg(feature = "proxy")]
+mod bandwidth;
+#[cfg(feature = "proxy")]
+mod build_info;
+#[cfg(feature = "client")]
 pub mod client;
+#[cfg(feature = "proxy")]
+pub mod clock;
+#[cfg(feature = "proxy")]
 mod control_stream;
+#[cfg(feature = "proxy")]
+mod destination;
+#[cfg(feature = "cli")]
+pub mod dev_server;
+#[cfg(feature = "proxy")]
 mod entity_id;
+#[cfg(feature = "gateway")]
 pub mod gateway;
+#[cfg(feature = "proxy")]
+mod histogram;
+#[cfg(feature = "proxy")]
+mod hold;
+#[cfg(feature = "proxy")]
+mod idle_cache;
+#[cfg(feature = "proxy")]
 mod io_duplex;
+#[cfg(feature = "proxy")]
+mod latency_budget;
+#[cfg(feature = "cli")]
+pub mod loadtest;
+#[cfg(feature = "proxy")]
+mod measurement;
+#[cfg(feature = "proxy")]
+mod memory_budget;
+#[cfg(feature = "proxy")]
+mod packet_flow;
+#[cfg(feature = "proxy")]
+mod packet_log;
+#[cfg(feature = "proxy")]
+mod packet_rate;
+#[cfg(feature = "proxy")]
 mod packet_translation;
-mod position;
-mod protocol;
+pub mod phase;
+pub mod position;
+#[cfg(feature = "proxy")]
+pub mod prelude;
+#[cfg(feature = "pprof")]
+pub mod profiling;
+pub mod protocol;
+#[cfg(feature = "proxy")]
 mod proxy;
+#[cfg(feature = "proxy")]
+mod redundancy;
+#[cfg(feature = "proxy")]
 mod sequence;
+#[cfg(feature = "proxy")]
+pub mod stats;
+#[cfg(feature = "proxy")]
 mod stream;
+#[cfg(feature = "proxy")]
 mod stream_allocation;
+#[cfg(feature = "proxy")]
 mod stream_priority;
+#[cfg(feature = "proxy")]
+mod tcp_options;
+#[cfg(feature = "cli")]
+pub mod test_vectors;
+#[cfg(feature = "proxy")]
+pub mod timeline;
+#[cfg(feature = "proxy")]
+mod watchdog;
+#[cfg(feature = "proxy")]
+mod write_batching;
 
+#[cfg(feature = "proxy")]
 pub use quinn;
-use quinn::{IdleTimeout, TransportConfig, VarInt};
-use std::time::Duration;
+#[cfg(feature = "proxy")]
+use quinn::{ClientConfig, IdleTimeout, TransportConfig, VarInt};
+#[cfg(feature = "proxy")]
+use std::{sync::Arc, time::Duration};
+
+/// ALPN protocol of this revision of the proxy protocol. Clients and
+/// gateways offering different protocols fail at the QUIC handshake,
+/// so that incompatible revisions are told apart before the control
+/// stream is opened. Peers that offer no protocol are refused as well.
+#[cfg(feature = "proxy")]
+pub const ALPN: &[u8] = b"mc-quic-proxy/1";
+
+/// Time without any packets after which a QUIC connection is closed.
+#[cfg(feature = "proxy")]
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Maximum number of unidirectional streams each side of a connection
+/// may have open at once.
+#[cfg(feature = "proxy")]
+const MAX_CONCURRENT_UNI_STREAMS: u32 = 16384;
 
 /// Gets the QUIC transport config for a proxied connection.
+///
+/// Pings are sent at a third of the idle timeout, so that the connection
+/// survives phases in which neither peer has anything to send (e.g. while
+/// the destination server is slowly preparing the configuration of a
+/// modded game), and only closes if the peer stops responding.
+#[cfg(feature = "proxy")]
 pub fn transport_config() -> TransportConfig {
     let mut config = TransportConfig::default();
     config
-        .max_concurrent_uni_streams(VarInt::from_u32(16384))
-        .max_idle_timeout(Some(
-            IdleTimeout::try_from(Duration::from_secs(30)).unwrap(),
-        ));
+        .max_concurrent_uni_streams(VarInt::from_u32(MAX_CONCURRENT_UNI_STREAMS))
+        .max_idle_timeout(Some(IdleTimeout::try_from(IDLE_TIMEOUT).unwrap()))
+        .keep_alive_interval(Some(IDLE_TIMEOUT / 3));
+    config
+}
+
+/// Gets a QUIC client config for connecting to gateways, verifying
+/// their certificates against the platform's root certificates.
+/// Its transport is `transport_config()`.
+#[cfg(feature = "proxy")]
+pub fn client_config() -> anyhow::Result<ClientConfig> {
+    let mut roots = rustls::RootCertStore::empty();
+    for certificate in rustls_native_certs::load_native_certs()? {
+        if let Err(e) = roots.add(&rustls::Certificate(certificate.0)) {
+            tracing::warn!("Skipping invalid root certificate: {e}");
+        }
+    }
+    let crypto = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(client_config_with_crypto(crypto))
+}
+
+/// Like `client_config`, but with the given TLS config, e.g. to verify
+/// certificates against other roots. Its ALPN protocols are replaced
+/// with `ALPN`.
+#[cfg(feature = "proxy")]
+pub fn client_config_with_crypto(mut crypto: rustls::ClientConfig) -> ClientConfig {
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    crypto.enable_early_data = true;
+    let mut config = ClientConfig::new(Arc::new(crypto));
+    config.transport_config(Arc::new(transport_config()));
     config
 }
+
+/// Gets a QUIC server config for a gateway from its TLS config,
+/// whose ALPN protocols are replaced with `ALPN`.
+#[cfg(feature = "gateway")]
+pub fn server_config_with_crypto(mut crypto: rustls::ServerConfig) -> quinn::ServerConfig {
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    quinn::ServerConfig::with_crypto(Arc::new(crypto))
+}
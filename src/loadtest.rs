@@ -0,0 +1,390 @@
+//! Soak testing of a gateway with synthetic clients.
+//!
+//! Each synthetic session opens a `ClientHandle` to the gateway and
+//! then plays the part of a Minecraft client over the local TCP port:
+//! handshake, login, configuration, then keepalives, pings and movement
+//! in the Play state.
+
+use crate::{
+    client::{
+        measurement::{MeasurementOptions, Transport},
+        resolver::{Resolver, ResolverBackend},
+        ClientHandle, ClientOptions,
+    },
+    dev_server,
+    dev_server::encode,
+    protocol::{
+        packet::{client, client::handshake::NextState, server, side, state},
+        PROTOCOL_VERSION,
+    },
+    proxy::{PacketIo, VanillaPacketIo},
+};
+use ahash::AHashMap;
+use anyhow::{bail, Context};
+use quinn::Endpoint;
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    net::SocketAddr,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    select, task,
+    task::LocalSet,
+    time::{interval, sleep_until},
+};
+
+/// Options for a load test.
+#[derive(Debug, Clone)]
+pub struct LoadTestOptions {
+    pub gateway_host: String,
+    pub gateway_port: u16,
+    pub authentication_key: String,
+    /// Number of concurrent sessions.
+    pub sessions: usize,
+    /// Time over which sessions are started.
+    pub ramp_up: Duration,
+    /// How long each session stays in the Play state.
+    pub duration: Duration,
+    /// Destination server. If unset, a built-in dummy server is started
+    /// locally, which only works if the gateway can reach this host.
+    pub destination: Option<SocketAddr>,
+    pub ping_interval: Duration,
+    pub movement_interval: Duration,
+    /// Skip verification of the gateway certificate,
+    /// e.g. for a gateway using a self-signed certificate.
+    pub insecure: bool,
+    /// See `ClientOptions::redundancy`.
+    pub redundancy: bool,
+    /// Also compare QUIC and TCP round-trip times to the gateway,
+    /// probing at the ping interval. See `ClientOptions::measurement`.
+    pub measure: bool,
+    /// Resolver for the gateway host, shared by all sessions.
+    pub resolver: ResolverBackend,
+}
+
+/// Results of a load test.
+#[derive(Debug, Default)]
+pub struct LoadTestReport {
+    pub sessions: usize,
+    pub succeeded: usize,
+    /// Failed sessions, grouped by error message.
+    pub failures: AHashMap<String, usize>,
+    /// Time from opening the session until reaching the Play state.
+    pub time_to_play: Vec<Duration>,
+    /// Round-trip times of Play-state pings through the proxy.
+    pub ping_rtts: Vec<Duration>,
+    pub keepalives: u64,
+    /// Round-trip times of measurement probes, if measuring.
+    pub quic_probe_rtts: Vec<Duration>,
+    pub tcp_probe_rtts: Vec<Duration>,
+}
+
+/// Runs a load test against a gateway.
+pub async fn run(options: &LoadTestOptions) -> anyhow::Result<LoadTestReport> {
+    let mut endpoint = Endpoint::client("0.0.0.0:0".parse()?)?;
+    let client_config = if options.insecure {
+        let crypto = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
+            .with_no_client_auth();
+        crate::client_config_with_crypto(crypto)
+    } else {
+        crate::client_config()?
+    };
+    endpoint.set_default_client_config(client_config);
+
+    let report = LocalSet::new()
+        .run_until(run_local(&endpoint, options))
+        .await;
+    endpoint.close(0u32.into(), b"load test finished");
+    report
+}
+
+async fn run_local(
+    endpoint: &Endpoint,
+    options: &LoadTestOptions,
+) -> anyhow::Result<LoadTestReport> {
+    let destination = match options.destination {
+        Some(destination) => destination,
+        None => {
+            let listener = TcpListener::bind("0.0.0.0:0").await?;
+            let address = listener.local_addr()?;
+            tracing::info!("Started dummy destination server on {address}");
+            task::spawn_local(dev_server::serve(listener));
+            address
+        }
+    };
+
+    let resolver = Resolver::new(options.resolver)?;
+    let report = Rc::new(RefCell::new(LoadTestReport {
+        sessions: options.sessions,
+        ..Default::default()
+    }));
+    let start_delay = options.ramp_up / options.sessions.max(1) as u32;
+    let mut tasks = Vec::with_capacity(options.sessions);
+    for i in 0..options.sessions {
+        let endpoint = endpoint.clone();
+        let options = options.clone();
+        let resolver = Arc::clone(&resolver);
+        let report = Rc::clone(&report);
+        tasks.push(task::spawn_local(async move {
+            tokio::time::sleep(start_delay * i as u32).await;
+            let result = run_session(&endpoint, &options, &resolver, destination, i, &report).await;
+            let mut report = report.borrow_mut();
+            match result {
+                Ok(()) => report.succeeded += 1,
+                Err(e) => *report.failures.entry(format!("{e:#}")).or_default() += 1,
+            }
+        }));
+    }
+    for task in tasks {
+        task.await?;
+    }
+
+    let report = Rc::into_inner(report).unwrap().into_inner();
+    Ok(report)
+}
+
+async fn run_session(
+    endpoint: &Endpoint,
+    options: &LoadTestOptions,
+    resolver: &Arc<Resolver>,
+    destination: SocketAddr,
+    index: usize,
+    report: &RefCell<LoadTestReport>,
+) -> anyhow::Result<()> {
+    let start = Instant::now();
+    let handle = ClientHandle::open_with_options(
+        endpoint,
+        &options.gateway_host,
+        options.gateway_port,
+        destination,
+        &options.authentication_key,
+        &ClientOptions {
+            redundancy: options.redundancy,
+            resolver: Some(Arc::clone(resolver)),
+            measurement: options.measure.then(|| MeasurementOptions {
+                interval: options.ping_interval,
+                ..Default::default()
+            }),
+            ..Default::default()
+        },
+    )
+    .await
+    .context("failed to connect to gateway")?;
+    let stream = TcpStream::connect(("127.0.0.1", handle.bound_port())).await?;
+    stream.set_nodelay(true)?;
+
+    let conn: VanillaPacketIo<side::Client, state::Handshake> = VanillaPacketIo::new(stream)?;
+    conn.send_packet(client::handshake::Packet::Handshake(
+        client::handshake::Handshake {
+            protocol_version: PROTOCOL_VERSION as u32,
+            server_address: destination.ip().to_string().as_str().into(),
+            server_port: destination.port(),
+            next_state: NextState::Login,
+        },
+    ))
+    .await?;
+
+    let conn = conn.switch_state::<state::Login>();
+    let username = format!("loadtest{index}");
+    conn.send_packet(client::login::Packet::LoginStart(
+        client::login::LoginStart {
+            ignored_data: encode(|encoder| {
+                encoder.write_string(&username);
+                encoder.write_slice(&(index as u128).to_be_bytes());
+            }),
+        },
+    ))
+    .await?;
+    loop {
+        match conn.recv_packet().await.context("login")? {
+            server::login::Packet::LoginSuccess(_) => break,
+            server::login::Packet::Disconnect(_) => bail!("disconnected during login"),
+            server::login::Packet::EncryptionRequest(_) => {
+                bail!("destination server is in online mode")
+            }
+            server::login::Packet::SetCompression(_) => {
+                bail!("destination server enables compression")
+            }
+            _ => {}
+        }
+    }
+    conn.send_packet(client::login::Packet::LoginAcknowledged(
+        client::login::LoginAcknowledged {
+            ignored_data: Vec::new(),
+        },
+    ))
+    .await?;
+
+    let conn = conn.switch_state::<state::Configuration>();
+    loop {
+        if let server::configuration::Packet::FinishConfiguration(_) =
+            conn.recv_packet().await.context("configuration")?
+        {
+            break;
+        }
+    }
+    conn.send_packet(client::configuration::Packet::FinishConfiguration(
+        client::configuration::FinishConfiguration {
+            ignored_data: Vec::new(),
+        },
+    ))
+    .await?;
+
+    let conn = conn.switch_state::<state::Play>();
+    report.borrow_mut().time_to_play.push(start.elapsed());
+    play(&conn, options, report).await?;
+    if let Some(measurement) = handle.measurement() {
+        let mut report = report.borrow_mut();
+        report
+            .quic_probe_rtts
+            .extend(measurement.samples(Transport::Quic));
+        report
+            .tcp_probe_rtts
+            .extend(measurement.samples(Transport::Tcp));
+    }
+    Ok(())
+}
+
+async fn play(
+    conn: &VanillaPacketIo<side::Client, state::Play>,
+    options: &LoadTestOptions,
+    report: &RefCell<LoadTestReport>,
+) -> anyhow::Result<()> {
+    let epoch = Instant::now();
+    let end = tokio::time::Instant::now() + options.duration;
+    let mut ping = interval(options.ping_interval);
+    let mut movement = interval(options.movement_interval);
+    let mut y = 64.0f64;
+    loop {
+        select! {
+            _ = sleep_until(end) => return Ok(()),
+            _ = ping.tick() => {
+                let payload = epoch.elapsed().as_nanos() as i64;
+                conn.send_packet(client::play::Packet::PingRequest(client::play::PingRequest {
+                    ignored_data: payload.to_be_bytes().to_vec(),
+                }))
+                .await?;
+            }
+            _ = movement.tick() => {
+                y = if y > 64.0 { 64.0 } else { 64.5 };
+                conn.send_packet(client::play::Packet::SetPlayerPosition(client::play::SetPlayerPosition {
+                    ignored_data: encode(|encoder| {
+                        encoder.write_f64(0.5);
+                        encoder.write_f64(y);
+                        encoder.write_f64(0.5);
+                        encoder.write_bool(true);
+                    }),
+                }))
+                .await?;
+            }
+            packet = conn.recv_packet() => match packet.context("play")? {
+                server::play::Packet::PingResponse(response) => {
+                    let payload: [u8; 8] = response.ignored_data.as_slice().try_into()?;
+                    let sent = Duration::from_nanos(i64::from_be_bytes(payload) as u64);
+                    report.borrow_mut().ping_rtts.push(epoch.elapsed() - sent);
+                }
+                server::play::Packet::KeepAlive(keepalive) => {
+                    conn.send_packet(client::play::Packet::KeepAlive(client::play::KeepAlive {
+                        ignored_data: keepalive.ignored_data,
+                    }))
+                    .await?;
+                    report.borrow_mut().keepalives += 1;
+                }
+                server::play::Packet::Disconnect(_) => bail!("disconnected during play"),
+                _ => {}
+            },
+        }
+    }
+}
+
+impl Display for LoadTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Sessions: {} succeeded, {} failed ({:.1}% success)",
+            self.succeeded,
+            self.sessions - self.succeeded,
+            self.succeeded as f64 / self.sessions.max(1) as f64 * 100.0
+        )?;
+        for (error, count) in &self.failures {
+            writeln!(f, "  {count}x {error}")?;
+        }
+        writeln!(
+            f,
+            "Time to Play state: {}",
+            Percentiles::new(&self.time_to_play)
+        )?;
+        writeln!(
+            f,
+            "Ping RTT:           {}",
+            Percentiles::new(&self.ping_rtts)
+        )?;
+        if !self.quic_probe_rtts.is_empty() || !self.tcp_probe_rtts.is_empty() {
+            writeln!(
+                f,
+                "QUIC probe RTT:     {}",
+                Percentiles::new(&self.quic_probe_rtts)
+            )?;
+            writeln!(
+                f,
+                "TCP probe RTT:      {}",
+                Percentiles::new(&self.tcp_probe_rtts)
+            )?;
+        }
+        write!(f, "Keepalives answered: {}", self.keepalives)
+    }
+}
+
+struct Percentiles(Vec<Duration>);
+
+impl Percentiles {
+    fn new(samples: &[Duration]) -> Self {
+        let mut samples = samples.to_vec();
+        samples.sort_unstable();
+        Self(samples)
+    }
+
+    fn percentile(&self, p: f64) -> Duration {
+        let index = ((self.0.len() - 1) as f64 * p).round() as usize;
+        self.0[index]
+    }
+}
+
+impl Display for Percentiles {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no samples");
+        }
+        write!(
+            f,
+            "p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {:.1?} ({} samples)",
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99),
+            self.percentile(1.0),
+            self.0.len()
+        )
+    }
+}
+
+struct SkipServerVerification;
+
+impl rustls::client::ServerCertVerifier for SkipServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        Ok(rustls::client::ServerCertVerified::assertion())
+    }
+}
@@ -1,113 +1,694 @@
 use anyhow::Context;
-use clap::{Args, Parser, Subcommand};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
 use mimalloc::MiMalloc;
-use minecraft_quic_proxy::{gateway, gateway::AuthenticationKey, transport_config};
-use quinn::{Endpoint, ServerConfig};
+use minecraft_quic_proxy::{
+    dev_server,
+    gateway::{
+        self,
+        config::{
+            CertificateConfig, CertificateReloadConfig, RetryConfig, TokenConfig,
+            TransportLimitsConfig,
+        },
+        AcmeOptions, AuthenticationKey, AuthenticationKeys, CheckOutcome, Listener,
+        PolicyEvaluation, PolicyEvaluationRequest, ReloadableCertificates, SelfTest, TokenClaims,
+    },
+    loadtest::{self, LoadTestOptions},
+    prelude::{BuildInfo, Destination, Gateway, GatewayConfig, ResolverBackend},
+    test_vectors, transport_config,
+};
+use quinn::{Endpoint, EndpointConfig, IdleTimeout, ServerConfig, TokioRuntime, VarInt};
 use std::{
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
+use tokio::{net::TcpListener, task::LocalSet};
 
 #[global_allocator]
 static ALLOCATOR: MiMalloc = MiMalloc;
 
 #[derive(Debug, Parser)]
+#[command(disable_version_flag = true, args_conflicts_with_subcommands = true)]
 struct Cli {
+    /// Print the version and what this build supports, then exit.
+    #[arg(short = 'V', long)]
+    version: bool,
+    /// With `--version`, print the report as JSON.
+    #[arg(long, requires = "version")]
+    json: bool,
     #[command(subcommand)]
-    command: Command,
+    command: Option<Command>,
 }
 
 #[derive(Debug, Subcommand)]
 enum Command {
     Gateway(GatewayArgs),
+    /// Soak-test a gateway with synthetic clients.
+    Loadtest(LoadtestArgs),
+    /// Run a minimal offline-mode Minecraft server to use as a destination
+    /// during development.
+    DevServer(DevServerArgs),
+    /// Write the protocol test vectors, or check them for drift.
+    TestVectors(TestVectorsArgs),
+    /// Ask a running gateway whether its policies would admit a
+    /// connection, and which rules decide it, without connecting.
+    EvaluatePolicy(EvaluatePolicyArgs),
+    /// Issue a short-lived authentication token for a player, signed
+    /// with the secret of the gateway's `tokens` configuration.
+    IssueToken(IssueTokenArgs),
+}
+
+#[derive(Debug, Args)]
+struct IssueTokenArgs {
+    /// File holding the token secret.
+    #[arg(long)]
+    secret_file: PathBuf,
+    /// Identity the token authenticates as.
+    #[arg(long, default_value = gateway::DEFAULT_IDENTITY)]
+    identity: String,
+    /// Destination server the token is limited to, as the client names it.
+    /// Any if unset.
+    #[arg(long)]
+    destination: Option<String>,
+    /// Time until the token expires.
+    #[arg(long, default_value_t = 3600)]
+    valid_for_secs: u64,
+}
+
+#[derive(Debug, Args)]
+struct EvaluatePolicyArgs {
+    /// Address of the gateway's admin API.
+    #[arg(long)]
+    admin: SocketAddr,
+    /// Identity the connection authenticates as.
+    #[arg(long, default_value = gateway::DEFAULT_IDENTITY)]
+    identity: String,
+    /// Listener the connection arrives on.
+    #[arg(long, default_value = gateway::DEFAULT_LISTENER)]
+    listener: String,
+    /// Destination server the connection asks for.
+    destination: SocketAddr,
+    /// Print the evaluation as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug, Args)]
+struct TestVectorsArgs {
+    #[arg(long, default_value = "test-vectors")]
+    dir: PathBuf,
+    /// Check the vectors in `--dir` instead of overwriting them.
+    #[arg(long)]
+    check: bool,
+}
+
+#[derive(Debug, Args)]
+struct DevServerArgs {
+    #[arg(short, long, default_value = "25565")]
+    port: u16,
 }
 
 #[derive(Debug, Args)]
 struct GatewayArgs {
     #[arg(short, long, default_value = "6666")]
     port: u16,
+    /// Address to listen on instead of all IPv4 addresses on `--port`.
+    /// May be given several times, e.g. `--listen 0.0.0.0:6666
+    /// --listen [::]:6666` to listen on IPv4 and IPv6.
+    #[arg(long, conflicts_with = "port")]
+    listen: Vec<SocketAddr>,
     #[arg(long)]
     self_signed_cert: bool,
     #[arg(long)]
     cert: Option<PathBuf>,
     #[arg(long)]
     priv_key: Option<PathBuf>,
+    /// Obtain and renew the certificate for this domain from an ACME
+    /// certificate authority (Let's Encrypt by default) instead of passing
+    /// `--cert` and `--priv-key`. May be given several times.
+    #[arg(long, conflicts_with_all = ["cert", "self_signed_cert"])]
+    acme_domain: Vec<String>,
+    /// Contact email of the ACME account, for expiry notices.
+    #[arg(long, requires = "acme_domain")]
+    acme_email: Option<String>,
+    #[arg(long, default_value = AcmeOptions::LETS_ENCRYPT_DIRECTORY)]
+    acme_directory: String,
+    /// Directory where the ACME account key and the certificate are kept.
+    #[arg(long, default_value = "acme")]
+    acme_cache_dir: PathBuf,
+    /// Address of the temporary HTTP listener answering ACME challenges.
+    /// Port 80 of each domain must reach it.
+    #[arg(long, default_value = "0.0.0.0:80")]
+    acme_challenge_address: SocketAddr,
+    /// Authentication key of the default identity. Prefer `--auth-key-file`
+    /// or the environment variable: arguments are visible to other users
+    /// of the host, e.g. in `ps`, and end up in shell history.
+    #[arg(
+        long,
+        env = "MCQP_AUTH_KEY",
+        hide_env_values = true,
+        required_unless_present_any = ["print_config_schema", "auth_key_file"]
+    )]
+    auth_key: Option<String>,
+    /// File with the authentication keys of the default identity, one per
+    /// line, any of which clients may use. Blank lines and lines starting
+    /// with `#` are ignored. Reread on SIGHUP, e.g. to rotate keys.
+    /// Takes precedence over `--auth-key`.
+    #[arg(long)]
+    auth_key_file: Option<PathBuf>,
+    /// Path to a TOML configuration file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Validate the setup, print the results and exit without starting.
+    #[arg(long)]
+    check: bool,
+    /// Print the JSON schema of the configuration file and exit.
+    #[arg(long)]
+    print_config_schema: bool,
+    /// Local address to connect to destination servers from, e.g. on
+    /// hosts with several addresses. May be given once per address family.
+    /// Overrides `outbound_bind` in the configuration file.
+    #[arg(long)]
+    outbound_bind: Vec<IpAddr>,
+    /// Refuse to start with plaintext authentication keys.
+    /// Same as `require_hashed_keys` in the configuration file.
     #[arg(long)]
+    require_hashed_key: bool,
+    /// Allow destinations in loopback, private and link-local ranges, e.g.
+    /// to test with the dev server on the same host. Same as
+    /// `private_destinations.allow_all` in the configuration file.
+    #[arg(long)]
+    allow_private_destinations: bool,
+    /// Address to serve CPU flamegraphs on (e.g. `127.0.0.1:6060`).
+    #[cfg(feature = "pprof")]
+    #[arg(long)]
+    pprof: Option<std::net::SocketAddr>,
+}
+
+impl GatewayArgs {
+    /// Gets the addresses the listener configured on the command line listens on.
+    fn listen_addresses(&self) -> Vec<SocketAddr> {
+        if self.listen.is_empty() {
+            vec![SocketAddr::from(([0, 0, 0, 0], self.port))]
+        } else {
+            self.listen.clone()
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct LoadtestArgs {
+    /// Gateway address as `host:port`.
+    #[arg(long)]
+    gateway: String,
+    #[arg(long, env = "MCQP_AUTH_KEY", hide_env_values = true)]
     auth_key: String,
+    /// Number of concurrent sessions.
+    #[arg(long, default_value = "100")]
+    sessions: usize,
+    /// Seconds over which sessions are started.
+    #[arg(long, default_value = "10")]
+    ramp_up: u64,
+    /// Seconds each session stays in the Play state.
+    #[arg(long, default_value = "60")]
+    duration: u64,
+    /// Destination server. Defaults to a built-in dummy server,
+    /// which the gateway must be able to reach on this host.
+    #[arg(long)]
+    destination: Option<SocketAddr>,
+    #[arg(long, default_value = "1000")]
+    ping_interval_millis: u64,
+    #[arg(long, default_value = "50")]
+    movement_interval_millis: u64,
+    /// Skip verification of the gateway certificate.
+    #[arg(long)]
+    insecure: bool,
+    /// Send keepalives and teleports redundantly.
+    #[arg(long)]
+    redundancy: bool,
+    /// Compare QUIC and TCP round-trip times to the gateway.
+    /// Requires a gateway with a measurement endpoint.
+    #[arg(long)]
+    measure: bool,
+    /// Resolver for the gateway host: `system`, `caching`
+    /// or `doh:<provider>` (cloudflare, google or quad9).
+    #[arg(long, default_value = "system")]
+    resolver: ResolverBackend,
 }
 
 #[tokio::main]
 pub async fn main() -> anyhow::Result<()> {
+    #[cfg(feature = "tokio-console")]
+    console_subscriber::init();
+    #[cfg(not(feature = "tokio-console"))]
     tracing_subscriber::fmt::init();
     let cli = Cli::parse();
 
-    let Command::Gateway(args) = cli.command;
+    if cli.version {
+        let build_info = BuildInfo::current();
+        if cli.json {
+            println!("{}", serde_json::to_string_pretty(&build_info)?);
+        } else {
+            println!("{build_info}");
+        }
+        return Ok(());
+    }
+    let Some(command) = cli.command else {
+        Cli::command()
+            .error(ErrorKind::MissingSubcommand, "a subcommand is required")
+            .exit();
+    };
+    match command {
+        Command::Gateway(args) => run_gateway(args).await,
+        Command::Loadtest(args) => run_loadtest(args).await,
+        Command::DevServer(args) => run_dev_server(args).await,
+        Command::TestVectors(args) => run_test_vectors(args),
+        Command::EvaluatePolicy(args) => run_evaluate_policy(args).await,
+        Command::IssueToken(args) => run_issue_token(args),
+    }
+}
+
+fn run_issue_token(args: IssueTokenArgs) -> anyhow::Result<()> {
+    if let Some(destination) = &args.destination {
+        destination.parse::<Destination>()?;
+    }
+    let secret = TokenConfig {
+        secret_file: args.secret_file,
+    }
+    .load_secret()?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let claims = TokenClaims {
+        identity: args.identity,
+        expires_at: now + args.valid_for_secs,
+        destination: args.destination,
+    };
+    println!("{}", claims.issue(&secret));
+    Ok(())
+}
 
-    let mut server_config = if args.self_signed_cert {
-        server_config_self_signed()?
+async fn run_evaluate_policy(args: EvaluatePolicyArgs) -> anyhow::Result<()> {
+    let response = reqwest::Client::new()
+        .post(format!("http://{}/policy/evaluate", args.admin))
+        .json(&PolicyEvaluationRequest {
+            identity: args.identity,
+            destination: args.destination,
+            listener: args.listener,
+        })
+        .send()
+        .await
+        .context("failed to reach the admin API")?;
+    if !response.status().is_success() {
+        let status = response.status();
+        anyhow::bail!("admin API returned {status}: {}", response.text().await?);
+    }
+    let evaluation: PolicyEvaluation = response.json().await?;
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&evaluation)?);
     } else {
-        server_config_with_cert(
-            args.cert
-                .as_ref()
-                .context("must provide a certificate path or enable --self-signed-cert")?,
+        print!("{evaluation}");
+    }
+    if !evaluation.allowed {
+        anyhow::bail!("connection would be denied");
+    }
+    Ok(())
+}
+
+fn run_test_vectors(args: TestVectorsArgs) -> anyhow::Result<()> {
+    if args.check {
+        test_vectors::verify(&args.dir)?;
+        println!("Test vectors in {} match", args.dir.display());
+    } else {
+        test_vectors::write(&args.dir)?;
+        println!("Wrote test vectors to {}", args.dir.display());
+    }
+    Ok(())
+}
+
+async fn run_gateway(args: GatewayArgs) -> anyhow::Result<()> {
+    if args.print_config_schema {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&GatewayConfig::schema())?
+        );
+        return Ok(());
+    }
+    let auth_keys = match &args.auth_key_file {
+        Some(path) => read_auth_keys(path)?,
+        None => vec![args
+            .auth_key
+            .clone()
+            .context("must provide --auth-key, --auth-key-file or MCQP_AUTH_KEY")?],
+    };
+
+    let mut config = match &args.config {
+        Some(path) => GatewayConfig::load(path)?,
+        None => GatewayConfig::default(),
+    };
+    config.require_hashed_keys |= args.require_hashed_key;
+    config.private_destinations.allow_all |= args.allow_private_destinations;
+    if !args.outbound_bind.is_empty() {
+        config.proxy.outbound_bind = args.outbound_bind.clone();
+    }
+
+    let self_test = self_test(&args, &auth_keys, &config);
+    if args.check {
+        print!("{self_test}");
+        if self_test.failed() {
+            anyhow::bail!("self test failed");
+        }
+        return Ok(());
+    }
+    for check in self_test.checks() {
+        if let CheckOutcome::Warning(warning) = &check.outcome {
+            tracing::warn!("{}: {warning}", check.name);
+        }
+    }
+    if self_test.failed() {
+        anyhow::bail!("self test failed:\n{self_test}");
+    }
+
+    #[cfg(feature = "pprof")]
+    if let Some(address) = args.pprof {
+        tokio::spawn(async move {
+            if let Err(e) = minecraft_quic_proxy::profiling::serve(address).await {
+                tracing::error!("CPU profiling server failed: {e:?}");
+            }
+        });
+    }
+
+    let mut listeners = Vec::new();
+    let mut reloadable_certificates = Vec::new();
+    let server_config = if args.self_signed_cert {
+        Some(server_config_self_signed()?)
+    } else if let Some(cert) = &args.cert {
+        let certificates = reloadable_certificates_for(
+            cert,
             args.priv_key
                 .as_ref()
                 .context("must provide a private key path")?,
-        )?
+        )?;
+        let server_config = certificates.server_config(config.client_certificates.as_ref())?;
+        reloadable_certificates.push((gateway::DEFAULT_LISTENER.to_owned(), certificates));
+        Some(server_config)
+    } else if !args.acme_domain.is_empty() {
+        let options = acme_options(&args);
+        options
+            .ensure_certificate()
+            .await
+            .context("failed to obtain certificate from ACME")?;
+        let certificates = ReloadableCertificates::load(vec![options.certificate_config()])?;
+        let server_config = certificates.server_config(config.client_certificates.as_ref())?;
+        tokio::spawn(options.renew_periodically(Arc::clone(&certificates)));
+        reloadable_certificates.push((gateway::DEFAULT_LISTENER.to_owned(), certificates));
+        Some(server_config)
+    } else if config.listeners.is_empty() {
+        anyhow::bail!(
+            "must provide a certificate path, enable --self-signed-cert or --acme-domain, or configure listeners"
+        );
+    } else {
+        None
     };
-    server_config.transport_config(Arc::new(transport_config()));
+    if let Some(server_config) = server_config {
+        listeners.extend(self::listeners(
+            gateway::DEFAULT_LISTENER,
+            server_config,
+            &args.listen_addresses(),
+            config.retry.as_ref(),
+            &config.transport,
+        )?);
+    }
+    for listener in &config.listeners {
+        let certificates = ReloadableCertificates::load(listener.certificates.clone())
+            .with_context(|| {
+                format!("failed to load certificates for listener {}", listener.name)
+            })?;
+        listeners.extend(self::listeners(
+            &listener.name,
+            certificates.server_config(config.client_certificates.as_ref())?,
+            listener.listen.addresses(),
+            config.retry.as_ref(),
+            &config.transport,
+        )?);
+        reloadable_certificates.push((listener.name.clone(), certificates));
+    }
+    spawn_certificate_reloading(reloadable_certificates, config.certificate_reload.as_ref())?;
 
-    let endpoint = Endpoint::server(
-        server_config,
-        format!("0.0.0.0:{}", args.port).parse().unwrap(),
-    )?;
+    let authentication_keys: Vec<_> = auth_keys
+        .into_iter()
+        .map(AuthenticationKey::parse)
+        .collect();
+    let main_key = authentication_keys[0].clone();
+    let authentication_keys = AuthenticationKeys::new(authentication_keys);
+    if let Some(path) = &args.auth_key_file {
+        spawn_auth_key_reloading(
+            path.clone(),
+            authentication_keys.clone(),
+            config.require_hashed_keys,
+        )?;
+    }
 
-    let authentication_key = if argon2::PasswordHash::new(&args.auth_key).is_ok() {
-        AuthenticationKey::Hashed(args.auth_key)
-    } else {
-        tracing::warn!("Using plaintext authentication key. This is likely to expose side channel vulnerabilities.");
-        AuthenticationKey::Plaintext(args.auth_key)
-    };
+    let gateway =
+        Gateway::new(&listeners, &main_key, config).with_authentication_keys(authentication_keys);
+    let shutdown = gateway.shutdown_handle();
+    tokio::spawn(async move {
+        match shutdown_signal().await {
+            Ok(()) => {
+                tracing::info!("Received shutdown signal");
+                shutdown.shutdown();
+            }
+            Err(e) => tracing::error!("Failed to listen for shutdown signals: {e}"),
+        }
+    });
+    gateway.run().await?;
 
-    tracing::info!("Listening on {}", endpoint.local_addr()?);
-    gateway::run(&endpoint, &authentication_key).await?;
+    Ok(())
+}
+
+/// Waits for Ctrl-C or, on Unix, SIGTERM.
+async fn shutdown_signal() -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result,
+            _ = terminate.recv() => Ok(()),
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await
+}
 
+/// Reads authentication keys from `path`, one per line,
+/// skipping blank lines and comments.
+fn read_auth_keys(path: &Path) -> anyhow::Result<Vec<String>> {
+    let keys: Vec<_> = fs_err::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+    if keys.is_empty() {
+        anyhow::bail!("no authentication keys in {}", path.display());
+    }
+    Ok(keys)
+}
+
+/// Rereads the authentication keys from `path` on SIGHUP (on Unix).
+/// If they fail to load, e.g. because the file is being written,
+/// the previous keys stay in use.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn spawn_auth_key_reloading(
+    path: PathBuf,
+    keys: AuthenticationKeys,
+    require_hashed: bool,
+) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let reload = move || -> anyhow::Result<usize> {
+            let new_keys: Vec<_> = read_auth_keys(&path)?
+                .into_iter()
+                .map(AuthenticationKey::parse)
+                .collect();
+            if require_hashed && new_keys.iter().any(AuthenticationKey::is_plaintext) {
+                anyhow::bail!("plaintext key, but hashed keys are required");
+            }
+            let count = new_keys.len();
+            keys.replace(new_keys);
+            Ok(count)
+        };
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match reload() {
+                    Ok(count) => tracing::info!("Reloaded {count} authentication keys"),
+                    Err(e) => tracing::error!("Failed to reload authentication keys: {e:#}"),
+                }
+            }
+        });
+    }
     Ok(())
 }
 
-fn server_config_with_cert(cert_path: &Path, priv_key_path: &Path) -> anyhow::Result<ServerConfig> {
-    // Code adapted from Quinn examples
-    let key = fs_err::read(priv_key_path).context("failed to read private key")?;
-    let mut key = key.as_slice();
-    let key = if priv_key_path.extension().map_or(false, |x| x == "der") {
-        rustls::PrivateKey(key.to_vec())
-    } else {
-        let mut pkcs8 = rustls_pemfile::pkcs8_private_keys(&mut key);
-        match pkcs8.next() {
-            Some(x) => rustls::PrivateKey(x?.secret_pkcs8_der().to_vec()),
-            None => {
-                drop(pkcs8);
-                let rsa = rustls_pemfile::rsa_private_keys(&mut key);
-                match rsa.into_iter().next() {
-                    Some(x) => rustls::PrivateKey(x?.secret_pkcs1_der().to_vec()),
-                    None => {
-                        anyhow::bail!("no private keys found");
+/// Reloads the listeners' certificates on SIGHUP (on Unix) and, if
+/// configured, whenever their files change. Certificates that fail to
+/// reload, e.g. because only the key has been replaced yet, stay in use.
+fn spawn_certificate_reloading(
+    certificates: Vec<(String, Arc<ReloadableCertificates>)>,
+    reload: Option<&CertificateReloadConfig>,
+) -> anyhow::Result<()> {
+    let certificates = Arc::new(certificates);
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+        let certificates = Arc::clone(&certificates);
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                tracing::info!("Received SIGHUP, reloading certificates");
+                for (listener, certificates) in certificates.iter() {
+                    match certificates.reload() {
+                        Ok(()) => tracing::info!("Reloaded certificates of listener {listener}"),
+                        Err(e) => tracing::error!(
+                            "Failed to reload certificates of listener {listener}: {e:#}"
+                        ),
+                    }
+                }
+            }
+        });
+    }
+    if let Some(reload) = reload {
+        let mut interval = tokio::time::interval(reload.check_interval());
+        tokio::spawn(async move {
+            loop {
+                interval.tick().await;
+                for (listener, certificates) in certificates.iter() {
+                    match certificates.reload_if_modified() {
+                        Ok(true) => {
+                            tracing::info!("Reloaded modified certificates of listener {listener}")
+                        }
+                        Ok(false) => {}
+                        Err(e) => tracing::error!(
+                            "Failed to reload modified certificates of listener {listener}: {e:#}"
+                        ),
                     }
                 }
             }
+        });
+    }
+    Ok(())
+}
+
+/// Runs the startup self-test, covering both the configuration file
+/// and the command line arguments.
+fn self_test(args: &GatewayArgs, auth_keys: &[String], config: &GatewayConfig) -> SelfTest {
+    let mut self_test = SelfTest::for_config(config);
+    for (i, auth_key) in auth_keys.iter().enumerate() {
+        let name = if auth_keys.len() == 1 {
+            "authentication key".to_owned()
+        } else {
+            format!("authentication key {}", i + 1)
+        };
+        self_test.authentication_key(&name, auth_key, config.require_hashed_keys);
+    }
+    if let (false, Some(cert), Some(priv_key)) = (args.self_signed_cert, &args.cert, &args.priv_key)
+    {
+        self_test.certificate_pair("certificate of listener default", cert, priv_key);
+    }
+    if !args.acme_domain.is_empty() {
+        self_test.tcp_bindable("ACME challenge listener", args.acme_challenge_address);
+    }
+    if args.self_signed_cert || args.cert.is_some() || !args.acme_domain.is_empty() {
+        for address in args.listen_addresses() {
+            self_test.udp_bindable(&format!("listener default on {address}"), address);
         }
+    }
+    self_test
+}
+
+async fn run_loadtest(args: LoadtestArgs) -> anyhow::Result<()> {
+    let (gateway_host, gateway_port) = args
+        .gateway
+        .rsplit_once(':')
+        .context("gateway address must be of the form host:port")?;
+    let options = LoadTestOptions {
+        gateway_host: gateway_host.to_owned(),
+        gateway_port: gateway_port.parse().context("invalid gateway port")?,
+        authentication_key: args.auth_key,
+        sessions: args.sessions,
+        ramp_up: Duration::from_secs(args.ramp_up),
+        duration: Duration::from_secs(args.duration),
+        destination: args.destination,
+        ping_interval: Duration::from_millis(args.ping_interval_millis),
+        movement_interval: Duration::from_millis(args.movement_interval_millis),
+        insecure: args.insecure,
+        redundancy: args.redundancy,
+        measure: args.measure,
+        resolver: args.resolver,
     };
-    let cert_chain = fs_err::read(cert_path).context("failed to read certificate chain")?;
-    let cert_chain = if cert_path.extension().map_or(false, |x| x == "der") {
-        vec![rustls::Certificate(cert_chain)]
-    } else {
-        rustls_pemfile::certs(&mut &*cert_chain)
-            .into_iter()
-            .map(|cert| cert.map(|der| rustls::Certificate(der.to_vec())))
-            .collect::<Result<Vec<_>, std::io::Error>>()?
-    };
+    let report = loadtest::run(&options).await?;
+    println!("{report}");
+    Ok(())
+}
 
-    Ok(quinn::ServerConfig::with_single_cert(cert_chain, key)?)
+/// Creates a listener named `name` on each of `addresses`.
+fn listeners(
+    name: &str,
+    mut server_config: ServerConfig,
+    addresses: &[SocketAddr],
+    retry: Option<&RetryConfig>,
+    transport_limits: &TransportLimitsConfig,
+) -> anyhow::Result<Vec<Listener>> {
+    let mut transport = transport_config();
+    transport
+        .max_idle_timeout(Some(IdleTimeout::try_from(
+            transport_limits.max_idle_timeout(),
+        )?))
+        .max_concurrent_uni_streams(VarInt::from_u32(transport_limits.max_uni_streams));
+    server_config.transport_config(Arc::new(transport));
+    if let Some(retry) = retry {
+        server_config
+            .use_retry(true)
+            .retry_token_lifetime(retry.token_lifetime());
+    }
+    gateway::bind_udp(addresses)?
+        .into_iter()
+        .map(|socket| {
+            let endpoint = Endpoint::new(
+                EndpointConfig::default(),
+                Some(server_config.clone()),
+                socket,
+                Arc::new(TokioRuntime),
+            )?;
+            Ok(Listener {
+                name: name.to_owned(),
+                endpoint,
+            })
+        })
+        .collect()
+}
+
+fn acme_options(args: &GatewayArgs) -> AcmeOptions {
+    AcmeOptions {
+        domains: args.acme_domain.clone(),
+        contact_email: args.acme_email.clone(),
+        directory_url: args.acme_directory.clone(),
+        cache_dir: args.acme_cache_dir.clone(),
+        challenge_address: args.acme_challenge_address,
+        renew_after: AcmeOptions::DEFAULT_RENEW_AFTER,
+    }
+}
+
+/// Loads the certificate of the listener configured on the command line.
+fn reloadable_certificates_for(
+    cert_path: &Path,
+    priv_key_path: &Path,
+) -> anyhow::Result<Arc<ReloadableCertificates>> {
+    ReloadableCertificates::load(vec![CertificateConfig {
+        server_names: Vec::new(),
+        cert: cert_path.to_owned(),
+        priv_key: priv_key_path.to_owned(),
+    }])
 }
 
 fn server_config_self_signed() -> anyhow::Result<ServerConfig> {
@@ -117,5 +698,18 @@ fn server_config_self_signed() -> anyhow::Result<ServerConfig> {
     let priv_key = rustls::PrivateKey(priv_key);
     let cert_chain = vec![rustls::Certificate(cert_der)];
 
-    Ok(ServerConfig::with_single_cert(cert_chain, priv_key)?)
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&rustls::version::TLS13])?
+        .with_no_client_auth()
+        .with_single_cert(cert_chain, priv_key)?;
+    crypto.max_early_data_size = u32::MAX;
+    Ok(minecraft_quic_proxy::server_config_with_crypto(crypto))
+}
+
+async fn run_dev_server(args: DevServerArgs) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(("0.0.0.0", args.port)).await?;
+    tracing::info!("Dev server listening on {}", listener.local_addr()?);
+    LocalSet::new().run_until(dev_server::serve(listener)).await
 }
@@ -0,0 +1,58 @@
+//! Dual transport measurement: the client sends the same small probes
+//! to the gateway over QUIC and over a plain TCP connection, and the
+//! gateway echoes them. Comparing the round-trip times of both shows
+//! what the proxy gains over TCP for that player and route.
+//!
+//! The QUIC probes use a bidirectional stream of their own, opened by the
+//! client after the gateway answers `ClientMessage::EnableMeasurement`
+//! with a `MeasurementEndpoint`. The TCP probes go to the endpoint's port,
+//! on a connection that starts with the endpoint's token. Probes carry
+//! no game data, only an ID, so the TCP connection only reveals timing.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Length of a probe: a big-endian `u64` ID, then zero padding.
+pub const PROBE_LEN: usize = 16;
+
+/// Single-use token that admits a TCP connection to the
+/// measurement endpoint for one session.
+pub type MeasurementToken = [u8; 16];
+
+/// Where the client sends its TCP probes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeasurementEndpoint {
+    /// TCP port on the gateway's address.
+    pub port: u16,
+    /// Sent first on the TCP connection.
+    pub token: MeasurementToken,
+}
+
+/// Issues a `MeasurementEndpoint` to a session that enables measurement.
+pub type MeasurementIssuer = Arc<dyn Fn() -> MeasurementEndpoint + Send + Sync>;
+
+pub fn encode_probe(id: u64) -> [u8; PROBE_LEN] {
+    let mut probe = [0; PROBE_LEN];
+    probe[..8].copy_from_slice(&id.to_be_bytes());
+    probe
+}
+
+pub fn decode_probe(probe: &[u8; PROBE_LEN]) -> u64 {
+    u64::from_be_bytes(probe[..8].try_into().unwrap())
+}
+
+/// Echoes probes back until the reader ends.
+pub async fn echo(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let mut probe = [0; PROBE_LEN];
+    loop {
+        match reader.read_exact(&mut probe).await {
+            Ok(_) => writer.write_all(&probe).await?,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
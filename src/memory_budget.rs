@@ -0,0 +1,111 @@
+//! Accounting of the bytes a connection buffers, so that a peer that
+//! sends faster than the other side drains, or that announces packets it
+//! never finishes, cannot make the proxy buffer without bound.
+//!
+//! Bytes are charged while they sit in the read buffers of the codecs of
+//! receive streams, and from the time a packet has been decoded until it
+//! has been sent on, covering the channels between the stream tasks and
+//! the `Proxy` as well as its pending send tasks. Once more bytes are
+//! charged than the budget allows, the charge fails with
+//! `MemoryBudgetExceeded`, which ends the connection.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+/// Bytes buffered by one connection, limited to a maximum.
+#[derive(Debug)]
+pub struct MemoryBudget {
+    used: AtomicUsize,
+    max_bytes: usize,
+}
+
+impl MemoryBudget {
+    pub fn new(max_bytes: usize) -> Arc<Self> {
+        Arc::new(Self {
+            used: AtomicUsize::new(0),
+            max_bytes,
+        })
+    }
+
+    /// Gets the bytes currently charged.
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    /// Charges `bytes` until the returned charge is dropped.
+    pub fn charge(self: &Arc<Self>, bytes: usize) -> Result<MemoryCharge, MemoryBudgetExceeded> {
+        self.reserve(bytes)?;
+        Ok(MemoryCharge {
+            budget: Arc::clone(self),
+            bytes,
+        })
+    }
+
+    /// Starts a charge of no bytes, to be resized as bytes are buffered.
+    pub fn empty_charge(self: &Arc<Self>) -> MemoryCharge {
+        MemoryCharge {
+            budget: Arc::clone(self),
+            bytes: 0,
+        }
+    }
+
+    fn reserve(&self, bytes: usize) -> Result<(), MemoryBudgetExceeded> {
+        let used = self.used.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if used > self.max_bytes {
+            self.used.fetch_sub(bytes, Ordering::Relaxed);
+            return Err(MemoryBudgetExceeded {
+                max_bytes: self.max_bytes,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Bytes charged to a `MemoryBudget`, released when dropped.
+#[derive(Debug)]
+pub struct MemoryCharge {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl MemoryCharge {
+    /// Changes the bytes charged to `bytes`. Leaves the
+    /// charge as it was if the budget cannot afford it.
+    pub fn resize(&mut self, bytes: usize) -> Result<(), MemoryBudgetExceeded> {
+        if bytes > self.bytes {
+            self.budget.reserve(bytes - self.bytes)?;
+        } else {
+            self.budget
+                .used
+                .fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+        }
+        self.bytes = bytes;
+        Ok(())
+    }
+
+    /// Moves up to `bytes` of this charge into a new one, e.g. when
+    /// buffered bytes are decoded into a packet that is passed on.
+    pub fn split_off(&mut self, bytes: usize) -> MemoryCharge {
+        let bytes = bytes.min(self.bytes);
+        self.bytes -= bytes;
+        MemoryCharge {
+            budget: Arc::clone(&self.budget),
+            bytes,
+        }
+    }
+}
+
+impl Drop for MemoryCharge {
+    fn drop(&mut self) {
+        self.budget.used.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+/// Error of a connection that buffered more bytes than its budget allows.
+#[derive(Debug, thiserror::Error)]
+#[error("connection buffered more than its memory budget of {max_bytes} bytes")]
+pub struct MemoryBudgetExceeded {
+    pub max_bytes: usize,
+}
@@ -0,0 +1,156 @@
+//! Live summaries of the packets proxied on a connection, for observers
+//! debugging a specific player's lag in real time.
+//!
+//! Only packet names, counts and sizes are recorded, never contents.
+//! Nothing is recorded (and packets are not encoded to measure their size)
+//! while no observer is attached.
+
+use crate::{
+    packet_log,
+    protocol::Encode,
+    stream_allocation::{AllocationClass, AllocationCounters},
+};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use strum::IntoEnumIterator;
+
+/// Direction a packet was proxied in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, strum::AsRefStr)]
+#[serde(rename_all = "snake_case")]
+#[strum(serialize_all = "snake_case")]
+pub enum Direction {
+    /// From the client to the destination server.
+    Serverbound,
+    /// From the destination server to the client.
+    Clientbound,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct PacketCounts {
+    pub count: u64,
+    /// Total uncompressed size in bytes.
+    pub bytes: u64,
+}
+
+/// Records the packet flow of a connection while observers are attached.
+#[derive(Debug, Default)]
+pub struct PacketFlow {
+    observers: AtomicUsize,
+    /// Cumulative counts since the first observer attached.
+    counts: Mutex<BTreeMap<(Direction, String), PacketCounts>>,
+}
+
+impl PacketFlow {
+    pub fn record(&self, direction: Direction, packet: &(impl Encode + AsRef<str>)) {
+        if self.observers.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let size = packet_log::encoded_size(packet) as u64;
+        let mut counts = self.counts.lock().unwrap();
+        let counts = counts
+            .entry((direction, packet.as_ref().to_owned()))
+            .or_default();
+        counts.count += 1;
+        counts.bytes += size;
+    }
+
+    /// Attaches an observer. Recording stops once all observers are dropped.
+    pub fn observe(
+        self: &Arc<Self>,
+        allocation_counters: Arc<AllocationCounters>,
+    ) -> PacketFlowObserver {
+        self.observers.fetch_add(1, Ordering::Relaxed);
+        let last_allocations = allocation_counts(&allocation_counters);
+        PacketFlowObserver {
+            flow: Arc::clone(self),
+            allocation_counters,
+            last_counts: self.counts.lock().unwrap().clone(),
+            last_allocations,
+        }
+    }
+}
+
+/// An attached observer, which takes summaries of the packets
+/// proxied since its previous summary.
+#[derive(Debug)]
+pub struct PacketFlowObserver {
+    flow: Arc<PacketFlow>,
+    allocation_counters: Arc<AllocationCounters>,
+    last_counts: BTreeMap<(Direction, String), PacketCounts>,
+    /// Allocation counts in `AllocationClass::iter()` order.
+    last_allocations: Vec<u64>,
+}
+
+impl PacketFlowObserver {
+    /// Summarizes the packet flow since the previous call.
+    pub fn summary(&mut self) -> PacketFlowSummary {
+        let counts = self.flow.counts.lock().unwrap().clone();
+        let packets = counts
+            .iter()
+            .filter_map(|((direction, packet), counts)| {
+                let last = self
+                    .last_counts
+                    .get(&(*direction, packet.clone()))
+                    .copied()
+                    .unwrap_or_default();
+                let delta = PacketCounts {
+                    count: counts.count - last.count,
+                    bytes: counts.bytes - last.bytes,
+                };
+                (delta.count > 0).then(|| PacketFlowEntry {
+                    direction: *direction,
+                    packet: packet.clone(),
+                    counts: delta,
+                })
+            })
+            .collect();
+        self.last_counts = counts;
+
+        let allocations = allocation_counts(&self.allocation_counters);
+        let allocation_deltas = AllocationClass::iter()
+            .zip(allocations.iter().zip(&self.last_allocations))
+            .filter(|(_, (count, last))| count > last)
+            .map(|(class, (count, last))| (class.as_ref().to_owned(), count - last))
+            .collect();
+        self.last_allocations = allocations;
+
+        PacketFlowSummary {
+            packets,
+            allocations: allocation_deltas,
+        }
+    }
+}
+
+impl Drop for PacketFlowObserver {
+    fn drop(&mut self) {
+        self.flow.observers.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn allocation_counts(counters: &AllocationCounters) -> Vec<u64> {
+    AllocationClass::iter()
+        .map(|class| counters.get(class))
+        .collect()
+}
+
+/// Packets proxied since the previous summary.
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketFlowSummary {
+    pub packets: Vec<PacketFlowEntry>,
+    /// Stream allocations made for Play-state packets, by class.
+    pub allocations: BTreeMap<String, u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PacketFlowEntry {
+    pub direction: Direction,
+    pub packet: String,
+    #[serde(flatten)]
+    pub counts: PacketCounts,
+}
@@ -0,0 +1,103 @@
+//! Sampled logging of proxied packets.
+//!
+//! Logging every packet floods the logs at Play-state packet rates,
+//! so packets are only logged if they pass a filter, which can be
+//! changed at runtime (e.g. through the gateway admin API).
+//! Packet contents are never logged, only names and sizes.
+
+use crate::protocol::{Encode, Encoder};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeSet,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        RwLock,
+    },
+};
+
+/// Selects which packets are logged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "gateway",
+    derive(schemars::JsonSchema),
+    schemars(deny_unknown_fields)
+)]
+#[serde(default)]
+pub struct PacketLogFilter {
+    /// Whether packet logging is enabled at all.
+    pub enabled: bool,
+    /// Only log packets with these names (e.g. `KeepAlive`).
+    /// All packets if unset.
+    pub packet_names: Option<BTreeSet<String>>,
+    /// Only log every Nth packet passing the other filters.
+    pub every_nth: u64,
+    /// Only log packets at least this large, in bytes (uncompressed).
+    pub min_size: Option<usize>,
+}
+
+impl Default for PacketLogFilter {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            packet_names: None,
+            every_nth: 1,
+            min_size: None,
+        }
+    }
+}
+
+static FILTER: Lazy<RwLock<PacketLogFilter>> = Lazy::new(Default::default);
+static MATCHED_PACKETS: AtomicU64 = AtomicU64::new(0);
+
+/// Gets the current filter.
+pub fn filter() -> PacketLogFilter {
+    FILTER.read().unwrap().clone()
+}
+
+/// Replaces the filter for all connections in this process.
+pub fn set_filter(filter: PacketLogFilter) {
+    tracing::info!("Packet log filter set to {filter:?}");
+    *FILTER.write().unwrap() = filter;
+}
+
+/// Logs a packet proxied in the given direction, if it passes the filter.
+pub fn log_packet(direction: &str, packet: &(impl Encode + AsRef<str>)) {
+    let filter = FILTER.read().unwrap();
+    if !filter.enabled {
+        return;
+    }
+
+    let name = packet.as_ref();
+    if let Some(names) = &filter.packet_names {
+        if !names.contains(name) {
+            return;
+        }
+    }
+
+    // Only encode to measure the size if it is needed.
+    let size = filter
+        .min_size
+        .map(|min_size| (min_size, encoded_size(packet)));
+    if let Some((min_size, size)) = size {
+        if size < min_size {
+            return;
+        }
+    }
+
+    let index = MATCHED_PACKETS.fetch_add(1, Ordering::Relaxed);
+    if !index.is_multiple_of(filter.every_nth.max(1)) {
+        return;
+    }
+
+    match size {
+        Some((_, size)) => tracing::info!("{direction}: {name} ({size} bytes)"),
+        None => tracing::info!("{direction}: {name}"),
+    }
+}
+
+pub(crate) fn encoded_size(packet: &impl Encode) -> usize {
+    let mut buf = Vec::new();
+    packet.encode(&mut Encoder::new(&mut buf));
+    buf.len()
+}
@@ -0,0 +1,80 @@
+//! Limiting of the rate of packets a client sends.
+//!
+//! Packets are cheap to send over QUIC, so a modified client can send
+//! far more of them than the destination server is prepared to handle.
+//! A `PacketRateLimit` counts the packets received from the client in a
+//! bucket that refills at an average rate and holds a burst. Once the
+//! bucket is empty, either the client is not read from until it has
+//! refilled, which lets QUIC flow control slow the client down, or the
+//! limit fails with `PacketRateExceeded`.
+
+use crate::clock::{Instant, SharedClock};
+use std::{future::Future, pin::Pin, time::Duration};
+
+/// Returned by `PacketRateLimit::record` when a limit
+/// that does not throttle is exceeded.
+#[derive(Debug, thiserror::Error)]
+#[error("client sent more than {max_packets_per_sec} packets per second")]
+pub struct PacketRateExceeded {
+    pub max_packets_per_sec: u32,
+}
+
+/// Limits the packets received from a client to an average
+/// rate with bursts up to a maximum.
+#[derive(Debug)]
+pub struct PacketRateLimit {
+    max_packets_per_sec: u32,
+    burst_packets: u32,
+    /// Whether to pause receiving until the bucket refills rather than fail.
+    throttle: bool,
+    /// Packets available, negative if the bucket is overdrawn,
+    /// and when they were last refilled.
+    available: f64,
+    refilled_at: Instant,
+    clock: SharedClock,
+}
+
+impl PacketRateLimit {
+    pub fn new(
+        max_packets_per_sec: u32,
+        burst_packets: u32,
+        throttle: bool,
+        clock: SharedClock,
+    ) -> Self {
+        Self {
+            max_packets_per_sec: max_packets_per_sec.max(1),
+            burst_packets,
+            throttle,
+            available: burst_packets as f64,
+            refilled_at: clock.now(),
+            clock,
+        }
+    }
+
+    /// Counts a received packet. If that overdraws the bucket, either
+    /// returns when it will have refilled to zero, until which no more
+    /// packets should be received, or fails, depending on the limit.
+    pub fn record(&mut self) -> Result<Option<Instant>, PacketRateExceeded> {
+        let now = self.clock.now();
+        self.available = (self.available
+            + now.duration_since(self.refilled_at).as_secs_f64() * self.max_packets_per_sec as f64)
+            .min(self.burst_packets as f64);
+        self.refilled_at = now;
+        self.available -= 1.0;
+        if self.available >= 0.0 {
+            return Ok(None);
+        }
+        if !self.throttle {
+            return Err(PacketRateExceeded {
+                max_packets_per_sec: self.max_packets_per_sec,
+            });
+        }
+        let wait = Duration::from_secs_f64(-self.available / self.max_packets_per_sec as f64);
+        Ok(Some(now + wait))
+    }
+
+    /// Waits until `deadline`, as returned by `record`, on the limit's clock.
+    pub fn sleep_until(&self, deadline: Instant) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        self.clock.sleep_until(deadline)
+    }
+}
@@ -1,13 +1,15 @@
 use crate::{
+    anomaly::{Anomaly, AnomalyCollector},
+    clock::{Instant, SharedClock},
     entity_id::EntityId,
-    position::{EntityPosition, EntityPositionDelta},
+    position::{ChunkPosition, EntityPosition, EntityPositionDelta},
     protocol::{
         packet,
         packet::{
             server,
             server::play::{
-                TeleportEntity, UpdateEntityPosition, UpdateEntityPositionAndRotation,
-                UpdateEntityRotation,
+                PlayerInfoActions, SetEntityVelocity, SynchronizePlayerPosition, TeleportEntity,
+                UpdateEntityPosition, UpdateEntityPositionAndRotation, UpdateEntityRotation,
             },
             side, state,
             state::Play,
@@ -16,6 +18,26 @@ use crate::{
     },
 };
 use ahash::AHashMap;
+use std::{collections::hash_map::Entry, sync::Arc, time::Duration};
+
+/// An entity ID reused within this duration of its previous entity's removal
+/// is given a fresh key. Streams idle for longer have been dropped
+/// anyway (see `STREAM_IDLE_DURATION`).
+const ENTITY_ID_REUSE_WINDOW: Duration = crate::stream_allocation::STREAM_IDLE_DURATION;
+
+/// Maximum number of removed entities to remember before
+/// forgetting those removed outside the reuse window.
+const MAX_REMOVED_ENTITIES: usize = 4096;
+
+/// Plugin message channel on which a client mod asks for light data to be
+/// stripped from chunks, because the client recomputes lighting itself.
+///
+/// The mod sends an empty message on the channel in the Configuration
+/// state. If the gateway strips light data, it answers with an empty message
+/// on the same channel, and strips light data from the following Play state
+/// on: light arrays are removed from `ChunkAndLightData` and `UpdateLight`
+/// is dropped. Without an answer, light data is sent as usual.
+pub const STRIP_LIGHT_CHANNEL: &str = "quic-proxy:strip_light";
 
 /// Certain packets need to be modified to work correctly with
 /// the QUIC protocol. For example, since entity movement packets
@@ -26,12 +48,125 @@ use ahash::AHashMap;
 pub struct PacketTranslator {
     /// Last received position of each entity from the server.
     entity_positions: AHashMap<EntityId, EntityPosition>,
+    /// Position of the player, as last set by the server.
+    /// Unknown until the first `SynchronizePlayerPosition`.
+    player_position: Option<EntityPosition>,
+    /// Set if reused entity IDs are remapped.
+    entity_keys: Option<EntityKeys>,
+    /// Set if bursts of velocity updates are coalesced.
+    velocity_coalescer: Option<VelocityCoalescer>,
+    /// Set if light data is stripped. See `STRIP_LIGHT_CHANNEL`.
+    strip_light: bool,
+    players: PlayerList,
+    anomalies: Arc<AnomalyCollector>,
+    clock: SharedClock,
 }
 
 impl PacketTranslator {
-    pub fn new() -> Self {
+    pub fn new(anomalies: Arc<AnomalyCollector>, clock: SharedClock) -> Self {
         Self {
             entity_positions: AHashMap::new(),
+            player_position: None,
+            entity_keys: None,
+            velocity_coalescer: None,
+            strip_light: false,
+            players: PlayerList::default(),
+            anomalies,
+            clock,
+        }
+    }
+
+    /// Enables remapping of entity IDs that the server reuses shortly after
+    /// removing their previous entity (common after chunk reloads).
+    ///
+    /// Without remapping, packets for the new entity share a stream and
+    /// sequence with those still in flight for the old one, so they can be
+    /// delayed behind them or dropped as out of date.
+    pub fn enable_entity_id_remapping(&mut self) {
+        let clock = &self.clock;
+        self.entity_keys
+            .get_or_insert_with(|| EntityKeys::new(Arc::clone(clock)));
+    }
+
+    /// Enables coalescing of velocity updates: an update following the
+    /// previous one of the same entity within `window` is held back until
+    /// the window ends, and only the latest of those held back is sent.
+    ///
+    /// Velocity is spammed for projectiles and explosions, often several
+    /// times per tick, while the client only acts on the latest value.
+    pub fn enable_velocity_coalescing(&mut self, window: Duration) {
+        self.velocity_coalescer = Some(VelocityCoalescer::new(window));
+    }
+
+    /// Enables stripping of light data from chunks, for a client
+    /// that recomputes lighting. See `STRIP_LIGHT_CHANNEL`.
+    pub fn enable_light_stripping(&mut self) {
+        self.strip_light = true;
+    }
+
+    /// Called once a packet held back by `TranslatePacket::coalesce_packet`
+    /// is due. Returns whether it should be sent, i.e. whether no newer
+    /// packet has superseded it in the meantime.
+    pub fn take_deferred(&mut self, deferred: &DeferredPacket) -> bool {
+        let now = self.clock.now();
+        self.velocity_coalescer
+            .as_mut()
+            .is_some_and(|coalescer| coalescer.take(deferred, now))
+    }
+
+    /// Gets the ID under which streams and sequences of an entity are keyed.
+    ///
+    /// This is the entity's own ID unless it was remapped.
+    pub fn entity_key(&self, entity_id: EntityId) -> EntityId {
+        self.entity_keys
+            .as_ref()
+            .map_or(entity_id, |keys| keys.get(entity_id))
+    }
+
+    /// Gets the entity a player in the tab list is spawned as, if any.
+    pub fn player_entity(&self, uuid: u128) -> Option<EntityId> {
+        self.players.entities.get(&uuid).copied().flatten()
+    }
+
+    /// Gets the UUID of the player an entity belongs to, if any.
+    pub fn entity_player(&self, entity_id: EntityId) -> Option<u128> {
+        self.players.uuids.get(&entity_id).copied()
+    }
+
+    /// Gets the player's position as of its last teleport by the server.
+    pub fn player_position(&self) -> Option<EntityPosition> {
+        self.player_position
+    }
+
+    /// Gets the keys of the entities last known to be in a chunk.
+    pub fn entities_in_chunk(&self, chunk: ChunkPosition) -> Vec<EntityId> {
+        self.entity_positions
+            .iter()
+            .filter(|(_, position)| position.chunk() == chunk)
+            .map(|(&entity_id, _)| self.entity_key(entity_id))
+            .collect()
+    }
+
+    fn synchronize_player_position(&mut self, packet: &SynchronizePlayerPosition) {
+        match self.player_position {
+            Some(current) => self.player_position = Some(packet.apply(current)),
+            None if packet.flags.is_empty() => {
+                self.player_position = Some(packet.apply(EntityPosition::default()))
+            }
+            None => {
+                tracing::trace!("Relative player teleport, but the player position is not known.");
+                self.anomalies.record(Anomaly::UnknownEntityPosition);
+            }
+        }
+    }
+
+    fn spawn_entity(&mut self, entity_id: EntityId, position: impl Into<EntityPosition>) {
+        self.register_entity_position(entity_id, position);
+        if let Some(keys) = &mut self.entity_keys {
+            if keys.spawn(entity_id) {
+                tracing::trace!("Entity ID {entity_id:?} reused, remapping its streams");
+                self.anomalies.record(Anomaly::EntityIdReused);
+            }
         }
     }
 
@@ -46,17 +181,231 @@ impl PacketTranslator {
     fn entity_position(&self, entity_id: EntityId) -> Option<EntityPosition> {
         let opt = self.entity_positions.get(&entity_id).copied();
         if opt.is_none() {
-            tracing::warn!("Requesting position of entity {entity_id:?}, but it is not known.");
+            tracing::trace!("Requesting position of entity {entity_id:?}, but it is not known.");
+            self.anomalies.record(Anomaly::UnknownEntityPosition);
         }
         opt
     }
 
     fn unload_entity(&mut self, entity_id: EntityId) {
         self.entity_positions.remove(&entity_id);
+        self.players.unload(entity_id);
+        if let Some(keys) = &mut self.entity_keys {
+            keys.remove(entity_id);
+        }
+        if let Some(coalescer) = &mut self.velocity_coalescer {
+            coalescer.entities.remove(&entity_id);
+        }
     }
 
     fn clear_entities(&mut self) {
+        if let Some(keys) = &mut self.entity_keys {
+            for &entity_id in self.entity_positions.keys() {
+                keys.remove(entity_id);
+            }
+        }
         self.entity_positions.clear();
+        self.players.clear_entities();
+        if let Some(coalescer) = &mut self.velocity_coalescer {
+            coalescer.entities.clear();
+        }
+    }
+}
+
+/// Players in the tab list and the entities they are spawned as.
+#[derive(Debug, Default)]
+struct PlayerList {
+    /// Entity of each listed player, if spawned.
+    entities: AHashMap<u128, Option<EntityId>>,
+    /// Inverse of `entities`.
+    uuids: AHashMap<EntityId, u128>,
+}
+
+impl PlayerList {
+    fn add(&mut self, uuid: u128) {
+        self.entities.entry(uuid).or_insert(None);
+    }
+
+    fn remove(&mut self, uuid: u128) {
+        if let Some(Some(entity_id)) = self.entities.remove(&uuid) {
+            self.uuids.remove(&entity_id);
+        }
+    }
+
+    /// Records a spawned entity, if it belongs to a listed player.
+    fn spawn(&mut self, uuid: u128, entity_id: EntityId) {
+        if let Some(entity) = self.entities.get_mut(&uuid) {
+            if let Some(previous) = entity.replace(entity_id) {
+                self.uuids.remove(&previous);
+            }
+            self.uuids.insert(entity_id, uuid);
+        }
+    }
+
+    fn unload(&mut self, entity_id: EntityId) {
+        if let Some(uuid) = self.uuids.remove(&entity_id) {
+            self.entities.insert(uuid, None);
+        }
+    }
+
+    fn clear_entities(&mut self) {
+        self.uuids.clear();
+        self.entities.values_mut().for_each(|entity| *entity = None);
+    }
+}
+
+/// Translation table from server entity IDs to the keys of their
+/// streams and sequences.
+///
+/// Fresh keys are allocated deterministically, counting up from `i32::MIN`,
+/// which the vanilla server (counting up from 0) never uses as entity IDs.
+#[derive(Debug)]
+struct EntityKeys {
+    /// Keys of entities whose ID was reused. Absent entities use their own ID.
+    remapped: AHashMap<EntityId, EntityId>,
+    /// Time each entity was last removed.
+    removed: AHashMap<EntityId, Instant>,
+    next_key: i32,
+    clock: SharedClock,
+}
+
+impl EntityKeys {
+    fn new(clock: SharedClock) -> Self {
+        Self {
+            remapped: AHashMap::new(),
+            removed: AHashMap::new(),
+            next_key: i32::MIN,
+            clock,
+        }
+    }
+
+    fn get(&self, entity_id: EntityId) -> EntityId {
+        self.remapped.get(&entity_id).copied().unwrap_or(entity_id)
+    }
+
+    /// Registers a spawned entity. Returns whether its ID was
+    /// reused within the window and therefore remapped.
+    fn spawn(&mut self, entity_id: EntityId) -> bool {
+        let reused = self.removed.remove(&entity_id).is_some_and(|removed| {
+            self.clock.now().duration_since(removed) < ENTITY_ID_REUSE_WINDOW
+        });
+        if reused {
+            let key = EntityId::new(self.next_key);
+            self.next_key = self.next_key.wrapping_add(1);
+            self.remapped.insert(entity_id, key);
+        } else {
+            self.remapped.remove(&entity_id);
+        }
+        reused
+    }
+
+    /// Registers a removed entity. Its key stays in place
+    /// until the ID is reused, so that packets still following
+    /// the removal use the same stream.
+    fn remove(&mut self, entity_id: EntityId) {
+        if self.removed.len() >= MAX_REMOVED_ENTITIES {
+            self.forget_expired();
+        }
+        self.removed.insert(entity_id, self.clock.now());
+    }
+
+    fn forget_expired(&mut self) {
+        let now = self.clock.now();
+        let remapped = &mut self.remapped;
+        self.removed.retain(|entity_id, removed| {
+            let retain = now.duration_since(*removed) < ENTITY_ID_REUSE_WINDOW;
+            if !retain {
+                remapped.remove(entity_id);
+            }
+            retain
+        });
+    }
+}
+
+/// Whether a packet is sent now or held back to be coalesced
+/// with the packets following it.
+#[derive(Debug)]
+pub enum Coalescing {
+    Send,
+    /// Wait until `DeferredPacket::until`, then
+    /// check `PacketTranslator::take_deferred`.
+    Defer(DeferredPacket),
+}
+
+/// A packet held back by `TranslatePacket::coalesce_packet`.
+#[derive(Debug)]
+pub struct DeferredPacket {
+    until: Instant,
+    entity_id: EntityId,
+    generation: u64,
+}
+
+impl DeferredPacket {
+    pub fn until(&self) -> Instant {
+        self.until
+    }
+}
+
+/// Limits the velocity updates of each entity to one per window,
+/// keeping the latest of those within a window.
+#[derive(Debug)]
+struct VelocityCoalescer {
+    window: Duration,
+    entities: AHashMap<EntityId, VelocityUpdates>,
+}
+
+#[derive(Debug)]
+struct VelocityUpdates {
+    last_sent: Instant,
+    /// Incremented on each update, so that a held back
+    /// update can tell whether it was superseded.
+    generation: u64,
+}
+
+impl VelocityCoalescer {
+    fn new(window: Duration) -> Self {
+        Self {
+            window,
+            entities: AHashMap::new(),
+        }
+    }
+
+    fn coalesce(&mut self, entity_id: EntityId, now: Instant) -> Coalescing {
+        match self.entities.entry(entity_id) {
+            Entry::Vacant(entry) => {
+                entry.insert(VelocityUpdates {
+                    last_sent: now,
+                    generation: 0,
+                });
+                Coalescing::Send
+            }
+            Entry::Occupied(entry) => {
+                let updates = entry.into_mut();
+                // Also supersedes any update still held back.
+                updates.generation += 1;
+                let until = updates.last_sent + self.window;
+                if now >= until {
+                    updates.last_sent = now;
+                    Coalescing::Send
+                } else {
+                    Coalescing::Defer(DeferredPacket {
+                        until,
+                        entity_id,
+                        generation: updates.generation,
+                    })
+                }
+            }
+        }
+    }
+
+    fn take(&mut self, deferred: &DeferredPacket, now: Instant) -> bool {
+        match self.entities.get_mut(&deferred.entity_id) {
+            Some(updates) if updates.generation == deferred.generation => {
+                updates.last_sent = now;
+                true
+            }
+            _ => false,
+        }
     }
 }
 
@@ -69,6 +418,23 @@ pub trait TranslatePacket<Side: packet::Side> {
         &mut self,
         packet: &Side::SendPacket<state::Play>,
     ) -> Option<Side::SendPacket<state::Play>>;
+
+    /// Splits a packet concerning several independent subjects
+    /// into one packet per subject, so that each can be allocated
+    /// its own stream. Returns `None` if the packet is not split,
+    /// and no packets if it is dropped.
+    fn split_packet(
+        &self,
+        _packet: &Side::SendPacket<state::Play>,
+    ) -> Option<Vec<Side::SendPacket<state::Play>>> {
+        None
+    }
+
+    /// Decides whether a (translated) packet is sent now or
+    /// held back to be coalesced with the packets following it.
+    fn coalesce_packet(&mut self, _packet: &Side::SendPacket<state::Play>) -> Coalescing {
+        Coalescing::Send
+    }
 }
 
 impl TranslatePacket<side::Client> for PacketTranslator {
@@ -115,14 +481,16 @@ impl TranslatePacket<side::Server> for PacketTranslator {
 
         match packet {
             Packet::SpawnEntity(packet) => {
-                self.register_entity_position(
+                self.spawn_entity(
                     EntityId::new(packet.entity_id),
                     (packet.x, packet.y, packet.z, packet.pitch, packet.yaw),
                 );
+                self.players
+                    .spawn(packet.uuid, EntityId::new(packet.entity_id));
                 None
             }
             Packet::SpawnExperienceOrb(packet) => {
-                self.register_entity_position(
+                self.spawn_entity(
                     EntityId::new(packet.entity_id),
                     (packet.x, packet.y, packet.z, 0.0, 0.0),
                 );
@@ -176,11 +544,66 @@ impl TranslatePacket<side::Server> for PacketTranslator {
                 }
                 None
             }
+            Packet::SynchronizePlayerPosition(packet) => {
+                self.synchronize_player_position(packet);
+                None
+            }
             Packet::Respawn(_) => {
                 self.clear_entities();
+                self.player_position = None;
                 None
             }
+            Packet::PlayerInfoUpdate(packet)
+                if packet.actions.contains(PlayerInfoActions::ADD_PLAYER) =>
+            {
+                for player in &packet.players {
+                    self.players.add(player.uuid);
+                }
+                None
+            }
+            Packet::PlayerInfoRemove(packet) => {
+                for &uuid in &packet.players {
+                    self.players.remove(uuid);
+                }
+                None
+            }
+            Packet::ChunkAndLightData(packet) if self.strip_light => match packet.without_light() {
+                Ok(stripped) => stripped.map(Packet::ChunkAndLightData),
+                Err(e) => {
+                    tracing::debug!("Failed to strip light data from chunk: {e}");
+                    None
+                }
+            },
             _ => None,
         }
     }
+
+    fn split_packet(&self, packet: &server::play::Packet) -> Option<Vec<server::play::Packet>> {
+        use server::play::Packet;
+
+        match packet {
+            // Updates of existing players can be allocated a stream per player.
+            // (See `AllocateStream` for why additions are not.)
+            Packet::PlayerInfoUpdate(packet)
+                if packet.players.len() > 1
+                    && !packet.actions.contains(PlayerInfoActions::ADD_PLAYER) =>
+            {
+                Some(packet.split().map(Packet::PlayerInfoUpdate).collect())
+            }
+            // The client computes the light itself.
+            Packet::UpdateLight(_) if self.strip_light => Some(Vec::new()),
+            _ => None,
+        }
+    }
+
+    fn coalesce_packet(&mut self, packet: &server::play::Packet) -> Coalescing {
+        let now = self.clock.now();
+        match (packet, &mut self.velocity_coalescer) {
+            (
+                server::play::Packet::SetEntityVelocity(SetEntityVelocity { entity_id, .. }),
+                Some(coalescer),
+            ) => coalescer.coalesce(EntityId::new(*entity_id), now),
+            _ => Coalescing::Send,
+        }
+    }
 }
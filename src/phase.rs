@@ -0,0 +1,128 @@
+//! The phases (protocol states) a proxied connection passes through,
+//! and the transitions allowed between them.
+//!
+//! The client and the gateway each track the phase of a connection on
+//! their side of the control stream, checking every transition against
+//! the same table. A new state, or a new way of entering one, only needs
+//! to be added to `Phase::successors` for both ends to accept it.
+
+use crate::protocol::packet::ProtocolState;
+use strum::{Display, EnumIter, EnumString, IntoStaticStr};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Display, EnumIter, EnumString, IntoStaticStr)]
+pub enum Phase {
+    Handshake,
+    Status,
+    Login,
+    Configuration,
+    Play,
+}
+
+impl Phase {
+    /// Gets the phase of a typed protocol state.
+    pub fn of<State: ProtocolState>() -> Self {
+        State::PHASE
+    }
+
+    /// Phases that may directly follow this one.
+    pub fn successors(self) -> &'static [Phase] {
+        match self {
+            Phase::Handshake => &[Phase::Status, Phase::Login],
+            Phase::Status => &[],
+            Phase::Login => &[Phase::Configuration],
+            Phase::Configuration => &[Phase::Play],
+            // The server may send the player back to reconfigure.
+            Phase::Play => &[Phase::Configuration],
+        }
+    }
+
+    pub fn can_transition_to(self, next: Phase) -> bool {
+        self.successors().contains(&next)
+    }
+}
+
+/// Error returned by `PhaseTracker::transition` for a
+/// transition not in the table.
+#[derive(Debug, thiserror::Error)]
+#[error("illegal transition from the {from} to the {to} state")]
+pub struct IllegalTransition {
+    pub from: Phase,
+    pub to: Phase,
+}
+
+/// The current phase of a connection.
+#[derive(Debug)]
+pub struct PhaseTracker {
+    current: Phase,
+}
+
+impl PhaseTracker {
+    /// Every connection starts with the handshake.
+    pub fn new() -> Self {
+        Self {
+            current: Phase::Handshake,
+        }
+    }
+
+    pub fn current(&self) -> Phase {
+        self.current
+    }
+
+    /// Moves to the `next` phase, if the transition is allowed.
+    pub fn transition(&mut self, next: Phase) -> Result<(), IllegalTransition> {
+        if !self.current.can_transition_to(next) {
+            return Err(IllegalTransition {
+                from: self.current,
+                to: next,
+            });
+        }
+        self.current = next;
+        Ok(())
+    }
+}
+
+impl Default for PhaseTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use strum::IntoEnumIterator;
+
+    /// The allowed transitions, spelled out independently of `successors`.
+    const ALLOWED: &[(Phase, Phase)] = &[
+        (Phase::Handshake, Phase::Status),
+        (Phase::Handshake, Phase::Login),
+        (Phase::Login, Phase::Configuration),
+        (Phase::Configuration, Phase::Play),
+        (Phase::Play, Phase::Configuration),
+    ];
+
+    #[test]
+    fn only_table_transitions_succeed() {
+        for from in Phase::iter() {
+            for to in Phase::iter() {
+                let mut tracker = PhaseTracker { current: from };
+                let result = tracker.transition(to);
+                if ALLOWED.contains(&(from, to)) {
+                    assert!(result.is_ok(), "{from} -> {to} should be allowed");
+                    assert_eq!(tracker.current(), to);
+                } else {
+                    let Err(error) = result else {
+                        panic!("{from} -> {to} should be illegal");
+                    };
+                    assert_eq!((error.from, error.to), (from, to));
+                    assert_eq!(tracker.current(), from);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn starts_at_handshake() {
+        assert_eq!(PhaseTracker::new().current(), Phase::Handshake);
+    }
+}
@@ -17,8 +17,8 @@ pub struct BlockPosition {
 impl BlockPosition {
     pub fn chunk(self) -> ChunkPosition {
         ChunkPosition {
-            x: self.x.rem_euclid(16),
-            z: self.z.rem_euclid(16),
+            x: self.x.div_euclid(16),
+            z: self.z.div_euclid(16),
         }
     }
 }
@@ -32,6 +32,15 @@ pub struct EntityPosition {
     pub pitch: f32,
 }
 
+impl EntityPosition {
+    pub fn chunk(self) -> ChunkPosition {
+        ChunkPosition {
+            x: (self.x.floor() as i32).div_euclid(16),
+            z: (self.z.floor() as i32).div_euclid(16),
+        }
+    }
+}
+
 /// Delta encoding for a new entity position.
 /// Used in the UpdateEntityPosition packet family.
 #[derive(Copy, Clone, Debug)]
@@ -0,0 +1,43 @@
+//! The stable public API of this crate.
+//!
+//! Everything reachable from here follows semver: breaking changes
+//! only happen in a new major (or, before 1.0, minor) version.
+//! Items not re-exported here, even if technically reachable,
+//! are implementation details and may change in any release.
+//!
+//! ```no_run
+//! use minecraft_quic_proxy::prelude::*;
+//! ```
+
+#[cfg(feature = "client")]
+pub use crate::client::{
+    lag_events::{LagEvent, LagEventKind, LagEventLog, LagSummary, LagThresholds},
+    measurement::{
+        MeasurementLog, MeasurementOptions, MeasurementReport, Transport, TransportSummary,
+    },
+    resolver::{DohProvider, Resolver, ResolverBackend},
+    resumption::ReconnectBackoff,
+    sessions::ClientSessions,
+    store::{ClientStore, GatewayRecord, TransportHints},
+    ClientHandle, ClientOptions,
+};
+#[cfg(feature = "gateway")]
+pub use crate::gateway::{
+    config::GatewayConfig, policy::PolicyViolation, session::SessionLimitReached, Gateway,
+};
+pub use crate::{
+    affinity::AffinityToken,
+    build_info::BuildInfo,
+    client_config, client_config_with_crypto,
+    control_stream::{ConnectedDestination, TransportPreferences},
+    destination::Destination,
+    packet_log::PacketLogFilter,
+    stats::{
+        AllocationClass, AllocationSummary, Anomaly, AnomalySummary, BandwidthCategory,
+        BandwidthTotals, BandwidthUsage, CategoryRates, ConnectionStats, NegotiatedParameters,
+        SessionReport, StateHistory, TransportStats,
+    },
+    tcp_options::TcpOptions,
+    timeline::{self, Timeline, TimelineEvent, TimelineEventKind, TimelineSource},
+    transport_config, ALPN,
+};
@@ -0,0 +1,70 @@
+//! CPU profiling hooks for the gateway (enabled with the `pprof` feature).
+//!
+//! Serves flamegraphs over HTTP so operators can tell whether compression,
+//! encryption, or task churn dominates the gateway's CPU time.
+
+use axum::{
+    extract::Query,
+    http::{header, StatusCode},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use serde::Deserialize;
+use std::{net::SocketAddr, time::Duration};
+use tokio::{net::TcpListener, task};
+
+/// Profile duration used when the request does not specify one.
+const DEFAULT_PROFILE_DURATION: Duration = Duration::from_secs(10);
+/// Upper bound on a single profile, to avoid tying up the profiler forever.
+const MAX_PROFILE_DURATION: Duration = Duration::from_secs(120);
+/// Sampling frequency in Hz. Not a multiple of common timer frequencies
+/// to avoid lockstep sampling.
+const SAMPLING_FREQUENCY: i32 = 99;
+
+/// Serves CPU flamegraphs on the given address.
+///
+/// `GET /debug/pprof/flamegraph?seconds=N` profiles the whole process
+/// for `N` seconds and returns an SVG flamegraph.
+pub async fn serve(address: SocketAddr) -> anyhow::Result<()> {
+    let app = Router::new().route("/debug/pprof/flamegraph", get(flamegraph));
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!(
+        "Serving CPU flamegraphs on http://{}/debug/pprof/flamegraph",
+        listener.local_addr()?
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+struct ProfileParams {
+    seconds: Option<u64>,
+}
+
+async fn flamegraph(Query(params): Query<ProfileParams>) -> impl IntoResponse {
+    let duration = params
+        .seconds
+        .map_or(DEFAULT_PROFILE_DURATION, Duration::from_secs)
+        .min(MAX_PROFILE_DURATION);
+
+    // The profiler guard is not `Send`, so the profile is taken on a blocking thread.
+    match task::spawn_blocking(move || profile(duration)).await {
+        Ok(Ok(svg)) => Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg)),
+        Ok(Err(e)) => Err((StatusCode::INTERNAL_SERVER_ERROR, format!("{e:#}"))),
+        Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+    }
+}
+
+fn profile(duration: Duration) -> anyhow::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(SAMPLING_FREQUENCY)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()?;
+    std::thread::sleep(duration);
+
+    let report = guard.report().build()?;
+    let mut svg = Vec::new();
+    report.flamegraph(&mut svg)?;
+    Ok(svg)
+}
@@ -1,4 +1,13 @@
 //! Implements the Minecraft protocol.
+//!
+//! Built without the `proxy` feature too, so that tools such as packet
+//! analyzers can depend on this crate with `default-features = false`
+//! and decode packets with the same definitions as the proxy.
+//!
+//! Only protocol version 765 (1.20.3 and 1.20.4) is modelled. The cookie
+//! and `Transfer` packets of 1.20.5 are not, and neither is following a
+//! transfer to another destination: 1.20.5 shifts the packet IDs of the
+//! Configuration and Play states, so it needs a second protocol version.
 
 pub const PROTOCOL_VERSION: i32 = 765; // 1.20.4
 
@@ -11,5 +20,16 @@ pub mod vanilla_codec;
 pub use decoder::{Decode, DecodeError, Decoder};
 pub use encoder::{Encode, Encoder};
 
-/// Limit to avoid out-of-memory DOS.
-const BUFFER_LIMIT: usize = 1024 * 1024; // 1 MiB
+/// Largest packet frame the vanilla protocol can express
+/// (a length prefix of at most three VarInt bytes).
+const MAX_FRAME_LENGTH: usize = (1 << 21) - 1; // 2 MiB
+
+/// Largest decompressed packet accepted, as in the vanilla client.
+/// Modded servers legitimately send packets of several MiB
+/// (e.g. registries); anything larger is treated as abuse.
+///
+/// Packets are always decoded whole, since the proxy translates them
+/// between codecs; there is no mode forwarding them in chunks. Frames
+/// cannot exceed `MAX_FRAME_LENGTH` anyway, and a packet beyond this
+/// limit would be rejected by the vanilla client too.
+const MAX_PACKET_LENGTH: usize = 8 * 1024 * 1024; // 8 MiB
@@ -12,6 +12,13 @@ pub enum DecodeError {
     VarIntTooLong,
     #[error("string exceeds max allowed length")]
     StringTooLong,
+    #[error("invalid NBT tag type {0}")]
+    InvalidNbtTag(u8),
+    #[error("NBT exceeds max allowed depth")]
+    NbtTooDeep,
+    /// A packet ID or enum discriminant with no matching variant.
+    #[error("invalid discriminant '{0}'")]
+    InvalidDiscriminant(i64),
     #[error(transparent)]
     Utf8(#[from] Utf8Error),
     #[error(transparent)]
@@ -32,6 +39,12 @@ pub type Result<T, E = DecodeError> = std::result::Result<T, E>;
 
 const MAX_STRING_LENGTH: usize = i16::MAX as usize;
 
+/// Maximum nesting depth of NBT compounds and lists (as enforced by the vanilla client).
+const MAX_NBT_DEPTH: usize = 512;
+
+/// Tag type ending an NBT compound, or denoting an empty root tag.
+const NBT_END: u8 = 0;
+
 /// A raw decoder for a Minecraft bitstream.
 #[derive(Debug)]
 pub struct Decoder<'a> {
@@ -192,6 +205,63 @@ impl<'a> Decoder<'a> {
         let fixed = self.read_u8()?;
         Ok((fixed as f32 / u8::MAX as f32) * 360.)
     }
+
+    /// Skips over an NBT tag in the network format (a root tag without a name),
+    /// as used for text components.
+    pub fn skip_nbt(&mut self) -> Result<()> {
+        let tag = self.read_u8()?;
+        if tag != NBT_END {
+            self.skip_nbt_payload(tag, 0)?;
+        }
+        Ok(())
+    }
+
+    fn skip_nbt_payload(&mut self, tag: u8, depth: usize) -> Result<()> {
+        if depth > MAX_NBT_DEPTH {
+            return Err(DecodeError::NbtTooDeep);
+        }
+        match tag {
+            1 => self.skip(1)?,
+            2 => self.skip(2)?,
+            3 | 5 => self.skip(4)?,
+            4 | 6 => self.skip(8)?,
+            7 => self.skip_nbt_array(1)?,
+            8 => self.skip_nbt_string()?,
+            9 => {
+                let element_tag = self.read_u8()?;
+                let length = self.read_i32()?;
+                for _ in 0..length {
+                    self.skip_nbt_payload(element_tag, depth + 1)?;
+                }
+            }
+            10 => loop {
+                let field_tag = self.read_u8()?;
+                if field_tag == NBT_END {
+                    break;
+                }
+                self.skip_nbt_string()?;
+                self.skip_nbt_payload(field_tag, depth + 1)?;
+            },
+            11 => self.skip_nbt_array(4)?,
+            12 => self.skip_nbt_array(8)?,
+            tag => return Err(DecodeError::InvalidNbtTag(tag)),
+        }
+        Ok(())
+    }
+
+    fn skip_nbt_string(&mut self) -> Result<()> {
+        let length = self.read_u16()?;
+        self.skip(length.into())
+    }
+
+    fn skip_nbt_array(&mut self, element_size: usize) -> Result<()> {
+        let length = usize::try_from(self.read_i32()?)?;
+        self.skip(length.saturating_mul(element_size))
+    }
+
+    fn skip(&mut self, n: usize) -> Result<()> {
+        self.consume_slice(n).map(|_| ())
+    }
 }
 
 /// A type that can be read from a [`Decoder`].
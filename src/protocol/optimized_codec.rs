@@ -1,6 +1,9 @@
 //! Alternative codec implementation designed for use over QUIC.
 //!
-//! The format is as follows:
+//! The framing format is versioned (see `CodecVersion`); the version is
+//! negotiated once per connection on the control stream.
+//!
+//! Version 1 is as follows:
 //! 1. VarInt - size of rest of packet, in bytes
 //! 2. 1 byte flags: 0x01 = compressed
 //! 3. Packet bytes. Compressed with `zstd` if the compression flag is set.
@@ -15,7 +18,7 @@
 
 use crate::protocol::{
     packet, packet::ProtocolState, vanilla_codec::var_int_size, Decode, DecodeError, Decoder,
-    Encode, Encoder, BUFFER_LIMIT,
+    Encode, Encoder, MAX_PACKET_LENGTH,
 };
 use anyhow::{bail, Context};
 use bitflags::bitflags;
@@ -32,6 +35,46 @@ bitflags! {
     }
 }
 
+/// Version of the optimized codec framing format.
+///
+/// New versions may change the framing (e.g. add dictionaries, checksums
+/// or batched frames). Peers agree on the highest version both support,
+/// so clients and gateways of different releases remain compatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, strum::FromRepr)]
+#[repr(u8)]
+pub enum CodecVersion {
+    V1 = 1,
+}
+
+impl CodecVersion {
+    /// Versions supported by this build, in ascending order.
+    pub const SUPPORTED: &'static [CodecVersion] = &[CodecVersion::V1];
+
+    pub fn from_u8(version: u8) -> Option<Self> {
+        Self::from_repr(version)
+    }
+
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns the raw version bytes of all supported versions,
+    /// as sent to the peer during negotiation.
+    pub fn supported_bytes() -> Vec<u8> {
+        Self::SUPPORTED.iter().map(|v| v.as_u8()).collect()
+    }
+
+    /// Picks the highest version supported by both this build and the peer.
+    /// Unknown versions sent by the peer are ignored.
+    pub fn negotiate(peer_versions: &[u8]) -> Option<Self> {
+        Self::SUPPORTED
+            .iter()
+            .rev()
+            .copied()
+            .find(|version| peer_versions.contains(&version.as_u8()))
+    }
+}
+
 /// Use a high compression value to reduce bandwidth usage over the QUIC connection.
 const COMPRESSION_LEVEL: CompressionLevel = 12;
 
@@ -39,6 +82,7 @@ const COMPRESSION_LEVEL: CompressionLevel = 12;
 ///
 /// Interface is the same as for `VanillaCodec`.
 pub struct OptimizedCodec<Side, State> {
+    version: CodecVersion,
     read_buffer: Vec<u8>,
     compressor: Compressor<'static>,
     decompressor: Decompressor<'static>,
@@ -50,7 +94,7 @@ where
     Side: packet::Side,
     State: ProtocolState,
 {
-    pub fn new() -> Self {
+    pub fn new(version: CodecVersion) -> Self {
         let mut compressor = Compressor::new(COMPRESSION_LEVEL).expect("failed to initialize zstd");
         let mut decompressor = Decompressor::new().expect("failed to initialize zstd");
         compressor.include_checksum(false).unwrap();
@@ -61,6 +105,7 @@ where
         decompressor.include_magicbytes(false).unwrap();
 
         Self {
+            version,
             read_buffer: Vec::new(),
             compressor,
             decompressor,
@@ -70,6 +115,7 @@ where
 
     pub fn switch_state<NewState: ProtocolState>(self) -> OptimizedCodec<Side, NewState> {
         OptimizedCodec {
+            version: self.version,
             read_buffer: self.read_buffer,
             compressor: self.compressor,
             decompressor: self.decompressor,
@@ -77,6 +123,10 @@ where
         }
     }
 
+    pub fn version(&self) -> CodecVersion {
+        self.version
+    }
+
     pub fn encode_packet(&mut self, packet: &Side::SendPacket<State>) -> anyhow::Result<Vec<u8>> {
         let mut plain_data = Vec::new();
         packet.encode(&mut Encoder::new(&mut plain_data));
@@ -108,14 +158,28 @@ where
         self.read_buffer.extend_from_slice(data);
     }
 
+    /// Gets the number of bytes given but not decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.read_buffer.len()
+    }
+
     pub fn decode_packet(&mut self) -> anyhow::Result<Option<Side::RecvPacket<State>>> {
+        Ok(self.decode_packet_sized()?.map(|(packet, _)| packet))
+    }
+
+    /// Like `decode_packet`, but also returns the number of
+    /// bytes the packet took up in the stream.
+    pub fn decode_packet_sized(
+        &mut self,
+    ) -> anyhow::Result<Option<(Side::RecvPacket<State>, usize)>> {
         let mut decoder = Decoder::new(&self.read_buffer);
         let length = match decoder.read_var_int() {
             Ok(x) => x.try_into()?,
             Err(DecodeError::EndOfStream(_, _)) => return Ok(None),
             Err(e) => return Err(e.into()),
         };
-        if length > BUFFER_LIMIT {
+        // Uncompressed packets are framed with their flags byte.
+        if length > MAX_PACKET_LENGTH + size_of::<u8>() {
             bail!("packet length of {length} is too large");
         }
 
@@ -132,12 +196,12 @@ where
         let result = if flags.contains(Flags::COMPRESSED) {
             let decompressed = self
                 .decompressor
-                .decompress(decoder.buffer(), BUFFER_LIMIT)?;
+                .decompress(decoder.buffer(), MAX_PACKET_LENGTH)?;
             let packet = Side::RecvPacket::<State>::decode(&mut Decoder::new(&decompressed))?;
-            Ok(Some(packet))
+            Ok(Some((packet, total_bytes_read)))
         } else {
             let packet = Side::RecvPacket::<State>::decode(&mut decoder)?;
-            Ok(Some(packet))
+            Ok(Some((packet, total_bytes_read)))
         };
 
         self.read_buffer.drain(..total_bytes_read);
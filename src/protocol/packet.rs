@@ -6,7 +6,10 @@
 //! of the packet's bytes. (This enables roundtrip encoding/decoding without
 //! loss of information.)
 
-use crate::protocol::{Decode, Encode};
+use crate::{
+    phase::Phase,
+    protocol::{Decode, Encode},
+};
 use std::fmt::Debug;
 
 pub mod client;
@@ -36,8 +39,16 @@ pub mod side {
     }
 }
 
+/// Gets the name of a protocol state, e.g. `Login`.
+pub fn state_name<State: ProtocolState>() -> &'static str {
+    let name = std::any::type_name::<State>();
+    name.rsplit("::").next().unwrap_or(name)
+}
+
 /// Type encoding for a protocol state.
 pub trait ProtocolState: Send + Sync + 'static {
+    /// The untyped equivalent of this state.
+    const PHASE: Phase;
     /// Packet type sent by the server in this state.
     type ServerPacket: Encode + Decode + Debug + AsRef<str> + Send + 'static;
     /// Packet type sent by the client in this state.
@@ -51,6 +62,7 @@ pub mod state {
     #[derive(Debug, Copy, Clone)]
     pub struct Handshake;
     impl ProtocolState for Handshake {
+        const PHASE: Phase = Phase::Handshake;
         type ServerPacket = EmptyPacket;
         type ClientPacket = client::handshake::Packet;
     }
@@ -67,6 +79,7 @@ pub mod state {
     #[derive(Debug, Copy, Clone)]
     pub struct Status;
     impl ProtocolState for Status {
+        const PHASE: Phase = Phase::Status;
         type ServerPacket = server::status::Packet;
         type ClientPacket = client::status::Packet;
     }
@@ -74,6 +87,7 @@ pub mod state {
     #[derive(Debug, Copy, Clone)]
     pub struct Login;
     impl ProtocolState for Login {
+        const PHASE: Phase = Phase::Login;
         type ServerPacket = server::login::Packet;
         type ClientPacket = client::login::Packet;
     }
@@ -81,6 +95,7 @@ pub mod state {
     #[derive(Debug, Copy, Clone)]
     pub struct Configuration;
     impl ProtocolState for Configuration {
+        const PHASE: Phase = Phase::Configuration;
         type ServerPacket = server::configuration::Packet;
         type ClientPacket = client::configuration::Packet;
     }
@@ -88,6 +103,7 @@ pub mod state {
     #[derive(Debug, Copy, Clone)]
     pub struct Play;
     impl ProtocolState for Play {
+        const PHASE: Phase = Phase::Play;
         type ServerPacket = server::play::Packet;
         type ClientPacket = client::play::Packet;
     }
@@ -25,8 +25,9 @@ pub struct ClientInformation {
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct PluginMessage {
+    pub channel: String,
     #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
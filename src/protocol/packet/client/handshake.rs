@@ -1,4 +1,6 @@
+use crate::protocol::{decoder, Decode, DecodeError, Decoder, Encode, Encoder};
 use minecraft_quic_proxy_macros::{Decode, Encode};
+use std::fmt;
 
 #[derive(Debug, Clone, Encode, Decode, strum::AsRefStr)]
 #[encoding(discriminant = "varint")]
@@ -11,11 +13,22 @@ pub enum Packet {
 pub struct Handshake {
     #[encoding(varint)]
     pub protocol_version: u32,
-    pub server_address: String,
+    pub server_address: ServerAddress,
     pub server_port: u16,
     pub next_state: NextState,
 }
 
+impl Handshake {
+    /// Replaces the server host and port, keeping any suffix
+    /// of the server address.
+    pub fn rewrite_address(&mut self, host: &str, port: u16) {
+        let mut address = host.as_bytes().to_vec();
+        address.extend_from_slice(self.server_address.suffix());
+        self.server_address = ServerAddress::new(address);
+        self.server_port = port;
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Encode, Decode)]
 #[encoding(discriminant = "varint")]
 pub enum NextState {
@@ -24,3 +37,165 @@ pub enum NextState {
     #[encoding(id = 2)]
     Login,
 }
+
+/// Maximum length of the server address, including any suffix.
+/// Larger than the vanilla limit of 255, since forwarding
+/// schemes append player data.
+const MAX_SERVER_ADDRESS_LENGTH: usize = i16::MAX as usize;
+
+/// The `server_address` field of the handshake.
+///
+/// Modded clients and proxies append data to the hostname, separated
+/// by NUL characters (e.g. `example.com\0FML3\0` for Forge, or the player's
+/// IP and UUID for BungeeCord forwarding). The raw bytes are kept
+/// so that such suffixes are forwarded exactly as received.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ServerAddress(Vec<u8>);
+
+impl ServerAddress {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self(bytes.into())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Gets the hostname, without any suffix.
+    ///
+    /// Returns `None` if it is not valid UTF-8.
+    pub fn host(&self) -> Option<&str> {
+        std::str::from_utf8(&self.0[..self.suffix_start()]).ok()
+    }
+
+    /// Gets the suffix appended after the hostname, starting with
+    /// its first NUL character. Empty for vanilla clients.
+    pub fn suffix(&self) -> &[u8] {
+        &self.0[self.suffix_start()..]
+    }
+
+    fn suffix_start(&self) -> usize {
+        self.0.iter().position(|&b| b == 0).unwrap_or(self.0.len())
+    }
+}
+
+impl fmt::Debug for ServerAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(&self.0))
+    }
+}
+
+impl From<&str> for ServerAddress {
+    fn from(s: &str) -> Self {
+        Self::new(s)
+    }
+}
+
+impl Decode for ServerAddress {
+    fn decode(decoder: &mut Decoder) -> decoder::Result<Self> {
+        let length = usize::try_from(decoder.read_var_int()?)?;
+        if length > MAX_SERVER_ADDRESS_LENGTH {
+            return Err(DecodeError::StringTooLong);
+        }
+        Ok(Self(decoder.consume_slice(length)?.to_vec()))
+    }
+}
+
+impl Encode for ServerAddress {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_var_int(self.0.len() as i32);
+        encoder.write_slice(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESSES: &[&[u8]] = &[
+        b"example.com",
+        b"example.com\0FML\0",
+        b"example.com\0FML2\0",
+        b"example.com\0FML3\0",
+        b"example.com\x00127.0.0.1\x00069a79f444e94726a5befca90e38aaf5\x00[]",
+        b"example.com\0\xff\xfe\x80\0\x01",
+        b"\xc3\x28.example\0\xf0\x90",
+        b"",
+    ];
+
+    fn handshake(address: &[u8]) -> Handshake {
+        Handshake {
+            protocol_version: 765,
+            server_address: ServerAddress::new(address),
+            server_port: 25565,
+            next_state: NextState::Login,
+        }
+    }
+
+    fn encode(value: &impl Encode) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        value.encode(&mut Encoder::new(&mut bytes));
+        bytes
+    }
+
+    #[test]
+    fn server_address_round_trips_exactly() {
+        for &address in ADDRESSES {
+            let mut bytes = vec![address.len() as u8];
+            bytes.extend_from_slice(address);
+
+            let mut decoder = Decoder::new(&bytes);
+            let decoded = ServerAddress::decode(&mut decoder).unwrap();
+            assert!(decoder.is_finished());
+            assert_eq!(decoded.as_bytes(), address);
+            assert_eq!(encode(&decoded), bytes);
+        }
+    }
+
+    #[test]
+    fn handshake_round_trips_exactly() {
+        for &address in ADDRESSES {
+            let bytes = encode(&handshake(address));
+            let decoded = Handshake::decode(&mut Decoder::new(&bytes)).unwrap();
+            assert_eq!(decoded.server_address.as_bytes(), address);
+            assert_eq!(encode(&decoded), bytes);
+        }
+    }
+
+    #[test]
+    fn splits_host_and_suffix() {
+        let address = ServerAddress::new(&b"example.com\0FML3\0"[..]);
+        assert_eq!(address.host(), Some("example.com"));
+        assert_eq!(address.suffix(), b"\0FML3\0");
+
+        let address = ServerAddress::new(&b"\xc3\x28\0FML\0"[..]);
+        assert_eq!(address.host(), None);
+        assert_eq!(address.suffix(), b"\0FML\0");
+    }
+
+    #[test]
+    fn rewrite_address_keeps_suffix() {
+        for &address in ADDRESSES {
+            let mut packet = handshake(address);
+            let suffix = packet.server_address.suffix().to_vec();
+            packet.rewrite_address("backend.internal", 25566);
+
+            let mut expected = b"backend.internal".to_vec();
+            expected.extend_from_slice(&suffix);
+            assert_eq!(packet.server_address.as_bytes(), expected);
+            assert_eq!(packet.server_port, 25566);
+        }
+    }
+
+    #[test]
+    fn rejects_overlong_address() {
+        let mut bytes = Vec::new();
+        let mut encoder = Encoder::new(&mut bytes);
+        encoder.write_var_int(MAX_SERVER_ADDRESS_LENGTH as i32 + 1);
+        encoder.write_slice(&vec![b'a'; MAX_SERVER_ADDRESS_LENGTH + 1]);
+        assert!(matches!(
+            ServerAddress::decode(&mut Decoder::new(&bytes)),
+            Err(DecodeError::StringTooLong)
+        ));
+    }
+}
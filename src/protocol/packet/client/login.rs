@@ -27,8 +27,13 @@ pub struct EncryptionResponse {
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct LoginPluginResponse {
+    #[encoding(varint)]
+    pub message_id: i32,
+    /// `false` if the client does not understand the request's channel.
+    pub successful: bool,
+    /// Only present if `successful`.
     #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -27,8 +27,9 @@ pub enum Packet {
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct PluginMessage {
+    pub channel: String,
     #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Encode, Decode)]
@@ -41,6 +41,9 @@ pub struct SetCompression {
 
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct LoginPluginRequest {
+    #[encoding(varint)]
+    pub message_id: i32,
+    pub channel: String,
     #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub data: Vec<u8>,
 }
@@ -1,8 +1,10 @@
 use crate::{
-    position::{BlockPosition, ChunkPosition},
+    position::{BlockPosition, ChunkPosition, EntityPosition},
     protocol::{decoder, Decode, Decoder, Encode, Encoder},
 };
+use bitflags::bitflags;
 use minecraft_quic_proxy_macros::{Decode, Encode};
+use std::ops::Add;
 
 #[derive(Debug, Clone, Encode, Decode, strum::AsRefStr)]
 #[encoding(discriminant = "varint")]
@@ -594,15 +596,132 @@ pub struct CombatDeath {
     #[encoding(length_prefix = "inferred")]
     pub ignored_data: Vec<u8>,
 }
-#[derive(Debug, Clone, Encode, Decode)]
+#[derive(Debug, Clone)]
 pub struct PlayerInfoRemove {
-    #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub players: Vec<u128>,
 }
-#[derive(Debug, Clone, Encode, Decode)]
+
+impl Encode for PlayerInfoRemove {
+    fn encode(&self, encoder: &mut Encoder) {
+        encoder.write_var_int(self.players.len().try_into().unwrap_or(i32::MAX));
+        for uuid in &self.players {
+            uuid.encode(encoder);
+        }
+    }
+}
+impl Decode for PlayerInfoRemove {
+    fn decode(decoder: &mut Decoder) -> decoder::Result<Self> {
+        let length = decoder.read_var_int()?;
+        let mut players = Vec::new();
+        for _ in 0..length {
+            players.push(u128::decode(decoder)?);
+        }
+        Ok(Self { players })
+    }
+}
+
+/// Updates the tab list. Only the player UUIDs are parsed; the fields
+/// of each player are kept as raw data.
+#[derive(Debug, Clone)]
 pub struct PlayerInfoUpdate {
-    #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub actions: PlayerInfoActions,
+    pub players: Vec<PlayerInfoEntry>,
+}
+
+impl PlayerInfoUpdate {
+    /// Splits the packet into one packet per player, with the same actions.
+    pub fn split(&self) -> impl Iterator<Item = PlayerInfoUpdate> + '_ {
+        self.players.iter().map(|player| PlayerInfoUpdate {
+            actions: self.actions,
+            players: vec![player.clone()],
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PlayerInfoEntry {
+    pub uuid: u128,
+    /// Fields for each of the packet's actions, in action order.
+    pub data: Vec<u8>,
+}
+
+bitflags! {
+    /// Actions performed by a `PlayerInfoUpdate`, determining
+    /// the fields present for each player.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PlayerInfoActions: u8 {
+        const ADD_PLAYER = 0x01;
+        const INITIALIZE_CHAT = 0x02;
+        const UPDATE_GAME_MODE = 0x04;
+        const UPDATE_LISTED = 0x08;
+        const UPDATE_LATENCY = 0x10;
+        const UPDATE_DISPLAY_NAME = 0x20;
+    }
+}
+
+impl PlayerInfoActions {
+    /// Skips over the fields of one player.
+    fn skip_fields(self, decoder: &mut Decoder) -> decoder::Result<()> {
+        if self.contains(Self::ADD_PLAYER) {
+            decoder.read_string()?;
+            let properties = decoder.read_var_int()?;
+            for _ in 0..properties {
+                decoder.read_string()?;
+                decoder.read_string()?;
+                if decoder.read_bool()? {
+                    decoder.read_string()?;
+                }
+            }
+        }
+        if self.contains(Self::INITIALIZE_CHAT) && decoder.read_bool()? {
+            // Chat session ID and public key expiry
+            decoder.consume_slice(16 + 8)?;
+            for _ in 0..2 {
+                // Public key and its signature
+                let length = usize::try_from(decoder.read_var_int()?)?;
+                decoder.consume_slice(length)?;
+            }
+        }
+        if self.contains(Self::UPDATE_GAME_MODE) {
+            decoder.read_var_int()?;
+        }
+        if self.contains(Self::UPDATE_LISTED) {
+            decoder.read_bool()?;
+        }
+        if self.contains(Self::UPDATE_LATENCY) {
+            decoder.read_var_int()?;
+        }
+        if self.contains(Self::UPDATE_DISPLAY_NAME) && decoder.read_bool()? {
+            decoder.skip_nbt()?;
+        }
+        Ok(())
+    }
+}
+
+impl Encode for PlayerInfoUpdate {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.actions.bits().encode(encoder);
+        encoder.write_var_int(self.players.len().try_into().unwrap_or(i32::MAX));
+        for player in &self.players {
+            player.uuid.encode(encoder);
+            encoder.write_slice(&player.data);
+        }
+    }
+}
+impl Decode for PlayerInfoUpdate {
+    fn decode(decoder: &mut Decoder) -> decoder::Result<Self> {
+        let actions = PlayerInfoActions::from_bits_retain(u8::decode(decoder)?);
+        let length = decoder.read_var_int()?;
+        let mut players = Vec::new();
+        for _ in 0..length {
+            let uuid = u128::decode(decoder)?;
+            let fields = decoder.buffer();
+            actions.skip_fields(decoder)?;
+            let data = fields[..fields.len() - decoder.buffer().len()].to_vec();
+            players.push(PlayerInfoEntry { uuid, data });
+        }
+        Ok(Self { actions, players })
+    }
 }
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct LookAt {
@@ -611,8 +730,65 @@ pub struct LookAt {
 }
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct SynchronizePlayerPosition {
-    #[encoding(length_prefix = "inferred")]
-    pub ignored_data: Vec<u8>,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub yaw: f32,
+    pub pitch: f32,
+    pub flags: RelativeFlags,
+    #[encoding(varint)]
+    pub teleport_id: i32,
+}
+
+impl SynchronizePlayerPosition {
+    /// Computes the player's new position, given its current one
+    /// (only used for relative fields).
+    pub fn apply(&self, current: EntityPosition) -> EntityPosition {
+        fn field<T: Add<Output = T>>(relative: bool, current: T, value: T) -> T {
+            if relative {
+                current + value
+            } else {
+                value
+            }
+        }
+        let flags = self.flags;
+        EntityPosition {
+            x: field(flags.contains(RelativeFlags::X), current.x, self.x),
+            y: field(flags.contains(RelativeFlags::Y), current.y, self.y),
+            z: field(flags.contains(RelativeFlags::Z), current.z, self.z),
+            yaw: field(flags.contains(RelativeFlags::YAW), current.yaw, self.yaw),
+            pitch: field(
+                flags.contains(RelativeFlags::PITCH),
+                current.pitch,
+                self.pitch,
+            ),
+        }
+    }
+}
+
+bitflags! {
+    /// Fields of `SynchronizePlayerPosition` that are relative
+    /// to the player's current position rather than absolute.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RelativeFlags: u8 {
+        const X = 0x01;
+        const Y = 0x02;
+        const Z = 0x04;
+        const YAW = 0x08;
+        const PITCH = 0x10;
+    }
+}
+
+impl Encode for RelativeFlags {
+    fn encode(&self, encoder: &mut Encoder) {
+        self.bits().encode(encoder);
+    }
+}
+
+impl Decode for RelativeFlags {
+    fn decode(decoder: &mut Decoder) -> decoder::Result<Self> {
+        Ok(Self::from_bits_retain(u8::decode(decoder)?))
+    }
 }
 #[derive(Debug, Clone, Encode, Decode)]
 pub struct UpdateRecipeBook {
@@ -681,6 +857,98 @@ pub struct UpdateSectionBlocks {
     pub ignored_data: Vec<u8>,
 }
 
+impl Disconnect {
+    /// Creates a packet disconnecting the client with a plain text reason.
+    pub fn with_reason(reason: &str) -> Self {
+        // The reason is a text component in network NBT, of
+        // which a string tag is the simplest form.
+        let mut string = Vec::new();
+        for c in reason.chars() {
+            // Modified UTF-8: NUL is encoded in two bytes, supplementary
+            // characters as the three-byte encodings of their surrogates.
+            match c {
+                '\0' => string.extend_from_slice(&[0xC0, 0x80]),
+                c if c.len_utf16() == 2 => {
+                    for unit in c.encode_utf16(&mut [0; 2]) {
+                        string.extend_from_slice(&[
+                            0xE0 | (*unit >> 12) as u8,
+                            0x80 | (*unit >> 6 & 0x3F) as u8,
+                            0x80 | (*unit & 0x3F) as u8,
+                        ]);
+                    }
+                }
+                c => string.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            }
+        }
+        string.truncate(u16::MAX as usize);
+        let mut ignored_data = vec![8];
+        ignored_data.extend_from_slice(&(string.len() as u16).to_be_bytes());
+        ignored_data.extend_from_slice(&string);
+        Self { ignored_data }
+    }
+}
+
+/// Light data that contains no light: empty sky light, block light,
+/// empty sky light and empty block light masks, followed by no
+/// sky light and block light arrays.
+const EMPTY_LIGHT_DATA: [u8; 6] = [0; 6];
+
+impl ChunkAndLightData {
+    /// Gets a copy of the packet with its light data replaced by empty
+    /// light data, for clients that compute lighting themselves.
+    /// Returns `None` if the packet carries no light data to begin with.
+    pub fn without_light(&self) -> decoder::Result<Option<Self>> {
+        let mut decoder = Decoder::new(&self.ignored_data);
+        // Heightmaps
+        decoder.skip_nbt()?;
+        let data_length = usize::try_from(decoder.read_var_int()?)?;
+        decoder.consume_slice(data_length)?;
+        let block_entities = decoder.read_var_int()?;
+        for _ in 0..block_entities {
+            // Packed XZ, Y and type
+            decoder.read_u8()?;
+            decoder.read_i16()?;
+            decoder.read_var_int()?;
+            decoder.skip_nbt()?;
+        }
+        let light_data = decoder.buffer();
+        if light_data == EMPTY_LIGHT_DATA {
+            return Ok(None);
+        }
+        // Check that the remainder is light data, so that a
+        // misparsed packet is left untouched rather than truncated.
+        skip_light_data(&mut decoder)?;
+        if !decoder.is_finished() {
+            return Err(anyhow::anyhow!("trailing bytes after light data").into());
+        }
+        let light_start = self.ignored_data.len() - light_data.len();
+        let mut ignored_data = self.ignored_data[..light_start].to_vec();
+        ignored_data.extend_from_slice(&EMPTY_LIGHT_DATA);
+        Ok(Some(Self {
+            chunk_x: self.chunk_x,
+            chunk_z: self.chunk_z,
+            ignored_data,
+        }))
+    }
+}
+
+fn skip_light_data(decoder: &mut Decoder) -> decoder::Result<()> {
+    // Bit sets of sky light, block light, empty sky light and empty block light
+    for _ in 0..4 {
+        let longs = usize::try_from(decoder.read_var_int()?)?;
+        decoder.consume_slice(longs.saturating_mul(8))?;
+    }
+    // Sky light and block light arrays
+    for _ in 0..2 {
+        let arrays = decoder.read_var_int()?;
+        for _ in 0..arrays {
+            let length = usize::try_from(decoder.read_var_int()?)?;
+            decoder.consume_slice(length)?;
+        }
+    }
+    Ok(())
+}
+
 impl UpdateSectionBlocks {
     pub fn chunk_position(&self) -> ChunkPosition {
         ChunkPosition {
@@ -1,7 +1,7 @@
 //! Codec implementation for the vanilla codec.
 //! Supports zlib compression and CFB8 encryption.
 
-use super::BUFFER_LIMIT;
+use super::{MAX_FRAME_LENGTH, MAX_PACKET_LENGTH};
 use crate::protocol::{
     packet, packet::ProtocolState, Decode, DecodeError, Decoder, Encode, Encoder,
 };
@@ -50,6 +50,16 @@ pub struct VanillaCodec<Side, State> {
     _marker: PhantomData<(Side, State)>,
 }
 
+impl<Side, State> Default for VanillaCodec<Side, State>
+where
+    Side: packet::Side,
+    State: ProtocolState,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<Side, State> VanillaCodec<Side, State>
 where
     Side: packet::Side,
@@ -129,14 +139,11 @@ where
             }
         };
 
-        match &mut self.encryption_state {
-            Some(EncryptionState { encryptor, .. }) => {
-                for x in &mut compressed_buf {
-                    let slice = slice::from_mut(x);
-                    encryptor.encrypt_block_mut(GenericArray::from_mut_slice(slice));
-                }
+        if let Some(EncryptionState { encryptor, .. }) = &mut self.encryption_state {
+            for x in &mut compressed_buf {
+                let slice = slice::from_mut(x);
+                encryptor.encrypt_block_mut(GenericArray::from_mut_slice(slice));
             }
-            None => {}
         }
 
         Ok(compressed_buf)
@@ -160,6 +167,17 @@ where
         self.read_buffer.extend_from_slice(data);
     }
 
+    /// Gets the number of bytes given but not decoded yet.
+    pub fn buffered(&self) -> usize {
+        self.read_buffer.len()
+    }
+
+    /// Gets the buffered bytes not decoded yet, e.g. to forward
+    /// them as they are once the stream is no longer decoded.
+    pub fn into_read_buffer(self) -> Vec<u8> {
+        self.read_buffer
+    }
+
     /// Attempts to decode a packet.
     /// This should be called in a loop after any call to `give_data`
     /// until this function returns `None`.
@@ -178,7 +196,7 @@ where
 
         let total_bytes = length + length_prefix_size;
 
-        if length > BUFFER_LIMIT {
+        if length > MAX_FRAME_LENGTH {
             bail!("packet length of {length} exceeds maximum allowed");
         }
         let packet_contents = match decoder.consume_slice(length) {
@@ -194,10 +212,22 @@ where
                 if uncompressed_length == 0 {
                     Cow::Borrowed(decoder.buffer())
                 } else {
-                    let mut buf = Vec::new();
+                    if uncompressed_length > MAX_PACKET_LENGTH {
+                        bail!(
+                            "decompressed packet length of {uncompressed_length} exceeds maximum allowed"
+                        );
+                    }
+                    // Read one byte past the declared length, so that data
+                    // inflating to more than declared is caught as well.
+                    let mut buf = Vec::with_capacity(uncompressed_length);
                     flate2::read::ZlibDecoder::new(decoder.buffer())
-                        .take(BUFFER_LIMIT.try_into().unwrap())
+                        .take(uncompressed_length as u64 + 1)
                         .read_to_end(&mut buf)?;
+                    if buf.len() != uncompressed_length {
+                        bail!(
+                            "packet decompressed to a different length than the declared {uncompressed_length}"
+                        );
+                    }
                     Cow::Owned(buf)
                 }
             }
@@ -222,3 +252,65 @@ struct CompressionState {
 pub fn var_int_size(x: i32) -> usize {
     Encoder::new(&mut Vec::new()).write_var_int(x)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::packet::{server, side, state};
+
+    fn keep_alive() -> Vec<u8> {
+        let mut plain = Vec::new();
+        server::play::Packet::KeepAlive(server::play::KeepAlive {
+            ignored_data: 0x0123_4567_89ab_cdef_u64.to_be_bytes().to_vec(),
+        })
+        .encode(&mut Encoder::new(&mut plain));
+        plain
+    }
+
+    /// Frames `plain` compressed, declaring `declared_length` as its
+    /// decompressed length.
+    fn compressed_frame(plain: &[u8], declared_length: usize) -> Vec<u8> {
+        let mut compressor = flate2::write::ZlibEncoder::new(Vec::new(), COMPRESSION_LEVEL);
+        compressor.write_all(plain).unwrap();
+        let compressed = compressor.finish().unwrap();
+
+        let mut contents = Vec::new();
+        let mut encoder = Encoder::new(&mut contents);
+        encoder.write_var_int(declared_length as i32);
+        encoder.write_slice(&compressed);
+
+        let mut frame = Vec::new();
+        let mut encoder = Encoder::new(&mut frame);
+        encoder.write_var_int(contents.len() as i32);
+        encoder.write_slice(&contents);
+        frame
+    }
+
+    fn decode(frame: Vec<u8>) -> anyhow::Result<Option<server::play::Packet>> {
+        let mut codec = VanillaCodec::<side::Client, state::Play>::new();
+        codec.enable_compression(CompressionThreshold::new(0));
+        codec.give_data(frame);
+        codec.decode_packet()
+    }
+
+    #[test]
+    fn decodes_compressed_packet_of_declared_length() {
+        let plain = keep_alive();
+        let packet = decode(compressed_frame(&plain, plain.len())).unwrap();
+        assert!(matches!(packet, Some(server::play::Packet::KeepAlive(_))));
+    }
+
+    #[test]
+    fn rejects_compressed_packet_shorter_than_declared() {
+        let plain = keep_alive();
+        let error = decode(compressed_frame(&plain, plain.len() + 1)).unwrap_err();
+        assert!(error.to_string().contains("declared"), "{error}");
+    }
+
+    #[test]
+    fn rejects_compressed_packet_longer_than_declared() {
+        let plain = keep_alive();
+        let error = decode(compressed_frame(&plain, plain.len() - 1)).unwrap_err();
+        assert!(error.to_string().contains("declared"), "{error}");
+    }
+}
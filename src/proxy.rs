@@ -1,20 +1,46 @@
 //! Implements proxy logic.
 
 use crate::{
-    packet_translation::{PacketTranslator, TranslatePacket},
+    anomaly::AnomalyCollector,
+    bandwidth::{BandwidthCategory, BandwidthMeter, BandwidthQuota},
+    clock::SharedClock,
+    control_stream::StateTransitions,
+    latency_budget::LatencyBudgets,
+    memory_budget::{MemoryBudget, MemoryCharge},
+    packet_flow::{Direction, PacketFlow},
+    packet_log,
+    packet_rate::PacketRateLimit,
+    packet_translation::{Coalescing, PacketTranslator, TranslatePacket},
+    position::EntityPosition,
     protocol::{
+        optimized_codec::CodecVersion,
         packet,
-        packet::{side, state, state::Play, ProtocolState},
+        packet::{side, state, state::Play, state_name, ProtocolState},
         vanilla_codec::{CompressionThreshold, EncryptionKey, VanillaCodec},
     },
+    redundancy::{CriticalPacket, DuplicateFilter},
     sequence::SequencesHandle,
-    stream::{RecvStreamHandle, SendStreamHandle},
-    stream_allocation::{AllocateStream, Allocation, StreamAllocator},
+    stream::{RecvStreamHandle, SendStreamHandle, StreamDiagnostics},
+    stream_allocation::{
+        AllocateStream, Allocation, AllocationClass, AllocationCounters, StreamAllocator,
+    },
     stream_priority,
+    timeline::Timeline,
+    watchdog::{Deliveries, StallWatchdog, Stalled},
+    write_batching::{self, BatchedWriter},
 };
 use anyhow::{bail, Context};
-use quinn::Connection;
-use std::{any::type_name, marker::PhantomData, ops::ControlFlow, sync::Arc};
+use futures::future;
+use quinn::{Connection, StreamId};
+use std::{
+    any::type_name,
+    future::Future,
+    io,
+    marker::PhantomData,
+    ops::ControlFlow,
+    sync::{atomic::AtomicU64, Arc},
+    time::{Duration, Instant},
+};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{
@@ -24,22 +50,60 @@ use tokio::{
     select,
     sync::Mutex,
     task,
-    task::JoinSet,
+    task::{JoinHandle, JoinSet},
 };
 
-pub trait PacketIo<Side: packet::Side, State: ProtocolState> {
-    async fn send_packet(&self, packet: Side::SendPacket<State>) -> anyhow::Result<()>;
+/// Sends and receives the packets of one side of a `Proxy`.
+///
+/// Implementations and their futures are `Send`, so that
+/// a proxy can forward packets on any runtime thread.
+pub trait PacketIo<Side: packet::Side, State: ProtocolState>: Send + Sync {
+    fn send_packet(
+        &self,
+        packet: Side::SendPacket<State>,
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Like `send_packet`, but also returns the class of the
+    /// allocation the packet was sent on, for transports that
+    /// allocate streams per packet.
+    fn send_packet_allocated(
+        &self,
+        packet: Side::SendPacket<State>,
+    ) -> impl Future<Output = anyhow::Result<Option<AllocationClass>>> + Send {
+        async move { self.send_packet(packet).await.map(|()| None) }
+    }
+
+    /// Describes the streams packets are sent on, for
+    /// transports with several, to diagnose stalls.
+    fn stream_diagnostics(&self) -> impl Future<Output = Vec<StreamDiagnostics>> + Send {
+        async { Vec::new() }
+    }
+
+    /// Attempts to unblock sending after no packets were delivered
+    /// for a while, returning whether anything was done.
+    fn recover_stalled_send(&self) -> impl Future<Output = anyhow::Result<bool>> + Send {
+        async { Ok(false) }
+    }
 
     /// _Must_ be cancellation-safe: if this future
     /// is cancelled, no received packet can be dropped.
     /// (This is required so that the proxy can call
     /// this future in a `select!` loop.)
-    async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<State>>;
+    fn recv_packet(&self) -> impl Future<Output = anyhow::Result<Side::RecvPacket<State>>> + Send;
+
+    /// Like `recv_packet`, but also returns the number of bytes the
+    /// packet took up on the wire, or zero if the transport does not
+    /// know. Must be cancellation-safe as well.
+    fn recv_packet_sized(
+        &self,
+    ) -> impl Future<Output = anyhow::Result<(Side::RecvPacket<State>, usize)>> + Send {
+        async { self.recv_packet().await.map(|packet| (packet, 0)) }
+    }
 }
 
 /// `PacketIo` over vanilla TCP.
 pub struct VanillaPacketIo<Side: packet::Side, State: ProtocolState> {
-    send_stream: Mutex<OwnedWriteHalf>,
+    send_stream: Mutex<PacketWriter>,
     recv_stream: Mutex<OwnedReadHalf>,
     send_codec: Mutex<VanillaCodec<Side, State>>,
     recv_codec: Mutex<VanillaCodec<Side, State>>,
@@ -53,13 +117,26 @@ where
     pub fn new(stream: TcpStream) -> anyhow::Result<Self> {
         let (recv_stream, send_stream) = stream.into_split();
         Ok(Self {
-            send_stream: Mutex::new(send_stream),
+            send_stream: Mutex::new(PacketWriter::Direct(send_stream)),
             recv_stream: Mutex::new(recv_stream),
             send_codec: Mutex::new(VanillaCodec::new()),
             recv_codec: Mutex::new(VanillaCodec::new()),
         })
     }
 
+    /// Combines packets sent within `max_delay` of each other, up to
+    /// `max_bytes`, into a single write. Latency probes are written immediately.
+    pub fn with_write_batching(mut self, max_delay: Duration, max_bytes: usize) -> Self {
+        let send_stream = match self.send_stream.into_inner() {
+            PacketWriter::Direct(stream) => {
+                PacketWriter::Batched(BatchedWriter::new(stream, max_delay, max_bytes))
+            }
+            batched => batched,
+        };
+        self.send_stream = Mutex::new(send_stream);
+        self
+    }
+
     pub fn enable_compression(&mut self, threshold: CompressionThreshold) {
         self.send_codec.get_mut().enable_compression(threshold);
         self.recv_codec.get_mut().enable_compression(threshold);
@@ -86,69 +163,179 @@ where
     State: ProtocolState,
 {
     async fn send_packet(&self, packet: Side::SendPacket<State>) -> anyhow::Result<()> {
+        let flush = write_batching::flushes_immediately(packet.as_ref());
         let bytes = {
             let mut codec = self.send_codec.lock().await;
             codec.encode_packet(&packet)?
         };
         let mut stream = self.send_stream.lock().await;
-        stream.write_all(&bytes).await?;
+        match &mut *stream {
+            PacketWriter::Direct(stream) => stream.write_all(&bytes).await?,
+            PacketWriter::Batched(writer) => writer.write(bytes, flush).await?,
+        }
         Ok(())
     }
 
     async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<State>> {
+        Ok(self.recv_packet_sized().await?.0)
+    }
+
+    async fn recv_packet_sized(&self) -> anyhow::Result<(Side::RecvPacket<State>, usize)> {
         let mut buffer = [0u8; 256];
         loop {
             // Both locks must occur here to ensure cancellation safety
             let mut codec = self.recv_codec.lock().await;
             let mut stream = self.recv_stream.lock().await;
 
+            let buffered = codec.buffered();
             if let Some(packet) = codec.decode_packet()? {
-                return Ok(packet);
+                return Ok((packet, buffered - codec.buffered()));
             }
 
             let bytes_read = stream.read(&mut buffer).await?;
             if bytes_read == 0 {
-                bail!("disconnected from TCP");
+                bail!(TcpDisconnected);
             }
             codec.give_data(&mut buffer[..bytes_read]);
         }
     }
 }
 
+/// Error of a `VanillaPacketIo` whose peer closed the TCP connection.
+#[derive(Debug, thiserror::Error)]
+#[error("disconnected from TCP")]
+pub struct TcpDisconnected;
+
+impl TcpDisconnected {
+    /// Whether an error means that the peer of a
+    /// `VanillaPacketIo` closed or reset the TCP connection.
+    pub fn is_cause_of(error: &anyhow::Error) -> bool {
+        error.is::<TcpDisconnected>()
+            || error.downcast_ref::<io::Error>().is_some_and(|e| {
+                matches!(
+                    e.kind(),
+                    io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::BrokenPipe
+                )
+            })
+    }
+}
+
+/// Error of a `QuicPacketIo` that failed to send a packet it could
+/// drop, while the connection stayed open. `Proxy::run` tolerates
+/// these up to a budget.
+#[derive(Debug, thiserror::Error)]
+#[error("dropped a packet allocated to {}", class.as_ref())]
+pub struct DroppedPacket {
+    pub class: AllocationClass,
+    #[source]
+    source: anyhow::Error,
+}
+
+/// Number of dropped packets that `Proxy::run` tolerates
+/// within `DROPPED_PACKET_WINDOW` before failing.
+const DROPPED_PACKET_BUDGET: u32 = 16;
+const DROPPED_PACKET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Counts the `DroppedPacket` errors of a `Proxy` against their budget.
+struct DroppedPacketBudget {
+    window_start: Instant,
+    dropped: u32,
+}
+
+impl DroppedPacketBudget {
+    fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Absorbs a failed send if it only dropped a packet and the budget
+    /// is not exhausted. Otherwise, returns the error.
+    fn absorb(&mut self, error: anyhow::Error) -> anyhow::Result<()> {
+        if !error.is::<DroppedPacket>() {
+            return Err(error);
+        }
+        if self.window_start.elapsed() >= DROPPED_PACKET_WINDOW {
+            self.window_start = Instant::now();
+            self.dropped = 0;
+        }
+        self.dropped += 1;
+        if self.dropped > DROPPED_PACKET_BUDGET {
+            return Err(error.context(format!(
+                "more than {DROPPED_PACKET_BUDGET} packets dropped within {DROPPED_PACKET_WINDOW:?}"
+            )));
+        }
+        tracing::debug!("{error:#}");
+        Ok(())
+    }
+}
+
+/// Where a `VanillaPacketIo` writes encoded packets.
+enum PacketWriter {
+    Direct(OwnedWriteHalf),
+    Batched(BatchedWriter),
+}
+
 /// Utility to listen for packets on all incoming
 /// QUIC streams (unidirectional only).
 struct QuicReceiver<Side: packet::Side, State: ProtocolState> {
     connection: Connection,
-    stream_receives_tx: flume::Sender<anyhow::Result<Side::RecvPacket<State>>>,
-    stream_receives: flume::Receiver<anyhow::Result<Side::RecvPacket<State>>>,
+    codec_version: CodecVersion,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    stream_receives_tx: flume::Sender<anyhow::Result<ChargedPacket<Side, State>>>,
+    stream_receives: flume::Receiver<anyhow::Result<ChargedPacket<Side, State>>>,
 }
 
+/// A received packet, the number of bytes it took up in its stream,
+/// and the charge of those bytes if the connection has a memory budget.
+type ChargedPacket<Side, State> = (
+    <Side as packet::Side>::RecvPacket<State>,
+    usize,
+    Option<MemoryCharge>,
+);
+
 impl<Side, State> QuicReceiver<Side, State>
 where
     Side: packet::Side,
     State: ProtocolState,
 {
-    pub fn new(connection: Connection) -> Self {
+    pub fn new(
+        connection: Connection,
+        codec_version: CodecVersion,
+        memory_budget: Option<Arc<MemoryBudget>>,
+    ) -> Self {
         let (stream_receives_tx, stream_receives) = flume::bounded(16);
         Self {
             connection,
+            codec_version,
+            memory_budget,
             stream_receives,
             stream_receives_tx,
         }
     }
 
-    pub async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<State>> {
+    /// Waits for a packet on any stream, returning it along with the
+    /// number of bytes it took up in the stream and their charge.
+    pub async fn recv_packet(&self) -> anyhow::Result<ChargedPacket<Side, State>> {
         loop {
             select! {
                 packet = self.stream_receives.recv_async() => {
                     return packet?;
                 }
-                new_stream = RecvStreamHandle::<Side, State>::accept(&self.connection, "incoming_any") => {
+                new_stream = RecvStreamHandle::<Side, State>::accept_with_budget(
+                    &self.connection,
+                    self.codec_version,
+                    "incoming_any",
+                    self.memory_budget.clone(),
+                ) => {
                     let new_stream = new_stream?;
                     let stream_receives = self.stream_receives_tx.clone();
                     task::spawn(async move {
                         loop {
-                            match new_stream.recv_packet().await {
+                            match new_stream.recv_packet_charged().await {
                                 Ok(Some(packet)) => if stream_receives.send_async(Ok(packet)).await.is_err() {
                                     break;
                                 }
@@ -170,12 +357,21 @@ where
 /// for all packets. This is used in the Handshake/Login/Status
 /// /Configuration states.
 ///
-/// Only one receive stream will be accepted. Others are ignored.
-/// (This ensures that state switching works correctly.)
+/// Each state uses a new stream in each direction. On a state switch,
+/// the stream of the old state is finished, and the receiving side drains
+/// it to its end before accepting the stream of the new state, so that
+/// streams cannot be attributed to the wrong state.
 pub struct SingleQuicPacketIo<Side: packet::Side, State: ProtocolState> {
     connection: Connection,
+    codec_version: CodecVersion,
+    timeline: Arc<Timeline>,
     send_stream: SendStreamHandle<Side, State>,
     recv_stream: Mutex<Option<RecvStreamHandle<Side, State>>>,
+    /// Drains the receive stream of the previous state. Must complete
+    /// before the receive stream of this state is accepted.
+    previous_recv_stream: Mutex<Option<JoinHandle<anyhow::Result<()>>>>,
+    /// ID of the receive stream of this state, if announced by the peer.
+    expected_recv_stream: Option<StreamId>,
 }
 
 impl<Side, State> SingleQuicPacketIo<Side, State>
@@ -183,28 +379,45 @@ where
     Side: packet::Side,
     State: ProtocolState,
 {
-    pub async fn new(connection: &Connection) -> anyhow::Result<Self> {
+    pub async fn new(
+        connection: &Connection,
+        codec_version: CodecVersion,
+        timeline: Arc<Timeline>,
+    ) -> anyhow::Result<Self> {
+        timeline.record_state_switch::<State>();
         Ok(Self {
             connection: connection.clone(),
+            codec_version,
+            timeline,
             send_stream: SendStreamHandle::open(
                 connection,
+                codec_version,
                 type_name::<State>(),
                 stream_priority::DEFAULT,
             )
             .await?,
             recv_stream: Mutex::new(None),
+            previous_recv_stream: Mutex::new(None),
+            expected_recv_stream: None,
         })
     }
 
     pub fn from_streams(
         connection: &Connection,
+        codec_version: CodecVersion,
+        timeline: Arc<Timeline>,
         send_stream: SendStreamHandle<Side, State>,
         recv_stream: RecvStreamHandle<Side, State>,
     ) -> Self {
+        timeline.record_state_switch::<State>();
         Self {
             connection: connection.clone(),
+            codec_version,
+            timeline,
             send_stream,
             recv_stream: Mutex::new(Some(recv_stream)),
+            previous_recv_stream: Mutex::new(None),
+            expected_recv_stream: None,
         }
     }
 
@@ -212,16 +425,114 @@ where
         &self.connection
     }
 
+    pub fn codec_version(&self) -> CodecVersion {
+        self.codec_version
+    }
+
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
     /// Changes to a new protocol state.
     ///
-    /// All current streams are dropped. Both the client and gateway
-    /// sides of the connection must make the state change at the same
-    /// time, so that stream cooperation works correctly.
+    /// The current send stream is finished and a new one opened. Both
+    /// the client and gateway sides of the connection must make the state
+    /// change at the same packet, coordinated through `transitions`.
     pub async fn switch_state<NewState: ProtocolState>(
         self,
+        transitions: &mut impl StateTransitions,
     ) -> anyhow::Result<SingleQuicPacketIo<Side, NewState>> {
-        SingleQuicPacketIo::new(&self.connection).await
+        let Self {
+            connection,
+            codec_version,
+            timeline,
+            send_stream,
+            recv_stream,
+            previous_recv_stream,
+            ..
+        } = self;
+        drop(send_stream);
+        let drain = task::spawn(drain_recv_stream(
+            connection.clone(),
+            codec_version,
+            previous_recv_stream.into_inner(),
+            recv_stream.into_inner(),
+        ));
+
+        let mut io =
+            SingleQuicPacketIo::<Side, NewState>::new(&connection, codec_version, timeline).await?;
+        io.previous_recv_stream = Mutex::new(Some(drain));
+        io.expected_recv_stream = transitions
+            .switch_state::<NewState>(Some(io.send_stream.id()))
+            .await?;
+        Ok(io)
+    }
+
+    /// Leaves the single-stream states for Play, returning
+    /// once the peer has finished its stream for this state.
+    ///
+    /// Otherwise, an unaccepted stream of this state would be
+    /// taken for one carrying Play packets.
+    pub async fn switch_to_play(
+        self,
+        transitions: &mut impl StateTransitions,
+    ) -> anyhow::Result<()> {
+        let Self {
+            connection,
+            codec_version,
+            send_stream,
+            recv_stream,
+            previous_recv_stream,
+            ..
+        } = self;
+        drop(send_stream);
+        transitions.switch_state::<Play>(None).await?;
+        drain_recv_stream(
+            connection,
+            codec_version,
+            previous_recv_stream.into_inner(),
+            recv_stream.into_inner(),
+        )
+        .await
+    }
+}
+
+/// Reads a receive stream of a previous state to its end, which
+/// the peer signals by finishing the stream when it switches states.
+///
+/// If the stream was never accepted, accepts it first. Packets still
+/// arriving on it cannot be decoded in the new state and are discarded.
+async fn drain_recv_stream<Side, State>(
+    connection: Connection,
+    codec_version: CodecVersion,
+    previous_recv_stream: Option<JoinHandle<anyhow::Result<()>>>,
+    recv_stream: Option<RecvStreamHandle<Side, State>>,
+) -> anyhow::Result<()>
+where
+    Side: packet::Side,
+    State: ProtocolState,
+{
+    let recv_stream = match recv_stream {
+        Some(stream) => stream,
+        None => {
+            if let Some(previous) = previous_recv_stream {
+                previous.await??;
+            }
+            RecvStreamHandle::accept(&connection, codec_version, type_name::<State>()).await?
+        }
+    };
+
+    let mut discarded = 0;
+    while recv_stream.recv_packet().await?.is_some() {
+        discarded += 1;
+    }
+    if discarded > 0 {
+        tracing::warn!(
+            "Discarded {discarded} {} packets received after the state switch",
+            state_name::<State>()
+        );
     }
+    Ok(())
 }
 
 impl<Side, State> PacketIo<Side, State> for SingleQuicPacketIo<Side, State>
@@ -230,98 +541,518 @@ where
     State: ProtocolState,
 {
     async fn send_packet(&self, packet: Side::SendPacket<State>) -> anyhow::Result<()> {
-        self.send_stream.send_packet(packet).await
+        self.timeline.record_packet_sent(&packet);
+        self.send_stream.send_packet(packet).await.map(|_| ())
     }
 
     async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<State>> {
+        Ok(self.recv_packet_sized().await?.0)
+    }
+
+    async fn recv_packet_sized(&self) -> anyhow::Result<(Side::RecvPacket<State>, usize)> {
         loop {
             let mut recv_stream = self.recv_stream.lock().await;
 
             match &mut *recv_stream {
                 Some(stream) => {
-                    return stream
-                        .recv_packet()
-                        .await
-                        .map(|opt| opt.context("end of stream"))?
+                    let (packet, size) =
+                        stream.recv_packet_sized().await?.context("end of stream")?;
+                    self.timeline.record_packet_received(&packet);
+                    return Ok((packet, size));
                 }
                 None => {
-                    *recv_stream = Some(
-                        RecvStreamHandle::accept(&self.connection, type_name::<State>()).await?,
-                    );
+                    let mut previous = self.previous_recv_stream.lock().await;
+                    if let Some(drain) = &mut *previous {
+                        drain.await??;
+                        *previous = None;
+                    }
+
+                    let stream = RecvStreamHandle::accept(
+                        &self.connection,
+                        self.codec_version,
+                        type_name::<State>(),
+                    )
+                    .await?;
+                    if let Some(expected) = self.expected_recv_stream {
+                        if stream.id() != expected {
+                            bail!(
+                                "peer sent {} packets on stream {}, but announced stream {expected}",
+                                state_name::<State>(),
+                                stream.id()
+                            );
+                        }
+                    }
+                    *recv_stream = Some(stream);
                 }
             }
         }
     }
 }
 
+/// Shared counters that a `QuicPacketIo` records into,
+/// so they can outlive a single Play state.
+#[derive(Debug, Clone)]
+pub struct Instrumentation {
+    pub allocation_counters: Arc<AllocationCounters>,
+    pub anomalies: Arc<AnomalyCollector>,
+    pub timeline: Arc<Timeline>,
+    /// Clock that idle streams and sequences expire by.
+    pub clock: SharedClock,
+    /// If set, records the bytes of each packet sent and received.
+    pub bandwidth: Option<Arc<BandwidthMeter>>,
+    /// If set, limits the bytes of the packets sent and received.
+    pub quota: Option<Arc<BandwidthQuota>>,
+    /// If set, limits the bytes buffered by the receive streams.
+    pub memory_budget: Option<Arc<MemoryBudget>>,
+    /// Counts received datagrams dropped as outdated.
+    pub dropped_datagrams: Arc<AtomicU64>,
+}
+
 /// `PacketIo` over QUIC, using full stream and datagram/sequence
 /// allocation.
 ///
 /// Only valid for `state::Play`.
 pub struct QuicPacketIo<Side: packet::Side> {
     connection: Connection,
+    codec_version: CodecVersion,
+    timeline: Arc<Timeline>,
     stream_allocator: Mutex<StreamAllocator<Side>>,
     packet_translator: Mutex<PacketTranslator>,
     receiver: QuicReceiver<Side, state::Play>,
     sequences: SequencesHandle<Side>,
+    bandwidth: Option<Arc<BandwidthMeter>>,
+    quota: Option<Arc<BandwidthQuota>>,
+    clock: SharedClock,
+    /// Set if critical packets are sent redundantly.
+    duplicate_filter: Option<std::sync::Mutex<DuplicateFilter>>,
 }
 
 impl<Side> QuicPacketIo<Side>
 where
     Side: packet::Side,
 {
-    pub async fn new(connection: Connection) -> anyhow::Result<Self> {
+    /// Creates a `QuicPacketIo` that records into the given counters.
+    pub async fn with_instrumentation(
+        connection: Connection,
+        codec_version: CodecVersion,
+        instrumentation: Instrumentation,
+    ) -> anyhow::Result<Self> {
+        let Instrumentation {
+            allocation_counters,
+            anomalies,
+            timeline,
+            clock,
+            bandwidth,
+            quota,
+            memory_budget,
+            dropped_datagrams,
+        } = instrumentation;
+        timeline.record_state_switch::<state::Play>();
         Ok(Self {
-            stream_allocator: Mutex::new(StreamAllocator::new(&connection).await?),
-            packet_translator: Mutex::new(PacketTranslator::new()),
-            sequences: SequencesHandle::new(connection.clone()),
-            receiver: QuicReceiver::new(connection.clone()),
+            stream_allocator: Mutex::new(
+                StreamAllocator::new(
+                    &connection,
+                    codec_version,
+                    allocation_counters,
+                    Arc::clone(&clock),
+                )
+                .await?,
+            ),
+            packet_translator: Mutex::new(PacketTranslator::new(
+                Arc::clone(&anomalies),
+                Arc::clone(&clock),
+            )),
+            sequences: SequencesHandle::new(
+                connection.clone(),
+                dropped_datagrams,
+                anomalies,
+                Arc::clone(&clock),
+            ),
+            receiver: QuicReceiver::new(connection.clone(), codec_version, memory_budget),
             connection,
+            codec_version,
+            timeline,
+            bandwidth,
+            quota,
+            clock,
+            duplicate_filter: None,
         })
     }
 
     pub fn connection(&self) -> &Connection {
         &self.connection
     }
+
+    pub fn codec_version(&self) -> CodecVersion {
+        self.codec_version
+    }
+
+    pub fn timeline(&self) -> &Arc<Timeline> {
+        &self.timeline
+    }
+
+    pub fn sequences(&self) -> &SequencesHandle<Side> {
+        &self.sequences
+    }
+
+    /// Gets the player's position as of its last teleport by the server.
+    pub async fn player_position(&self) -> Option<EntityPosition> {
+        self.packet_translator.lock().await.player_position()
+    }
+
+    /// Enables remapping of entity IDs reused by the server.
+    /// See `PacketTranslator::enable_entity_id_remapping`.
+    pub fn with_entity_id_remapping(mut self) -> Self {
+        self.packet_translator
+            .get_mut()
+            .enable_entity_id_remapping();
+        self
+    }
+
+    /// Coalesces bursts of velocity updates.
+    /// See `PacketTranslator::enable_velocity_coalescing`.
+    pub fn with_velocity_coalescing(mut self, window: Duration) -> Self {
+        self.packet_translator
+            .get_mut()
+            .enable_velocity_coalescing(window);
+        self
+    }
+
+    /// Strips light data from chunks.
+    /// See `PacketTranslator::enable_light_stripping`.
+    pub fn with_light_stripping(mut self) -> Self {
+        self.packet_translator.get_mut().enable_light_stripping();
+        self
+    }
+
+    /// Sends entity movement on streams rather than as datagrams.
+    /// See `StreamAllocator::disable_datagrams`.
+    pub fn without_datagrams(mut self) -> Self {
+        self.stream_allocator.get_mut().disable_datagrams();
+        self
+    }
+
+    /// Sends critical packets twice and drops the second copy
+    /// of those received. Both ends must enable this.
+    /// See the `redundancy` module.
+    pub fn with_redundancy(mut self) -> Self {
+        self.duplicate_filter = Some(Default::default());
+        self
+    }
 }
 
 impl<Side> PacketIo<Side, state::Play> for QuicPacketIo<Side>
 where
     Side: packet::Side,
+    Side::SendPacket<Play>: CriticalPacket,
+    Side::RecvPacket<Play>: CriticalPacket,
     StreamAllocator<Side>: AllocateStream<Side>,
     PacketTranslator: TranslatePacket<Side>,
 {
     async fn send_packet(&self, packet: Side::SendPacket<Play>) -> anyhow::Result<()> {
-        let packet = self
-            .packet_translator
-            .lock()
-            .await
+        self.send_packet_allocated(packet).await.map(|_| ())
+    }
+
+    /// Split packets report the class of their first part.
+    async fn send_packet_allocated(
+        &self,
+        packet: Side::SendPacket<Play>,
+    ) -> anyhow::Result<Option<AllocationClass>> {
+        let split = self.packet_translator.lock().await.split_packet(&packet);
+        match split {
+            Some(packets) => {
+                let mut first_class = None;
+                for packet in packets {
+                    let class = self.send_single_packet(packet).await?;
+                    first_class.get_or_insert(class);
+                }
+                Ok(first_class)
+            }
+            None => self.send_single_packet(packet).await.map(Some),
+        }
+    }
+
+    /// Empty if the stream allocator is busy, e.g. stuck opening a stream.
+    async fn stream_diagnostics(&self) -> Vec<StreamDiagnostics> {
+        match self.stream_allocator.try_lock() {
+            Ok(stream_allocator) => stream_allocator.shared_stream_diagnostics(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Reopens the misc stream, which carries most packets
+    /// that are not sent on a stream of their own. Does
+    /// nothing if the stream allocator is busy.
+    async fn recover_stalled_send(&self) -> anyhow::Result<bool> {
+        let Ok(mut stream_allocator) = self.stream_allocator.try_lock() else {
+            return Ok(false);
+        };
+        stream_allocator.reopen_misc_stream().await?;
+        Ok(true)
+    }
+
+    async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<Play>> {
+        Ok(self.recv_packet_sized().await?.0)
+    }
+
+    async fn recv_packet_sized(&self) -> anyhow::Result<(Side::RecvPacket<Play>, usize)> {
+        loop {
+            // The charge is released once the packet is returned,
+            // for the caller to charge it on as it sees fit.
+            let (packet, size, _charge) = select! {
+                packet = self.sequences.recv_packet() => {
+                    let (packet, size) = packet?;
+                    (packet, size, None)
+                }
+                packet = self.receiver.recv_packet() => packet?,
+            };
+            if let Some(bandwidth) = &self.bandwidth {
+                bandwidth.record_received(BandwidthCategory::of_packet(packet.as_ref()), size);
+            }
+            if let Some(quota) = &self.quota {
+                quota.consume(size).await?;
+            }
+            if let Some(duplicate_filter) = &self.duplicate_filter {
+                if duplicate_filter.lock().unwrap().is_duplicate(&packet) {
+                    continue;
+                }
+            }
+            self.timeline.record_packet_received(&packet);
+            return Ok((packet, size));
+        }
+    }
+}
+
+impl<Side> QuicPacketIo<Side>
+where
+    Side: packet::Side,
+    Side::SendPacket<Play>: CriticalPacket,
+    StreamAllocator<Side>: AllocateStream<Side>,
+    PacketTranslator: TranslatePacket<Side>,
+{
+    async fn send_single_packet(
+        &self,
+        packet: Side::SendPacket<Play>,
+    ) -> anyhow::Result<AllocationClass> {
+        let mut packet_translator = self.packet_translator.lock().await;
+        let packet = packet_translator
             .translate_packet(&packet)
             .unwrap_or(packet);
+        if let Coalescing::Defer(deferred) = packet_translator.coalesce_packet(&packet) {
+            drop(packet_translator);
+            self.clock.sleep_until(deferred.until()).await;
+            packet_translator = self.packet_translator.lock().await;
+            if !packet_translator.take_deferred(&deferred) {
+                // Superseded by a newer packet; reported as
+                // the allocation the newer one is sent on.
+                return Ok(AllocationClass::EntityMovement);
+            }
+        }
+        self.timeline.record_packet_sent(&packet);
 
         let mut stream_allocator = self.stream_allocator.lock().await;
-        let allocation = stream_allocator.allocate_stream_for(&packet).await?;
+        let allocation = stream_allocator
+            .allocate_stream_for(&packet, &packet_translator)
+            .await?;
+        let redundant_stream = match &self.duplicate_filter {
+            Some(_) if packet.is_critical() => {
+                Some(stream_allocator.open_redundant_stream().await?)
+            }
+            _ => None,
+        };
         drop(stream_allocator);
+        drop(packet_translator);
+
+        let class = allocation.class();
+        let stream = match &allocation {
+            Allocation::Stream(stream, _) => Some(stream.id()),
+            Allocation::UnreliableSequence(_) => None,
+        };
+        let category = BandwidthCategory::of_packet(packet.as_ref());
+        let size = match redundant_stream {
+            Some(redundant_stream) => {
+                let copy = packet.clone();
+                let (size, copy_size) = future::join(
+                    self.send_allocated(allocation, packet),
+                    redundant_stream.send_packet(copy),
+                )
+                .await;
+                let size = self.recover_send_error(class, stream, size).await?;
+                let copy_size = copy_size.unwrap_or_else(|e| {
+                    tracing::debug!("Failed to send redundant copy: {e:#}");
+                    0
+                });
+                size + copy_size
+            }
+            None => {
+                let size = self.send_allocated(allocation, packet).await;
+                self.recover_send_error(class, stream, size).await?
+            }
+        };
+        if let Some(bandwidth) = &self.bandwidth {
+            bandwidth.record_sent(category, size);
+        }
+        if let Some(quota) = &self.quota {
+            quota.consume(size).await?;
+        }
+        Ok(class)
+    }
+
+    /// Turns a failed send into a `DroppedPacket` if the connection
+    /// is still open and the packet's allocation class is recoverable,
+    /// dropping the stream it failed on so that it is reopened.
+    async fn recover_send_error(
+        &self,
+        class: AllocationClass,
+        stream: Option<StreamId>,
+        result: anyhow::Result<usize>,
+    ) -> anyhow::Result<usize> {
+        let Err(e) = result else {
+            return result;
+        };
+        if self.connection.close_reason().is_some() || !class.is_recoverable() {
+            return Err(e);
+        }
+        self.stream_allocator
+            .lock()
+            .await
+            .recover_dropped_packet(class, stream);
+        Err(DroppedPacket { class, source: e }.into())
+    }
 
+    /// Returns the number of bytes written.
+    async fn send_allocated(
+        &self,
+        allocation: Allocation<Side>,
+        packet: Side::SendPacket<Play>,
+    ) -> anyhow::Result<usize> {
         match allocation {
-            Allocation::Stream(stream) => stream.send_packet(packet).await,
+            Allocation::Stream(stream, _) => stream.send_packet(packet).await,
             Allocation::UnreliableSequence(key) => self.sequences.send_packet(key, packet).await,
         }
     }
+}
 
-    async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<Play>> {
-        select! {
-            packet = self.sequences.recv_packet() => packet,
-            packet = self.receiver.recv_packet() => packet,
+/// What to do with a packet seen by a `Proxy::run_intercepting` callback.
+#[derive(Debug)]
+pub enum Interception<R> {
+    /// Forward the packet and keep proxying.
+    Continue,
+    /// Forward the packet, then stop proxying.
+    Break(R),
+    /// Drop the packet instead of forwarding it, then stop proxying.
+    Withhold(R),
+}
+
+impl<R> From<ControlFlow<R>> for Interception<R> {
+    fn from(control_flow: ControlFlow<R>) -> Self {
+        match control_flow {
+            ControlFlow::Continue(()) => Interception::Continue,
+            ControlFlow::Break(result) => Interception::Break(result),
         }
     }
 }
 
+/// A packet that originated from neither peer, sent by the proxy itself.
+enum Injection<State: ProtocolState> {
+    ToClient(<side::Server as packet::Side>::SendPacket<State>),
+    ToServer(<side::Client as packet::Side>::SendPacket<State>),
+}
+
+/// Handle for injecting packets into a running `Proxy`, e.g. from
+/// interception callbacks or from tasks running alongside the proxy.
+///
+/// Injected packets are sent like forwarded ones, on the allocation
+/// their type calls for, after any packet forwarded before they were
+/// injected. Those injected before `run` returns have been sent once it does.
+pub struct Injector<State: ProtocolState> {
+    sender: flume::Sender<Injection<State>>,
+}
+
+impl<State: ProtocolState> Clone for Injector<State> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<State: ProtocolState> Injector<State> {
+    pub fn inject_to_client(&self, packet: <side::Server as packet::Side>::SendPacket<State>) {
+        // The proxy owns the receiver, so sending cannot fail while it exists.
+        self.sender.send(Injection::ToClient(packet)).ok();
+    }
+
+    pub fn inject_to_server(&self, packet: <side::Client as packet::Side>::SendPacket<State>) {
+        self.sender.send(Injection::ToServer(packet)).ok();
+    }
+}
+
+/// Bytes charged to the memory budget for each forwarded packet on top
+/// of its size on the wire, for the task sending it and the decoded packet.
+const PENDING_PACKET_OVERHEAD: usize = 512;
+
+/// Sends a forwarded packet, recording its latency and delivery.
+/// The packet's charge to the memory budget is released once sent.
+async fn forward<Side, State, Io>(
+    io: Arc<Io>,
+    packet: Side::SendPacket<State>,
+    direction: Direction,
+    received_at: Instant,
+    latency_budgets: Option<Arc<LatencyBudgets>>,
+    deliveries: Option<Arc<Deliveries>>,
+    _charge: Option<MemoryCharge>,
+) -> anyhow::Result<()>
+where
+    Side: packet::Side,
+    State: ProtocolState,
+    Io: PacketIo<Side, State>,
+{
+    match latency_budgets {
+        Some(latency_budgets) => {
+            let packet_name = packet.as_ref().to_owned();
+            let class = io.send_packet_allocated(packet).await?;
+            latency_budgets.record(direction, &packet_name, class, received_at.elapsed());
+        }
+        None => io.send_packet(packet).await?,
+    }
+    if let Some(deliveries) = deliveries {
+        deliveries.record(direction);
+    }
+    Ok(())
+}
+
+/// Waits for `watchdog`'s next stall. Cancel safe.
+async fn next_stall(watchdog: Option<&mut StallWatchdog>) -> Stalled {
+    match watchdog {
+        Some(watchdog) => watchdog.next_stall().await,
+        None => future::pending().await,
+    }
+}
+
+async fn client_unpaused(limit: Option<&PacketRateLimit>, paused_until: Option<Instant>) {
+    match (limit, paused_until) {
+        (Some(limit), Some(paused_until)) => limit.sleep_until(paused_until).await,
+        _ => future::pending().await,
+    }
+}
+
 /// Utility to proxy packets between two `PacketIo` instances.
-pub struct Proxy<Client, Server, State> {
+pub struct Proxy<Client, Server, State: ProtocolState> {
     pending_tasks: JoinSet<anyhow::Result<()>>,
+    dropped_packets: DroppedPacketBudget,
     client: Arc<Client>,
     server: Arc<Server>,
+    packet_flow: Option<Arc<PacketFlow>>,
+    latency_budgets: Option<Arc<LatencyBudgets>>,
+    watchdog: Option<StallWatchdog>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    packet_rate_limit: Option<PacketRateLimit>,
+    /// When packets may be received from the client again,
+    /// while the packet rate limit throttles it.
+    client_paused_until: Option<Instant>,
+    injector: Injector<State>,
+    injections: flume::Receiver<Injection<State>>,
     _marker: PhantomData<State>,
 }
 
@@ -332,14 +1063,59 @@ where
     State: ProtocolState,
 {
     pub fn new(client: Client, server: Server) -> Self {
+        let (sender, injections) = flume::unbounded();
         Self {
             pending_tasks: JoinSet::new(),
+            dropped_packets: DroppedPacketBudget::new(),
             client: Arc::new(client),
             server: Arc::new(server),
+            packet_flow: None,
+            latency_budgets: None,
+            watchdog: None,
+            memory_budget: None,
+            packet_rate_limit: None,
+            client_paused_until: None,
+            injector: Injector { sender },
+            injections,
             _marker: PhantomData,
         }
     }
 
+    /// Records forwarded packets into the given packet flow.
+    pub fn with_packet_flow(mut self, packet_flow: Arc<PacketFlow>) -> Self {
+        self.packet_flow = Some(packet_flow);
+        self
+    }
+
+    /// Records the latency of forwarded packets into the given histograms.
+    pub fn with_latency_budgets(mut self, latency_budgets: Arc<LatencyBudgets>) -> Self {
+        self.latency_budgets = Some(latency_budgets);
+        self
+    }
+
+    /// Fails the proxy with `Stalled` if one direction delivers
+    /// no packets for `timeout` while the other keeps delivering,
+    /// after one attempt at recovering (see the `watchdog` module).
+    pub fn with_stall_watchdog(mut self, clock: SharedClock, timeout: Duration) -> Self {
+        self.watchdog = Some(StallWatchdog::new(clock, timeout));
+        self
+    }
+
+    /// Fails the proxy with `MemoryBudgetExceeded` once the packets
+    /// received but not yet forwarded take up more than `budget` allows.
+    /// See the `memory_budget` module.
+    pub fn with_memory_budget(mut self, budget: Arc<MemoryBudget>) -> Self {
+        self.memory_budget = Some(budget);
+        self
+    }
+
+    /// Limits the rate of packets received from the client.
+    /// See the `packet_rate` module.
+    pub fn with_packet_rate_limit(mut self, limit: PacketRateLimit) -> Self {
+        self.packet_rate_limit = Some(limit);
+        self
+    }
+
     pub fn client_mut(&mut self) -> &mut Client {
         Arc::get_mut(&mut self.client).unwrap()
     }
@@ -348,6 +1124,55 @@ where
         Arc::get_mut(&mut self.server).unwrap()
     }
 
+    /// Gets a handle for injecting packets while the proxy runs.
+    pub fn injector(&self) -> Injector<State> {
+        self.injector.clone()
+    }
+
+    /// Sends a packet to the client that the server did not send.
+    /// For use while the proxy is not running; see `injector` otherwise.
+    pub async fn inject_to_client(
+        &self,
+        packet: <side::Server as packet::Side>::SendPacket<State>,
+    ) -> anyhow::Result<()> {
+        packet_log::log_packet("(injected) => client", &packet);
+        self.client.send_packet(packet).await
+    }
+
+    /// Sends a packet to the server that the client did not send.
+    /// For use while the proxy is not running; see `injector` otherwise.
+    pub async fn inject_to_server(
+        &self,
+        packet: <side::Client as packet::Side>::SendPacket<State>,
+    ) -> anyhow::Result<()> {
+        packet_log::log_packet("(injected) => server", &packet);
+        self.server.send_packet(packet).await
+    }
+
+    /// Sends an injected packet in the background, like a forwarded one.
+    fn spawn_injection(&mut self, injection: Injection<State>) {
+        match injection {
+            Injection::ToClient(packet) => {
+                packet_log::log_packet("(injected) => client", &packet);
+                if let Some(packet_flow) = &self.packet_flow {
+                    packet_flow.record(Direction::Clientbound, &packet);
+                }
+                let client = Arc::clone(&self.client);
+                self.pending_tasks
+                    .spawn(async move { client.send_packet(packet).await });
+            }
+            Injection::ToServer(packet) => {
+                packet_log::log_packet("(injected) => server", &packet);
+                if let Some(packet_flow) = &self.packet_flow {
+                    packet_flow.record(Direction::Serverbound, &packet);
+                }
+                let server = Arc::clone(&self.server);
+                self.pending_tasks
+                    .spawn(async move { server.send_packet(packet).await });
+            }
+        }
+    }
+
     /// Proxies packets between the two endpoints.
     ///
     /// Returns once either
@@ -361,50 +1186,180 @@ where
         mut intercept_server_packet: impl FnMut(
             &mut <side::Server as packet::Side>::SendPacket<State>,
         ) -> ControlFlow<R>,
+    ) -> anyhow::Result<R> {
+        self.run_intercepting(
+            |packet| intercept_client_packet(packet).into(),
+            |packet| intercept_server_packet(packet).into(),
+        )
+        .await
+    }
+
+    /// Like `run`, but the callbacks may also withhold
+    /// a packet from being forwarded.
+    ///
+    /// All packets forwarded before the callback returned
+    /// have been sent once this returns.
+    pub async fn run_intercepting<R>(
+        &mut self,
+        mut intercept_client_packet: impl FnMut(
+            &mut <side::Client as packet::Side>::SendPacket<State>,
+        ) -> Interception<R>,
+        mut intercept_server_packet: impl FnMut(
+            &mut <side::Server as packet::Side>::SendPacket<State>,
+        ) -> Interception<R>,
     ) -> anyhow::Result<R> {
         let result = loop {
             select! {
-                client_packet = self.client.recv_packet() => {
-                    let mut client_packet= client_packet?;
-                    let control_flow = intercept_client_packet(&mut client_packet);
+                client_packet = self.client.recv_packet_sized(), if self.client_paused_until.is_none() => {
+                    let received_at = Instant::now();
+                    let (mut client_packet, size) = client_packet?;
+                    let charge = self.charge(size)?;
+                    if let Some(limit) = &mut self.packet_rate_limit {
+                        self.client_paused_until = limit.record()?;
+                    }
+                    let interception = intercept_client_packet(&mut client_packet);
+
+                    if let Interception::Withhold(result) = interception {
+                        packet_log::log_packet("client => (withheld)", &client_packet);
+                        break Ok(result);
+                    }
 
-                    tracing::trace!("client => server: {}", client_packet.as_ref());
+                    packet_log::log_packet("client => server", &client_packet);
+                    if let Some(packet_flow) = &self.packet_flow {
+                        packet_flow.record(Direction::Serverbound, &client_packet);
+                    }
                     let server = Arc::clone(&self.server);
-                    self.pending_tasks.spawn_local(async move {
-                        server.send_packet(client_packet).await
-                    });
+                    self.pending_tasks.spawn(forward::<side::Client, State, _>(
+                        server,
+                        client_packet,
+                        Direction::Serverbound,
+                        received_at,
+                        self.latency_budgets.clone(),
+                        self.watchdog.as_ref().map(StallWatchdog::deliveries),
+                        charge,
+                    ));
 
-                    if let ControlFlow::Break(result) = control_flow{
+                    if let Interception::Break(result) = interception {
                         break Ok(result);
                     }
                 }
-                server_packet = self.server.recv_packet() => {
-                    let mut server_packet = server_packet?;
-                    let control_flow = intercept_server_packet(&mut server_packet);
+                server_packet = self.server.recv_packet_sized() => {
+                    let received_at = Instant::now();
+                    let (mut server_packet, size) = server_packet?;
+                    let charge = self.charge(size)?;
+                    let interception = intercept_server_packet(&mut server_packet);
+
+                    if let Interception::Withhold(result) = interception {
+                        packet_log::log_packet("server => (withheld)", &server_packet);
+                        break Ok(result);
+                    }
 
-                    tracing::trace!("server => client: {}", server_packet.as_ref());
+                    packet_log::log_packet("server => client", &server_packet);
+                    if let Some(packet_flow) = &self.packet_flow {
+                        packet_flow.record(Direction::Clientbound, &server_packet);
+                    }
                     let client = Arc::clone(&self.client);
-                    self.pending_tasks.spawn_local(async move {
-                       client.send_packet(server_packet).await
-                    });
+                    self.pending_tasks.spawn(forward::<side::Server, State, _>(
+                        client,
+                        server_packet,
+                        Direction::Clientbound,
+                        received_at,
+                        self.latency_budgets.clone(),
+                        self.watchdog.as_ref().map(StallWatchdog::deliveries),
+                        charge,
+                    ));
 
-                    if let ControlFlow::Break(result) = control_flow {
-                        break Ok(result );
+                    if let Interception::Break(result) = interception {
+                        break Ok(result);
+                    }
+                }
+                injection = self.injections.recv_async() => {
+                    // The proxy holds a sender itself, so the channel never closes.
+                    if let Ok(injection) = injection {
+                        self.spawn_injection(injection);
                     }
                 }
                 opt_result = self.pending_tasks.join_next(), if !self.pending_tasks.is_empty() => {
-                    opt_result.expect("no task?")??;
+                    if let Err(e) = opt_result.expect("no task?")? {
+                        self.dropped_packets.absorb(e)?;
+                    }
+                }
+                stall = next_stall(self.watchdog.as_mut()), if self.watchdog.is_some() => {
+                    self.handle_stall(stall).await?;
+                }
+                () = client_unpaused(self.packet_rate_limit.as_ref(), self.client_paused_until), if self.client_paused_until.is_some() => {
+                    self.client_paused_until = None;
                 }
             }
         };
 
+        // Packets injected by the callback that ended the loop.
+        while let Ok(injection) = self.injections.try_recv() {
+            self.spawn_injection(injection);
+        }
         while let Some(result) = self.pending_tasks.join_next().await {
-            result??;
+            if let Err(e) = result? {
+                self.dropped_packets.absorb(e)?;
+            }
         }
 
         result
     }
 
+    /// Charges a received packet of `size` bytes to the memory budget, if any.
+    fn charge(&self, size: usize) -> anyhow::Result<Option<MemoryCharge>> {
+        Ok(self
+            .memory_budget
+            .as_ref()
+            .map(|budget| budget.charge(size + PENDING_PACKET_OVERHEAD))
+            .transpose()?)
+    }
+
+    /// Logs the send streams of both sides, then attempts to recover
+    /// the stalled direction once, failing if that was already done.
+    async fn handle_stall(&mut self, stall: Stalled) -> anyhow::Result<()> {
+        let describe = |streams: Vec<StreamDiagnostics>| {
+            streams
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("; ")
+        };
+        tracing::warn!(
+            "Stall detected: {stall}. Streams to the client: [{}]. Streams to the server: [{}]",
+            describe(self.client.stream_diagnostics().await),
+            describe(self.server.stream_diagnostics().await),
+        );
+
+        let watchdog = self.watchdog.as_mut().expect("stall without watchdog");
+        if watchdog.recovery_attempted(&stall) {
+            return Err(stall.into());
+        }
+        let recovered = match stall.direction {
+            Direction::Serverbound => self.server.recover_stalled_send().await?,
+            Direction::Clientbound => self.client.recover_stalled_send().await?,
+        };
+        if !recovered {
+            return Err(stall.into());
+        }
+        tracing::info!(
+            "Attempted to recover from {} stall",
+            stall.direction.as_ref()
+        );
+        watchdog.note_recovery(&stall);
+        Ok(())
+    }
+
+    /// Waits for the packets forwarded by a `run` that failed to be sent,
+    /// ignoring errors sending them.
+    pub async fn finish_pending(&mut self) {
+        while let Some(result) = self.pending_tasks.join_next().await {
+            if let Ok(Err(e)) = result {
+                tracing::debug!("Failed to forward packet: {e:#}");
+            }
+        }
+    }
+
     pub fn into_parts(self) -> (Client, Server) {
         (
             Arc::into_inner(self.client).unwrap(),
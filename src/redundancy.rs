@@ -0,0 +1,77 @@
+//! Optional redundant transmission of critical packets.
+//!
+//! On very lossy links, a lost QUIC packet delays everything on its stream
+//! by at least a retransmission timeout. For a few packets, that delay is
+//! what players notice: a late keepalive response gets them kicked, and
+//! a late teleport confirmation causes rubber-banding.
+//!
+//! When both ends agree (see `ClientOptions::redundancy`), each critical
+//! packet is sent on its normal stream and again on a new stream of its own.
+//! The receiver forwards whichever copy arrives first and drops the other.
+//! Critical packets carry an ID (the keepalive or teleport ID), so copies
+//! are recognized by their encoding without any extra framing.
+
+use crate::protocol::{
+    packet::{client, server},
+    Encode, Encoder,
+};
+use std::collections::VecDeque;
+
+/// Maximum number of critical packets remembered while waiting
+/// for their second copy.
+const MAX_PENDING_COPIES: usize = 64;
+
+/// A Play packet type, some of whose packets are sent redundantly.
+pub trait CriticalPacket: Encode + Clone {
+    fn is_critical(&self) -> bool;
+}
+
+impl CriticalPacket for client::play::Packet {
+    fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            client::play::Packet::KeepAlive(_) | client::play::Packet::ConfirmTeleportation(_)
+        )
+    }
+}
+
+impl CriticalPacket for server::play::Packet {
+    fn is_critical(&self) -> bool {
+        matches!(
+            self,
+            server::play::Packet::KeepAlive(_) | server::play::Packet::SynchronizePlayerPosition(_)
+        )
+    }
+}
+
+/// Drops the second copy of each redundantly sent packet.
+#[derive(Debug, Default)]
+pub struct DuplicateFilter {
+    /// Encodings of critical packets of which one copy has arrived.
+    pending_copies: VecDeque<Vec<u8>>,
+}
+
+impl DuplicateFilter {
+    /// Returns whether the packet is the second copy of
+    /// an already received packet, and should be dropped.
+    pub fn is_duplicate(&mut self, packet: &impl CriticalPacket) -> bool {
+        if !packet.is_critical() {
+            return false;
+        }
+        let mut encoded = Vec::new();
+        packet.encode(&mut Encoder::new(&mut encoded));
+        if let Some(index) = self
+            .pending_copies
+            .iter()
+            .position(|pending| *pending == encoded)
+        {
+            self.pending_copies.remove(index);
+            return true;
+        }
+        if self.pending_copies.len() == MAX_PENDING_COPIES {
+            self.pending_copies.pop_front();
+        }
+        self.pending_copies.push_back(encoded);
+        false
+    }
+}
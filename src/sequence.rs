@@ -1,25 +1,28 @@
 use crate::{
+    anomaly::{Anomaly, AnomalyCollector},
+    clock::SharedClock,
     entity_id::EntityId,
+    idle_cache::IdleCache,
     protocol::{packet, packet::state, Decode, Decoder, Encode, Encoder},
 };
 use anyhow::Context;
 use bincode::Options;
-use mini_moka::unsync::Cache;
 use quinn::Connection;
 use serde::{Deserialize, Serialize};
 use std::{
-    cell::{Cell, RefCell},
     marker::PhantomData,
-    rc::Rc,
-    thread,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::Duration,
 };
-use tokio::{sync::oneshot, task::LocalSet};
+use tokio::{sync::oneshot, task};
 
 type SendPacket<Side> = (
     SequenceKey,
     <Side as packet::Side>::SendPacket<state::Play>,
-    oneshot::Sender<anyhow::Result<()>>,
+    oneshot::Sender<anyhow::Result<usize>>,
 );
 
 /// Manages sending and receiving sequenced datagrams.
@@ -37,7 +40,8 @@ type SendPacket<Side> = (
 #[derive(Clone)]
 pub struct SequencesHandle<Side: packet::Side> {
     sender: flume::Sender<SendPacket<Side>>,
-    receiver: flume::Receiver<anyhow::Result<Side::RecvPacket<state::Play>>>,
+    receiver: flume::Receiver<anyhow::Result<(Side::RecvPacket<state::Play>, usize)>>,
+    dropped_datagrams: Arc<AtomicU64>,
 }
 
 /// Idle duration after which the state for a certain sequence
@@ -48,78 +52,96 @@ impl<Side> SequencesHandle<Side>
 where
     Side: packet::Side,
 {
-    pub fn new(connection: Connection) -> Self {
+    /// Creates the sequences of a connection, counting the datagrams
+    /// they drop into `dropped_datagrams`.
+    pub fn new(
+        connection: Connection,
+        dropped_datagrams: Arc<AtomicU64>,
+        anomalies: Arc<AnomalyCollector>,
+        clock: SharedClock,
+    ) -> Self {
         let (packets_inbound_tx, packets_inbound_rx) = flume::bounded(16);
         let (packets_outbound_tx, packets_outbound_rx) = flume::bounded::<SendPacket<Side>>(16);
 
-        let runtime = tokio::runtime::Handle::current();
-        thread::spawn(move || {
-            let local_set = LocalSet::new();
-            let sequences = Rc::new(Sequences::<Side>::new(connection));
-
-            local_set.spawn_local({
-                let sequences = Rc::clone(&sequences);
-                async move {
-                    loop {
-                        match sequences.recv_packet().await {
-                            Ok(packet) => {
-                                if packets_inbound_tx.send_async(Ok(packet)).await.is_err() {
-                                    break;
-                                }
-                            }
-                            Err(e) => {
-                                packets_inbound_tx.send_async(Err(e)).await.ok();
+        let sequences = Arc::new(Sequences::<Side>::new(
+            connection,
+            Arc::clone(&dropped_datagrams),
+            anomalies,
+            clock,
+        ));
+        task::spawn({
+            let sequences = Arc::clone(&sequences);
+            async move {
+                loop {
+                    match sequences.recv_packet().await {
+                        Ok(packet) => {
+                            if packets_inbound_tx.send_async(Ok(packet)).await.is_err() {
                                 break;
                             }
                         }
+                        Err(e) => {
+                            packets_inbound_tx.send_async(Err(e)).await.ok();
+                            break;
+                        }
                     }
                 }
-            });
-            local_set.spawn_local(async move {
-                while let Ok((sequence_key, packet, completion)) =
-                    packets_outbound_rx.recv_async().await
-                {
-                    let result = sequences.send_packet(sequence_key, packet).await;
-                    let is_error = result.is_err();
-                    completion.send(result).ok();
-                    if is_error {
-                        break;
-                    }
+            }
+        });
+        task::spawn(async move {
+            while let Ok((sequence_key, packet, completion)) =
+                packets_outbound_rx.recv_async().await
+            {
+                let result = sequences.send_packet(sequence_key, packet).await;
+                let is_error = result.is_err();
+                completion.send(result).ok();
+                if is_error {
+                    break;
                 }
-            });
-
-            runtime.block_on(local_set);
+            }
         });
 
         Self {
             sender: packets_outbound_tx,
             receiver: packets_inbound_rx,
+            dropped_datagrams,
         }
     }
 
+    /// Gets the number of received datagrams that were dropped because a
+    /// newer one in the same sequence had already arrived, including those
+    /// counted by earlier sequences into the same counter.
+    pub fn dropped_datagrams(&self) -> u64 {
+        self.dropped_datagrams.load(Ordering::Relaxed)
+    }
+
+    /// Sends a packet on the given sequence, returning
+    /// the size of its datagram.
     pub async fn send_packet(
         &self,
         sequence_key: SequenceKey,
         packet: Side::SendPacket<state::Play>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<usize> {
         let (completion_tx, completion_rx) = oneshot::channel();
         self.sender
             .send_async((sequence_key, packet, completion_tx))
             .await
             .ok()
             .context("disconnected")?;
-        completion_rx.await.context("disconnected")??;
-        Ok(())
+        completion_rx.await.context("disconnected")?
     }
 
-    pub async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<state::Play>> {
+    /// Waits for the next packet, returning it
+    /// along with the size of its datagram.
+    pub async fn recv_packet(&self) -> anyhow::Result<(Side::RecvPacket<state::Play>, usize)> {
         self.receiver.recv_async().await.context("disconnected")?
     }
 }
 
 struct Sequences<Side> {
     connection: Connection,
-    sequences: RefCell<Cache<SequenceKey, Rc<Sequence>>>,
+    sequences: IdleCache<SequenceKey, Arc<Sequence>>,
+    dropped_datagrams: Arc<AtomicU64>,
+    anomalies: Arc<AnomalyCollector>,
     _marker: PhantomData<Side>,
 }
 
@@ -127,14 +149,17 @@ impl<Side> Sequences<Side>
 where
     Side: packet::Side,
 {
-    pub fn new(connection: Connection) -> Self {
+    pub fn new(
+        connection: Connection,
+        dropped_datagrams: Arc<AtomicU64>,
+        anomalies: Arc<AnomalyCollector>,
+        clock: SharedClock,
+    ) -> Self {
         Self {
             connection,
-            sequences: RefCell::new(
-                Cache::builder()
-                    .time_to_idle(SEQUENCE_IDLE_DURATION)
-                    .build(),
-            ),
+            dropped_datagrams,
+            anomalies,
+            sequences: IdleCache::new(clock, SEQUENCE_IDLE_DURATION),
             _marker: PhantomData,
         }
     }
@@ -144,7 +169,7 @@ where
         &self,
         sequence_key: SequenceKey,
         packet: Side::SendPacket<state::Play>,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<usize> {
         let sequence = self.get_sequence(sequence_key);
         let ordinal = sequence.next_send_ordinal();
         let bytes = self.encode_packet(
@@ -154,31 +179,43 @@ where
                 key: sequence_key,
             },
         )?;
+        let size = bytes.len();
         self.connection.send_datagram(bytes.into())?;
-        Ok(())
+        Ok(size)
     }
 
     /// Waits for the next datagram.
-    /// Ignores any out-of-date packets, as per the sequence logic.
-    pub async fn recv_packet(&self) -> anyhow::Result<Side::RecvPacket<state::Play>> {
+    /// Ignores any out-of-date packets, as per the sequence logic,
+    /// and any datagrams that fail to decode.
+    pub async fn recv_packet(&self) -> anyhow::Result<(Side::RecvPacket<state::Play>, usize)> {
         loop {
             let datagram = self.connection.read_datagram().await?;
-            let (header, packet) = self.decode_packet(&datagram)?;
+            let (header, packet) = match self.decode_packet(&datagram) {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    // Datagrams are unreliable anyway, so dropping
+                    // a malformed one is no worse than losing it.
+                    tracing::trace!("Failed to decode datagram: {e}");
+                    self.anomalies.record(Anomaly::of_decode_error(&e));
+                    continue;
+                }
+            };
             let sequence = self.get_sequence(header.key);
             if sequence.receive_packet(header.ordinal) {
-                return Ok(packet);
+                return Ok((packet, datagram.len()));
             }
+            self.dropped_datagrams.fetch_add(1, Ordering::Relaxed);
         }
     }
 
-    fn get_sequence(&self, key: SequenceKey) -> Rc<Sequence> {
-        let mut sequences = self.sequences.borrow_mut();
-        if let Some(sequence) = sequences.get(&key) {
-            return Rc::clone(sequence);
+    fn get_sequence(&self, key: SequenceKey) -> Arc<Sequence> {
+        if let Some(sequence) = self.sequences.get(&key) {
+            return sequence;
         }
 
-        sequences.insert(key, Rc::new(Sequence::new()));
-        Rc::clone(sequences.get(&key).unwrap())
+        let sequence = Arc::new(Sequence::new());
+        self.sequences.insert(key, Arc::clone(&sequence));
+        sequence
     }
 
     /// Encodes a packet to its datagram representation,
@@ -188,11 +225,7 @@ where
         packet: &impl Encode,
         header: DatagramHeader,
     ) -> anyhow::Result<Vec<u8>> {
-        let mut buf = bincode::options()
-            .allow_trailing_bytes()
-            .serialize(&header)?;
-        packet.encode(&mut Encoder::new(&mut buf));
-        Ok(buf)
+        encode_datagram(packet, header.key, header.ordinal)
     }
 
     /// Decodes a packet from its datagram representation, using the given
@@ -210,6 +243,20 @@ where
     }
 }
 
+/// Encodes a packet to its datagram representation,
+/// using the given sequence key and ordinal.
+pub(crate) fn encode_datagram(
+    packet: &impl Encode,
+    key: SequenceKey,
+    ordinal: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut buf = bincode::options()
+        .allow_trailing_bytes()
+        .serialize(&DatagramHeader { key, ordinal })?;
+    packet.encode(&mut Encoder::new(&mut buf));
+    Ok(buf)
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct DatagramHeader {
     key: SequenceKey,
@@ -217,34 +264,31 @@ struct DatagramHeader {
 }
 
 struct Sequence {
-    send_counter: Cell<u64>,
-    newest_received: Cell<u64>,
+    send_counter: AtomicU64,
+    newest_received: AtomicU64,
 }
 
 impl Sequence {
     pub fn new() -> Self {
         Self {
-            send_counter: Cell::new(0),
-            newest_received: Cell::new(0),
+            send_counter: AtomicU64::new(0),
+            newest_received: AtomicU64::new(0),
         }
     }
 
     pub fn next_send_ordinal(&self) -> u64 {
-        let ordinal = self.send_counter.get();
-        self.send_counter.set(ordinal.wrapping_add(1));
-        ordinal
+        // Wraps around on overflow.
+        self.send_counter.fetch_add(1, Ordering::Relaxed)
     }
 
     /// Called when a datagram is received.
     /// Returns whether the packet should be kept (`true`) or dropped (`false`).
     pub fn receive_packet(&self, packet_ordinal: u64) -> bool {
         // use `>=` to handle the initial case where ordinal == 0
-        if packet_ordinal >= self.newest_received.get() {
-            self.newest_received.set(packet_ordinal);
-            true
-        } else {
-            false
-        }
+        packet_ordinal
+            >= self
+                .newest_received
+                .fetch_max(packet_ordinal, Ordering::Relaxed)
     }
 }
 
@@ -0,0 +1,191 @@
+//! Statistics about proxied connections.
+
+use crate::timeline::Timeline;
+pub use crate::{
+    anomaly::{Anomaly, AnomalySummary},
+    bandwidth::{BandwidthCategory, BandwidthTotals, BandwidthUsage, CategoryRates},
+    stream_allocation::{AllocationClass, AllocationSummary},
+    timeline::StateHistory,
+};
+use quinn::{
+    congestion::{Bbr, Cubic, NewReno},
+    crypto::rustls::HandshakeData,
+    Connection,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{self, Display},
+    time::Duration,
+};
+
+/// QUIC version 1 (RFC 9000). Neither the client nor the gateway
+/// configures another version, so it is the only one that can be negotiated.
+const QUIC_VERSION_1: u32 = 1;
+
+/// Snapshot of the QUIC transport statistics of a connection.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TransportStats {
+    /// Current smoothed round-trip time estimate, in milliseconds.
+    pub rtt_millis: f64,
+    /// Current congestion window, in bytes.
+    pub congestion_window: u64,
+    pub congestion_events: u64,
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub lost_bytes: u64,
+    /// UDP bytes sent, including QUIC overhead.
+    pub sent_bytes: u64,
+    /// UDP bytes received, including QUIC overhead.
+    pub received_bytes: u64,
+    /// Unreliable datagram frames sent.
+    pub sent_datagrams: u64,
+    /// Unreliable datagram frames received.
+    pub received_datagrams: u64,
+}
+
+impl TransportStats {
+    pub fn from_connection(connection: &Connection) -> Self {
+        let stats = connection.stats();
+        Self {
+            rtt_millis: stats.path.rtt.as_secs_f64() * 1000.0,
+            congestion_window: stats.path.cwnd,
+            congestion_events: stats.path.congestion_events,
+            sent_packets: stats.path.sent_packets,
+            lost_packets: stats.path.lost_packets,
+            lost_bytes: stats.path.lost_bytes,
+            sent_bytes: stats.udp_tx.bytes,
+            received_bytes: stats.udp_rx.bytes,
+            sent_datagrams: stats.frame_tx.datagram,
+            received_datagrams: stats.frame_rx.datagram,
+        }
+    }
+}
+
+/// Snapshot of the statistics of a client's connection to the
+/// gateway, e.g. for a network quality overlay.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    /// Statistics of the current QUIC connection. Restart
+    /// from zero when a session is resumed on a new connection.
+    pub transport: TransportStats,
+    /// Datagrams received from the gateway that were dropped because a
+    /// newer one in the same sequence had already arrived.
+    pub dropped_datagrams: u64,
+    /// Play packets received from and sent to the gateway, with their
+    /// bytes as sent over QUIC (i.e. after compression).
+    pub play: BandwidthTotals,
+}
+
+/// Report on a session that has ended, for embedders
+/// implementing their own logging or retry logic.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionReport {
+    pub duration_millis: u64,
+    /// Final transport statistics, including the bytes transferred.
+    pub transport: TransportStats,
+    /// The protocol states the session switched through.
+    pub states: StateHistory,
+    /// The error that ended the session, followed by its causes.
+    /// Empty if the session ended cleanly.
+    pub error_chain: Vec<String>,
+}
+
+impl SessionReport {
+    pub(crate) fn new(
+        duration: Duration,
+        connection: &Connection,
+        timeline: &Timeline,
+        result: &anyhow::Result<()>,
+    ) -> Self {
+        Self {
+            duration_millis: duration.as_millis() as u64,
+            transport: TransportStats::from_connection(connection),
+            states: timeline.state_history(),
+            error_chain: match result {
+                Ok(()) => Vec::new(),
+                Err(e) => e.chain().map(ToString::to_string).collect(),
+            },
+        }
+    }
+
+    /// Whether the session ended without an error.
+    pub fn is_clean(&self) -> bool {
+        self.error_chain.is_empty()
+    }
+}
+
+/// Parameters of a connection that were negotiated or chosen during setup,
+/// for confirming which mode a connection actually runs in.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NegotiatedParameters {
+    pub quic_version: u32,
+    /// Application protocol negotiated with ALPN, if any.
+    pub alpn: Option<String>,
+    /// Congestion controller of the local endpoint.
+    pub congestion_controller: String,
+    /// Maximum size of an unreliable datagram, or `None`
+    /// if the peer does not support datagrams.
+    pub max_datagram_size: Option<usize>,
+    /// Whether entity movement is sent as unreliable datagrams.
+    pub datagrams: bool,
+    /// Whether the connection was established with 0-RTT data.
+    /// The client does not attempt 0-RTT yet, so this is always false.
+    pub zero_rtt: bool,
+    /// Version of the optimized codec agreed on the control stream.
+    pub codec_version: u8,
+}
+
+impl NegotiatedParameters {
+    pub fn from_connection(connection: &Connection, codec_version: u8) -> Self {
+        let alpn = connection
+            .handshake_data()
+            .and_then(|data| data.downcast::<HandshakeData>().ok())
+            .and_then(|data| data.protocol)
+            .map(|protocol| String::from_utf8_lossy(&protocol).into_owned());
+        let max_datagram_size = connection.max_datagram_size();
+        Self {
+            quic_version: QUIC_VERSION_1,
+            alpn,
+            congestion_controller: congestion_controller_name(connection).to_owned(),
+            max_datagram_size,
+            datagrams: max_datagram_size.is_some(),
+            zero_rtt: false,
+            codec_version,
+        }
+    }
+}
+
+impl Display for NegotiatedParameters {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "QUIC v{}, ALPN {}, congestion controller {}, ",
+            self.quic_version,
+            self.alpn.as_deref().unwrap_or("none"),
+            self.congestion_controller
+        )?;
+        match self.max_datagram_size {
+            Some(size) => write!(f, "datagrams up to {size} bytes, ")?,
+            None => write!(f, "datagrams unavailable, ")?,
+        }
+        write!(
+            f,
+            "0-RTT {}, codec v{}",
+            if self.zero_rtt { "accepted" } else { "unused" },
+            self.codec_version
+        )
+    }
+}
+
+fn congestion_controller_name(connection: &Connection) -> &'static str {
+    let controller = connection.congestion_state().into_any();
+    if controller.is::<Cubic>() {
+        "cubic"
+    } else if controller.is::<NewReno>() {
+        "new_reno"
+    } else if controller.is::<Bbr>() {
+        "bbr"
+    } else {
+        "custom"
+    }
+}
@@ -1,14 +1,34 @@
-use crate::protocol::{optimized_codec::OptimizedCodec, packet, packet::ProtocolState};
+use crate::{
+    memory_budget::{MemoryBudget, MemoryCharge},
+    protocol::{
+        optimized_codec::{CodecVersion, OptimizedCodec},
+        packet,
+        packet::ProtocolState,
+    },
+};
 use anyhow::anyhow;
-use quinn::{Connection, RecvStream, SendStream};
-use std::borrow::Cow;
+use quinn::{Connection, RecvStream, SendStream, StreamId};
+use std::{
+    borrow::Cow,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::{sync::oneshot, task};
 
 type SendPacket<Side, State> = (
     <Side as packet::Side>::SendPacket<State>,
-    oneshot::Sender<anyhow::Result<()>>,
+    oneshot::Sender<anyhow::Result<usize>>,
 );
 
+/// A received packet, the number of bytes it took up in the stream,
+/// and the charge of those bytes if the stream has a memory budget.
+type RecvPacket<Side, State> = anyhow::Result<(
+    <Side as packet::Side>::RecvPacket<State>,
+    usize,
+    Option<MemoryCharge>,
+)>;
+
 /// An open sending QUIC stream.
 ///
 /// This combines a `quinn::SendStream` with the codec
@@ -16,7 +36,49 @@ type SendPacket<Side, State> = (
 /// to a Tokio task.
 #[derive(Clone)]
 pub struct SendStreamHandle<Side: packet::Side, State: ProtocolState> {
+    id: StreamId,
+    name: Cow<'static, str>,
     send_data: flume::Sender<SendPacket<Side, State>>,
+    activity: Arc<SendActivity>,
+}
+
+/// Write activity of a send stream's task, for diagnosing stalls.
+#[derive(Debug, Default)]
+struct SendActivity {
+    /// When the write in progress started, if any.
+    write_started: Mutex<Option<Instant>>,
+    /// When the last write finished.
+    last_write: Mutex<Option<Instant>>,
+}
+
+/// Snapshot of a send stream's activity.
+#[derive(Debug, Clone)]
+pub struct StreamDiagnostics {
+    pub name: String,
+    pub id: u64,
+    /// Packets waiting for the write in progress to finish.
+    pub queued: usize,
+    /// How long the write in progress has been blocked, if any.
+    pub blocked_for: Option<Duration>,
+    /// Time since the last write finished, if any.
+    pub idle_for: Option<Duration>,
+}
+
+impl fmt::Display for StreamDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (QUIC ID = {}): {} queued",
+            self.name, self.id, self.queued
+        )?;
+        if let Some(blocked_for) = self.blocked_for {
+            write!(f, ", write blocked for {blocked_for:.1?}")?;
+        }
+        match self.idle_for {
+            Some(idle_for) => write!(f, ", last write {idle_for:.1?} ago"),
+            None => write!(f, ", never written"),
+        }
+    }
 }
 
 impl<Side, State> SendStreamHandle<Side, State>
@@ -27,36 +89,78 @@ where
     /// Opens a new stream.
     pub async fn open(
         connection: &Connection,
+        codec_version: CodecVersion,
         name: impl Into<Cow<'static, str>>,
         priority: i32,
     ) -> anyhow::Result<Self> {
         let stream = connection.open_uni().await?;
         stream.set_priority(priority)?;
-        Ok(Self::from_stream(stream, name))
+        Ok(Self::from_stream(stream, codec_version, name))
     }
 
-    fn from_stream(mut stream: SendStream, name: impl Into<Cow<'static, str>>) -> Self {
+    fn from_stream(
+        mut stream: SendStream,
+        codec_version: CodecVersion,
+        name: impl Into<Cow<'static, str>>,
+    ) -> Self {
         let name = name.into();
+        let id = stream.id();
         let (sender, receiver) = flume::bounded::<SendPacket<Side, State>>(4);
+        let activity = Arc::new(SendActivity::default());
+        let shared = (name.clone(), Arc::clone(&activity));
         task::spawn(async move {
-            let mut codec = OptimizedCodec::<Side, State>::new();
+            let (name, activity) = shared;
+            let mut codec = OptimizedCodec::<Side, State>::new(codec_version);
             while let Ok((packet, completion)) = receiver.recv_async().await {
                 let data = codec.encode_packet(&packet).expect("encoding failed");
+                *activity.write_started.lock().unwrap() = Some(Instant::now());
                 let result = stream.write_all(&data).await;
+                *activity.write_started.lock().unwrap() = None;
+                *activity.last_write.lock().unwrap() = Some(Instant::now());
                 let errored = result.is_err();
-                completion.send(result.map_err(anyhow::Error::from)).ok();
+                completion
+                    .send(result.map(|()| data.len()).map_err(anyhow::Error::from))
+                    .ok();
                 if errored {
                     break;
                 }
             }
-            let id = stream.id();
             tracing::trace!("Closing send stream {name} (QUIC ID = {id:?})");
         });
-        Self { send_data: sender }
+        Self {
+            id,
+            name,
+            send_data: sender,
+            activity,
+        }
+    }
+
+    /// Gets the QUIC ID of the stream.
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
+    /// Describes the stream's write activity.
+    pub fn diagnostics(&self) -> StreamDiagnostics {
+        let now = Instant::now();
+        let since = |instant: &Mutex<Option<Instant>>| {
+            instant
+                .lock()
+                .unwrap()
+                .map(|instant| now.saturating_duration_since(instant))
+        };
+        StreamDiagnostics {
+            name: self.name.clone().into_owned(),
+            id: self.id.index(),
+            queued: self.send_data.len(),
+            blocked_for: since(&self.activity.write_started),
+            idle_for: since(&self.activity.last_write),
+        }
     }
 
-    /// Sends a packet on this stream.
-    pub async fn send_packet(&self, packet: Side::SendPacket<State>) -> anyhow::Result<()> {
+    /// Sends a packet on this stream, returning the
+    /// number of bytes written.
+    pub async fn send_packet(&self, packet: Side::SendPacket<State>) -> anyhow::Result<usize> {
         let (completion_tx, completion_rx) = oneshot::channel();
         self.send_data
             .send_async((packet, completion_tx))
@@ -73,7 +177,8 @@ where
 /// to a separate task (with backpressure).
 #[derive(Clone)]
 pub struct RecvStreamHandle<Side: packet::Side, State: ProtocolState> {
-    recv_data: flume::Receiver<anyhow::Result<Side::RecvPacket<State>>>,
+    id: StreamId,
+    recv_data: flume::Receiver<RecvPacket<Side, State>>,
 }
 
 impl<Side, State> RecvStreamHandle<Side, State>
@@ -84,31 +189,79 @@ where
     /// Accepts the next stream on the connection.
     pub async fn accept(
         connection: &Connection,
+        codec_version: CodecVersion,
+        name: impl Into<Cow<'static, str>>,
+    ) -> anyhow::Result<Self> {
+        Self::accept_with_budget(connection, codec_version, name, None).await
+    }
+
+    /// Like `accept`, but charges the bytes buffered by
+    /// the stream to `memory_budget`, if set.
+    pub async fn accept_with_budget(
+        connection: &Connection,
+        codec_version: CodecVersion,
         name: impl Into<Cow<'static, str>>,
+        memory_budget: Option<Arc<MemoryBudget>>,
     ) -> anyhow::Result<Self> {
         let stream = connection.accept_uni().await?;
-        Ok(Self::from_stream(stream, name))
+        Ok(Self::from_stream(
+            stream,
+            codec_version,
+            name,
+            memory_budget,
+        ))
     }
 
-    fn from_stream(mut stream: RecvStream, name: impl Into<Cow<'static, str>>) -> Self {
+    fn from_stream(
+        mut stream: RecvStream,
+        codec_version: CodecVersion,
+        name: impl Into<Cow<'static, str>>,
+        memory_budget: Option<Arc<MemoryBudget>>,
+    ) -> Self {
         let name = name.into();
-        let (sender, receiver) = flume::bounded::<anyhow::Result<Side::RecvPacket<State>>>(4);
+        let id = stream.id();
+        let (sender, receiver) = flume::bounded::<RecvPacket<Side, State>>(4);
 
         task::spawn(async move {
-            let mut codec = OptimizedCodec::<Side, State>::new();
-            let id = stream.id();
-            drive_recv_stream(&mut stream, &mut codec, sender).await;
+            let mut codec = OptimizedCodec::<Side, State>::new(codec_version);
+            drive_recv_stream(&mut stream, &mut codec, sender, memory_budget).await;
             tracing::trace!("Lost receive stream {name} (QUIC ID = {id:?})");
         });
 
         Self {
+            id,
             recv_data: receiver,
         }
     }
 
+    /// Gets the QUIC ID of the stream.
+    pub fn id(&self) -> StreamId {
+        self.id
+    }
+
     /// Waits for the next packet to be received on this stream.
     /// Returns `None` if the stream was closed and there are no more packets.
     pub async fn recv_packet(&self) -> anyhow::Result<Option<Side::RecvPacket<State>>> {
+        Ok(self.recv_packet_sized().await?.map(|(packet, _)| packet))
+    }
+
+    /// Like `recv_packet`, but also returns the number of
+    /// bytes the packet took up in the stream.
+    pub async fn recv_packet_sized(
+        &self,
+    ) -> anyhow::Result<Option<(Side::RecvPacket<State>, usize)>> {
+        Ok(self
+            .recv_packet_charged()
+            .await?
+            .map(|(packet, size, _)| (packet, size)))
+    }
+
+    /// Like `recv_packet_sized`, but also returns the charge of the
+    /// packet's bytes to the memory budget, for the caller to keep
+    /// until it has passed the packet on.
+    pub async fn recv_packet_charged(
+        &self,
+    ) -> anyhow::Result<Option<(Side::RecvPacket<State>, usize, Option<MemoryCharge>)>> {
         match self.recv_data.recv_async().await {
             Ok(Ok(packet)) => Ok(Some(packet)),
             Ok(Err(e)) => Err(e),
@@ -120,14 +273,21 @@ where
 async fn drive_recv_stream<Side: packet::Side, State: ProtocolState>(
     stream: &mut RecvStream,
     codec: &mut OptimizedCodec<Side, State>,
-    sender: flume::Sender<anyhow::Result<Side::RecvPacket<State>>>,
+    sender: flume::Sender<RecvPacket<Side, State>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
 ) {
+    // Charges the bytes in the codec's read buffer. Decoded
+    // packets take their share of it along.
+    let mut buffered_charge = memory_budget.map(|budget| budget.empty_charge());
     let mut buffer = [0u8; 256];
     loop {
         loop {
-            match codec.decode_packet() {
-                Ok(Some(packet)) => {
-                    if sender.send_async(Ok(packet)).await.is_err() {
+            match codec.decode_packet_sized() {
+                Ok(Some((packet, size))) => {
+                    let charge = buffered_charge
+                        .as_mut()
+                        .map(|charge| charge.split_off(size));
+                    if sender.send_async(Ok((packet, size, charge))).await.is_err() {
                         return;
                     }
                 }
@@ -144,6 +304,12 @@ async fn drive_recv_stream<Side: packet::Side, State: ProtocolState>(
         match stream.read(&mut buffer).await {
             Ok(Some(bytes_read)) => {
                 codec.give_data(&buffer[..bytes_read]);
+                if let Some(charge) = &mut buffered_charge {
+                    if let Err(e) = charge.resize(codec.buffered()) {
+                        sender.send_async(Err(e.into())).await.ok();
+                        break;
+                    }
+                }
             }
             Ok(None) => break,
             Err(e) => {
@@ -156,6 +322,7 @@ async fn drive_recv_stream<Side: packet::Side, State: ProtocolState>(
 
 pub async fn accept_bi<Side, State>(
     connection: &Connection,
+    codec_version: CodecVersion,
     name: impl Into<Cow<'static, str>>,
 ) -> anyhow::Result<(SendStreamHandle<Side, State>, RecvStreamHandle<Side, State>)>
 where
@@ -165,13 +332,14 @@ where
     let name = name.into();
     let (send, recv) = connection.accept_bi().await?;
     Ok((
-        SendStreamHandle::from_stream(send, name.clone()),
-        RecvStreamHandle::from_stream(recv, name),
+        SendStreamHandle::from_stream(send, codec_version, name.clone()),
+        RecvStreamHandle::from_stream(recv, codec_version, name, None),
     ))
 }
 
 pub async fn open_bi<Side, State>(
     connection: &Connection,
+    codec_version: CodecVersion,
     name: impl Into<Cow<'static, str>>,
 ) -> anyhow::Result<(SendStreamHandle<Side, State>, RecvStreamHandle<Side, State>)>
 where
@@ -181,7 +349,7 @@ where
     let name = name.into();
     let (send, recv) = connection.open_bi().await?;
     Ok((
-        SendStreamHandle::from_stream(send, name.clone()),
-        RecvStreamHandle::from_stream(recv, name),
+        SendStreamHandle::from_stream(send, codec_version, name.clone()),
+        RecvStreamHandle::from_stream(recv, codec_version, name, None),
     ))
 }
@@ -12,13 +12,17 @@
 //! all opened streams are unidirectional.
 //!
 //! - During the Handshake, Status, Login, and Configuration stages of the connection, all
-//!  packets are sent on the same stream.
+//!   packets are sent on the same stream.
 //! - During the Play state:
 //!   - All entity movement packets (including players) are sent as unreliable datagrams and tagged
-//!      with an ordinal. Only a packet that has a greater ordinal than all previously received datagrams
-//!      associated with that entity is used. Older datagrams are dropped.
+//!     with an ordinal. Only a packet that has a greater ordinal than all previously received datagrams
+//!     associated with that entity is used. Older datagrams are dropped.
 //!   - Other packets sent for specific entities are sent on a stream belonging to that entity.
+//!     The stream is finished once the entity is removed or its chunk unloaded.
+//!   - Tab list updates for existing players are sent on a stream belonging to that player.
+//!     (Packets updating several players are split up first.)
 //!   - Packets updating blocks or chunks are sent on a stream belonging to that chunk.
+//!     The stream is finished once the chunk is unloaded.
 //!   - Packets pertaining to chat use the chat stream.
 //!   - The following packets use a new stream for each packet (i.e., reliable unordered):
 //!       - Keepalives
@@ -26,31 +30,42 @@
 //!   - All other packets use the shared "miscellaneous" stream.
 
 use crate::{
+    clock::SharedClock,
     entity_id::EntityId,
+    idle_cache::IdleCache,
+    packet_translation::PacketTranslator,
     position::ChunkPosition,
     protocol::{
+        optimized_codec::CodecVersion,
         packet,
         packet::{
-            client, server,
-            server::play::EntityAnimation,
-            side,
+            client, server, side,
             side::{Client, Server},
             state,
         },
     },
     sequence::SequenceKey,
-    stream::SendStreamHandle,
+    stream::{SendStreamHandle, StreamDiagnostics},
     stream_priority,
 };
-use mini_moka::sync::Cache;
-use quinn::Connection;
-use std::time::Duration;
+use quinn::{Connection, StreamId};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use strum::IntoEnumIterator;
 
 /// Tells the proxy how to transmit a packet.
 pub enum Allocation<Side: packet::Side> {
     /// The packet will be sent on the given stream
     /// (reliable, ordered only with respect to that stream)
-    Stream(SendStreamHandle<Side, state::Play>),
+    Stream(SendStreamHandle<Side, state::Play>, AllocationClass),
     /// The packet should be sent as an unreliable datagram
     /// on the connection, with an ordinal allocated from
     /// the given sequence.
@@ -58,6 +73,101 @@ pub enum Allocation<Side: packet::Side> {
     UnreliableSequence(SequenceKey),
 }
 
+impl<Side: packet::Side> Allocation<Side> {
+    pub fn class(&self) -> AllocationClass {
+        match self {
+            Allocation::Stream(_, class) => *class,
+            Allocation::UnreliableSequence(_) => AllocationClass::EntityMovement,
+        }
+    }
+}
+
+/// Broad category of an allocation, used for diagnostics.
+#[derive(
+    Copy, Clone, Debug, PartialEq, Eq, Hash, strum::AsRefStr, strum::IntoStaticStr, strum::EnumIter,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum AllocationClass {
+    Chat,
+    /// Reliable unordered: a new stream for each packet.
+    Keepalive,
+    Chunks,
+    BlockUpdates,
+    Entity,
+    Player,
+    /// Unreliable sequenced datagrams.
+    EntityMovement,
+    Misc,
+}
+
+impl AllocationClass {
+    /// Whether a packet of this class can be dropped if sending it fails,
+    /// without the connection being lost. Such packets are sent on a stream
+    /// of their own, on a per-entity, per-chunk or per-player stream that
+    /// is reopened for the next packet, or as a datagram.
+    ///
+    /// Packets on the shared chat, misc and chunk streams cannot be
+    /// dropped without the game state diverging.
+    pub fn is_recoverable(self) -> bool {
+        matches!(
+            self,
+            AllocationClass::Keepalive
+                | AllocationClass::BlockUpdates
+                | AllocationClass::Entity
+                | AllocationClass::Player
+                | AllocationClass::EntityMovement
+        )
+    }
+}
+
+/// Counts the allocations made by a `StreamAllocator`, per class.
+#[derive(Debug, Default)]
+pub struct AllocationCounters {
+    counts: [AtomicU64; 8],
+    dropped: [AtomicU64; 8],
+}
+
+impl AllocationCounters {
+    fn record(&self, class: AllocationClass) {
+        self.counts[class as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_dropped(&self, class: AllocationClass) {
+        self.dropped[class as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, class: AllocationClass) -> u64 {
+        self.counts[class as usize].load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of packets of a class dropped because sending them failed.
+    pub fn dropped(&self, class: AllocationClass) -> u64 {
+        self.dropped[class as usize].load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of dropped packets for each class.
+    pub fn dropped_summary(&self) -> AllocationSummary {
+        AllocationSummary(
+            AllocationClass::iter()
+                .map(|class| (class.as_ref().to_owned(), self.dropped(class)))
+                .collect(),
+        )
+    }
+
+    /// Gets the number of allocations for each class.
+    pub fn summary(&self) -> AllocationSummary {
+        AllocationSummary(
+            AllocationClass::iter()
+                .map(|class| (class.as_ref().to_owned(), self.get(class)))
+                .collect(),
+        )
+    }
+}
+
+/// Number of packets allocated to each `AllocationClass`.
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocationSummary(BTreeMap<String, u64>);
+
 /// Stores all QUIC streams used for _transmitting_ packets on a connection.
 ///
 /// Note that this is only used during the Play connection state. During the login/setup states,
@@ -71,7 +181,11 @@ pub enum Allocation<Side: packet::Side> {
 /// (e.g., entity ID or chunk position). These streams can become stale
 /// after their game entities are no longer alive / in sight.
 ///
-/// To avoid a memory leak, streams can be automatically dropped
+/// Streams are dropped (and thus finished, once their pending packets
+/// are sent) as soon as `RemoveEntities` or `UnloadChunk` indicates
+/// they will receive no further updates.
+///
+/// To avoid a memory leak in other cases, streams are also dropped
 /// after being unused for `STREAM_IDLE_DURATION`. Technically,
 /// this allows packets on the same logical stream to be received
 /// out of order (if the stream corresponding to that entity was re-created
@@ -79,13 +193,19 @@ pub enum Allocation<Side: packet::Side> {
 /// rare for sufficiently high idle duration.
 pub struct StreamAllocator<Side: packet::Side> {
     connection: Connection,
+    codec_version: CodecVersion,
 
-    entity_streams: Cache<EntityId, SendStreamHandle<Side, state::Play>>,
-    block_update_streams: Cache<ChunkPosition, SendStreamHandle<Side, state::Play>>,
+    entity_streams: IdleCache<EntityId, SendStreamHandle<Side, state::Play>>,
+    player_streams: IdleCache<u128, SendStreamHandle<Side, state::Play>>,
+    block_update_streams: IdleCache<ChunkPosition, SendStreamHandle<Side, state::Play>>,
 
     chunk_stream: SendStreamHandle<Side, state::Play>,
     chat_stream: SendStreamHandle<Side, state::Play>,
     misc_stream: SendStreamHandle<Side, state::Play>,
+
+    counters: Arc<AllocationCounters>,
+    /// Whether entity movement is sent as unreliable datagrams.
+    datagrams: bool,
 }
 
 /// Minimum duration a stream must be kept with no activity.
@@ -95,35 +215,183 @@ impl<Side> StreamAllocator<Side>
 where
     Side: packet::Side + Clone,
 {
-    pub async fn new(connection: &Connection) -> anyhow::Result<Self> {
-        let chat_stream =
-            SendStreamHandle::open(connection, "chat", stream_priority::CHAT_STREAM).await?;
-        let misc_stream =
-            SendStreamHandle::open(connection, "misc", stream_priority::MISC_STREAM).await?;
+    pub async fn new(
+        connection: &Connection,
+        codec_version: CodecVersion,
+        counters: Arc<AllocationCounters>,
+        clock: SharedClock,
+    ) -> anyhow::Result<Self> {
+        let chat_stream = SendStreamHandle::open(
+            connection,
+            codec_version,
+            "chat",
+            stream_priority::CHAT_STREAM,
+        )
+        .await?;
+        let misc_stream = SendStreamHandle::open(
+            connection,
+            codec_version,
+            "misc",
+            stream_priority::MISC_STREAM,
+        )
+        .await?;
         let chunk_stream =
-            SendStreamHandle::open(connection, "chunks", stream_priority::DEFAULT).await?;
+            SendStreamHandle::open(connection, codec_version, "chunks", stream_priority::BULK)
+                .await?;
 
-        let entity_streams = Cache::builder().time_to_idle(STREAM_IDLE_DURATION).build();
-        let block_update_streams = Cache::builder().time_to_idle(STREAM_IDLE_DURATION).build();
+        let entity_streams = IdleCache::new(Arc::clone(&clock), STREAM_IDLE_DURATION);
+        let player_streams = IdleCache::new(Arc::clone(&clock), STREAM_IDLE_DURATION);
+        let block_update_streams = IdleCache::new(clock, STREAM_IDLE_DURATION);
         Ok(Self {
             connection: connection.clone(),
+            codec_version,
             entity_streams,
+            player_streams,
             block_update_streams,
             chunk_stream,
             chat_stream,
             misc_stream,
+            counters,
+            datagrams: true,
         })
     }
 
+    /// Sends entity movement on the streams of the entities, ordered
+    /// with their other updates, rather than as unreliable datagrams.
+    pub fn disable_datagrams(&mut self) {
+        self.datagrams = false;
+    }
+
+    fn allocate(
+        &self,
+        class: AllocationClass,
+        stream: &SendStreamHandle<Side, state::Play>,
+    ) -> Allocation<Side> {
+        self.counters.record(class);
+        Allocation::Stream(stream.clone(), class)
+    }
+
+    /// Allocates a new stream for a single packet (reliable unordered).
+    async fn allocate_new_stream(&self) -> anyhow::Result<Allocation<Side>> {
+        let new_stream = SendStreamHandle::open(
+            &self.connection,
+            self.codec_version,
+            "keepalive",
+            stream_priority::KEEPALIVE,
+        )
+        .await?;
+        Ok(self.allocate(AllocationClass::Keepalive, &new_stream))
+    }
+
+    /// Opens a new stream for the second copy of a packet
+    /// sent redundantly (see the `redundancy` module).
+    /// Not counted as an allocation.
+    pub async fn open_redundant_stream(
+        &self,
+    ) -> anyhow::Result<SendStreamHandle<Side, state::Play>> {
+        SendStreamHandle::open(
+            &self.connection,
+            self.codec_version,
+            "redundant",
+            stream_priority::KEEPALIVE,
+        )
+        .await
+    }
+
+    async fn allocate_block_update_stream(
+        &self,
+        chunk: ChunkPosition,
+    ) -> anyhow::Result<Allocation<Side>> {
+        let stream = self.block_update_stream(chunk).await?;
+        Ok(self.allocate(AllocationClass::BlockUpdates, &stream))
+    }
+
+    async fn allocate_entity_stream(
+        &self,
+        entity_id: EntityId,
+    ) -> anyhow::Result<Allocation<Side>> {
+        let stream = self.entity_stream(entity_id).await?;
+        Ok(self.allocate(AllocationClass::Entity, &stream))
+    }
+
+    async fn allocate_player_stream(&self, uuid: u128) -> anyhow::Result<Allocation<Side>> {
+        let stream = self.player_stream(uuid).await?;
+        Ok(self.allocate(AllocationClass::Player, &stream))
+    }
+
+    async fn allocate_sequence(&self, key: SequenceKey) -> anyhow::Result<Allocation<Side>> {
+        if !self.datagrams {
+            return match key {
+                SequenceKey::EntityPosition(entity_id) | SequenceKey::EntityVelocity(entity_id) => {
+                    self.allocate_entity_stream(entity_id).await
+                }
+                SequenceKey::ThePlayerPosition => {
+                    Ok(self.allocate(AllocationClass::Misc, &self.misc_stream))
+                }
+            };
+        }
+        self.counters.record(AllocationClass::EntityMovement);
+        Ok(Allocation::UnreliableSequence(key))
+    }
+
+    /// Records a packet dropped because sending it failed, and drops the
+    /// stream it failed on, if any, so that the next packet reopens it.
+    pub fn recover_dropped_packet(&self, class: AllocationClass, stream: Option<StreamId>) {
+        self.counters.record_dropped(class);
+        if let Some(id) = stream {
+            self.entity_streams.retain(|_, handle| handle.id() != id);
+            self.player_streams.retain(|_, handle| handle.id() != id);
+            self.block_update_streams
+                .retain(|_, handle| handle.id() != id);
+        }
+    }
+
+    /// Describes the activity of the shared chat, misc and chunk streams.
+    pub fn shared_stream_diagnostics(&self) -> Vec<StreamDiagnostics> {
+        [&self.chat_stream, &self.misc_stream, &self.chunk_stream]
+            .into_iter()
+            .map(SendStreamHandle::diagnostics)
+            .collect()
+    }
+
+    /// Replaces the misc stream with a new one, for when it appears wedged.
+    ///
+    /// Packets still queued on the old stream are sent unordered with
+    /// respect to those on the new one.
+    pub async fn reopen_misc_stream(&mut self) -> anyhow::Result<()> {
+        self.misc_stream = SendStreamHandle::open(
+            &self.connection,
+            self.codec_version,
+            "misc",
+            stream_priority::MISC_STREAM,
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Drops the streams of removed entities.
+    fn evict_entity_streams(&self, entities: impl IntoIterator<Item = EntityId>) {
+        for entity in entities {
+            self.entity_streams.remove(&entity);
+        }
+    }
+
+    /// Drops the streams of an unloaded chunk and of the entities in it.
+    fn evict_chunk_streams(&self, chunk: ChunkPosition, translator: &PacketTranslator) {
+        self.block_update_streams.remove(&chunk);
+        self.evict_entity_streams(translator.entities_in_chunk(chunk));
+    }
+
     async fn block_update_stream(
         &self,
         chunk: ChunkPosition,
     ) -> anyhow::Result<SendStreamHandle<Side, state::Play>> {
         match self.block_update_streams.get(&chunk) {
-            Some(stream) => Ok(stream.clone()),
+            Some(stream) => Ok(stream),
             None => {
                 let stream = SendStreamHandle::open(
                     &self.connection,
+                    self.codec_version,
                     format!("{chunk:?}"),
                     stream_priority::GAME_UPDATES,
                 )
@@ -134,15 +402,36 @@ where
         }
     }
 
+    async fn player_stream(
+        &self,
+        uuid: u128,
+    ) -> anyhow::Result<SendStreamHandle<Side, state::Play>> {
+        match self.player_streams.get(&uuid) {
+            Some(stream) => Ok(stream),
+            None => {
+                let stream = SendStreamHandle::open(
+                    &self.connection,
+                    self.codec_version,
+                    "player",
+                    stream_priority::MISC_STREAM,
+                )
+                .await?;
+                self.player_streams.insert(uuid, stream.clone());
+                Ok(stream)
+            }
+        }
+    }
+
     async fn entity_stream(
         &self,
         entity_id: EntityId,
     ) -> anyhow::Result<SendStreamHandle<Side, state::Play>> {
         match self.entity_streams.get(&entity_id) {
-            Some(stream) => Ok(stream.clone()),
+            Some(stream) => Ok(stream),
             None => {
                 let stream = SendStreamHandle::open(
                     &self.connection,
+                    self.codec_version,
                     "entity",
                     stream_priority::GAME_UPDATES,
                 )
@@ -156,37 +445,36 @@ where
 
 /// `StreamAllocator` implements this for both `Side = Client` and `Side = Server`
 /// (the only two `Side` implementors).
-pub trait AllocateStream<Side: packet::Side + 'static> {
+pub trait AllocateStream<Side: packet::Side + 'static>: Send {
     /// Allocates a stream for the given packet.
-    async fn allocate_stream_for(
+    ///
+    /// Streams and sequences of entities are keyed by
+    /// `PacketTranslator::entity_key`.
+    fn allocate_stream_for(
         &mut self,
         packet: &Side::SendPacket<state::Play>,
-    ) -> anyhow::Result<Allocation<Side>>;
+        translator: &PacketTranslator,
+    ) -> impl Future<Output = anyhow::Result<Allocation<Side>>> + Send;
 }
 
 impl AllocateStream<side::Client> for StreamAllocator<side::Client> {
     async fn allocate_stream_for(
         &mut self,
         packet: &client::play::Packet,
+        _translator: &PacketTranslator,
     ) -> anyhow::Result<Allocation<Client>> {
         use client::play::Packet;
 
         let allocation = match packet {
             Packet::ChatCommand(_) | Packet::ChatMessage(_) | Packet::AcknowledgeMessage(_) => {
-                Allocation::Stream(self.chat_stream.clone())
+                self.allocate(AllocationClass::Chat, &self.chat_stream)
             }
 
             Packet::KeepAlive(_) | Packet::PingRequest(_) | Packet::Pong(_) => {
-                let new_stream = SendStreamHandle::open(
-                    &self.connection,
-                    "keepalive",
-                    stream_priority::KEEPALIVE,
-                )
-                .await?;
-                Allocation::Stream(new_stream)
+                self.allocate_new_stream().await?
             }
 
-            _ => Allocation::Stream(self.misc_stream.clone()),
+            _ => self.allocate(AllocationClass::Misc, &self.misc_stream),
         };
         Ok(allocation)
     }
@@ -196,8 +484,10 @@ impl AllocateStream<side::Server> for StreamAllocator<side::Server> {
     async fn allocate_stream_for(
         &mut self,
         packet: &server::play::Packet,
+        translator: &PacketTranslator,
     ) -> anyhow::Result<Allocation<Server>> {
         use server::play::*;
+        let entity_key = |entity_id: &i32| translator.entity_key(EntityId::new(*entity_id));
         let allocation = match packet {
             // Chat stream
             Packet::ChatSuggestions(_)
@@ -211,7 +501,9 @@ impl AllocateStream<side::Server> for StreamAllocator<side::Server> {
             | Packet::SetActionBarText(_)
             | Packet::SetSubtitleText(_)
             | Packet::SetTitleText(_)
-            | Packet::SetTitleAnimationTimes(_) => Allocation::Stream(self.chat_stream.clone()),
+            | Packet::SetTitleAnimationTimes(_) => {
+                self.allocate(AllocationClass::Chat, &self.chat_stream)
+            }
 
             // New stream (reliable unordered)
             Packet::Particle(_)
@@ -221,30 +513,33 @@ impl AllocateStream<side::Server> for StreamAllocator<side::Server> {
             | Packet::SetHealth(_)
             | Packet::KeepAlive(_)
             | Packet::Ping(_)
-            | Packet::PingResponse(_) => {
-                let new_stream = SendStreamHandle::open(
-                    &self.connection,
-                    "keepalive",
-                    stream_priority::KEEPALIVE,
-                )
-                .await?;
-                Allocation::Stream(new_stream)
-            }
+            | Packet::PingResponse(_) => self.allocate_new_stream().await?,
 
             // Chunk stream
-            Packet::UnloadChunk(_)
-            | Packet::ChunkAndLightData(_)
+            Packet::UnloadChunk(UnloadChunk { chunk_x, chunk_z }) => {
+                self.evict_chunk_streams(
+                    ChunkPosition {
+                        x: *chunk_x,
+                        z: *chunk_z,
+                    },
+                    translator,
+                );
+                self.allocate(AllocationClass::Chunks, &self.chunk_stream)
+            }
+            Packet::ChunkAndLightData(_)
             | Packet::UpdateLight(_)
             | Packet::ChunkBatchFinished(_)
             | Packet::ChunkBatchStart(_)
-            | Packet::ChunkBiomes(_) => Allocation::Stream(self.chunk_stream.clone()),
+            | Packet::ChunkBiomes(_) => self.allocate(AllocationClass::Chunks, &self.chunk_stream),
 
             // Block update streams (ordered on chunk)
             Packet::UpdateSectionBlocks(packet) => {
-                Allocation::Stream(self.block_update_stream(packet.chunk_position()).await?)
+                self.allocate_block_update_stream(packet.chunk_position())
+                    .await?
             }
             Packet::BlockUpdate(packet) => {
-                Allocation::Stream(self.block_update_stream(packet.position.chunk()).await?)
+                self.allocate_block_update_stream(packet.position.chunk())
+                    .await?
             }
 
             // Entity update streams (ordered on entity ID)
@@ -254,12 +549,20 @@ impl AllocateStream<side::Server> for StreamAllocator<side::Server> {
             | Packet::SetHeadRotation(SetHeadRotation { entity_id, .. })
             | Packet::EntityEffect(EntityEffect { entity_id, .. })
             | Packet::DamageEvent(DamageEvent { entity_id, .. }) => {
-                Allocation::Stream(self.entity_stream(EntityId::new(*entity_id)).await?)
+                self.allocate_entity_stream(entity_key(entity_id)).await?
             }
             Packet::RemoveEntities(RemoveEntities { entities, .. }) if entities.len() == 1 => {
                 // TODO: cover case where entities.len() > 1, likely by splitting the packet into multiple
                 // RemoveEntities messages.
-                Allocation::Stream(self.entity_stream(EntityId::new(entities[0])).await?)
+                let allocation = self
+                    .allocate_entity_stream(entity_key(&entities[0]))
+                    .await?;
+                self.evict_entity_streams([entity_key(&entities[0])]);
+                allocation
+            }
+            Packet::RemoveEntities(RemoveEntities { entities, .. }) => {
+                self.evict_entity_streams(entities.iter().map(entity_key));
+                self.allocate(AllocationClass::Misc, &self.misc_stream)
             }
 
             // Unreliable entity datagrams
@@ -270,19 +573,27 @@ impl AllocateStream<side::Server> for StreamAllocator<side::Server> {
             })
             | Packet::UpdateEntityPosition(UpdateEntityPosition { entity_id, .. })
             | Packet::TeleportEntity(TeleportEntity { entity_id, .. }) => {
-                Allocation::UnreliableSequence(SequenceKey::EntityPosition(EntityId::new(
-                    *entity_id,
-                )))
+                self.allocate_sequence(SequenceKey::EntityPosition(entity_key(entity_id)))
+                    .await?
             }
 
             Packet::SetEntityVelocity(SetEntityVelocity { entity_id, .. }) => {
-                Allocation::UnreliableSequence(SequenceKey::EntityVelocity(EntityId::new(
-                    *entity_id,
-                )))
+                self.allocate_sequence(SequenceKey::EntityVelocity(entity_key(entity_id)))
+                    .await?
+            }
+
+            // Player streams (ordered on player)
+            // Additions and removals stay on the misc stream, in order with
+            // the player's SpawnEntity: the client ignores spawns of players
+            // it does not know.
+            Packet::PlayerInfoUpdate(PlayerInfoUpdate {
+                actions, players, ..
+            }) if players.len() == 1 && !actions.contains(PlayerInfoActions::ADD_PLAYER) => {
+                self.allocate_player_stream(players[0].uuid).await?
             }
 
             // Default case - shared stream
-            _ => Allocation::Stream(self.misc_stream.clone()),
+            _ => self.allocate(AllocationClass::Misc, &self.misc_stream),
         };
         Ok(allocation)
     }
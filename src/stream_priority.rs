@@ -1,4 +1,18 @@
 //! Constants used for different stream priorities.
+//!
+//! quinn sends pending data, including retransmissions of lost data,
+//! from higher-priority streams first. So this ordering also decides
+//! what is retransmitted first after a loss: keepalives are repaired
+//! first, then game updates, then chat and other streams, and bulk
+//! chunk data comes last.
+//!
+//! The priorities are fixed. They are not adapted to the connection at
+//! run time, and their effect on retransmissions is not measured: the
+//! `loadtest` harness does not simulate packet loss.
+
+/// Chunk data is large and not latency critical,
+/// so it yields to every other stream.
+pub const BULK: i32 = -5;
 
 pub const DEFAULT: i32 = 0;
 
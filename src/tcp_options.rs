@@ -0,0 +1,77 @@
+//! Socket options of the TCP connections carrying vanilla packets: the
+//! gateway's connections to destination servers, and the connection from
+//! the game to the client's local listener.
+
+use serde::{Deserialize, Serialize};
+use socket2::{SockRef, TcpKeepalive};
+use std::{io, time::Duration};
+use tokio::net::{TcpSocket, TcpStream};
+
+/// Socket options of a TCP connection. Unset options are left
+/// at the system's defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "gateway",
+    derive(schemars::JsonSchema),
+    schemars(deny_unknown_fields)
+)]
+#[serde(default)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm, so that packets are sent without
+    /// waiting for the acknowledgement of earlier ones.
+    pub nodelay: bool,
+    /// Idle time after which keepalive probes are sent, and the interval
+    /// between them where the platform supports it, so that dead peers
+    /// are noticed. No keepalives if unset.
+    pub keepalive_secs: Option<u64>,
+    /// Size of the kernel send buffer, e.g. larger for high-throughput
+    /// chunk streaming over high-latency links.
+    pub send_buffer_bytes: Option<u32>,
+    /// Size of the kernel receive buffer.
+    pub recv_buffer_bytes: Option<u32>,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_secs: None,
+            send_buffer_bytes: None,
+            recv_buffer_bytes: None,
+        }
+    }
+}
+
+impl TcpOptions {
+    /// Applies the buffer sizes to a socket before it connects or listens,
+    /// so that the TCP window scale negotiated accounts for them.
+    pub fn configure_socket(&self, socket: &TcpSocket) -> io::Result<()> {
+        if let Some(size) = self.send_buffer_bytes {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_bytes {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the remaining options to a connected stream.
+    pub fn configure_stream(&self, stream: &TcpStream) -> io::Result<()> {
+        stream.set_nodelay(self.nodelay)?;
+        if let Some(secs) = self.keepalive_secs {
+            let time = Duration::from_secs(secs);
+            let keepalive = TcpKeepalive::new().with_time(time);
+            #[cfg(any(
+                target_os = "linux",
+                target_os = "android",
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "freebsd",
+                windows
+            ))]
+            let keepalive = keepalive.with_interval(time);
+            SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,296 @@
+//! Protocol test vectors: fixed packets and messages, encoded as they
+//! appear on the wire by the vanilla codec, the optimized codec,
+//! the control stream and sequenced datagrams.
+//!
+//! The vectors are committed under `test-vectors/` in the repository, so
+//! that other implementations of the protocol (such as a JVM-side codec
+//! in the client mod) can check their output against the same bytes.
+//! `minecraft-quic-proxy test-vectors --check` regenerates them and
+//! reports any that no longer match, which catches accidental changes
+//! to the wire format.
+//!
+//! Payloads are kept below the compression thresholds, since
+//! compressed output depends on the zlib and zstd implementations.
+
+use crate::{
+    control_stream,
+    entity_id::EntityId,
+    protocol::{
+        optimized_codec::{CodecVersion, OptimizedCodec},
+        packet::{
+            client::{
+                self,
+                handshake::{Handshake, NextState},
+            },
+            server, side, state, ProtocolState, Side,
+        },
+        vanilla_codec::{CompressionThreshold, EncryptionKey, VanillaCodec},
+        PROTOCOL_VERSION,
+    },
+    sequence::{encode_datagram, SequenceKey},
+};
+use anyhow::{bail, Context};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Write, fs, path::Path};
+
+/// A named encoding, as lowercase hex.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TestVector {
+    pub name: String,
+    pub hex: String,
+}
+
+/// The contents of one file of test vectors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TestVectorFile {
+    pub description: String,
+    pub protocol_version: i32,
+    pub vectors: Vec<TestVector>,
+}
+
+impl TestVectorFile {
+    fn new(description: &str, vectors: Vec<(&str, Vec<u8>)>) -> Self {
+        Self {
+            description: description.to_owned(),
+            protocol_version: PROTOCOL_VERSION,
+            vectors: vectors
+                .into_iter()
+                .map(|(name, bytes)| TestVector {
+                    name: name.to_owned(),
+                    hex: to_hex(&bytes),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Generates the test vectors, keyed by file name.
+pub fn generate() -> anyhow::Result<Vec<(&'static str, TestVectorFile)>> {
+    Ok(vec![
+        (
+            "packets.json",
+            TestVectorFile::new(
+                "Minecraft packets framed by the vanilla codec (as sent over TCP) \
+                 and by the optimized codec (as sent over QUIC streams).",
+                packets()?,
+            ),
+        ),
+        (
+            "control.json",
+            TestVectorFile::new(
+                "Control stream messages: bincode with variable-length little-endian \
+                 integers, framed with a 4-byte big-endian length prefix.",
+                control_stream::sample_messages()?,
+            ),
+        ),
+        (
+            "datagrams.json",
+            TestVectorFile::new(
+                "Sequenced datagrams: a bincode header (sequence key and ordinal) \
+                 followed by the packet without a length prefix.",
+                datagrams()?,
+            ),
+        ),
+    ])
+}
+
+/// Writes the test vectors to `dir`, creating it if needed.
+pub fn write(dir: &Path) -> anyhow::Result<()> {
+    fs::create_dir_all(dir)?;
+    for (file_name, file) in generate()? {
+        let path = dir.join(file_name);
+        let mut json = serde_json::to_string_pretty(&file)?;
+        json.push('\n');
+        fs::write(&path, json).with_context(|| format!("failed to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Checks the test vectors in `dir` against freshly generated ones,
+/// failing with the names of those that differ or are missing.
+pub fn verify(dir: &Path) -> anyhow::Result<()> {
+    let mut mismatches = Vec::new();
+    for (file_name, expected) in generate()? {
+        let path = dir.join(file_name);
+        let actual: TestVectorFile = serde_json::from_str(
+            &fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?,
+        )
+        .with_context(|| format!("failed to parse {}", path.display()))?;
+        for vector in &expected.vectors {
+            match actual.vectors.iter().find(|v| v.name == vector.name) {
+                Some(v) if v.hex == vector.hex => {}
+                Some(_) => mismatches.push(format!("{file_name}: {} differs", vector.name)),
+                None => mismatches.push(format!("{file_name}: {} is missing", vector.name)),
+            }
+        }
+        for vector in &actual.vectors {
+            if !expected.vectors.iter().any(|v| v.name == vector.name) {
+                mismatches.push(format!(
+                    "{file_name}: {} is no longer generated",
+                    vector.name
+                ));
+            }
+        }
+    }
+    if !mismatches.is_empty() {
+        bail!(
+            "test vectors do not match (regenerate them if the change is intended):\n{}",
+            mismatches.join("\n")
+        );
+    }
+    Ok(())
+}
+
+fn packets() -> anyhow::Result<Vec<(&'static str, Vec<u8>)>> {
+    let handshake = client::handshake::Packet::Handshake(Handshake {
+        protocol_version: PROTOCOL_VERSION as u32,
+        server_address: "localhost".into(),
+        server_port: 25565,
+        next_state: NextState::Login,
+    });
+    let keep_alive = server::play::Packet::KeepAlive(server::play::KeepAlive {
+        ignored_data: 0x0123_4567_89ab_cdef_u64.to_be_bytes().to_vec(),
+    });
+    let teleport_entity = server::play::Packet::TeleportEntity(teleport_entity());
+
+    let mut compressed = vanilla::<side::Server, state::Play>();
+    compressed.enable_compression(CompressionThreshold::new(256));
+    let mut encrypted = vanilla::<side::Server, state::Play>();
+    encrypted.enable_encryption(EncryptionKey::new(std::array::from_fn(|i| i as u8)));
+
+    Ok(vec![
+        (
+            "vanilla/handshake",
+            vanilla::<side::Client, state::Handshake>().encode_packet(&handshake)?,
+        ),
+        (
+            "vanilla/play/keep_alive",
+            vanilla::<side::Server, state::Play>().encode_packet(&keep_alive)?,
+        ),
+        (
+            "vanilla/play/keep_alive_below_compression_threshold",
+            compressed.encode_packet(&keep_alive)?,
+        ),
+        (
+            "vanilla/play/keep_alive_encrypted",
+            encrypted.encode_packet(&keep_alive)?,
+        ),
+        (
+            "optimized_v1/play/keep_alive",
+            optimized::<side::Server, state::Play>().encode_packet(&keep_alive)?,
+        ),
+        (
+            "optimized_v1/play/teleport_entity",
+            optimized::<side::Server, state::Play>().encode_packet(&teleport_entity)?,
+        ),
+    ])
+}
+
+fn datagrams() -> anyhow::Result<Vec<(&'static str, Vec<u8>)>> {
+    let teleport_entity = server::play::Packet::TeleportEntity(teleport_entity());
+    let player_position =
+        client::play::Packet::SetPlayerPosition(client::play::SetPlayerPosition {
+            ignored_data: [
+                1.5_f64.to_be_bytes(),
+                64.0_f64.to_be_bytes(),
+                (-2.5_f64).to_be_bytes(),
+            ]
+            .concat()
+            .into_iter()
+            .chain([1])
+            .collect(),
+        });
+    Ok(vec![
+        (
+            "entity_position/teleport_entity",
+            encode_datagram(
+                &teleport_entity,
+                SequenceKey::EntityPosition(EntityId::new(42)),
+                7,
+            )?,
+        ),
+        (
+            "the_player_position/set_player_position",
+            encode_datagram(&player_position, SequenceKey::ThePlayerPosition, 1000)?,
+        ),
+    ])
+}
+
+fn teleport_entity() -> server::play::TeleportEntity {
+    server::play::TeleportEntity {
+        entity_id: 42,
+        x: 1.5,
+        y: 64.0,
+        z: -2.5,
+        yaw: 90.0,
+        pitch: -45.0,
+        on_ground: true,
+    }
+}
+
+fn vanilla<S: Side, State: ProtocolState>() -> VanillaCodec<S, State> {
+    VanillaCodec::new()
+}
+
+fn optimized<S: Side, State: ProtocolState>() -> OptimizedCodec<S, State> {
+    OptimizedCodec::new(CodecVersion::V1)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_hex(hex: &str) -> Vec<u8> {
+        assert_eq!(hex.len() % 2, 0, "odd-length hex {hex:?}");
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn committed(file_name: &str) -> TestVectorFile {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-vectors")
+            .join(file_name);
+        serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn encoders_match_committed_vectors() {
+        for (file_name, generated) in generate().unwrap() {
+            let committed = committed(file_name);
+            assert_eq!(committed.protocol_version, generated.protocol_version);
+
+            let names = |file: &TestVectorFile| {
+                file.vectors
+                    .iter()
+                    .map(|v| v.name.clone())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(names(&committed), names(&generated), "{file_name}");
+
+            for (committed, generated) in committed.vectors.iter().zip(&generated.vectors) {
+                assert_eq!(
+                    from_hex(&generated.hex),
+                    from_hex(&committed.hex),
+                    "{file_name}: {}",
+                    committed.name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn verify_accepts_committed_vectors() {
+        verify(&Path::new(env!("CARGO_MANIFEST_DIR")).join("test-vectors")).unwrap();
+    }
+}
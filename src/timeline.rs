@@ -0,0 +1,163 @@
+//! Timestamped events of a connection, recorded on both the client
+//! and the gateway so they can be merged into a single ordered timeline
+//! when debugging desyncs.
+//!
+//! Timestamps are on the gateway's clock: the client estimates its clock
+//! offset to the gateway with a time sync exchange on the control stream
+//! and applies it to every event it records.
+
+use crate::{
+    phase::Phase,
+    protocol::packet::{state_name, ProtocolState},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Maximum number of events retained per timeline.
+const MAX_EVENTS: usize = 1024;
+/// Maximum number of states retained in a `StateHistory`.
+const MAX_STATE_HISTORY: usize = 64;
+
+/// The endpoint that recorded an event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimelineSource {
+    Client,
+    Gateway,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum TimelineEventKind {
+    /// A packet was sent over QUIC.
+    PacketSent { packet: String },
+    /// A packet was received over QUIC.
+    PacketReceived { packet: String },
+    /// The QUIC side of the connection switched protocol state.
+    StateSwitched { state: String },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimelineEvent {
+    /// Microseconds since the Unix epoch, on the gateway's clock.
+    pub timestamp_micros: i64,
+    pub source: TimelineSource,
+    #[serde(flatten)]
+    pub kind: TimelineEventKind,
+}
+
+/// The protocol states the QUIC side of a connection switched through.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct StateHistory {
+    /// The most recent states switched to, oldest first.
+    pub states: VecDeque<String>,
+    /// Total number of switches, including those no longer in `states`.
+    pub switches: u64,
+    /// Switches from the Play to the Configuration state.
+    pub reconfigurations: u64,
+}
+
+/// A bounded history of the most recent events on one endpoint.
+#[derive(Debug)]
+pub struct Timeline {
+    source: TimelineSource,
+    /// Added to local timestamps to get the gateway's time.
+    clock_offset_micros: AtomicI64,
+    events: Mutex<VecDeque<TimelineEvent>>,
+    /// Kept apart from `events`, so that it is not displaced by packets.
+    state_history: Mutex<(Option<Phase>, StateHistory)>,
+}
+
+impl Timeline {
+    pub fn new(source: TimelineSource) -> Self {
+        Self {
+            source,
+            clock_offset_micros: AtomicI64::new(0),
+            events: Mutex::new(VecDeque::new()),
+            state_history: Mutex::default(),
+        }
+    }
+
+    pub fn set_clock_offset_micros(&self, offset: i64) {
+        self.clock_offset_micros.store(offset, Ordering::Relaxed);
+    }
+
+    pub fn record(&self, kind: TimelineEventKind) {
+        let event = TimelineEvent {
+            timestamp_micros: unix_micros() + self.clock_offset_micros.load(Ordering::Relaxed),
+            source: self.source,
+            kind,
+        };
+        let mut events = self.events.lock().unwrap();
+        if events.len() == MAX_EVENTS {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    pub(crate) fn record_state_switch<State: ProtocolState>(&self) {
+        self.record(TimelineEventKind::StateSwitched {
+            state: state_name::<State>().to_owned(),
+        });
+        let mut state_history = self.state_history.lock().unwrap();
+        let (phase, history) = &mut *state_history;
+        if *phase == Some(Phase::Play) && State::PHASE == Phase::Configuration {
+            history.reconfigurations += 1;
+        }
+        *phase = Some(State::PHASE);
+        if history.states.len() == MAX_STATE_HISTORY {
+            history.states.pop_front();
+        }
+        history.states.push_back(State::PHASE.to_string());
+        history.switches += 1;
+    }
+
+    pub(crate) fn record_packet_sent(&self, packet: &impl AsRef<str>) {
+        self.record(TimelineEventKind::PacketSent {
+            packet: packet.as_ref().to_owned(),
+        });
+    }
+
+    pub(crate) fn record_packet_received(&self, packet: &impl AsRef<str>) {
+        self.record(TimelineEventKind::PacketReceived {
+            packet: packet.as_ref().to_owned(),
+        });
+    }
+
+    /// Gets the phase of the protocol state last switched to.
+    pub(crate) fn phase(&self) -> Option<Phase> {
+        self.state_history.lock().unwrap().0
+    }
+
+    pub fn state_history(&self) -> StateHistory {
+        self.state_history.lock().unwrap().1.clone()
+    }
+
+    /// Gets the retained events, oldest first.
+    pub fn events(&self) -> Vec<TimelineEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// Merges timelines from several endpoints into one, ordered by time.
+pub fn merge(timelines: impl IntoIterator<Item = Vec<TimelineEvent>>) -> Vec<TimelineEvent> {
+    let mut events: Vec<_> = timelines.into_iter().flatten().collect();
+    // Stable, so events of one endpoint with equal timestamps stay in order.
+    events.sort_by_key(|event| event.timestamp_micros);
+    events
+}
+
+/// Current time in microseconds since the Unix epoch.
+pub(crate) fn unix_micros() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as i64
+}
@@ -0,0 +1,149 @@
+//! Detection of Play sessions in which one direction silently stalls.
+//!
+//! Both directions of a healthy Play session carry packets every few
+//! seconds at most: the game reports its position, and the server sends
+//! keepalives and the time of day. When one direction delivers nothing
+//! for the stall timeout while the other keeps delivering, a stream or
+//! task is likely wedged and the player is looking at a frozen world.
+//!
+//! The proxy then logs the activity of each send stream and asks the
+//! stalled direction's sender to recover once (the QUIC transport reopens
+//! its misc stream). If the direction is still silent a timeout later,
+//! the proxy fails with `Stalled` so that the session is closed cleanly.
+
+use crate::{
+    clock::{Instant, Interval, SharedClock},
+    packet_flow::Direction,
+};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Times at which each direction last delivered a packet,
+/// shared with the proxy's send tasks.
+#[derive(Debug)]
+pub struct Deliveries {
+    clock: SharedClock,
+    serverbound: Mutex<Instant>,
+    clientbound: Mutex<Instant>,
+}
+
+impl Deliveries {
+    fn new(clock: SharedClock) -> Self {
+        let now = clock.now();
+        Self {
+            clock,
+            serverbound: Mutex::new(now),
+            clientbound: Mutex::new(now),
+        }
+    }
+
+    fn slot(&self, direction: Direction) -> &Mutex<Instant> {
+        match direction {
+            Direction::Serverbound => &self.serverbound,
+            Direction::Clientbound => &self.clientbound,
+        }
+    }
+
+    /// Records that a forwarded packet was delivered in `direction`.
+    pub fn record(&self, direction: Direction) {
+        *self.slot(direction).lock().unwrap() = self.clock.now();
+    }
+
+    fn last(&self, direction: Direction) -> Instant {
+        *self.slot(direction).lock().unwrap()
+    }
+}
+
+/// Error ending a proxy whose direction stayed stalled after recovery.
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error(
+    "no {} packets delivered for {silent_for:.1?} while the other direction was active",
+    direction.as_ref()
+)]
+pub struct Stalled {
+    pub direction: Direction,
+    pub silent_for: Duration,
+}
+
+/// Watches the deliveries of a proxy for one-directional stalls.
+#[derive(Debug)]
+pub struct StallWatchdog {
+    timeout: Duration,
+    deliveries: Arc<Deliveries>,
+    interval: Interval,
+    /// Direction a recovery was attempted for, and when,
+    /// until it delivers again.
+    recovering: Option<(Direction, Instant)>,
+}
+
+impl StallWatchdog {
+    pub fn new(clock: SharedClock, timeout: Duration) -> Self {
+        Self {
+            timeout,
+            deliveries: Arc::new(Deliveries::new(Arc::clone(&clock))),
+            interval: Interval::new(clock, timeout / 4),
+            recovering: None,
+        }
+    }
+
+    pub fn deliveries(&self) -> Arc<Deliveries> {
+        Arc::clone(&self.deliveries)
+    }
+
+    /// Waits until a direction stalls. Cancel safe.
+    pub async fn next_stall(&mut self) -> Stalled {
+        loop {
+            let now = self.interval.tick().await;
+            if let Some(stall) = self.check(now) {
+                return stall;
+            }
+        }
+    }
+
+    fn check(&mut self, now: Instant) -> Option<Stalled> {
+        if let Some((direction, since)) = self.recovering {
+            if self.deliveries.last(direction) > since {
+                tracing::info!("{} packets are delivered again", direction.as_ref());
+                self.recovering = None;
+            }
+        }
+
+        [Direction::Serverbound, Direction::Clientbound]
+            .into_iter()
+            .find_map(|direction| {
+                let other = match direction {
+                    Direction::Serverbound => Direction::Clientbound,
+                    Direction::Clientbound => Direction::Serverbound,
+                };
+                let silent_for = now.saturating_duration_since(self.silent_since(direction));
+                let other_idle = now.saturating_duration_since(self.deliveries.last(other));
+                (silent_for >= self.timeout && other_idle < self.timeout).then_some(Stalled {
+                    direction,
+                    silent_for,
+                })
+            })
+    }
+
+    /// When a direction last delivered, or a recovery was attempted for it.
+    fn silent_since(&self, direction: Direction) -> Instant {
+        let last = self.deliveries.last(direction);
+        match self.recovering {
+            Some((recovering, since)) if recovering == direction => last.max(since),
+            _ => last,
+        }
+    }
+
+    /// Whether a recovery was attempted for the stalled direction
+    /// and it has not delivered since.
+    pub fn recovery_attempted(&self, stall: &Stalled) -> bool {
+        matches!(self.recovering, Some((direction, _)) if direction == stall.direction)
+    }
+
+    /// Notes that a recovery was attempted for the stalled direction,
+    /// giving it another timeout to deliver.
+    pub fn note_recovery(&mut self, stall: &Stalled) {
+        self.recovering = Some((stall.direction, self.deliveries.clock.now()));
+    }
+}
@@ -0,0 +1,110 @@
+//! Write combining for TCP connections.
+//!
+//! Packets are buffered for a short time before being written, so that
+//! bursts of small packets result in one syscall and fewer, fuller
+//! TCP segments.
+
+use anyhow::bail;
+use std::{io, time::Duration};
+use tokio::{
+    io::AsyncWriteExt, net::tcp::OwnedWriteHalf, task, task::JoinHandle, time, time::Instant,
+};
+
+/// Packets that are written immediately, along with any packets
+/// buffered before them. These are used to measure latency, so
+/// delaying them would skew the measurement.
+const FLUSH_IMMEDIATELY: &[&str] = &["KeepAlive", "PingRequest", "Pong"];
+
+/// Number of packets that may be queued for the writer
+/// before `BatchedWriter::write` waits.
+const QUEUE_CAPACITY: usize = 64;
+
+/// Whether a packet must be written without waiting for further packets.
+pub fn flushes_immediately(packet_name: &str) -> bool {
+    FLUSH_IMMEDIATELY.contains(&packet_name)
+}
+
+struct QueuedPacket {
+    data: Vec<u8>,
+    flush: bool,
+}
+
+/// Buffers encoded packets and writes them to a TCP stream from
+/// a separate task, at the latest `max_delay` after the first packet
+/// of a batch was queued.
+pub struct BatchedWriter {
+    packets: flume::Sender<QueuedPacket>,
+    task: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl BatchedWriter {
+    pub fn new(stream: OwnedWriteHalf, max_delay: Duration, max_bytes: usize) -> Self {
+        // Batches are flushed explicitly, so Nagle's algorithm
+        // would only add further delay.
+        if let Err(e) = stream.as_ref().set_nodelay(true) {
+            tracing::debug!("Failed to disable Nagle's algorithm: {e}");
+        }
+        let (packets, receiver) = flume::bounded(QUEUE_CAPACITY);
+        let task = task::spawn(write_batches(stream, receiver, max_delay, max_bytes));
+        Self {
+            packets,
+            task: Some(task),
+        }
+    }
+
+    /// Queues an encoded packet. If `flush` is set, the batch
+    /// is written without waiting for the deadline.
+    ///
+    /// Write errors are returned by the next call after they occur.
+    pub async fn write(&mut self, data: Vec<u8>, flush: bool) -> anyhow::Result<()> {
+        if self
+            .packets
+            .send_async(QueuedPacket { data, flush })
+            .await
+            .is_ok()
+        {
+            return Ok(());
+        }
+
+        // The writer task only stops once a write has failed.
+        if let Some(task) = self.task.take() {
+            task.await??;
+        }
+        bail!("TCP writer stopped")
+    }
+}
+
+async fn write_batches(
+    mut stream: OwnedWriteHalf,
+    packets: flume::Receiver<QueuedPacket>,
+    max_delay: Duration,
+    max_bytes: usize,
+) -> io::Result<()> {
+    let mut buffer = Vec::new();
+    while let Ok(packet) = packets.recv_async().await {
+        let deadline = Instant::now() + max_delay;
+        let mut flush = packet.flush;
+        buffer.extend_from_slice(&packet.data);
+
+        while !flush && buffer.len() < max_bytes {
+            match time::timeout_at(deadline, packets.recv_async()).await {
+                Ok(Ok(packet)) => {
+                    flush = packet.flush;
+                    buffer.extend_from_slice(&packet.data);
+                }
+                Ok(Err(_)) | Err(_) => break,
+            }
+        }
+        // Packets queued in the meantime cost no extra delay.
+        while buffer.len() < max_bytes {
+            match packets.try_recv() {
+                Ok(packet) => buffer.extend_from_slice(&packet.data),
+                Err(_) => break,
+            }
+        }
+
+        stream.write_all(&buffer).await?;
+        buffer.clear();
+    }
+    Ok(())
+}